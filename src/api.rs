@@ -0,0 +1,361 @@
+use crate::attach::AttachSession;
+use crate::events::{Event, EventQuery};
+use crate::filesystem::FileChange;
+use crate::image_store::{ImageDetails, ImageSummary};
+use crate::logs::{LogEntry, LogQuery};
+use crate::network::{Network, NetworkDetails, NetworkOptions};
+use crate::process::{ExecIo, ExecOptions};
+use crate::{Container, ContainerConfig, ContainerStats, TurbineRuntime};
+use serde::{Deserialize, Serialize};
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Version of the control API spoken over the daemon socket. Bumped on any
+/// incompatible change to `Request` or `Response`.
+pub const API_VERSION: u32 = 8;
+
+pub const DEFAULT_SOCKET_PATH: &str = "/run/turbine/turbined.sock";
+
+/// How often a followed log is checked for new entries.
+const LOG_FOLLOW_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestEnvelope {
+    pub version: u32,
+    pub request: Request,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseEnvelope {
+    pub version: u32,
+    pub response: Response,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    Version,
+    Create { config: Box<ContainerConfig> },
+    Start { container_id: String },
+    Stop { container_id: String, force: bool },
+    Restart { container_id: String },
+    Remove { container_id: String, force: bool },
+    List,
+    Logs { container_id: String, query: LogQuery },
+    Exec { container_id: String, command: Vec<String>, options: ExecOptions },
+    /// Attach to the container's main process, forwarding input if `stdin` is set and
+    /// the container was created with `stdin_open`.
+    Attach { container_id: String, stdin: bool },
+    Events { query: EventQuery },
+    Stats { container_id: String },
+    Diff { container_id: String },
+    Pause { container_id: String },
+    Resume { container_id: String },
+    Deploy { name: String, image: String, port: u16 },
+    Cleanup,
+    ImageImport { source: PathBuf, tag: Option<String> },
+    ImageList,
+    ImageInspect { reference: String },
+    ImageRemove { reference: String },
+    ImagePrune,
+    NetworkCreate { options: NetworkOptions },
+    NetworkList,
+    NetworkInspect { name: String },
+    NetworkRemove { name: String },
+    NetworkConnect { network: String, container_id: String, aliases: Vec<String> },
+    NetworkDisconnect { network: String, container_id: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Response {
+    Version { api_version: u32, daemon_version: String },
+    Done,
+    ContainerId(String),
+    Container(Box<Container>),
+    Containers(Vec<Container>),
+    LogEntries(Vec<LogEntry>),
+    /// The exec'd process is running. Its stdio file descriptors, as laid out by
+    /// `ExecIo::into_fds`, are passed along with this response.
+    ExecStarted { tty: bool, stdin: bool },
+    ExitCode(i32),
+    /// Attached to a container. Its output, and its stdin if `stdin` is set, are passed
+    /// along with this response, laid out as `ExecIo::into_fds` lays out pipes.
+    Attached { stdin: bool },
+    Events(Vec<Event>),
+    Stats(ContainerStats),
+    Changes(Vec<FileChange>),
+    Images(Vec<ImageSummary>),
+    Image(Box<ImageDetails>),
+    Networks(Vec<Network>),
+    Network(Box<NetworkDetails>),
+    Messages(Vec<String>),
+    Error(String),
+}
+
+impl Request {
+    /// Whether the request only reads state. Only these are served without `turbined`,
+    /// since nothing would supervise the processes the others start once the CLI exits.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Request::Version
+                | Request::List
+                | Request::Logs { .. }
+                | Request::Events { .. }
+                | Request::Stats { .. }
+                | Request::Diff { .. }
+                | Request::ImageList
+                | Request::ImageInspect { .. }
+                | Request::NetworkList
+                | Request::NetworkInspect { .. }
+        )
+    }
+}
+
+impl RequestEnvelope {
+    pub fn new(request: Request) -> Self {
+        Self {
+            version: API_VERSION,
+            request,
+        }
+    }
+}
+
+impl ResponseEnvelope {
+    pub fn new(response: Response) -> Self {
+        Self {
+            version: API_VERSION,
+            response,
+        }
+    }
+}
+
+impl<T> From<crate::Result<T>> for Response
+where
+    T: Into<Response>,
+{
+    fn from(result: crate::Result<T>) -> Self {
+        match result {
+            Ok(value) => value.into(),
+            Err(e) => Response::Error(e.to_string()),
+        }
+    }
+}
+
+impl From<()> for Response {
+    fn from(_: ()) -> Self {
+        Response::Done
+    }
+}
+
+/// Executes a single API request against `runtime`. Used both by `turbined` and by
+/// the CLI when no daemon is reachable.
+pub async fn dispatch(runtime: &TurbineRuntime, request: Request) -> Response {
+    match request {
+        Request::Version => Response::Version {
+            api_version: API_VERSION,
+            daemon_version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+        Request::Create { config } => runtime.create_container(*config).await
+            .map(Response::ContainerId)
+            .into(),
+        Request::Start { container_id } => runtime.start_container(&container_id).await.into(),
+        Request::Stop { container_id, force } => runtime.stop_container(&container_id, force).await.into(),
+        Request::Restart { container_id } => runtime.restart_container(&container_id).await.into(),
+        Request::Remove { container_id, force } => runtime.remove_container(&container_id, force).await.into(),
+        Request::List => runtime.list_containers().await
+            .map(Response::Containers)
+            .into(),
+        Request::Logs { container_id, query } => runtime.get_container_logs(&container_id, &query).await
+            .map(|(entries, _)| Response::LogEntries(entries))
+            .into(),
+        Request::Exec { .. } => Response::Error("Exec needs a streaming connection".to_string()),
+        Request::Attach { .. } => Response::Error("Attach needs a streaming connection".to_string()),
+        Request::Events { query } => runtime.events(&query)
+            .map(|(events, _)| Response::Events(events))
+            .into(),
+        Request::Stats { container_id } => runtime.get_container_stats(&container_id).await
+            .map(Response::Stats)
+            .into(),
+        Request::Diff { container_id } => runtime.container_diff(&container_id).await
+            .map(Response::Changes)
+            .into(),
+        Request::Pause { container_id } => runtime.pause_container(&container_id).await.into(),
+        Request::Resume { container_id } => runtime.resume_container(&container_id).await.into(),
+        Request::Deploy { name, image, port } => runtime.deploy_web_app(name, image, port).await
+            .map(|container| Response::Container(Box::new(container)))
+            .into(),
+        Request::Cleanup => runtime.cleanup().await.into(),
+        Request::ImageImport { source, tag } => runtime.import_image(&source, tag.as_deref()).await
+            .map(Response::Images)
+            .into(),
+        Request::ImageList => runtime.list_images().await
+            .map(Response::Images)
+            .into(),
+        Request::ImageInspect { reference } => runtime.inspect_image(&reference).await
+            .map(|details| Response::Image(Box::new(details)))
+            .into(),
+        Request::ImageRemove { reference } => runtime.remove_image(&reference).await
+            .map(Response::Messages)
+            .into(),
+        Request::ImagePrune => runtime.prune_images().await
+            .map(Response::Messages)
+            .into(),
+        Request::NetworkCreate { options } => runtime.create_network(options).await
+            .map(|network| Response::Networks(vec![network]))
+            .into(),
+        Request::NetworkList => runtime.list_networks().await
+            .map(Response::Networks)
+            .into(),
+        Request::NetworkInspect { name } => runtime.inspect_network(&name).await
+            .map(|details| Response::Network(Box::new(details)))
+            .into(),
+        Request::NetworkRemove { name } => runtime.remove_network(&name).await.into(),
+        Request::NetworkConnect { network, container_id, aliases } => runtime.connect_network(&network, &container_id, aliases).await.into(),
+        Request::NetworkDisconnect { network, container_id } => runtime.disconnect_network(&network, &container_id).await.into(),
+    }
+}
+
+/// Receives the responses `dispatch_streaming` produces for a request.
+pub trait ResponseSink: Send {
+    fn send(&mut self, response: Response) -> impl Future<Output = crate::Result<()>> + Send;
+
+    /// Sends a response together with file descriptors. Sinks that cannot use them
+    /// close them.
+    fn send_with_fds(&mut self, response: Response, fds: Vec<OwnedFd>) -> impl Future<Output = crate::Result<()>> + Send {
+        drop(fds);
+        self.send(response)
+    }
+}
+
+/// Executes a request whose answer may be streamed. `Logs` and `Events` requests are
+/// answered with any number of `LogEntries` or `Events` responses ended by `Done` or
+/// `Error`. `Exec` requests get `ExecStarted` and the process's stdio, followed by its
+/// `ExitCode`. `Attach` requests get `Attached` and the container's stdio, then `Done`
+/// once the client detaches or the container's output ends. Every other request gets
+/// the single response `dispatch` gives.
+pub async fn dispatch_streaming<S: ResponseSink>(
+    runtime: &TurbineRuntime,
+    request: Request,
+    sink: &mut S,
+) -> crate::Result<()> {
+    match request {
+        Request::Logs { container_id, query } => {
+            match stream_logs(runtime, &container_id, &query, sink).await {
+                Ok(()) => sink.send(Response::Done).await,
+                Err(e) => sink.send(Response::Error(e.to_string())).await,
+            }
+        }
+        Request::Exec { container_id, command, options } => {
+            let (mut process, io) = match runtime.execute_in_container(&container_id, command, &options).await {
+                Ok(process) => process,
+                Err(e) => return sink.send(Response::Error(e.to_string())).await,
+            };
+            let started = Response::ExecStarted {
+                tty: matches!(io, ExecIo::Tty(_)),
+                stdin: options.interactive,
+            };
+
+            if let Err(e) = sink.send_with_fds(started, io.into_fds()).await {
+                let _ = process.kill().await;
+                return Err(e);
+            }
+
+            let status = process.wait().await?;
+
+            sink.send(Response::ExitCode(status.exit_code().unwrap_or(1))).await
+        }
+        Request::Events { query } => {
+            match stream_events(runtime, &query, sink).await {
+                Ok(()) => sink.send(Response::Done).await,
+                Err(e) => sink.send(Response::Error(e.to_string())).await,
+            }
+        }
+        Request::Attach { container_id, stdin } => {
+            let attached = runtime.attach_container(&container_id).await
+                .and_then(|streams| AttachSession::open(streams, stdin));
+            let (session, fds) = match attached {
+                Ok(attached) => attached,
+                Err(e) => return sink.send(Response::Error(e.to_string())).await,
+            };
+
+            sink.send_with_fds(Response::Attached { stdin: session.forwards_stdin() }, fds).await?;
+            session.run().await;
+            sink.send(Response::Done).await
+        }
+        request => sink.send(dispatch(runtime, request).await).await,
+    }
+}
+
+/// Sends the recorded events matching `query` and, when following, new ones as they
+/// are emitted until `query.until` passes.
+async fn stream_events(
+    runtime: &TurbineRuntime,
+    query: &EventQuery,
+    sink: &mut impl ResponseSink,
+) -> crate::Result<()> {
+    let (events, mut receiver) = runtime.events(query)?;
+    let newest = events.last().map(|event| event.time);
+
+    sink.send(Response::Events(events)).await?;
+    if !query.follow {
+        return Ok(());
+    }
+
+    let deadline = query.until.map(|until| {
+        tokio::time::Instant::now() + (until - chrono::Utc::now()).to_std().unwrap_or_default()
+    });
+
+    loop {
+        let received = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(received) => received,
+                Err(_) => return Ok(()),
+            },
+            None => receiver.recv().await,
+        };
+
+        match received {
+            // Events emitted while the journal was being read were also received.
+            Ok(event) if newest.is_some_and(|newest| event.time <= newest) => continue,
+            Ok(event) if query.matches(&event) => sink.send(Response::Events(vec![event])).await?,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Sends the logged entries matching `query` and, when following, new ones as they
+/// are written until the container stops or `query.until` passes.
+async fn stream_logs(
+    runtime: &TurbineRuntime,
+    container_id: &str,
+    query: &LogQuery,
+    sink: &mut impl ResponseSink,
+) -> crate::Result<()> {
+    let (entries, mut follower) = runtime.get_container_logs(container_id, query).await?;
+
+    sink.send(Response::LogEntries(entries)).await?;
+    if !query.follow {
+        return Ok(());
+    }
+
+    loop {
+        let container = runtime.get_container(container_id).await?;
+        let active = container.is_running() || container.is_paused() || container.is_restarting();
+
+        // Sampled before the wait so output written just before an exit is still sent.
+        tokio::time::sleep(LOG_FOLLOW_INTERVAL).await;
+
+        let entries = follower.poll()?;
+        if !entries.is_empty() {
+            sink.send(Response::LogEntries(entries)).await?;
+        }
+
+        if !active || query.until.is_some_and(|until| chrono::Utc::now() > until) {
+            return Ok(());
+        }
+    }
+}
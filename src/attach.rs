@@ -0,0 +1,191 @@
+use crate::logs::LogStream;
+use crate::{TurbineError, Result};
+use std::fs::File;
+use std::os::fd::OwnedFd;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::pipe;
+use tokio::net::UnixStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Chunks of output buffered for each attached client. A client that falls further
+/// behind misses the oldest ones.
+const OUTPUT_BACKLOG: usize = 1024;
+
+/// A piece of a container's output, as it was read from the process.
+#[derive(Debug, Clone)]
+pub struct OutputChunk {
+    pub stream: LogStream,
+    pub data: Vec<u8>,
+}
+
+/// Live stdio of a container's main process: its output fanned out to every attached
+/// client, and its stdin when the container was created with `stdin_open`.
+pub struct ContainerStreams {
+    output: Mutex<OutputState>,
+    stdin: Option<tokio::sync::Mutex<pipe::Sender>>,
+}
+
+struct OutputState {
+    sender: Option<broadcast::Sender<OutputChunk>>,
+    /// Output streams the process still has open.
+    open: usize,
+}
+
+impl ContainerStreams {
+    /// Must be called within the tokio runtime, which the container's stdin is
+    /// registered with.
+    pub fn new(stdin: Option<File>) -> Result<Self> {
+        let stdin = stdin.map(pipe::Sender::from_file).transpose()?;
+
+        Ok(Self {
+            output: Mutex::new(OutputState {
+                sender: Some(broadcast::channel(OUTPUT_BACKLOG).0),
+                open: 2,
+            }),
+            stdin: stdin.map(tokio::sync::Mutex::new),
+        })
+    }
+
+    /// Hands a chunk of output to the attached clients, if any.
+    pub fn publish(&self, stream: LogStream, data: &[u8]) {
+        let output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(sender) = &output.sender {
+            let _ = sender.send(OutputChunk { stream, data: data.to_vec() });
+        }
+    }
+
+    /// Records that one of the process's output streams has closed. Attached clients are
+    /// let go once both have.
+    pub fn close_stream(&self) {
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+
+        output.open = output.open.saturating_sub(1);
+        if output.open == 0 {
+            output.sender = None;
+        }
+    }
+
+    /// Subscribes to the output written from now on, or `None` if the process has
+    /// closed its output.
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<OutputChunk>> {
+        let output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+
+        output.sender.as_ref().map(broadcast::Sender::subscribe)
+    }
+
+    pub fn has_stdin(&self) -> bool {
+        self.stdin.is_some()
+    }
+
+    async fn write_stdin(&self, data: &[u8]) -> Result<()> {
+        let stdin = self.stdin.as_ref()
+            .ok_or_else(|| TurbineError::ProcessError("Container stdin is not open".to_string()))?;
+
+        stdin.lock().await.write_all(data).await?;
+
+        Ok(())
+    }
+}
+
+/// One client attached to a container. The client is handed its ends of a socket pair
+/// per stream; closing them detaches it.
+pub struct AttachSession {
+    streams: Arc<ContainerStreams>,
+    output: broadcast::Receiver<OutputChunk>,
+    stdout: UnixStream,
+    stderr: UnixStream,
+    stdin: Option<UnixStream>,
+}
+
+impl AttachSession {
+    /// Attaches to `streams`, forwarding the client's input when `stdin` is set and the
+    /// container's stdin is open. Returns the session and the client's file descriptors,
+    /// laid out as `ExecIo::into_fds` lays out pipes.
+    pub fn open(streams: Arc<ContainerStreams>, stdin: bool) -> Result<(Self, Vec<OwnedFd>)> {
+        let output = streams.subscribe()
+            .ok_or_else(|| TurbineError::ContainerError("Container is not running".to_string()))?;
+        let (stdout, client_stdout) = socket_pair()?;
+        let (stderr, client_stderr) = socket_pair()?;
+        let (stdin, client_stdin) = if stdin && streams.has_stdin() {
+            let (stdin, client_stdin) = socket_pair()?;
+            (Some(stdin), Some(client_stdin))
+        } else {
+            (None, None)
+        };
+        let session = Self { streams, output, stdout, stderr, stdin };
+        let fds = client_stdin.into_iter().chain([client_stdout, client_stderr]).collect();
+
+        Ok((session, fds))
+    }
+
+    pub fn forwards_stdin(&self) -> bool {
+        self.stdin.is_some()
+    }
+
+    /// Relays output and input until the container's output ends or the client detaches.
+    pub async fn run(mut self) {
+        // Input has a task of its own: a process that does not read its stdin must not
+        // hold up its output.
+        let input = self.stdin.take().map(|stdin| tokio::spawn(forward_stdin(stdin, Arc::clone(&self.streams))));
+
+        self.relay_output().await;
+
+        if let Some(input) = input {
+            input.abort();
+        }
+    }
+
+    async fn relay_output(&mut self) {
+        let mut probe = [0u8; 1];
+
+        loop {
+            tokio::select! {
+                chunk = self.output.recv() => {
+                    let chunk = match chunk {
+                        Ok(chunk) => chunk,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return,
+                    };
+                    let target = match chunk.stream {
+                        LogStream::Stdout => &mut self.stdout,
+                        LogStream::Stderr => &mut self.stderr,
+                    };
+
+                    if target.write_all(&chunk.data).await.is_err() {
+                        return;
+                    }
+                }
+                // The client never writes to its output sockets, so anything read from
+                // one means it has closed its end.
+                _ = self.stdout.read(&mut probe) => return,
+            }
+        }
+    }
+}
+
+/// Writes what the client sends to the container's stdin until either side closes.
+async fn forward_stdin(mut stdin: UnixStream, streams: Arc<ContainerStreams>) {
+    let mut input = [0u8; 8192];
+
+    loop {
+        let length = match stdin.read(&mut input).await {
+            Ok(0) | Err(_) => return,
+            Ok(length) => length,
+        };
+
+        if let Err(e) = streams.write_stdin(&input[..length]).await {
+            eprintln!("Warning: Failed to write to container stdin: {}", e);
+            return;
+        }
+    }
+}
+
+fn socket_pair() -> Result<(UnixStream, OwnedFd)> {
+    let (local, remote) = std::os::unix::net::UnixStream::pair()?;
+
+    local.set_nonblocking(true)?;
+
+    Ok((UnixStream::from_std(local)?, remote.into()))
+}
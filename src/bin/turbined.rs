@@ -0,0 +1,34 @@
+use clap::Parser;
+use std::path::PathBuf;
+use turbine::api::DEFAULT_SOCKET_PATH;
+use turbine::cgroup::DEFAULT_CGROUP_PARENT;
+use turbine::daemon::Daemon;
+use turbine::{TurbineRuntime, Result};
+
+#[derive(Parser)]
+#[command(name = "turbined")]
+#[command(about = "Turbine container runtime daemon")]
+#[command(version = "0.1.0")]
+struct Cli {
+    #[arg(long, default_value = "/tmp/turbine")]
+    base_path: PathBuf,
+    #[arg(long, default_value = DEFAULT_SOCKET_PATH)]
+    socket: PathBuf,
+    /// Cgroup, relative to the cgroup v2 root, that container cgroups are created under
+    #[arg(long, default_value = DEFAULT_CGROUP_PARENT)]
+    cgroup_parent: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let runtime = TurbineRuntime::new(&cli.base_path)?
+        .with_cgroup_parent(&cli.cgroup_parent);
+
+    runtime.initialize().await?;
+
+    let daemon = Daemon::new(runtime, &cli.socket);
+
+    println!("turbined listening on {:?}", cli.socket);
+    daemon.run().await
+}
@@ -0,0 +1,315 @@
+use crate::{Container, IoLimit, ResourceLimits, TurbineError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Parent cgroup, relative to the cgroup v2 root, that container cgroups are created under.
+pub const DEFAULT_CGROUP_PARENT: &str = "turbine";
+
+const CPU_PERIOD_US: u64 = 100_000;
+
+const CONTROLLERS: [&str; 5] = ["cpuset", "cpu", "io", "memory", "pids"];
+
+/// Usage counters of a container cgroup. Counters whose controller is not enabled read as zero.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CgroupStats {
+    pub memory_current: u64,
+    pub memory_max: Option<u64>,
+    /// Page cache charged to the cgroup (`file` in `memory.stat`).
+    pub memory_file: u64,
+    pub cpu_usage_usec: u64,
+    pub pids_current: u64,
+    pub io_read_bytes: u64,
+    pub io_write_bytes: u64,
+}
+
+/// Places each container in its own cgroup v2 leaf and enforces its `ResourceLimits`.
+pub struct CgroupManager {
+    mount_point: Option<PathBuf>,
+    parent: PathBuf,
+}
+
+impl CgroupManager {
+    pub fn new<P: AsRef<Path>>(parent: P) -> Self {
+        let parent = parent.as_ref();
+
+        Self {
+            mount_point: find_cgroup2_mount(),
+            parent: parent.strip_prefix("/").unwrap_or(parent).to_path_buf(),
+        }
+    }
+
+    /// Creates the container's cgroup, or reuses it on restart, and writes its limits.
+    /// Returns `None` when the host has no cgroup v2 hierarchy.
+    pub fn setup(&self, container: &Container) -> Result<Option<PathBuf>> {
+        let Some(mount_point) = &self.mount_point else {
+            eprintln!("Warning: No cgroup v2 hierarchy mounted, resource limits are not enforced");
+            return Ok(None);
+        };
+        let path = mount_point.join(&self.parent).join(&container.id);
+
+        self.enable_controllers(mount_point)?;
+        fs::create_dir_all(&path)?;
+
+        if let Err(e) = apply_limits(&path, container) {
+            let _ = fs::remove_dir(&path);
+            return Err(e);
+        }
+
+        Ok(Some(path))
+    }
+
+    /// Delegates the controllers turbine uses down every level from the cgroup root to
+    /// the parent cgroup, creating intermediate cgroups as needed.
+    fn enable_controllers(&self, mount_point: &Path) -> Result<()> {
+        let mut dir = mount_point.to_path_buf();
+        let mut components = self.parent.components();
+
+        loop {
+            let available = read_controllers(&dir.join("cgroup.controllers"))?;
+            let enabled = read_controllers(&dir.join("cgroup.subtree_control"))?;
+
+            for controller in CONTROLLERS {
+                if available.contains(controller) && !enabled.contains(controller) {
+                    write_file(&dir.join("cgroup.subtree_control"), &format!("+{}", controller))?;
+                }
+            }
+
+            match components.next() {
+                Some(component) => {
+                    dir.push(component);
+                    fs::create_dir_all(&dir)?;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Kills anything left in the container's cgroup and removes it.
+    pub async fn remove(&self, container: &Container) -> Result<()> {
+        let Some(path) = &container.cgroup_path else {
+            return Ok(());
+        };
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let kill_file = path.join("cgroup.kill");
+        if kill_file.exists() {
+            write_file(&kill_file, "1")?;
+        } else {
+            for pid in fs::read_to_string(path.join("cgroup.procs"))?.lines() {
+                if let Ok(pid) = pid.parse::<i32>() {
+                    let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), nix::sys::signal::Signal::SIGKILL);
+                }
+            }
+        }
+
+        for _ in 0..50 {
+            match fs::remove_dir(path) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Err(TurbineError::CgroupError(format!("Cgroup {:?} still has processes", path)))
+    }
+}
+
+pub fn read_stats(path: &Path) -> Result<CgroupStats> {
+    let memory_stat = read_keyed(&path.join("memory.stat"))?;
+    let cpu_stat = read_keyed(&path.join("cpu.stat"))?;
+    let pids_current = match read_value(&path.join("pids.current"))? {
+        Some(value) => value.parse().unwrap_or(0),
+        None => fs::read_to_string(path.join("cgroup.procs"))?.lines().count() as u64,
+    };
+    let mut stats = CgroupStats {
+        memory_current: read_value(&path.join("memory.current"))?
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0),
+        memory_max: read_value(&path.join("memory.max"))?
+            .and_then(|value| value.parse().ok()),
+        memory_file: memory_stat.get("file").copied().unwrap_or(0),
+        cpu_usage_usec: cpu_stat.get("usage_usec").copied().unwrap_or(0),
+        pids_current,
+        ..Default::default()
+    };
+
+    for line in read_value(&path.join("io.stat"))?.unwrap_or_default().lines() {
+        for field in line.split_whitespace().skip(1) {
+            match field.split_once('=') {
+                Some(("rbytes", value)) => stats.io_read_bytes += value.parse().unwrap_or(0),
+                Some(("wbytes", value)) => stats.io_write_bytes += value.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Reads a cgroup interface file, or `None` if its controller is not enabled.
+fn read_value(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(value) => Ok(Some(value.trim().to_string())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Parses a flat-keyed file such as `memory.stat` or `cpu.stat`.
+fn read_keyed(path: &Path) -> Result<HashMap<String, u64>> {
+    Ok(read_value(path)?
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key.to_string(), value.parse().ok()?))
+        })
+        .collect())
+}
+
+/// Freezes or thaws every process in the cgroup, returning once `cgroup.events`
+/// confirms the new state.
+pub async fn set_frozen(path: &Path, frozen: bool) -> Result<()> {
+    write_file(&path.join("cgroup.freeze"), if frozen { "1" } else { "0" })?;
+
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+
+    while is_frozen(path)? != frozen {
+        if tokio::time::Instant::now() >= deadline {
+            return Err(TurbineError::CgroupError(format!(
+                "Timed out waiting for {:?} to {}", path, if frozen { "freeze" } else { "thaw" }
+            )));
+        }
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    Ok(())
+}
+
+pub fn is_frozen(path: &Path) -> Result<bool> {
+    Ok(read_keyed(&path.join("cgroup.events"))?.get("frozen") == Some(&1))
+}
+
+/// Processes in the cgroup killed by the OOM killer so far.
+pub fn oom_kills(path: &Path) -> Result<u64> {
+    Ok(read_keyed(&path.join("memory.events"))?.get("oom_kill").copied().unwrap_or(0))
+}
+
+/// Moves `pid` into the cgroup at `path`.
+pub fn add_process(path: &Path, pid: u32) -> Result<()> {
+    write_file(&path.join("cgroup.procs"), &pid.to_string())
+}
+
+/// Writes the container's resource limits to its cgroup at `path`, skipping those of
+/// controllers that are unavailable there.
+fn apply_limits(path: &Path, container: &Container) -> Result<()> {
+    let available = read_controllers(&path.join("cgroup.controllers"))?;
+
+    for (file, value) in limit_settings(&container.config.resources)? {
+        let controller = file.split('.').next().unwrap_or(file);
+        if !available.contains(controller) {
+            eprintln!("Warning: cgroup controller '{}' is unavailable, {} not applied", controller, file);
+            continue;
+        }
+
+        write_file(&path.join(file), &value)?;
+    }
+
+    Ok(())
+}
+
+/// The cgroup interface files and values that enforce `resources`.
+fn limit_settings(resources: &ResourceLimits) -> Result<Vec<(&'static str, String)>> {
+    let mut settings = Vec::new();
+
+    if let Some(cpus) = &resources.cpuset_cpus {
+        settings.push(("cpuset.cpus", cpus.clone()));
+    }
+
+    if let Some(mems) = &resources.cpuset_mems {
+        settings.push(("cpuset.mems", mems.clone()));
+    }
+
+    if let Some(memory_mb) = resources.memory_mb {
+        settings.push(("memory.max", (memory_mb * 1024 * 1024).to_string()));
+    }
+
+    if let Some(swap_mb) = resources.memory_swap_mb {
+        settings.push(("memory.swap.max", (swap_mb * 1024 * 1024).to_string()));
+    }
+
+    if let Some(cpu_quota) = resources.cpu_quota {
+        let quota = (cpu_quota * CPU_PERIOD_US as f64).round() as u64;
+        settings.push(("cpu.max", format!("{} {}", quota, CPU_PERIOD_US)));
+    }
+
+    if let Some(weight) = resources.cpu_weight {
+        settings.push(("cpu.weight", weight.to_string()));
+    }
+
+    if let Some(max_processes) = resources.max_processes {
+        settings.push(("pids.max", max_processes.to_string()));
+    }
+
+    for limit in &resources.io_limits {
+        settings.push(("io.max", io_max_line(limit)?));
+    }
+
+    Ok(settings)
+}
+
+fn io_max_line(limit: &IoLimit) -> Result<String> {
+    let metadata = fs::metadata(&limit.device).map_err(|e| TurbineError::CgroupError(
+        format!("Cannot access block device {:?}: {}", limit.device, e)
+    ))?;
+    let rdev = metadata.rdev();
+    let mut line = format!("{}:{}", libc::major(rdev), libc::minor(rdev));
+
+    for (key, value) in [
+        ("rbps", limit.read_bps),
+        ("wbps", limit.write_bps),
+        ("riops", limit.read_iops),
+        ("wiops", limit.write_iops),
+    ] {
+        if let Some(value) = value {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+    }
+
+    Ok(line)
+}
+
+fn find_cgroup2_mount() -> Option<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+
+    mountinfo.lines().find_map(|line| {
+        let (mount, fs_info) = line.split_once(" - ")?;
+        if fs_info.split_whitespace().next() != Some("cgroup2") {
+            return None;
+        }
+
+        mount.split_whitespace().nth(4).map(PathBuf::from)
+    })
+}
+
+fn read_controllers(path: &Path) -> Result<HashSet<String>> {
+    Ok(fs::read_to_string(path)?
+        .split_whitespace()
+        .map(str::to_string)
+        .collect())
+}
+
+fn write_file(path: &Path, value: &str) -> Result<()> {
+    fs::write(path, value)
+        .map_err(|e| TurbineError::CgroupError(format!("Failed to write '{}' to {:?}: {}", value, path, e)))
+}
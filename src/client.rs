@@ -0,0 +1,130 @@
+use crate::api::{Request, RequestEnvelope, Response, ResponseEnvelope, ResponseSink, API_VERSION};
+use crate::{TurbineError, Result};
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+use std::io::IoSliceMut;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncWriteExt, Interest};
+use tokio::net::UnixStream;
+
+/// Client side of the `turbined` control API.
+pub struct DaemonClient {
+    socket_path: PathBuf,
+}
+
+impl DaemonClient {
+    pub fn new<P: AsRef<Path>>(socket_path: P) -> Self {
+        Self {
+            socket_path: socket_path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Returns true if a daemon is accepting connections on the socket.
+    pub async fn is_available(&self) -> bool {
+        UnixStream::connect(&self.socket_path).await.is_ok()
+    }
+
+    pub async fn call(&self, request: Request) -> Result<Response> {
+        let mut reader = self.send(request).await?;
+
+        Ok(reader.next().await?.0)
+    }
+
+    /// Sends a request answered by a stream of responses, such as a `Logs` or `Exec`
+    /// request, passing each one and any file descriptors that came with it to `sink`,
+    /// up to and including the final `Done`, `ExitCode` or `Error`.
+    pub async fn call_streaming(&self, request: Request, sink: &mut impl ResponseSink) -> Result<()> {
+        let mut reader = self.send(request).await?;
+
+        loop {
+            let (response, fds) = reader.next().await?;
+            let last = matches!(response, Response::Done | Response::ExitCode(_) | Response::Error(_));
+
+            if fds.is_empty() {
+                sink.send(response).await?;
+            } else {
+                sink.send_with_fds(response, fds).await?;
+            }
+
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn send(&self, request: Request) -> Result<ResponseReader> {
+        let mut stream = UnixStream::connect(&self.socket_path).await
+            .map_err(|e| TurbineError::RuntimeError(
+                format!("Failed to connect to daemon at {:?}: {}", self.socket_path, e)
+            ))?;
+        let mut payload = serde_json::to_vec(&RequestEnvelope::new(request))?;
+
+        payload.push(b'\n');
+        stream.write_all(&payload).await?;
+
+        Ok(ResponseReader {
+            stream,
+            buffer: Vec::new(),
+            fds: Vec::new(),
+        })
+    }
+}
+
+/// Reads newline-delimited response envelopes, collecting the file descriptors the
+/// daemon passes with them.
+struct ResponseReader {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+    fds: Vec<OwnedFd>,
+}
+
+impl ResponseReader {
+    async fn next(&mut self) -> Result<(Response, Vec<OwnedFd>)> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                let envelope: ResponseEnvelope = serde_json::from_slice(&line)?;
+                if envelope.version != API_VERSION {
+                    return Err(TurbineError::RuntimeError(format!(
+                        "Daemon speaks API version {}, client expects {}", envelope.version, API_VERSION
+                    )));
+                }
+
+                return Ok((envelope.response, std::mem::take(&mut self.fds)));
+            }
+
+            self.stream.readable().await?;
+
+            let mut chunk = [0u8; 8192];
+            let received = self.stream.try_io(Interest::READABLE, || {
+                recv_with_fds(self.stream.as_raw_fd(), &mut chunk, &mut self.fds)
+            });
+
+            match received {
+                Ok(0) => return Err(TurbineError::RuntimeError("Daemon closed the connection".to_string())),
+                Ok(length) => self.buffer.extend_from_slice(&chunk[..length]),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+fn recv_with_fds(socket: RawFd, chunk: &mut [u8], fds: &mut Vec<OwnedFd>) -> std::io::Result<usize> {
+    let mut control = nix::cmsg_space!([RawFd; 4]);
+    let mut iov = [IoSliceMut::new(chunk)];
+    let message = recvmsg::<()>(socket, &mut iov, Some(&mut control), MsgFlags::MSG_CMSG_CLOEXEC)?;
+
+    for control in message.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(received) = control {
+            // SAFETY: the kernel just installed these descriptors in this process.
+            fds.extend(received.into_iter().map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+        }
+    }
+
+    Ok(message.bytes)
+}
@@ -1,15 +1,22 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContainerConfig {
     pub name: String,
     pub image: String,
+    /// Full argv of the container process. When empty, the image's `Entrypoint` and `Cmd` are used.
+    #[serde(default)]
     pub command: Vec<String>,
     pub working_dir: Option<String>,
     pub environment: HashMap<String, String>,
     pub ports: Vec<PortMapping>,
+    /// How published ports reach the container.
+    #[serde(default)]
+    pub port_mode: PortMode,
     pub volumes: Vec<VolumeMount>,
     pub resources: ResourceLimits,
     pub network: NetworkConfig,
@@ -18,13 +25,51 @@ pub struct ContainerConfig {
     pub gid: Option<u32>,
     pub groups: Option<Vec<u32>>,
     pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub log_driver: LogDriverConfig,
+    /// Keep the process's stdin open so attached clients can write to it.
+    #[serde(default)]
+    pub stdin_open: bool,
+    #[serde(default)]
+    pub healthcheck: Option<HealthCheckConfig>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Container ports published on the host. Written as a table, or on the command line
+/// as `[host_ip:]host_port[-end]:container_port[-end][/protocol]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PortMapping {
+    /// Host address the ports are published on; every address when unset.
+    #[serde(default)]
+    pub host_ip: Option<IpAddr>,
     pub host_port: u16,
+    /// Last host port of a range. Each port in it maps to the container port at the
+    /// same offset from `container_port`.
+    #[serde(default)]
+    pub host_port_end: Option<u16>,
     pub container_port: u16,
-    pub protocol: String,
+    #[serde(default)]
+    pub protocol: PortProtocol,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortProtocol {
+    #[default]
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+/// How connections to published ports are forwarded to the container.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortMode {
+    /// Destination NAT rules in the host's firewall.
+    #[default]
+    Nat,
+    /// A userspace proxy in `turbined` listening on the host, which also serves
+    /// connections to the host's loopback addresses and from other containers.
+    Proxy,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,35 +82,439 @@ pub struct VolumeMount {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResourceLimits {
     pub memory_mb: Option<u64>,
+    /// CPUs' worth of time per scheduling period, e.g. `0.5` for half a core.
     pub cpu_quota: Option<f64>,
     pub disk_mb: Option<u64>,
     pub max_processes: Option<u32>,
+    /// Swap the container may use on top of `memory_mb`.
+    #[serde(default)]
+    pub memory_swap_mb: Option<u64>,
+    /// Relative CPU share under contention, from 1 to 10000 (the kernel default is 100).
+    #[serde(default)]
+    pub cpu_weight: Option<u64>,
+    /// CPUs the container may run on, in cpuset list format (e.g. `"0-3,6"`).
+    #[serde(default)]
+    pub cpuset_cpus: Option<String>,
+    /// Memory nodes the container may allocate from, in cpuset list format.
+    #[serde(default)]
+    pub cpuset_mems: Option<String>,
+    #[serde(default)]
+    pub io_limits: Vec<IoLimit>,
 }
 
+/// Bandwidth and IOPS caps for one block device.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IoLimit {
+    pub device: PathBuf,
+    #[serde(default)]
+    pub read_bps: Option<u64>,
+    #[serde(default)]
+    pub write_bps: Option<u64>,
+    #[serde(default)]
+    pub read_iops: Option<u64>,
+    #[serde(default)]
+    pub write_iops: Option<u64>,
+}
+
+/// Where a container's output goes. Written as a `[log_driver]` table whose `type` is
+/// `json-file` (the default), `syslog`, `journald` or `tcp`, next to that driver's options.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum LogDriverConfig {
+    /// Size-rotated JSON lines under the runtime's base path. The only driver whose
+    /// output `turbine logs` can read back.
+    JsonFile {
+        #[serde(default = "default_log_max_size_kb")]
+        max_size_kb: u64,
+        /// Log files kept, including the active one.
+        #[serde(default = "default_log_max_files")]
+        max_files: u32,
+    },
+    /// RFC 5424 messages sent to `unix:///path/to/socket` or `udp://host:port`.
+    Syslog {
+        #[serde(default = "default_syslog_address")]
+        address: String,
+        #[serde(default = "default_syslog_facility")]
+        facility: String,
+        /// APP-NAME of each message; defaults to the short container ID.
+        #[serde(default)]
+        tag: Option<String>,
+    },
+    /// journald's native protocol over its datagram socket.
+    Journald {
+        /// `SYSLOG_IDENTIFIER` of each entry; defaults to the short container ID.
+        #[serde(default)]
+        tag: Option<String>,
+    },
+    /// Newline-delimited JSON records over a TCP connection to `host:port`.
+    Tcp {
+        address: String,
+    },
+}
+
+/// How to tell whether a container's application works. Written as a `[healthcheck]`
+/// table whose `type` is `exec`, `http` or `tcp`, next to that probe's options.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthCheckConfig {
+    #[serde(flatten)]
+    pub probe: HealthProbe,
+    /// Seconds between probes. The first probe runs this long after the start.
+    #[serde(default = "default_health_interval_secs")]
+    pub interval_secs: u64,
+    /// Seconds a probe may take before it counts as failed.
+    #[serde(default = "default_health_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Consecutive failed probes that make the container unhealthy.
+    #[serde(default = "default_health_retries")]
+    pub retries: u32,
+    /// Seconds after the start during which failed probes are not counted.
+    #[serde(default)]
+    pub start_period_secs: u64,
+    /// Kill the container once it turns unhealthy, leaving it to the restart policy
+    /// to start it again.
+    #[serde(default)]
+    pub restart_on_unhealthy: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum HealthProbe {
+    /// A command run in the container; healthy when it exits with 0.
+    Exec {
+        command: Vec<String>,
+    },
+    /// A GET of `path` from `port` on the container's loopback address; healthy on a
+    /// 2xx or 3xx status.
+    Http {
+        port: u16,
+        #[serde(default = "default_health_path")]
+        path: String,
+    },
+    /// A connection to `port` on the container's loopback address.
+    Tcp {
+        port: u16,
+    },
+}
+
+impl HealthCheckConfig {
+    pub fn new(probe: HealthProbe) -> Self {
+        Self {
+            probe,
+            interval_secs: default_health_interval_secs(),
+            timeout_secs: default_health_timeout_secs(),
+            retries: default_health_retries(),
+            start_period_secs: 0,
+            restart_on_unhealthy: false,
+        }
+    }
+}
+
+impl PortMapping {
+    pub fn new(host_port: u16, container_port: u16, protocol: PortProtocol) -> Self {
+        Self {
+            host_ip: None,
+            host_port,
+            host_port_end: None,
+            container_port,
+            protocol,
+        }
+    }
+
+    /// Number of ports published.
+    pub fn port_count(&self) -> u16 {
+        self.host_port_end.map_or(1, |end| end.saturating_sub(self.host_port).saturating_add(1))
+    }
+
+    pub fn host_ports(&self) -> RangeInclusive<u16> {
+        self.host_port..=self.host_port.saturating_add(self.port_count() - 1)
+    }
+
+    pub fn container_ports(&self) -> RangeInclusive<u16> {
+        self.container_port..=self.container_port.saturating_add(self.port_count() - 1)
+    }
+
+    /// Whether both mappings would publish the same host port and protocol on a
+    /// common address.
+    pub fn conflicts_with(&self, other: &PortMapping) -> bool {
+        let (ours, theirs) = (self.host_ports(), other.host_ports());
+
+        self.protocol == other.protocol
+            && (self.host_ip.is_none() || other.host_ip.is_none() || self.host_ip == other.host_ip)
+            && ours.start() <= theirs.end()
+            && theirs.start() <= ours.end()
+    }
+}
+
+impl std::str::FromStr for PortMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (ports, protocol) = match s.split_once('/') {
+            Some((ports, protocol)) => (ports, protocol.parse()?),
+            None => (s, PortProtocol::Tcp),
+        };
+        let (host, container) = ports.rsplit_once(':')
+            .ok_or_else(|| format!("Port mapping '{}' needs a host and a container port", s))?;
+        let (host_ip, host) = match host.rsplit_once(':') {
+            Some((ip, host)) => {
+                let ip = ip.trim_start_matches('[').trim_end_matches(']');
+
+                (Some(ip.parse().map_err(|_| format!("Invalid host address '{}' in port mapping '{}'", ip, s))?), host)
+            }
+            None => (None, host),
+        };
+        let (host_port, host_port_end) = parse_port_range(host, s)?;
+        let (container_port, container_port_end) = parse_port_range(container, s)?;
+        let mapping = PortMapping { host_ip, host_port, host_port_end, container_port, protocol };
+
+        if container_port_end.is_some_and(|end| end != *mapping.container_ports().end()) {
+            return Err(format!("Host and container port ranges in '{}' differ in length", s));
+        }
+
+        Ok(mapping)
+    }
+}
+
+impl std::fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.host_ip {
+            Some(IpAddr::V6(ip)) => write!(f, "[{}]:", ip)?,
+            Some(ip) => write!(f, "{}:", ip)?,
+            None => {}
+        }
+
+        match self.host_port_end {
+            Some(end) => write!(f, "{}-{}:{}-{}", self.host_port, end, self.container_port, self.container_ports().end())?,
+            None => write!(f, "{}:{}", self.host_port, self.container_port)?,
+        }
+
+        write!(f, "/{}", self.protocol)
+    }
+}
+
+impl std::str::FromStr for PortProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(PortProtocol::Tcp),
+            "udp" => Ok(PortProtocol::Udp),
+            "sctp" => Ok(PortProtocol::Sctp),
+            _ => Err(format!("Unknown port protocol '{}'", s)),
+        }
+    }
+}
+
+/// Reads the protocol in any case, as `from_str` does.
+impl<'de> Deserialize<'de> for PortProtocol {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for PortProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PortProtocol::Tcp => "tcp",
+            PortProtocol::Udp => "udp",
+            PortProtocol::Sctp => "sctp",
+        })
+    }
+}
+
+impl std::str::FromStr for PortMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nat" => Ok(PortMode::Nat),
+            "proxy" => Ok(PortMode::Proxy),
+            _ => Err(format!("Unknown port mode '{}'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for PortMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PortMode::Nat => "nat",
+            PortMode::Proxy => "proxy",
+        })
+    }
+}
+
+impl NetworkConfig {
+    /// Splits an `extra_hosts` entry into its hostname and address.
+    pub fn parse_extra_host(entry: &str) -> Option<(&str, IpAddr)> {
+        let (hostname, ip) = entry.split_once(':')?;
+
+        is_valid_hostname(hostname).then_some(())?;
+        Some((hostname, ip.parse().ok()?))
+    }
+}
+
+impl LogDriverConfig {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogDriverConfig::JsonFile { .. } => "json-file",
+            LogDriverConfig::Syslog { .. } => "syslog",
+            LogDriverConfig::Journald { .. } => "journald",
+            LogDriverConfig::Tcp { .. } => "tcp",
+        }
+    }
+}
+
+/// Whether `name` is a DNS name of letters, digits and hyphens in dot-separated labels.
+pub(crate) fn is_valid_hostname(name: &str) -> bool {
+    name.len() <= 253 && name.split('.').all(|label| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// Parses `port` or `start-end` from the port mapping `spec`.
+fn parse_port_range(ports: &str, spec: &str) -> std::result::Result<(u16, Option<u16>), String> {
+    let port = |port: &str| port.parse::<u16>()
+        .map_err(|_| format!("Invalid port '{}' in port mapping '{}'", port, spec));
+
+    match ports.split_once('-') {
+        Some((start, end)) => Ok((port(start)?, Some(port(end)?))),
+        None => Ok((port(ports)?, None)),
+    }
+}
+
+fn default_health_interval_secs() -> u64 {
+    30
+}
+
+fn default_health_timeout_secs() -> u64 {
+    30
+}
+
+fn default_health_retries() -> u32 {
+    3
+}
+
+fn default_health_path() -> String {
+    "/".to_string()
+}
+
+fn default_log_max_size_kb() -> u64 {
+    10 * 1024
+}
+
+fn default_log_max_files() -> u32 {
+    3
+}
+
+fn default_syslog_address() -> String {
+    "unix:///dev/log".to_string()
+}
+
+fn default_syslog_facility() -> String {
+    "daemon".to_string()
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct NetworkConfig {
+    /// Bridge of the network to join when `networks` is empty.
     pub bridge: Option<String>,
+    /// Networks to join, by name. The default network when empty and `bridge` is unset.
+    #[serde(default)]
+    pub networks: Vec<String>,
+    /// Addresses to use instead of automatically picked ones, each on the network whose
+    /// subnet contains it.
+    #[serde(default)]
+    pub ips: Vec<IpAddr>,
+    /// Names other containers find this one by on each of its networks, besides its
+    /// name and hostname.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Nameservers for the container's `resolv.conf`. When empty, the host's are used,
+    /// except those on its loopback addresses, which containers cannot reach.
     pub dns: Vec<String>,
+    /// Defaults to the short container ID.
     pub hostname: Option<String>,
+    /// Search domains for the container's `resolv.conf`.
+    #[serde(default)]
+    pub dns_search: Vec<String>,
+    /// `resolv.conf` options, e.g. `ndots:2`.
+    #[serde(default)]
+    pub dns_options: Vec<String>,
+    /// Additional `/etc/hosts` entries, as `hostname:ip`.
+    #[serde(default)]
+    pub extra_hosts: Vec<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What to do when a container's process exits on its own. Written as `"Never"`,
+/// `"Always"`, `"UnlessStopped"`, `"OnFailure"` or `"OnFailure:<max retries>"`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub enum RestartPolicy {
     Never,
     Always,
-    OnFailure,
+    OnFailure(Option<u32>),
     UnlessStopped,
 }
 
+impl std::str::FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None => match s {
+                "Never" => Ok(RestartPolicy::Never),
+                "Always" => Ok(RestartPolicy::Always),
+                "OnFailure" => Ok(RestartPolicy::OnFailure(None)),
+                "UnlessStopped" => Ok(RestartPolicy::UnlessStopped),
+                _ => Err(format!("Unknown restart policy '{}'", s)),
+            },
+            Some(("OnFailure", retries)) => retries.parse()
+                .map(|retries| RestartPolicy::OnFailure(Some(retries)))
+                .map_err(|_| format!("Invalid retry count in restart policy '{}'", s)),
+            Some(_) => Err(format!("Only OnFailure takes a retry count, got '{}'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestartPolicy::Never => write!(f, "Never"),
+            RestartPolicy::Always => write!(f, "Always"),
+            RestartPolicy::OnFailure(None) => write!(f, "OnFailure"),
+            RestartPolicy::OnFailure(Some(retries)) => write!(f, "OnFailure:{}", retries),
+            RestartPolicy::UnlessStopped => write!(f, "UnlessStopped"),
+        }
+    }
+}
+
+impl TryFrom<String> for RestartPolicy {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<RestartPolicy> for String {
+    fn from(policy: RestartPolicy) -> Self {
+        policy.to_string()
+    }
+}
+
 impl Default for ContainerConfig {
     fn default() -> Self {
         Self {
             name: String::new(),
             image: String::new(),
-            command: vec!["/bin/sh".to_string()],
-            working_dir: Some("/app".to_string()),
+            command: Vec::new(),
+            working_dir: None,
             environment: HashMap::new(),
             ports: Vec::new(),
+            port_mode: PortMode::default(),
             volumes: Vec::new(),
             resources: ResourceLimits::default(),
             network: NetworkConfig::default(),
@@ -74,6 +523,9 @@ impl Default for ContainerConfig {
             gid: None,
             groups: None,
             restart_policy: RestartPolicy::Never,
+            log_driver: LogDriverConfig::default(),
+            stdin_open: false,
+            healthcheck: None,
         }
     }
 }
@@ -85,16 +537,20 @@ impl Default for ResourceLimits {
             cpu_quota: Some(1.0),
             disk_mb: Some(1024),
             max_processes: Some(256),
+            memory_swap_mb: None,
+            cpu_weight: None,
+            cpuset_cpus: None,
+            cpuset_mems: None,
+            io_limits: Vec::new(),
         }
     }
 }
 
-impl Default for NetworkConfig {
+impl Default for LogDriverConfig {
     fn default() -> Self {
-        Self {
-            bridge: None,
-            dns: vec!["8.8.8.8".to_string(), "8.8.4.4".to_string()],
-            hostname: None,
+        LogDriverConfig::JsonFile {
+            max_size_kb: default_log_max_size_kb(),
+            max_files: default_log_max_files(),
         }
     }
 }
@@ -124,9 +580,23 @@ impl ContainerConfig {
             return Err(anyhow::anyhow!("Container image cannot be empty"));
         }
 
-        for port in &self.ports {
+        for (i, port) in self.ports.iter().enumerate() {
             if port.host_port == 0 || port.container_port == 0 {
-                return Err(anyhow::anyhow!("Invalid port mapping"));
+                return Err(anyhow::anyhow!("Invalid port mapping: {}", port));
+            }
+
+            if port.host_port_end.is_some_and(|end| end < port.host_port)
+                || port.container_port.checked_add(port.port_count() - 1).is_none()
+            {
+                return Err(anyhow::anyhow!("Invalid port range: {}", port));
+            }
+
+            if let Some(other) = self.ports[..i].iter().find(|other| other.conflicts_with(port)) {
+                return Err(anyhow::anyhow!("Port mappings {} and {} overlap", other, port));
+            }
+
+            if self.port_mode == PortMode::Proxy && port.protocol == PortProtocol::Sctp {
+                return Err(anyhow::anyhow!("SCTP ports cannot be proxied: {}", port));
             }
         }
 
@@ -136,8 +606,110 @@ impl ContainerConfig {
             }
         }
 
+        if self.resources.cpu_quota.is_some_and(|quota| quota <= 0.0) {
+            return Err(anyhow::anyhow!("CPU quota must be positive"));
+        }
+
+        if self.resources.cpu_weight.is_some_and(|weight| !(1..=10000).contains(&weight)) {
+            return Err(anyhow::anyhow!("CPU weight must be between 1 and 10000"));
+        }
+
+        for limit in &self.resources.io_limits {
+            if !limit.device.exists() {
+                return Err(anyhow::anyhow!("Block device does not exist: {:?}", limit.device));
+            }
+        }
+
+        match &self.log_driver {
+            LogDriverConfig::JsonFile { max_size_kb, max_files } => {
+                if *max_size_kb == 0 || *max_files == 0 {
+                    return Err(anyhow::anyhow!("Log rotation needs a positive max_size_kb and max_files"));
+                }
+            }
+            LogDriverConfig::Syslog { address, facility, .. } => {
+                if !address.starts_with("unix://") && !address.starts_with("udp://") {
+                    return Err(anyhow::anyhow!("Syslog address must be unix:///path or udp://host:port: {}", address));
+                }
+
+                if crate::log_drivers::facility_code(facility).is_none() {
+                    return Err(anyhow::anyhow!("Unknown syslog facility: {}", facility));
+                }
+            }
+            LogDriverConfig::Journald { .. } => {}
+            LogDriverConfig::Tcp { address } => {
+                if address.is_empty() {
+                    return Err(anyhow::anyhow!("TCP log driver needs an address"));
+                }
+            }
+        }
+
+        if let Some(healthcheck) = &self.healthcheck {
+            if healthcheck.interval_secs == 0 || healthcheck.timeout_secs == 0 || healthcheck.retries == 0 {
+                return Err(anyhow::anyhow!("Health check interval_secs, timeout_secs and retries must be positive"));
+            }
+
+            match &healthcheck.probe {
+                HealthProbe::Exec { command } if command.is_empty() => {
+                    return Err(anyhow::anyhow!("Exec health check needs a command"));
+                }
+                HealthProbe::Http { port: 0, .. } | HealthProbe::Tcp { port: 0 } => {
+                    return Err(anyhow::anyhow!("Health check port cannot be 0"));
+                }
+                HealthProbe::Http { path, .. } if !path.starts_with('/') => {
+                    return Err(anyhow::anyhow!("Health check path must start with '/': {}", path));
+                }
+                _ => {}
+            }
+        }
+
+        for (index, ip) in self.network.ips.iter().enumerate() {
+            if ip.is_unspecified() || ip.is_loopback() || ip.is_multicast() {
+                return Err(anyhow::anyhow!("Invalid container address: {}", ip));
+            }
+
+            if self.network.ips[..index].contains(ip) {
+                return Err(anyhow::anyhow!("Duplicate container address: {}", ip));
+            }
+        }
+
+        for alias in &self.network.aliases {
+            if !is_valid_hostname(alias) {
+                return Err(anyhow::anyhow!("Invalid network alias: {}", alias));
+            }
+        }
+
+        for server in &self.network.dns {
+            if server.parse::<IpAddr>().is_err() {
+                return Err(anyhow::anyhow!("DNS server must be an IP address: {}", server));
+            }
+        }
+
+        if let Some(hostname) = &self.network.hostname {
+            if !is_valid_hostname(hostname) {
+                return Err(anyhow::anyhow!("Invalid hostname: {}", hostname));
+            }
+        }
+
+        for domain in &self.network.dns_search {
+            if !is_valid_hostname(domain) {
+                return Err(anyhow::anyhow!("Invalid DNS search domain: {}", domain));
+            }
+        }
+
+        for option in &self.network.dns_options {
+            if option.is_empty() || option.contains(char::is_whitespace) {
+                return Err(anyhow::anyhow!("Invalid DNS option: {:?}", option));
+            }
+        }
+
+        for entry in &self.network.extra_hosts {
+            if NetworkConfig::parse_extra_host(entry).is_none() {
+                return Err(anyhow::anyhow!("Extra host must be hostname:ip: {}", entry));
+            }
+        }
+
         if let Some(uid) = self.uid {
-            if uid == 0 && self.user.as_ref().map_or(false, |u| u != "root") {
+            if uid == 0 && self.user.as_ref().is_some_and(|u| u != "root") {
                 return Err(anyhow::anyhow!("UID 0 should only be used with user 'root'"));
             }
         }
@@ -146,11 +718,9 @@ impl ContainerConfig {
     }
 
     pub fn set_web_defaults(&mut self, port: u16) {
-        self.ports.push(PortMapping {
-            host_port: port,
-            container_port: 8080,
-            protocol: "tcp".to_string(),
-        });
+        self.ports.push(PortMapping::new(port, 8080, PortProtocol::Tcp));
+        // Deploy announces the application on localhost, which NAT rules do not reliably reach.
+        self.port_mode = PortMode::Proxy;
 
         self.environment.insert("PORT".to_string(), "8080".to_string());
         self.environment.insert("NODE_ENV".to_string(), "production".to_string());
@@ -162,6 +732,14 @@ impl ContainerConfig {
         if self.resources.cpu_quota.is_none() {
             self.resources.cpu_quota = Some(0.5);
         }
+
+        if self.healthcheck.is_none() {
+            self.healthcheck = Some(HealthCheckConfig {
+                interval_secs: 5,
+                timeout_secs: 5,
+                ..HealthCheckConfig::new(HealthProbe::Tcp { port: 8080 })
+            });
+        }
     }
 
     pub fn set_user(&mut self, user: String, uid: Option<u32>, gid: Option<u32>) {
@@ -194,3 +772,100 @@ impl ContainerConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn mapping(spec: &str) -> PortMapping {
+        spec.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_port_ranges_with_protocol() {
+        let port = mapping("8080-8090:80-90/udp");
+
+        assert_eq!(port.host_ip, None);
+        assert_eq!(port.host_ports(), 8080..=8090);
+        assert_eq!(port.container_ports(), 80..=90);
+        assert_eq!(port.port_count(), 11);
+        assert_eq!(port.protocol, PortProtocol::Udp);
+        assert_eq!(port.to_string(), "8080-8090:80-90/udp");
+        assert_eq!(mapping("8080-8090:80/UDP"), port);
+    }
+
+    #[test]
+    fn parses_host_addresses() {
+        let port = mapping("127.0.0.1:8080:80");
+
+        assert_eq!(port.host_ip, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert_eq!((port.host_port, port.container_port), (8080, 80));
+        assert_eq!(port.protocol, PortProtocol::Tcp);
+        assert_eq!(port.to_string(), "127.0.0.1:8080:80/tcp");
+
+        let port = mapping("[::1]:53:5353/udp");
+
+        assert_eq!(port.host_ip, Some("::1".parse().unwrap()));
+        assert_eq!(port.to_string(), "[::1]:53:5353/udp");
+        assert_eq!(mapping(&port.to_string()), port);
+    }
+
+    #[test]
+    fn rejects_malformed_mappings() {
+        for spec in ["8080", "8080:80/icmp", "localhost:8080:80", "8080:80-81x", "70000:80", ":80"] {
+            assert!(spec.parse::<PortMapping>().is_err(), "{} parsed", spec);
+        }
+    }
+
+    #[test]
+    fn rejects_ranges_of_different_lengths() {
+        let error = "8080-8090:80-85".parse::<PortMapping>().unwrap_err();
+
+        assert!(error.contains("differ in length"), "{}", error);
+        assert!("8080:80-81".parse::<PortMapping>().is_err());
+        assert!("8080-8081:80-81".parse::<PortMapping>().is_ok());
+    }
+
+    #[test]
+    fn overlapping_ranges_conflict() {
+        let range = mapping("8080-8090:80-90");
+
+        assert!(range.conflicts_with(&mapping("8090-8095:90-95")));
+        assert!(range.conflicts_with(&mapping("8085:80")));
+        assert!(mapping("8085:80").conflicts_with(&range));
+        assert!(range.conflicts_with(&mapping("127.0.0.1:8080:80")));
+    }
+
+    #[test]
+    fn adjacent_or_distinct_ranges_do_not_conflict() {
+        let range = mapping("8080-8090:80-90");
+
+        assert!(!range.conflicts_with(&mapping("8091-8095:91-95")));
+        assert!(!range.conflicts_with(&mapping("8070-8079:70-79")));
+        assert!(!range.conflicts_with(&mapping("8080-8090:80-90/udp")));
+        assert!(!mapping("127.0.0.1:8080:80").conflicts_with(&mapping("127.0.0.2:8080:80")));
+    }
+
+    #[test]
+    fn protocols_deserialize_in_any_case() {
+        #[derive(Deserialize)]
+        struct Ports {
+            ports: Vec<PortMapping>,
+        }
+
+        let ports: Ports = toml::from_str(r#"
+            ports = [
+                { host_port = 8080, container_port = 80, protocol = "TCP" },
+                { host_port = 53, container_port = 53, protocol = "Udp" },
+                { host_port = 9000, container_port = 9000 },
+            ]
+        "#).unwrap();
+        let protocols: Vec<PortProtocol> = ports.ports.iter().map(|port| port.protocol).collect();
+
+        assert_eq!(protocols, [PortProtocol::Tcp, PortProtocol::Udp, PortProtocol::Tcp]);
+        assert_eq!(serde_json::from_str::<PortProtocol>(r#""SCTP""#).unwrap(), PortProtocol::Sctp);
+        assert_eq!(serde_json::to_string(&PortProtocol::Sctp).unwrap(), r#""sctp""#);
+        assert!(serde_json::from_str::<PortProtocol>(r#""icmp""#).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use crate::api::{Response, ResponseSink};
+use crate::process::ExecIo;
+use crate::{TurbineError, Result};
+use nix::sys::socket::{shutdown, Shutdown};
+use nix::sys::termios::{self, LocalFlags, SetArg, Termios};
+use std::fs::File;
+use std::io::{ErrorKind, IsTerminal, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::thread::JoinHandle;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Keys that detach from a container without stopping it, in `--detach-keys` syntax.
+pub const DEFAULT_DETACH_KEYS: &str = "ctrl-p,ctrl-q";
+
+/// Parses a comma-separated key sequence such as `ctrl-p,ctrl-q`. Each key is a single
+/// character or `ctrl-` followed by a letter or one of `@[\]^_`.
+pub fn parse_detach_keys(keys: &str) -> Result<Vec<u8>> {
+    let invalid = || TurbineError::RuntimeError(format!("Invalid detach keys: {}", keys));
+
+    keys.split(',')
+        .map(|key| match key.strip_prefix("ctrl-") {
+            Some(control) => match control.as_bytes() {
+                [byte @ (b'a'..=b'z' | b'@' | b'[' | b'\\' | b']' | b'^' | b'_')] => Ok(byte & 0x1f),
+                [byte @ b'A'..=b'Z'] => Ok(byte & 0x1f),
+                _ => Err(invalid()),
+            },
+            None => match key.as_bytes() {
+                [byte] if byte.is_ascii() => Ok(*byte),
+                _ => Err(invalid()),
+            },
+        })
+        .collect()
+}
+
+/// Puts the terminal on stdin into raw mode, restoring its previous settings on drop.
+pub struct RawMode {
+    saved: Termios,
+}
+
+impl RawMode {
+    pub fn enable() -> Result<Self> {
+        Self::set(termios::cfmakeraw)
+    }
+
+    /// Raw mode for input only, for sessions whose output does not come from a terminal:
+    /// keys arrive one at a time and unprocessed, but newlines in the output still start
+    /// a new line and ctrl-c still interrupts the client.
+    pub fn enable_input() -> Result<Self> {
+        Self::set(|raw| {
+            let (output_flags, signals) = (raw.output_flags, raw.local_flags & LocalFlags::ISIG);
+
+            termios::cfmakeraw(raw);
+            raw.output_flags = output_flags;
+            raw.local_flags |= signals;
+        })
+    }
+
+    fn set(configure: impl FnOnce(&mut Termios)) -> Result<Self> {
+        let stdin = std::io::stdin();
+        let saved = termios::tcgetattr(stdin.as_fd())
+            .map_err(|e| TurbineError::RuntimeError(format!("Failed to read terminal settings: {}", e)))?;
+        let mut raw = saved.clone();
+
+        configure(&mut raw);
+        termios::tcsetattr(stdin.as_fd(), SetArg::TCSANOW, &raw)
+            .map_err(|e| TurbineError::RuntimeError(format!("Failed to set raw mode: {}", e)))?;
+
+        Ok(Self { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(std::io::stdin().as_fd(), SetArg::TCSANOW, &self.saved);
+    }
+}
+
+/// Size of the terminal on `fd` as (rows, columns), or `None` if it is not a terminal.
+pub fn window_size(fd: impl AsFd) -> Option<(u16, u16)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+
+    match unsafe { libc::ioctl(fd.as_fd().as_raw_fd(), libc::TIOCGWINSZ, &mut size) } {
+        0 => Some((size.ws_row, size.ws_col)),
+        _ => None,
+    }
+}
+
+/// Resizes the pseudo-terminal whose master is `fd`; the kernel signals its foreground
+/// process group with SIGWINCH.
+pub fn set_window_size(fd: impl AsFd, (rows, columns): (u16, u16)) -> Result<()> {
+    let size = libc::winsize {
+        ws_row: rows,
+        ws_col: columns,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    if unsafe { libc::ioctl(fd.as_fd().as_raw_fd(), libc::TIOCSWINSZ, &size) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// Client side of an exec or attach session. Connects the local terminal to the
+/// process's stdio once `ExecStarted` or `Attached` arrives, and records an exec'd
+/// process's exit code.
+#[derive(Default)]
+pub struct Console {
+    output: Vec<JoinHandle<()>>,
+    resize: Option<tokio::task::JoinHandle<()>>,
+    raw_mode: Option<RawMode>,
+    detach_keys: Option<Vec<u8>>,
+    exit_code: Option<i32>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Detaches when `keys` are typed on stdin, for sessions whose output arrives over
+    /// sockets.
+    pub fn with_detach_keys(mut self, keys: Vec<u8>) -> Self {
+        self.detach_keys = Some(keys);
+        self
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    fn attach(&mut self, io: ExecIo, forward_stdin: bool) -> Result<()> {
+        match io {
+            ExecIo::Tty(master) => {
+                let master = File::from(master);
+
+                if std::io::stdin().is_terminal() {
+                    self.raw_mode = Some(RawMode::enable()?);
+                }
+
+                if forward_stdin {
+                    spawn_copy(std::io::stdin(), master.try_clone()?)?;
+                }
+
+                self.output.push(spawn_copy(master.try_clone()?, std::io::stdout())?);
+                self.resize = Some(tokio::spawn(async move {
+                    let Ok(mut window_changes) = signal(SignalKind::window_change()) else {
+                        return;
+                    };
+
+                    while window_changes.recv().await.is_some() {
+                        if let Some(size) = window_size(std::io::stdout()) {
+                            let _ = set_window_size(&master, size);
+                        }
+                    }
+                }));
+            }
+            ExecIo::Pipes { stdin, stdout, stderr } => {
+                let (stdout, stderr) = (File::from(stdout), File::from(stderr));
+                let terminal = std::io::stdin().is_terminal();
+
+                // A cooked terminal holds keys back until a newline and keeps ctrl-q
+                // for flow control, so the detach keys would never arrive.
+                if terminal && self.detach_keys.is_some() {
+                    self.raw_mode = Some(RawMode::enable_input()?);
+                }
+
+                match (stdin, &self.detach_keys) {
+                    (Some(stdin), Some(keys)) => {
+                        let outputs = vec![stdout.try_clone()?, stderr.try_clone()?];
+                        spawn_input(File::from(stdin), keys.clone(), outputs)?;
+                    }
+                    (Some(stdin), None) => {
+                        spawn_copy(std::io::stdin(), File::from(stdin))?;
+                    }
+                    // Without the process's stdin, the terminal is still read for the
+                    // detach keys; what else is typed is dropped.
+                    (None, Some(keys)) if terminal => {
+                        let outputs = vec![stdout.try_clone()?, stderr.try_clone()?];
+                        spawn_input(std::io::sink(), keys.clone(), outputs)?;
+                    }
+                    (None, _) => {}
+                }
+
+                self.output.push(spawn_copy(stdout, std::io::stdout())?);
+                self.output.push(spawn_copy(stderr, std::io::stderr())?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the process's remaining output and restores the terminal.
+    async fn detach(&mut self) {
+        let output = std::mem::take(&mut self.output);
+        let _ = tokio::task::spawn_blocking(move || {
+            for copy in output {
+                let _ = copy.join();
+            }
+        }).await;
+
+        if let Some(resize) = self.resize.take() {
+            resize.abort();
+        }
+
+        self.raw_mode = None;
+    }
+}
+
+impl ResponseSink for Console {
+    async fn send(&mut self, response: Response) -> Result<()> {
+        match response {
+            Response::Done => {
+                self.detach().await;
+                Ok(())
+            }
+            Response::ExitCode(code) => {
+                self.detach().await;
+                self.exit_code = Some(code);
+                Ok(())
+            }
+            Response::Error(message) => {
+                self.detach().await;
+                Err(TurbineError::RuntimeError(message))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn send_with_fds(&mut self, response: Response, fds: Vec<OwnedFd>) -> Result<()> {
+        match response {
+            Response::ExecStarted { tty, stdin } => self.attach(ExecIo::from_fds(tty, stdin, fds)?, stdin),
+            Response::Attached { stdin } => self.attach(ExecIo::from_fds(false, stdin, fds)?, stdin),
+            response => self.send(response).await,
+        }
+    }
+}
+
+/// Copies `from` to `to` on a background thread until either side closes. Reading a
+/// pseudo-terminal master fails with EIO once the process side is gone, which also
+/// ends the copy.
+fn spawn_copy<R, W>(mut from: R, mut to: W) -> Result<JoinHandle<()>>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let handle = std::thread::Builder::new().spawn(move || {
+        let mut buffer = [0u8; 8192];
+
+        loop {
+            match from.read(&mut buffer) {
+                Ok(0) => return,
+                Ok(length) => {
+                    if to.write_all(&buffer[..length]).and_then(|_| to.flush()).is_err() {
+                        return;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            }
+        }
+    })?;
+
+    Ok(handle)
+}
+
+/// Forwards stdin to `to` until the detach keys are typed, then shuts down the
+/// session's output sockets, which ends the session on both sides. Keys that start the
+/// sequence are held back until it is clear whether they complete it.
+fn spawn_input<W>(mut to: W, detach_keys: Vec<u8>, outputs: Vec<File>) -> Result<()>
+where
+    W: Write + Send + 'static,
+{
+    std::thread::Builder::new().spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buffer = [0u8; 8192];
+        let mut matched = 0;
+
+        loop {
+            let length = match stdin.read(&mut buffer) {
+                Ok(0) => return,
+                Ok(length) => length,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+            let mut input = Vec::with_capacity(length);
+
+            for &byte in &buffer[..length] {
+                if byte == detach_keys[matched] {
+                    matched += 1;
+                    if matched == detach_keys.len() {
+                        let _ = to.write_all(&input);
+                        for output in &outputs {
+                            let _ = shutdown(output.as_raw_fd(), Shutdown::Both);
+                        }
+                        return;
+                    }
+                } else {
+                    input.extend_from_slice(&detach_keys[..matched]);
+                    matched = usize::from(byte == detach_keys[0]);
+                    if matched == 0 {
+                        input.push(byte);
+                    }
+                }
+            }
+
+            if to.write_all(&input).is_err() {
+                return;
+            }
+        }
+    })?;
+
+    Ok(())
+}
@@ -1,7 +1,12 @@
 use crate::{ContainerConfig, Result};
+use crate::health::Health;
+use crate::network::NetworkAttachment;
+use crate::state::{StateLock, StateStore};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
+use tokio::sync::{RwLock, RwLockWriteGuard};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -10,9 +15,36 @@ pub enum ContainerState {
     Running,
     Stopped,
     Paused,
+    /// Exited and waiting out the restart policy's backoff before being started again.
+    Restarting,
     Error(String),
 }
 
+/// How a container process ended: an exit code, or the signal that killed it. Both are
+/// unknown when the process was not a child of this runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitStatus {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// Whether the process is known to have failed, by a non-zero exit code or a signal.
+    /// An unknown status is not a failure.
+    pub fn failed(&self) -> bool {
+        self.code.is_some_and(|code| code != 0) || self.signal.is_some()
+    }
+
+    /// The status as a shell reports it: the exit code, or 128 plus the signal number.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.code.or(self.signal.map(|signal| 128 + signal))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Container {
     pub id: String,
@@ -20,6 +52,30 @@ pub struct Container {
     pub state: ContainerState,
     pub pid: Option<u32>,
     pub root_path: PathBuf,
+    /// Manifest digest of the stored image the container was created from.
+    #[serde(default)]
+    pub image_digest: Option<String>,
+    /// Overlay lower directories, topmost first. Empty when the root is a plain directory.
+    #[serde(default)]
+    pub lower_dirs: Vec<PathBuf>,
+    /// The container's cgroup v2 directory, set once it has been started.
+    #[serde(default)]
+    pub cgroup_path: Option<PathBuf>,
+    #[serde(default)]
+    pub exit_status: Option<ExitStatus>,
+    /// Restarts made by the restart policy since the container was last started by hand.
+    #[serde(default)]
+    pub restart_count: u32,
+    /// Set when the container was stopped by request, which `UnlessStopped` respects.
+    #[serde(default)]
+    pub manually_stopped: bool,
+    /// Set while the container runs with a health check.
+    #[serde(default)]
+    pub health: Option<Health>,
+    /// Networks the container is connected to. Its published ports forward to its
+    /// addresses on the first.
+    #[serde(default)]
+    pub networks: Vec<NetworkAttachment>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub started_at: Option<chrono::DateTime<chrono::Utc>>,
     pub stopped_at: Option<chrono::DateTime<chrono::Utc>>,
@@ -36,12 +92,26 @@ impl Container {
             state: ContainerState::Created,
             pid: None,
             root_path,
+            image_digest: None,
+            lower_dirs: Vec::new(),
+            cgroup_path: None,
+            exit_status: None,
+            restart_count: 0,
+            manually_stopped: false,
+            health: None,
+            networks: Vec::new(),
             created_at: chrono::Utc::now(),
            started_at: None,
            stopped_at: None,
         })
     }
 
+    /// The configured hostname, or the short container ID.
+    pub fn hostname(&self) -> String {
+        self.config.network.hostname.clone()
+            .unwrap_or_else(|| self.id.chars().take(12).collect())
+    }
+
     pub fn is_running(&self) -> bool {
         matches!(self.state, ContainerState::Running)
     }
@@ -50,15 +120,24 @@ impl Container {
         matches!(self.state, ContainerState::Stopped)
     }
 
+    pub fn is_paused(&self) -> bool {
+        matches!(self.state, ContainerState::Paused)
+    }
+
+    pub fn is_restarting(&self) -> bool {
+        matches!(self.state, ContainerState::Restarting)
+    }
+
     pub fn set_state(&mut self, state: ContainerState) {
         match state {
-            ContainerState::Running => {
+            ContainerState::Running if !self.is_paused() => {
                 self.started_at = Some(chrono::Utc::now());
                 self.stopped_at = None;
             }
             ContainerState::Stopped => {
                 self.stopped_at = Some(chrono::Utc::now());
                 self.pid = None;
+                self.health = None;
             }
             _ => {}
         }
@@ -89,23 +168,101 @@ impl Container {
 
 pub struct ContainerRegistry {
     containers: HashMap<String, Container>,
+    store: Option<StateStore>,
+    /// The store's generation the containers were read at or last written as.
+    generation: u64,
+    /// Held while a change is being made; see `write`.
+    lock: Option<StateLock>,
 }
 
 impl ContainerRegistry {
     pub fn new() -> Self {
         Self {
             containers: HashMap::new(),
+            store: None,
+            generation: 0,
+            lock: None,
         }
     }
 
-    pub fn register(&mut self, container: Container) -> Result<()> {
-        let id = container.id.clone();
+    pub fn with_store(store: StateStore) -> Result<Self> {
+        let (containers, generation) = store.load_all()?;
+
+        Ok(Self {
+            containers: containers.into_iter().map(|c| (c.id.clone(), c)).collect(),
+            store: Some(store),
+            generation,
+            lock: None,
+        })
+    }
+
+    /// Takes `registry` for writing along with the state store's lock, first reading
+    /// the containers again if another process changed them since. Changes made through
+    /// the guard thus apply to the current state, and no other process writes before
+    /// the guard is dropped.
+    pub async fn write(registry: &RwLock<Self>) -> Result<RegistryWriteGuard<'_>> {
+        let mut guard = registry.write().await;
+
+        guard.lock()?;
+        Ok(RegistryWriteGuard(guard))
+    }
+
+    /// Takes the state store's lock, reloading the containers if they are out of date.
+    pub fn lock(&mut self) -> Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let lock = store.lock()?;
+
+        if store.generation(&lock)? != self.generation {
+            let (containers, generation) = store.load_all_locked(&lock)?;
+
+            self.containers = containers.into_iter().map(|c| (c.id.clone(), c)).collect();
+            self.generation = generation;
+        }
+
+        self.lock = Some(lock);
+        Ok(())
+    }
+
+    pub fn unlock(&mut self) {
+        self.lock = None;
+    }
+
+    /// Runs `write` under the state store's lock, taking it for just this write unless
+    /// it is held already.
+    fn store<F>(&mut self, write: F) -> Result<()>
+    where
+        F: FnOnce(&StateStore, &StateLock) -> Result<u64>,
+    {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
 
-        self.containers.insert(id, container);
+        self.generation = match &self.lock {
+            Some(lock) => write(store, lock)?,
+            None => write(store, &store.lock()?)?,
+        };
 
         Ok(())
     }
 
+    pub fn register(&mut self, container: Container) -> Result<()> {
+        self.store(|store, lock| store.save(lock, &container))?;
+        self.containers.insert(container.id.clone(), container);
+
+        Ok(())
+    }
+
+    /// Writes the current in-memory state of a container through to the state store.
+    pub fn persist(&mut self, id: &str) -> Result<()> {
+        let Some(container) = self.containers.get(id).cloned() else {
+            return Ok(());
+        };
+
+        self.store(|store, lock| store.save(lock, &container))
+    }
+
     pub fn get(&self, id: &str) -> Option<&Container> {
         self.containers.get(id)
     }
@@ -114,8 +271,10 @@ impl ContainerRegistry {
         self.containers.get_mut(id)
     }
 
-    pub fn remove(&mut self, id: &str) -> Option<Container> {
-        self.containers.remove(id)
+    pub fn remove(&mut self, id: &str) -> Result<Option<Container>> {
+        self.store(|store, lock| store.remove(lock, id))?;
+
+        Ok(self.containers.remove(id))
     }
 
     pub fn list(&self) -> Vec<&Container> {
@@ -136,3 +295,94 @@ impl Default for ContainerRegistry {
         Self::new()
     }
 }
+
+/// Write access to the registry holding the state store's lock, released on drop.
+pub struct RegistryWriteGuard<'a>(RwLockWriteGuard<'a, ContainerRegistry>);
+
+impl Deref for RegistryWriteGuard<'_> {
+    type Target = ContainerRegistry;
+
+    fn deref(&self) -> &ContainerRegistry {
+        &self.0
+    }
+}
+
+impl DerefMut for RegistryWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut ContainerRegistry {
+        &mut self.0
+    }
+}
+
+impl Drop for RegistryWriteGuard<'_> {
+    fn drop(&mut self) {
+        self.0.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A registry over the state under `base_path`, as another process would open it.
+    fn open(base_path: &std::path::Path) -> ContainerRegistry {
+        ContainerRegistry::with_store(StateStore::open(base_path).unwrap()).unwrap()
+    }
+
+    fn container(name: &str) -> Container {
+        Container::new(ContainerConfig {
+            name: name.to_string(),
+            image: "alpine".to_string(),
+            ..Default::default()
+        }).unwrap()
+    }
+
+    #[test]
+    fn locking_picks_up_changes_of_other_processes() {
+        let base_path = std::env::temp_dir().join(format!("turbine-state-{}", Uuid::new_v4()));
+        let mut first = open(&base_path);
+        let mut second = open(&base_path);
+        let web = container("web");
+        let db = container("db");
+
+        first.lock().unwrap();
+        first.register(web.clone()).unwrap();
+        first.unlock();
+
+        second.lock().unwrap();
+        assert!(second.get(&web.id).is_some());
+        second.register(db.clone()).unwrap();
+        second.remove(&web.id).unwrap();
+        second.unlock();
+
+        // Changing the removed container must not bring it back.
+        first.lock().unwrap();
+        assert!(first.get_mut(&web.id).is_none());
+        first.get_mut(&db.id).unwrap().restart_count = 3;
+        first.persist(&db.id).unwrap();
+        first.unlock();
+
+        let reopened = open(&base_path);
+
+        assert!(reopened.get(&web.id).is_none());
+        assert_eq!(reopened.get(&db.id).map(|c| c.restart_count), Some(3));
+
+        let _ = std::fs::remove_dir_all(&base_path);
+    }
+
+    #[test]
+    fn locking_keeps_unchanged_state() {
+        let base_path = std::env::temp_dir().join(format!("turbine-state-{}", Uuid::new_v4()));
+        let mut registry = open(&base_path);
+        let web = container("web");
+
+        registry.register(web.clone()).unwrap();
+        registry.get_mut(&web.id).unwrap().restart_count = 1;
+
+        // Nothing else wrote, so the unsaved change is still there.
+        registry.lock().unwrap();
+        assert_eq!(registry.get(&web.id).map(|c| c.restart_count), Some(1));
+        registry.unlock();
+
+        let _ = std::fs::remove_dir_all(&base_path);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::api::{self, RequestEnvelope, Response, ResponseEnvelope, ResponseSink, API_VERSION};
+use crate::{TurbineError, TurbineRuntime, Result};
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+use nix::sys::stat::{umask, Mode};
+use std::io::IoSlice;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Interest};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Long-running owner of a single `TurbineRuntime`, serving the control API as
+/// newline-delimited JSON over a Unix socket.
+pub struct Daemon {
+    runtime: Arc<TurbineRuntime>,
+    socket_path: PathBuf,
+}
+
+impl Daemon {
+    pub fn new<P: AsRef<Path>>(runtime: TurbineRuntime, socket_path: P) -> Self {
+        Self {
+            runtime: Arc::new(runtime),
+            socket_path: socket_path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let listener = self.bind()?;
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let supervisor = tokio::spawn(Arc::clone(&self.runtime).supervise());
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    match accepted {
+                        Ok((stream, _)) => {
+                            let runtime = Arc::clone(&self.runtime);
+
+                            tokio::spawn(async move {
+                                if let Err(e) = handle_connection(runtime, stream).await {
+                                    eprintln!("Warning: Connection error: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) => eprintln!("Warning: Failed to accept connection: {}", e),
+                    }
+                }
+                _ = sigterm.recv() => break,
+                _ = sigint.recv() => break,
+            }
+        }
+
+        supervisor.abort();
+        let _ = std::fs::remove_file(&self.socket_path);
+
+        Ok(())
+    }
+
+    fn bind(&self) -> Result<UnixListener> {
+        if let Some(parent) = self.socket_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        if self.socket_path.exists() {
+            if std::os::unix::net::UnixStream::connect(&self.socket_path).is_ok() {
+                return Err(TurbineError::RuntimeError(
+                    format!("Another daemon is already listening on {:?}", self.socket_path)
+                ));
+            }
+
+            std::fs::remove_file(&self.socket_path)?;
+        }
+
+        // Create the socket without group or other access so no client can connect
+        // before its mode is set. The umask is process-wide, but bind runs before
+        // the daemon starts any task that creates files.
+        let previous = umask(Mode::from_bits_truncate(0o177));
+        let listener = UnixListener::bind(&self.socket_path);
+        umask(previous);
+        let listener = listener?;
+
+        std::fs::set_permissions(&self.socket_path, std::fs::Permissions::from_mode(0o660))?;
+
+        Ok(listener)
+    }
+}
+
+/// Writes responses to a client connection as newline-delimited envelopes.
+struct ResponseWriter(OwnedWriteHalf);
+
+impl ResponseSink for ResponseWriter {
+    async fn send(&mut self, response: Response) -> Result<()> {
+        self.0.write_all(&encode(response)?).await?;
+
+        Ok(())
+    }
+
+    /// Passes `fds` to the client with `SCM_RIGHTS`, attached to the response's first byte.
+    async fn send_with_fds(&mut self, response: Response, fds: Vec<OwnedFd>) -> Result<()> {
+        let payload = encode(response)?;
+        let raw_fds: Vec<RawFd> = fds.iter().map(AsRawFd::as_raw_fd).collect();
+        let stream: &UnixStream = self.0.as_ref();
+        let sent = loop {
+            stream.writable().await?;
+
+            let sent = stream.try_io(Interest::WRITABLE, || {
+                let rights = [ControlMessage::ScmRights(&raw_fds)];
+
+                Ok(sendmsg::<()>(stream.as_raw_fd(), &[IoSlice::new(&payload)], &rights, MsgFlags::empty(), None)?)
+            });
+
+            match sent {
+                Ok(sent) => break sent,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        self.0.write_all(&payload[sent..]).await?;
+
+        Ok(())
+    }
+}
+
+fn encode(response: Response) -> Result<Vec<u8>> {
+    let mut payload = serde_json::to_vec(&ResponseEnvelope::new(response))?;
+
+    payload.push(b'\n');
+    Ok(payload)
+}
+
+async fn handle_connection(runtime: Arc<TurbineRuntime>, stream: UnixStream) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut writer = ResponseWriter(writer);
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request = match serde_json::from_str::<RequestEnvelope>(&line) {
+            Ok(envelope) if envelope.version != API_VERSION => Err(format!(
+                "Unsupported API version {} (daemon speaks {})", envelope.version, API_VERSION
+            )),
+            Ok(envelope) => Ok(envelope.request),
+            Err(e) => Err(format!("Malformed request: {}", e)),
+        };
+        match request {
+            Ok(request) => api::dispatch_streaming(&runtime, request, &mut writer).await?,
+            Err(message) => writer.send(Response::Error(message)).await?,
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,594 @@
+use crate::container::ContainerRegistry;
+use crate::filesystem::host_nameservers;
+use crate::network::Network;
+use crate::{Container, TurbineError, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{RwLock, Semaphore};
+use tokio::task::JoinHandle;
+
+pub const DNS_PORT: u16 = 53;
+
+/// How long resolvers may cache the addresses of a container.
+const RECORD_TTL: u32 = 60;
+
+/// How long an upstream resolver gets to answer a forwarded query before the next one
+/// is tried.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Queries answered at once, across networks. Further queries wait in their socket's
+/// receive buffer, which drops what does not fit.
+const MAX_CONCURRENT_QUERIES: usize = 64;
+
+/// Large enough for EDNS answers, which UDP resolvers mostly cap at 4096 bytes.
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// What every client accepts over UDP. Local answers are capped to fit, as queries
+/// are not served over TCP for clients to retry a truncated response on.
+const MAX_UDP_MESSAGE_SIZE: usize = 512;
+
+const HEADER_LEN: usize = 12;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RCODE_FORMERR: u8 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+const RCODE_REFUSED: u8 = 5;
+
+/// DNS resolver listening on the gateways of networks, over UDP. Containers connected
+/// to a network are found by name, hostname or network alias there; other names are
+/// forwarded to the querying container's configured nameservers or else the host's.
+/// Internal networks answer for their containers only.
+pub struct DnsServer {
+    registry: Arc<RwLock<ContainerRegistry>>,
+    /// Set once `start` ran; only then are networks served. The CLI's in-process
+    /// runtime never starts it, as nothing would answer once it exits.
+    active: AtomicBool,
+    /// Listener tasks of each network, one per gateway.
+    listeners: Mutex<HashMap<String, Vec<JoinHandle<()>>>>,
+    /// Permits for the queries being answered.
+    queries: Arc<Semaphore>,
+}
+
+impl DnsServer {
+    pub fn new(registry: Arc<RwLock<ContainerRegistry>>) -> Self {
+        Self {
+            registry,
+            active: AtomicBool::new(false),
+            listeners: Mutex::new(HashMap::new()),
+            queries: Arc::new(Semaphore::new(MAX_CONCURRENT_QUERIES)),
+        }
+    }
+
+    /// Serves `networks` and, from now on, networks passed to `serve`.
+    pub fn start(&self, networks: &[Network]) {
+        self.active.store(true, Ordering::SeqCst);
+
+        for network in networks {
+            self.serve(network);
+        }
+    }
+
+    /// Starts answering queries on the network's gateways, unless the server was not
+    /// started or already serves the network. A network that cannot be served is
+    /// warned about and left without DNS.
+    pub fn serve(&self, network: &Network) {
+        if let Err(e) = self.listen_on(network) {
+            eprintln!("Warning: No DNS for network {}: {}", network.name, e);
+        }
+    }
+
+    /// The bridge must have its gateway addresses.
+    fn listen_on(&self, network: &Network) -> Result<()> {
+        if !self.active.load(Ordering::SeqCst) || self.listeners.lock().unwrap().contains_key(&network.name) {
+            return Ok(());
+        }
+
+        let mut sockets = Vec::new();
+
+        for gateway in network.gateways() {
+            let socket = std::net::UdpSocket::bind(SocketAddr::new(gateway, DNS_PORT))
+                .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+                .and_then(UdpSocket::from_std)
+                .map_err(|e| TurbineError::NetworkError(format!(
+                    "Failed to listen for DNS queries on {}: {}", gateway, e
+                )))?;
+
+            sockets.push(Arc::new(socket));
+        }
+
+        let handles = sockets.into_iter()
+            .map(|socket| tokio::spawn(listen(Arc::clone(&self.registry), network.clone(), socket, Arc::clone(&self.queries))))
+            .collect();
+
+        self.listeners.lock().unwrap().insert(network.name.clone(), handles);
+        Ok(())
+    }
+
+    pub fn stop(&self, network: &str) {
+        for handle in self.listeners.lock().unwrap().remove(network).unwrap_or_default() {
+            handle.abort();
+        }
+    }
+
+    pub fn stop_all(&self) {
+        for (_, handles) in self.listeners.lock().unwrap().drain() {
+            for handle in handles {
+                handle.abort();
+            }
+        }
+    }
+
+    /// The address containers on `network` query, if it is being served.
+    pub fn resolver(&self, network: &Network) -> Option<IpAddr> {
+        if !self.listeners.lock().unwrap().contains_key(&network.name) {
+            return None;
+        }
+
+        network.gateway_ipv4.map(IpAddr::V4).or(network.gateway_ipv6.map(IpAddr::V6))
+    }
+}
+
+async fn listen(registry: Arc<RwLock<ContainerRegistry>>, network: Network, socket: Arc<UdpSocket>, queries: Arc<Semaphore>) {
+    let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+
+    loop {
+        let Ok(permit) = Arc::clone(&queries).acquire_owned().await else {
+            return;
+        };
+        let (length, client) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("Warning: DNS listener of network {} failed: {}", network.name, e);
+                return;
+            }
+        };
+        let query = buffer[..length].to_vec();
+        let registry = Arc::clone(&registry);
+        let network = network.clone();
+        let socket = Arc::clone(&socket);
+
+        // Forwarded queries wait on upstream resolvers, so each query gets a task,
+        // holding a permit until it is answered.
+        tokio::spawn(async move {
+            if let Some(response) = answer(&registry, &network, client.ip(), &query).await {
+                let _ = socket.send_to(&response, client).await;
+            }
+
+            drop(permit);
+        });
+    }
+}
+
+/// The response to `query` from `client`, or nothing for messages that are not worth
+/// an answer, such as responses.
+async fn answer(registry: &RwLock<ContainerRegistry>, network: &Network, client: IpAddr, query: &[u8]) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LEN || query[2] & 0x80 != 0 {
+        return None;
+    }
+
+    // Standard queries only.
+    if (query[2] >> 3) & 0x0f != 0 {
+        return Some(response(query, None, RCODE_NOTIMP, &[]));
+    }
+
+    let Some(question) = Question::parse(query) else {
+        return Some(response(query, None, RCODE_FORMERR, &[]));
+    };
+
+    let upstreams = {
+        let registry = registry.read().await;
+        let containers = registry.list();
+
+        if question.class == CLASS_IN {
+            if let Some(ips) = lookup(&containers, network, &question.name) {
+                let records: Vec<IpAddr> = ips.into_iter()
+                    .filter(|ip| match question.record_type {
+                        TYPE_A => ip.is_ipv4(),
+                        TYPE_AAAA => ip.is_ipv6(),
+                        _ => false,
+                    })
+                    .collect();
+
+                return Some(response(query, Some(&question), 0, &records));
+            }
+        }
+
+        if network.internal {
+            return Some(response(query, Some(&question), RCODE_REFUSED, &[]));
+        }
+
+        upstreams(&containers, network, client)
+    };
+
+    match forward(query, &upstreams).await {
+        Some(response) => Some(response),
+        None if upstreams.is_empty() => Some(response(query, Some(&question), RCODE_NXDOMAIN, &[])),
+        None => Some(response(query, Some(&question), RCODE_SERVFAIL, &[])),
+    }
+}
+
+/// Addresses on `network` of the running containers `name` refers to, or nothing if it
+/// refers to none.
+fn lookup(containers: &[&Container], network: &Network, name: &str) -> Option<Vec<IpAddr>> {
+    let mut ips = Vec::new();
+    let mut found = false;
+
+    for container in containers.iter().filter(|container| container.is_running() || container.is_paused()) {
+        let Some(attachment) = container.networks.iter().find(|attachment| attachment.network == network.name) else {
+            continue;
+        };
+        let matches = container.config.name.eq_ignore_ascii_case(name)
+            || container.hostname().eq_ignore_ascii_case(name)
+            || attachment.aliases.iter().any(|alias| alias.eq_ignore_ascii_case(name));
+
+        if matches {
+            found = true;
+            ips.extend(&attachment.ips);
+        }
+    }
+
+    found.then_some(ips)
+}
+
+/// Where to forward the queries of the container at `client` on `network`: its
+/// configured nameservers, or else the host's.
+fn upstreams(containers: &[&Container], network: &Network, client: IpAddr) -> Vec<SocketAddr> {
+    let configured = containers.iter()
+        .find(|container| container.networks.iter().any(|attachment| {
+            attachment.network == network.name && attachment.ips.contains(&client)
+        }))
+        .map(|container| container.config.network.dns.iter()
+            .filter_map(|server| server.parse::<IpAddr>().ok())
+            .collect::<Vec<_>>())
+        .unwrap_or_default();
+    let servers = if configured.is_empty() { host_nameservers(true) } else { configured };
+
+    servers.into_iter()
+        // Not back to ourselves.
+        .filter(|server| !network.gateways().contains(server))
+        .map(|server| SocketAddr::new(server, DNS_PORT))
+        .collect()
+}
+
+/// Sends `query` to each upstream in turn and returns the first response to it.
+async fn forward(query: &[u8], upstreams: &[SocketAddr]) -> Option<Vec<u8>> {
+    for upstream in upstreams {
+        let local: SocketAddr = if upstream.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().ok()?;
+        let exchange = async {
+            let socket = UdpSocket::bind(local).await?;
+            let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+
+            socket.connect(upstream).await?;
+            socket.send(query).await?;
+
+            loop {
+                let length = socket.recv(&mut buffer).await?;
+
+                if length >= HEADER_LEN && buffer[..2] == query[..2] {
+                    buffer.truncate(length);
+                    return Ok::<_, std::io::Error>(buffer);
+                }
+            }
+        };
+
+        if let Ok(Ok(response)) = tokio::time::timeout(UPSTREAM_TIMEOUT, exchange).await {
+            return Some(response);
+        }
+    }
+
+    None
+}
+
+/// The first question of a query. Queries with other than exactly one are rejected.
+struct Question {
+    /// Lowercase, without the trailing dot.
+    name: String,
+    record_type: u16,
+    class: u16,
+    /// End of the question in the query.
+    end: usize,
+}
+
+impl Question {
+    fn parse(query: &[u8]) -> Option<Self> {
+        let count = query.get(4..6)?;
+
+        if u16::from_be_bytes([count[0], count[1]]) != 1 {
+            return None;
+        }
+
+        let mut labels = Vec::new();
+        let mut position = HEADER_LEN;
+
+        loop {
+            let length = *query.get(position)? as usize;
+            position += 1;
+
+            if length == 0 {
+                break;
+            }
+
+            // Compression pointers have no use in a question that comes first.
+            if length > 63 {
+                return None;
+            }
+
+            labels.push(String::from_utf8_lossy(query.get(position..position + length)?).to_ascii_lowercase());
+            position += length;
+        }
+
+        let fixed = query.get(position..position + 4)?;
+
+        Some(Self {
+            name: labels.join("."),
+            record_type: u16::from_be_bytes([fixed[0], fixed[1]]),
+            class: u16::from_be_bytes([fixed[2], fixed[3]]),
+            end: position + 4,
+        })
+    }
+}
+
+/// An authoritative response to `query` echoing its question, with a record of the
+/// question's name for each of `ips` that fits in a UDP message.
+fn response(query: &[u8], question: Option<&Question>, rcode: u8, ips: &[IpAddr]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(MAX_UDP_MESSAGE_SIZE);
+
+    message.extend_from_slice(&query[..2]);
+    // QR and AA set, opcode and RD kept; RA set.
+    message.push(0x80 | (query[2] & 0x79) | 0x04);
+    message.push(0x80 | rcode);
+    message.extend_from_slice(&(question.is_some() as u16).to_be_bytes());
+    // The answer count, once known.
+    message.extend_from_slice(&[0, 0]);
+    message.extend_from_slice(&[0, 0, 0, 0]);
+
+    if let Some(question) = question {
+        message.extend_from_slice(&query[HEADER_LEN..question.end]);
+    }
+
+    let mut answers: u16 = 0;
+
+    for ip in ips {
+        let (record_type, data) = match ip {
+            IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+        };
+
+        if message.len() + 12 + data.len() > MAX_UDP_MESSAGE_SIZE {
+            break;
+        }
+
+        // The name is a pointer to the question's.
+        message.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        message.extend_from_slice(&record_type.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&RECORD_TTL.to_be_bytes());
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(&data);
+        answers += 1;
+    }
+
+    message[6..8].copy_from_slice(&answers.to_be_bytes());
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{NetworkAttachment, NetworkConfig};
+    use crate::{ContainerConfig, ContainerState};
+    use std::net::Ipv4Addr;
+
+    /// A standard query with recursion desired for `name`.
+    fn query(name: &str, record_type: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+
+        query.push(0);
+        query.extend_from_slice(&record_type.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    fn network(internal: bool) -> Network {
+        Network {
+            name: "test".to_string(),
+            bridge: "turbine-test".to_string(),
+            subnet: NetworkConfig::IPv4 { subnet: Ipv4Addr::new(172, 30, 0, 0), prefix: 24 },
+            gateway_ipv4: Some(Ipv4Addr::new(172, 30, 0, 1)),
+            gateway_ipv6: None,
+            mtu: None,
+            internal,
+            reserved: Vec::new(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    /// A registry with a running container `web` on `network` at `ips`, which forwards
+    /// to nowhere but the network's own resolver.
+    fn registry(network: &Network, ips: &[&str]) -> RwLock<ContainerRegistry> {
+        let mut container = Container::new(ContainerConfig {
+            name: "web".to_string(),
+            image: "alpine".to_string(),
+            ..Default::default()
+        }).unwrap();
+
+        container.config.network.dns = vec!["172.30.0.1".to_string()];
+        container.networks.push(NetworkAttachment {
+            network: network.name.clone(),
+            interface: "eth0".to_string(),
+            ips: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            aliases: vec!["api".to_string()],
+        });
+        container.set_state(ContainerState::Running);
+
+        let mut registry = ContainerRegistry::new();
+        registry.register(container).unwrap();
+        RwLock::new(registry)
+    }
+
+    fn rcode(response: &[u8]) -> u8 {
+        response[3] & 0x0f
+    }
+
+    fn answer_count(response: &[u8]) -> u16 {
+        u16::from_be_bytes([response[6], response[7]])
+    }
+
+    #[test]
+    fn parse_reads_the_question() {
+        let query = query("Web.Example", TYPE_AAAA);
+        let question = Question::parse(&query).unwrap();
+
+        assert_eq!(question.name, "web.example");
+        assert_eq!(question.record_type, TYPE_AAAA);
+        assert_eq!(question.class, CLASS_IN);
+        assert_eq!(question.end, query.len());
+    }
+
+    #[test]
+    fn parse_rejects_truncated_questions() {
+        let query = query("web.example", TYPE_A);
+
+        for length in 0..query.len() {
+            assert!(Question::parse(&query[..length]).is_none(), "parsed {} bytes", length);
+        }
+    }
+
+    #[test]
+    fn parse_rejects_other_than_one_question() {
+        let mut query = query("web", TYPE_A);
+
+        query[5] = 0;
+        assert!(Question::parse(&query).is_none());
+
+        query[5] = 2;
+        assert!(Question::parse(&query).is_none());
+    }
+
+    #[test]
+    fn parse_rejects_compression_pointers() {
+        let mut query = query("web", TYPE_A);
+
+        // The name points at itself.
+        query.splice(HEADER_LEN..HEADER_LEN + 5, [0xc0, HEADER_LEN as u8]);
+        assert!(Question::parse(&query).is_none());
+    }
+
+    #[test]
+    fn response_encodes_a_and_aaaa_records() {
+        let query = query("web", TYPE_A);
+        let question = Question::parse(&query).unwrap();
+        let v6: std::net::Ipv6Addr = "fd00::2".parse().unwrap();
+        let ips = [IpAddr::from([172, 30, 0, 2]), IpAddr::V6(v6)];
+        let response = response(&query, Some(&question), 0, &ips);
+
+        assert_eq!(response[..2], query[..2]);
+        // QR, AA and RD; RA.
+        assert_eq!(response[2..4], [0x85, 0x80]);
+        assert_eq!(answer_count(&response), 2);
+        assert_eq!(response[HEADER_LEN..question.end], query[HEADER_LEN..]);
+
+        let records = &response[question.end..];
+
+        assert_eq!(records[..16], [0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 172, 30, 0, 2]);
+        assert_eq!(records[16..28], [0xc0, 12, 0, 28, 0, 1, 0, 0, 0, 60, 0, 16]);
+        assert_eq!(records[28..], v6.octets());
+    }
+
+    #[test]
+    fn response_caps_answers_to_a_udp_message() {
+        let query = query("web", TYPE_AAAA);
+        let question = Question::parse(&query).unwrap();
+        let ips: Vec<IpAddr> = (1..=40u16).map(|i| IpAddr::from([0xfd00, 0, 0, 0, 0, 0, 0, i])).collect();
+        let response = response(&query, Some(&question), 0, &ips);
+        let fitting = (MAX_UDP_MESSAGE_SIZE - question.end) / 28;
+
+        assert!(response.len() <= MAX_UDP_MESSAGE_SIZE);
+        assert_eq!(answer_count(&response) as usize, fitting);
+        assert_eq!(response.len(), question.end + fitting * 28);
+    }
+
+    #[tokio::test]
+    async fn answer_ignores_short_messages_and_responses() {
+        let network = network(false);
+        let registry = registry(&network, &["172.30.0.2"]);
+        let client = IpAddr::from([172, 30, 0, 2]);
+        let mut response = query("web", TYPE_A);
+
+        response[2] |= 0x80;
+
+        assert!(answer(&registry, &network, client, &[0x12, 0x34, 0x01]).await.is_none());
+        assert!(answer(&registry, &network, client, &response).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn answer_rejects_malformed_queries() {
+        let network = network(false);
+        let registry = registry(&network, &["172.30.0.2"]);
+        let client = IpAddr::from([172, 30, 0, 2]);
+        let query = query("web", TYPE_A);
+        let mut inverse = query.clone();
+
+        inverse[2] |= 0x08;
+
+        let truncated = answer(&registry, &network, client, &query[..HEADER_LEN + 2]).await.unwrap();
+        assert_eq!(rcode(&truncated), RCODE_FORMERR);
+        assert_eq!(truncated.len(), HEADER_LEN);
+
+        assert_eq!(rcode(&answer(&registry, &network, client, &inverse).await.unwrap()), RCODE_NOTIMP);
+    }
+
+    #[tokio::test]
+    async fn answer_resolves_containers_by_name_and_alias() {
+        let network = network(false);
+        let registry = registry(&network, &["172.30.0.2", "fd00::2"]);
+        let client = IpAddr::from([172, 30, 0, 3]);
+
+        for name in ["web", "API"] {
+            let a = answer(&registry, &network, client, &query(name, TYPE_A)).await.unwrap();
+            let aaaa = answer(&registry, &network, client, &query(name, TYPE_AAAA)).await.unwrap();
+
+            assert_eq!((rcode(&a), answer_count(&a)), (0, 1));
+            assert_eq!(a[a.len() - 4..], [172, 30, 0, 2]);
+            assert_eq!((rcode(&aaaa), answer_count(&aaaa)), (0, 1));
+        }
+    }
+
+    #[tokio::test]
+    async fn answer_fails_unknown_names_without_upstreams() {
+        let network = network(false);
+        let registry = registry(&network, &["172.30.0.2"]);
+        // The container's only nameserver is the network's resolver itself.
+        let client = IpAddr::from([172, 30, 0, 2]);
+
+        let response = answer(&registry, &network, client, &query("db", TYPE_A)).await.unwrap();
+
+        assert_eq!(rcode(&response), RCODE_NXDOMAIN);
+        assert_eq!(answer_count(&response), 0);
+    }
+
+    #[tokio::test]
+    async fn internal_networks_refuse_other_names() {
+        let network = network(true);
+        let registry = registry(&network, &["172.30.0.2"]);
+        let client = IpAddr::from([172, 30, 0, 2]);
+
+        let response = answer(&registry, &network, client, &query("example.com", TYPE_A)).await.unwrap();
+
+        assert_eq!(rcode(&response), RCODE_REFUSED);
+    }
+}
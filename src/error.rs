@@ -5,13 +5,22 @@ pub enum TurbineError {
     ConfigError(String),
     ContainerError(String),
     NetworkError(String),
+    /// A network object to be created already exists.
+    NetworkExists(String),
+    NetworkNotFound(String),
+    NetworkPermissionDenied(String),
     FilesystemError(String),
     ProcessError(String),
     SecurityError(String),
     RuntimeError(String),
+    StateError(String),
+    ImageError(String),
+    CgroupError(String),
+    LogError(String),
     IoError(std::io::Error),
     SerdeError(toml::de::Error),
     SerdeSerError(toml::ser::Error),
+    JsonError(serde_json::Error),
 }
 
 impl fmt::Display for TurbineError {
@@ -19,14 +28,22 @@ impl fmt::Display for TurbineError {
         match self {
             TurbineError::ConfigError(msg) => write!(f, "Configuration error: {}", msg),
             TurbineError::ContainerError(msg) => write!(f, "Container error: {}", msg),
-            TurbineError::NetworkError(msg) => write!(f, "Network error: {}", msg),
+            TurbineError::NetworkError(msg)
+            | TurbineError::NetworkExists(msg)
+            | TurbineError::NetworkNotFound(msg)
+            | TurbineError::NetworkPermissionDenied(msg) => write!(f, "Network error: {}", msg),
             TurbineError::FilesystemError(msg) => write!(f, "Filesystem error: {}", msg),
             TurbineError::ProcessError(msg) => write!(f, "Process error: {}", msg),
             TurbineError::SecurityError(msg) => write!(f, "Security error: {}", msg),
             TurbineError::RuntimeError(msg) => write!(f, "Runtime error: {}", msg),
+            TurbineError::StateError(msg) => write!(f, "State error: {}", msg),
+            TurbineError::ImageError(msg) => write!(f, "Image error: {}", msg),
+            TurbineError::CgroupError(msg) => write!(f, "Cgroup error: {}", msg),
+            TurbineError::LogError(msg) => write!(f, "Log driver error: {}", msg),
             TurbineError::IoError(err) => write!(f, "IO error: {}", err),
             TurbineError::SerdeError(err) => write!(f, "TOML deserialization error: {}", err),
             TurbineError::SerdeSerError(err) => write!(f, "TOML serialization error: {}", err),
+            TurbineError::JsonError(err) => write!(f, "JSON error: {}", err),
         }
     }
 }
@@ -51,6 +68,12 @@ impl From<toml::ser::Error> for TurbineError {
     }
 }
 
+impl From<serde_json::Error> for TurbineError {
+    fn from(err: serde_json::Error) -> Self {
+        TurbineError::JsonError(err)
+    }
+}
+
 impl From<anyhow::Error> for TurbineError {
     fn from(err: anyhow::Error) -> Self {
         TurbineError::RuntimeError(err.to_string())
@@ -0,0 +1,342 @@
+use crate::health::HealthStatus;
+use crate::state::lock_file;
+use crate::{Container, TurbineError, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast;
+
+/// Journal of past events under the base path. Once it grows past
+/// `MAX_JOURNAL_BYTES` it is moved to `events.jsonl.1`, replacing the previous one.
+const JOURNAL_FILE: &str = "events.jsonl";
+
+const MAX_JOURNAL_BYTES: u64 = 1024 * 1024;
+
+/// Live events buffered for each subscriber. A subscriber that falls further behind
+/// misses the oldest ones.
+const EVENT_BACKLOG: usize = 256;
+
+/// What happened to a container.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum EventAction {
+    Create,
+    Start,
+    /// The container's process exited. The exit code is 128 plus the signal number
+    /// for processes killed by a signal, and unknown for processes this runtime did
+    /// not start.
+    Die { exit_code: Option<i32> },
+    /// The OOM killer killed a process in the container.
+    Oom,
+    Pause,
+    Unpause,
+    Restart,
+    Destroy,
+    HealthStatus { status: HealthStatus },
+}
+
+impl EventAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventAction::Create => "create",
+            EventAction::Start => "start",
+            EventAction::Die { .. } => "die",
+            EventAction::Oom => "oom",
+            EventAction::Pause => "pause",
+            EventAction::Unpause => "unpause",
+            EventAction::Restart => "restart",
+            EventAction::Destroy => "destroy",
+            EventAction::HealthStatus { .. } => "health_status",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub time: DateTime<Utc>,
+    pub container_id: String,
+    pub container_name: String,
+    #[serde(flatten)]
+    pub action: EventAction,
+}
+
+/// Selects the events returned by `TurbineRuntime::events`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventQuery {
+    /// Keep streaming new events as they happen.
+    pub follow: bool,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Containers, by ID, ID prefix or name. Empty matches every container.
+    pub containers: Vec<String>,
+    /// Event names such as `die`. Empty matches every event.
+    pub actions: Vec<String>,
+}
+
+impl EventQuery {
+    pub fn matches(&self, event: &Event) -> bool {
+        self.since.is_none_or(|since| event.time >= since)
+            && self.until.is_none_or(|until| event.time <= until)
+            && (self.containers.is_empty() || self.containers.iter().any(|container| {
+                event.container_id.starts_with(container.as_str()) || event.container_name == *container
+            }))
+            && (self.actions.is_empty() || self.actions.iter().any(|action| action == event.action.name()))
+    }
+
+    /// Narrows the query by a `container=<container>` or `event=<event>` filter.
+    pub fn add_filter(&mut self, filter: &str) -> Result<()> {
+        match filter.split_once('=') {
+            Some(("container", value)) => self.containers.push(value.to_string()),
+            Some(("event", value)) => self.actions.push(value.to_string()),
+            _ => return Err(TurbineError::ConfigError(format!(
+                "Invalid filter '{}', expected container=<container> or event=<event>", filter
+            ))),
+        }
+
+        Ok(())
+    }
+}
+
+/// Records container events to the journal and hands them to live subscribers.
+pub struct EventBus {
+    dir: PathBuf,
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn open<P: AsRef<Path>>(base_path: P) -> Result<Self> {
+        let dir = base_path.as_ref().join("events");
+
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir,
+            sender: broadcast::channel(EVENT_BACKLOG).0,
+        })
+    }
+
+    /// Records that `action` happened to `container`. Failing to journal the event is
+    /// only warned about; live subscribers still get it.
+    pub fn emit(&self, container: &Container, action: EventAction) {
+        let event = Event {
+            time: Utc::now(),
+            container_id: container.id.clone(),
+            container_name: container.config.name.clone(),
+            action,
+        };
+
+        if let Err(e) = self.append(&event) {
+            eprintln!("Warning: Failed to record {} event: {}", event.action.name(), e);
+        }
+
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribes to the events emitted from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Reads the journaled events matching `query`, oldest first.
+    pub fn read(&self, query: &EventQuery) -> Result<Vec<Event>> {
+        let _lock = lock_file(&self.dir.join("events.lock"), false)?;
+        let mut events = Vec::new();
+
+        for path in [self.rotated_path(), self.journal_path()] {
+            let content = match fs::read(&path) {
+                Ok(content) => content,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            events.extend(content
+                .split(|&byte| byte == b'\n')
+                .filter_map(|line| serde_json::from_slice::<Event>(line).ok())
+                .filter(|event| query.matches(event)));
+        }
+
+        Ok(events)
+    }
+
+    fn append(&self, event: &Event) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        let _lock = lock_file(&self.dir.join("events.lock"), true)?;
+        let path = self.journal_path();
+
+        line.push(b'\n');
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() + line.len() as u64 > MAX_JOURNAL_BYTES => {
+                fs::rename(&path, self.rotated_path())?;
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        file.write_all(&line)
+            .map_err(|e| TurbineError::StateError(format!("Failed to write {:?}: {}", path, e)))
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        self.dir.join(JOURNAL_FILE)
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.1", JOURNAL_FILE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ContainerConfig;
+    use chrono::TimeDelta;
+
+    struct TestBus {
+        bus: EventBus,
+        base_path: PathBuf,
+    }
+
+    impl TestBus {
+        fn open() -> Self {
+            let base_path = std::env::temp_dir().join(format!("turbine-events-{}", uuid::Uuid::new_v4()));
+
+            Self { bus: EventBus::open(&base_path).unwrap(), base_path }
+        }
+
+        /// Fills the journal with copies of `event` until one more would not fit,
+        /// returning how many were written.
+        fn fill(&self, event: &Event) -> usize {
+            let mut line = serde_json::to_vec(event).unwrap();
+            line.push(b'\n');
+
+            let count = (MAX_JOURNAL_BYTES / line.len() as u64) as usize;
+
+            fs::write(self.bus.journal_path(), line.repeat(count)).unwrap();
+            count
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base_path);
+        }
+    }
+
+    fn container(name: &str) -> Container {
+        Container::new(ContainerConfig {
+            name: name.to_string(),
+            image: "alpine".to_string(),
+            ..Default::default()
+        }).unwrap()
+    }
+
+    fn event(container_id: &str, container_name: &str, action: EventAction, time: DateTime<Utc>) -> Event {
+        Event {
+            time,
+            container_id: container_id.to_string(),
+            container_name: container_name.to_string(),
+            action,
+        }
+    }
+
+    #[test]
+    fn journal_moves_aside_at_the_cap() {
+        let test = TestBus::open();
+        let old = event("old", "old", EventAction::Start, Utc::now());
+        let filled = test.fill(&old);
+
+        test.bus.emit(&container("new"), EventAction::Create);
+
+        let events = test.bus.read(&EventQuery::default()).unwrap();
+        assert_eq!(events.len(), filled + 1);
+        assert_eq!(events[0], old);
+        assert_eq!(events[filled].container_name, "new");
+        assert!(fs::metadata(test.bus.journal_path()).unwrap().len() < MAX_JOURNAL_BYTES / 100);
+
+        // Only the previous journal is kept.
+        let older = event("older", "older", EventAction::Destroy, Utc::now());
+        let refilled = test.fill(&older);
+
+        test.bus.emit(&container("newer"), EventAction::Create);
+
+        let events = test.bus.read(&EventQuery::default()).unwrap();
+        assert_eq!(events.len(), refilled + 1);
+        assert!(events.iter().all(|event| event.container_name != "old" && event.container_name != "new"));
+    }
+
+    #[test]
+    fn read_skips_unreadable_lines() {
+        let test = TestBus::open();
+
+        test.bus.emit(&container("web"), EventAction::Start);
+        fs::OpenOptions::new().append(true).open(test.bus.journal_path()).unwrap()
+            .write_all(b"{\"truncated\n").unwrap();
+        test.bus.emit(&container("web"), EventAction::Pause);
+
+        let actions: Vec<_> = test.bus.read(&EventQuery::default()).unwrap()
+            .into_iter()
+            .map(|event| event.action)
+            .collect();
+        assert_eq!(actions, [EventAction::Start, EventAction::Pause]);
+    }
+
+    #[test]
+    fn filters_by_container_and_event() {
+        let now = Utc::now();
+        let start = event("3f9a0c", "web", EventAction::Start, now);
+        let die = event("3f9a0c", "web", EventAction::Die { exit_code: Some(1) }, now);
+        let other = event("77b1e2", "db", EventAction::Start, now);
+        let query = |filters: &[&str]| {
+            let mut query = EventQuery::default();
+
+            for filter in filters {
+                query.add_filter(filter).unwrap();
+            }
+
+            query
+        };
+        let matching = |query: &EventQuery| [&start, &die, &other].into_iter()
+            .filter(|event| query.matches(event))
+            .count();
+
+        assert_eq!(matching(&query(&[])), 3);
+        assert_eq!(matching(&query(&["container=3f9"])), 2);
+        assert_eq!(matching(&query(&["container=db"])), 1);
+        assert_eq!(matching(&query(&["container=we"])), 0);
+        assert_eq!(matching(&query(&["event=die"])), 1);
+        assert_eq!(matching(&query(&["event=start", "event=die"])), 3);
+        assert_eq!(matching(&query(&["container=web", "event=start"])), 1);
+        assert_eq!(matching(&query(&["container=web", "container=db", "event=start"])), 2);
+    }
+
+    #[test]
+    fn rejects_unknown_filters() {
+        let mut query = EventQuery::default();
+
+        for filter in ["container", "image=alpine", ""] {
+            assert!(query.add_filter(filter).is_err(), "accepted '{}'", filter);
+        }
+    }
+
+    #[test]
+    fn since_and_until_are_inclusive() {
+        let now = Utc::now();
+        let event = event("3f9a0c", "web", EventAction::Start, now);
+        let query = |since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>| EventQuery {
+            since,
+            until,
+            ..Default::default()
+        };
+        let second = TimeDelta::seconds(1);
+
+        assert!(query(Some(now), Some(now)).matches(&event));
+        assert!(query(Some(now - second), None).matches(&event));
+        assert!(!query(Some(now + second), None).matches(&event));
+        assert!(!query(None, Some(now - second)).matches(&event));
+    }
+}
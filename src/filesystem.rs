@@ -1,7 +1,28 @@
 use crate::{Container, TurbineError, Result};
+use crate::image::OciImage;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::{Path, PathBuf};
-use std::os::unix::fs::PermissionsExt;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+/// Upper bound on the overlay mount option string, which the kernel limits to one page.
+const MAX_MOUNT_OPTIONS_LEN: usize = 4000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    Deleted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileChange {
+    pub kind: ChangeKind,
+    pub path: PathBuf,
+}
 
 pub struct FilesystemManager {
     base_path: PathBuf,
@@ -27,7 +48,14 @@ impl FilesystemManager {
         }
     }
 
-    pub fn create_container_root(&self, container: &Container) -> Result<()> {
+    fn overlay_path(&self, container_id: &str) -> PathBuf {
+        self.base_path.join("overlay").join(container_id)
+    }
+
+    /// Builds the container root as an overlay of `layers` (topmost first) below a
+    /// per-container init layer and a writable upper directory. Falls back to unpacking
+    /// the image into a plain directory when overlay mounts are unavailable.
+    pub fn create_container_root(&self, container: &mut Container, image: &OciImage, layers: Vec<PathBuf>) -> Result<()> {
         let root_path = if container.root_path.is_absolute() {
             container.root_path.clone()
         } else {
@@ -42,15 +70,109 @@ impl FilesystemManager {
 
         fs::create_dir_all(&root_path)?;
 
-        let subdirs = ["bin", "etc", "lib", "tmp", "var", "proc", "sys", "dev", "app"];
+        let overlay_path = self.overlay_path(&container.id);
+        let init_path = overlay_path.join("init");
+
+        fs::create_dir_all(&init_path)?;
+        fs::create_dir_all(overlay_path.join("upper"))?;
+        fs::create_dir_all(overlay_path.join("work"))?;
+        self.setup_skeleton(&init_path)?;
+
+        let mut lower_dirs = vec![init_path];
+
+        lower_dirs.extend(layers);
+
+        match self.mount_overlay(&root_path, &container.id, &lower_dirs) {
+            Ok(()) => container.lower_dirs = lower_dirs,
+            Err(e) => {
+                eprintln!("Warning: Overlay root unavailable, unpacking image instead: {}", e);
+                fs::remove_dir_all(&overlay_path)?;
+                image.unpack(&root_path)?;
+                self.setup_skeleton(&root_path)?;
+            }
+        }
+
+        self.setup_default_users(&root_path)?;
+
+        Ok(())
+    }
+
+    fn mount_overlay(&self, target: &Path, container_id: &str, lower_dirs: &[PathBuf]) -> Result<()> {
+        let overlay_path = self.overlay_path(container_id);
+        let lower = lower_dirs.iter()
+            .map(|dir| dir.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(":");
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lower,
+            overlay_path.join("upper").display(),
+            overlay_path.join("work").display()
+        );
+        if options.len() > MAX_MOUNT_OPTIONS_LEN {
+            return Err(TurbineError::FilesystemError(
+                format!("Too many image layers to mount ({})", lower_dirs.len())
+            ));
+        }
+
+        mount(Some("overlay"), target, Some("overlay"), MsFlags::empty(), Some(options.as_str()))
+            .map_err(|e| TurbineError::FilesystemError(format!("Failed to mount overlay on {:?}: {}", target, e)))
+    }
+
+    /// Re-mounts a container's overlay root if it is not mounted, e.g. after a host reboot.
+    /// Returns whether it did.
+    pub fn mount_rootfs(&self, container: &Container) -> Result<bool> {
+        if container.lower_dirs.is_empty() || is_mountpoint(&container.root_path)? {
+            return Ok(false);
+        }
+
+        fs::create_dir_all(&container.root_path)?;
+        self.mount_overlay(&container.root_path, &container.id, &container.lower_dirs)?;
+
+        Ok(true)
+    }
+
+    /// Unmounts a container's overlay root, keeping its upper layer.
+    pub fn unmount_rootfs(&self, container: &Container) -> Result<()> {
+        if !is_mountpoint(&container.root_path)? {
+            return Ok(());
+        }
+
+        umount2(&container.root_path, MntFlags::MNT_DETACH).map_err(|e| TurbineError::FilesystemError(
+            format!("Failed to unmount {:?}: {}", container.root_path, e)
+        ))
+    }
+
+    /// Lists paths added, changed or deleted in the container's upper layer.
+    pub fn container_changes(&self, container: &Container) -> Result<Vec<FileChange>> {
+        if container.lower_dirs.is_empty() {
+            return Err(TurbineError::FilesystemError(
+                "Container does not use an overlay root filesystem".to_string()
+            ));
+        }
+
+        let mut changes = Vec::new();
+
+        collect_changes(
+            &self.overlay_path(&container.id).join("upper"),
+            Path::new("/"),
+            &container.lower_dirs,
+            &mut changes,
+        )?;
+
+        Ok(changes)
+    }
+
+    fn setup_skeleton(&self, root_path: &Path) -> Result<()> {
+        let subdirs = ["etc", "tmp", "proc", "sys", "dev"];
 
         for subdir in &subdirs {
             let path = root_path.join(subdir);
-            fs::create_dir_all(&path)?;
+            if fs::symlink_metadata(&path).is_err() {
+                fs::create_dir_all(&path)?;
+            }
         }
 
-        self.setup_basic_files(&root_path)?;
-
         Ok(())
     }
 
@@ -62,9 +184,7 @@ impl FilesystemManager {
                 ));
             }
 
-            let target_path = container.root_path.join(
-                volume.container_path.strip_prefix("/").unwrap_or(&volume.container_path)
-            );
+            let target_path = secure_join(&container.root_path, &volume.container_path)?;
             if let Some(parent) = target_path.parent() {
                 fs::create_dir_all(parent)?;
             }
@@ -105,18 +225,67 @@ impl FilesystemManager {
         Ok(())
     }
 
-    fn setup_basic_files(&self, root_path: &Path) -> Result<()> {
-        let resolv_conf = root_path.join("etc/resolv.conf");
-        fs::write(&resolv_conf, "nameserver 8.8.8.8\nnameserver 8.8.4.4\n")?;
+    /// Writes the container's `/etc/resolv.conf`, `/etc/hosts` and `/etc/hostname` from
+    /// its network configuration, with its hostname resolving to its addresses on each
+    /// of its networks. With a `resolver`, an embedded DNS server that forwards to the
+    /// configured nameservers, it is the only nameserver.
+    pub fn write_network_files(&self, container: &Container, resolver: Option<IpAddr>) -> Result<()> {
+        let etc = secure_join(&container.root_path, "etc")?;
+        let network = &container.config.network;
+        let hostname = container.hostname();
+        let nameservers = match resolver {
+            Some(resolver) => vec![resolver.to_string()],
+            None if network.dns.is_empty() => host_nameservers(false).iter().map(IpAddr::to_string).collect(),
+            None => network.dns.clone(),
+        };
 
-        let passwd = root_path.join("etc/passwd");
-        fs::write(&passwd, "turbine:x:1000:1000:Turbine User:/app:/bin/sh\n")?;
+        if nameservers.is_empty() {
+            eprintln!(
+                "Warning: The host has no nameserver containers can reach; set dns for container {} to resolve names",
+                container.id
+            );
+        }
+
+        let mut resolv_conf: String = nameservers.iter()
+            .map(|server| format!("nameserver {}\n", server))
+            .collect();
+
+        if !network.dns_search.is_empty() {
+            resolv_conf += &format!("search {}\n", network.dns_search.join(" "));
+        }
+
+        if !network.dns_options.is_empty() {
+            resolv_conf += &format!("options {}\n", network.dns_options.join(" "));
+        }
+
+        let mut hosts = String::from("127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n");
 
-        let group = root_path.join("etc/group");
-        fs::write(&group, "turbine:x:1000:\n")?;
+        for ip in container.networks.iter().flat_map(|attachment| &attachment.ips) {
+            hosts += &format!("{}\t{}\n", ip, hostname);
+        }
+
+        for (name, ip) in network.extra_hosts.iter().filter_map(|entry| crate::NetworkConfig::parse_extra_host(entry)) {
+            hosts += &format!("{}\t{}\n", ip, name);
+        }
 
-        let hosts = root_path.join("etc/hosts");
-        fs::write(&hosts, "127.0.0.1 localhost\n::1 localhost\n")?;
+        fs::create_dir_all(&etc)?;
+        replace_file(&etc.join("resolv.conf"), &resolv_conf)?;
+        replace_file(&etc.join("hosts"), &hosts)?;
+        replace_file(&etc.join("hostname"), &format!("{}\n", hostname))
+    }
+
+    fn setup_default_users(&self, root_path: &Path) -> Result<()> {
+        let etc = secure_join(root_path, "etc")?;
+
+        let passwd = etc.join("passwd");
+        if fs::symlink_metadata(&passwd).is_err() {
+            fs::write(&passwd, "turbine:x:1000:1000:Turbine User:/app:/bin/sh\n")?;
+        }
+
+        let group = etc.join("group");
+        if fs::symlink_metadata(&group).is_err() {
+            fs::write(&group, "turbine:x:1000:\n")?;
+        }
 
         Ok(())
     }
@@ -124,9 +293,21 @@ impl FilesystemManager {
     pub fn cleanup_container(&self, container: &Container) -> Result<()> {
         if container.root_path.exists() {
             self.unmount_volumes(container)?;
+
+            if is_mountpoint(&container.root_path)? {
+                umount2(&container.root_path, MntFlags::MNT_DETACH).map_err(|e| TurbineError::FilesystemError(
+                    format!("Failed to unmount {:?}: {}", container.root_path, e)
+                ))?;
+            }
+
             fs::remove_dir_all(&container.root_path)?;
         }
 
+        let overlay_path = self.overlay_path(&container.id);
+        if overlay_path.exists() {
+            fs::remove_dir_all(&overlay_path)?;
+        }
+
         Ok(())
     }
 
@@ -134,9 +315,7 @@ impl FilesystemManager {
         use std::process::Command;
 
         for volume in &container.config.volumes {
-            let target_path = container.root_path.join(
-                volume.container_path.strip_prefix("/").unwrap_or(&volume.container_path)
-            );
+            let target_path = secure_join(&container.root_path, &volume.container_path)?;
 
             if target_path.exists() {
                 let output = Command::new("umount")
@@ -154,9 +333,7 @@ impl FilesystemManager {
 
     pub fn create_working_directory(&self, container: &Container) -> Result<()> {
         if let Some(working_dir) = &container.config.working_dir {
-            let work_path = container.root_path.join(
-                working_dir.strip_prefix("/").unwrap_or(working_dir)
-            );
+            let work_path = secure_join(&container.root_path, working_dir)?;
             fs::create_dir_all(&work_path)?;
 
             let permissions = fs::Permissions::from_mode(0o755);
@@ -168,3 +345,142 @@ impl FilesystemManager {
     }
 }
 
+/// Returns true if `path` is the mount point of a filesystem in the current mount namespace.
+pub fn is_mountpoint(path: &Path) -> Result<bool> {
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+
+    Ok(mountinfo.lines()
+        .filter_map(|line| line.split_whitespace().nth(4))
+        .any(|mount_point| Path::new(&unescape_mount_path(mount_point)) == path))
+}
+
+/// Decodes the octal escapes (`\040` for space, etc.) used in `/proc/self/mountinfo`.
+fn unescape_mount_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(|b| (b'0'..=b'7').contains(b)) {
+            decoded.push((bytes[i + 1] - b'0') * 64 + (bytes[i + 2] - b'0') * 8 + (bytes[i + 3] - b'0'));
+            i += 4;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn collect_changes(dir: &Path, relative: &Path, lower_dirs: &[PathBuf], changes: &mut Vec<FileChange>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = relative.join(entry.file_name());
+        let meta = entry.metadata()?;
+        if meta.file_type().is_char_device() && meta.rdev() == 0 {
+            changes.push(FileChange { kind: ChangeKind::Deleted, path });
+            continue;
+        }
+
+        let lower_path = path.strip_prefix("/").unwrap_or(&path);
+        let in_lower = lower_dirs.iter().any(|lower| fs::symlink_metadata(lower.join(lower_path)).is_ok());
+        let kind = if in_lower { ChangeKind::Changed } else { ChangeKind::Added };
+
+        changes.push(FileChange { kind, path: path.clone() });
+
+        if meta.is_dir() {
+            collect_changes(&entry.path(), &path, lower_dirs, changes)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Nameservers of the host's `resolv.conf`. Those on loopback addresses are left out
+/// unless `loopback` is set, as containers cannot reach them.
+pub(crate) fn host_nameservers(loopback: bool) -> Vec<IpAddr> {
+    let content = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+
+    content.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|server| server.trim().parse::<IpAddr>().ok())
+        .filter(|server| loopback || !server.is_loopback())
+        .collect()
+}
+
+/// Writes `contents` to `path`, replacing rather than following any symlink already there.
+fn replace_file(path: &Path, contents: &str) -> Result<()> {
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+        fs::remove_file(path)?;
+    }
+
+    fs::write(path, contents)?;
+
+    Ok(())
+}
+
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// Resolves `path` inside `root` the way a chroot would: `..` and absolute symlinks are
+/// interpreted relative to `root`, so the result never escapes it. Components that do
+/// not exist yet are appended as-is.
+pub fn secure_join<P: AsRef<Path>>(root: &Path, path: P) -> Result<PathBuf> {
+    let mut resolved = root.to_path_buf();
+    let mut pending: Vec<PathBuf> = Vec::new();
+    let mut links = 0;
+
+    push_components(&mut pending, path.as_ref());
+
+    while let Some(part) = pending.pop() {
+        if part.as_os_str() == ".." {
+            if resolved != root {
+                resolved.pop();
+            }
+            continue;
+        }
+
+        let candidate = resolved.join(&part);
+
+        match fs::symlink_metadata(&candidate) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                links += 1;
+                if links > MAX_SYMLINK_DEPTH {
+                    return Err(TurbineError::FilesystemError(
+                        format!("Too many levels of symbolic links resolving {:?}", path.as_ref())
+                    ));
+                }
+
+                let target = fs::read_link(&candidate)?;
+                if target.is_absolute() {
+                    resolved = root.to_path_buf();
+                }
+
+                push_components(&mut pending, &target);
+            }
+            Ok(_) => resolved = candidate,
+            Err(e) if e.kind() == ErrorKind::NotFound => resolved = candidate,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(resolved)
+}
+
+fn push_components(pending: &mut Vec<PathBuf>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(part) => pending.push(PathBuf::from(part)),
+            Component::ParentDir => pending.push(PathBuf::from("..")),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+}
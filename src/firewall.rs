@@ -0,0 +1,731 @@
+use crate::network::Network;
+use crate::{PortMapping, TurbineError, Result};
+use std::ops::RangeInclusive;
+use std::io::Write;
+use std::net::IpAddr;
+use std::process::{Command, Output, Stdio};
+
+/// The nftables table turbine keeps all of its rules in.
+const NFT_TABLE: &str = "inet turbine";
+
+/// The nftables set of every turbine bridge, which a network's traffic may not be
+/// forwarded to unless it is the network's own.
+const NFT_BRIDGES: &str = "bridges";
+
+/// The iptables chain dropping traffic forwarded to any turbine bridge; networks jump
+/// to it for traffic leaving through a bridge other than their own.
+const ISOLATION_CHAIN: &str = "TURBINE-ISOLATION";
+
+/// NAT and filtering for bridge networks: masquerading for traffic leaving them,
+/// isolation between them, and the rules that publish containers' ports. Each
+/// network's and each container's rules live in chains of their own, so they are
+/// added and removed as a unit.
+pub trait Firewall: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Masquerades traffic from the network leaving through anything but its bridge and
+    /// lets it be forwarded, except to other turbine networks. Traffic of an internal
+    /// network never leaves its bridge. Safe to call again on a host already set up.
+    fn setup(&self, network: &Network) -> Result<()>;
+
+    /// Forwards each host port in `ports`, on its mapping's host address if it has one,
+    /// to the container's address of each family in `ips`, from other hosts and from
+    /// the host itself, and lets that traffic through.
+    fn publish(&self, container_id: &str, ips: &[IpAddr], ports: &[PortMapping]) -> Result<()>;
+
+    /// Removes the rules `publish` added for the container, if any.
+    fn unpublish(&self, container_id: &str) -> Result<()>;
+
+    /// Removes the rules `setup` added for the network.
+    fn teardown(&self, network: &Network) -> Result<()>;
+}
+
+/// The nftables backend when the `nft` tool is available, iptables otherwise.
+pub fn detect() -> Box<dyn Firewall> {
+    let nft = Command::new("nft").arg("--version").output();
+
+    if nft.is_ok_and(|output| output.status.success()) {
+        Box::new(Nftables)
+    } else {
+        Box::new(Iptables)
+    }
+}
+
+/// Lets the host route between the bridge and other interfaces, and lets connections
+/// to published ports on the host's loopback address be forwarded to containers.
+pub fn enable_forwarding(network: &Network) {
+    let mut settings = vec![
+        "/proc/sys/net/ipv4/ip_forward".to_string(),
+        format!("/proc/sys/net/ipv4/conf/{}/route_localnet", network.bridge),
+    ];
+
+    if network.subnets().iter().any(|(subnet, _)| subnet.is_ipv6()) {
+        settings.push("/proc/sys/net/ipv6/conf/all/forwarding".to_string());
+    }
+
+    for setting in settings {
+        if let Err(e) = std::fs::write(&setting, "1") {
+            eprintln!("Warning: Failed to enable {}: {}", setting, e);
+        }
+    }
+}
+
+/// Keeps everything in the `inet turbine` table, changing it one transaction at a time.
+pub struct Nftables;
+
+impl Nftables {
+    fn chains(container_id: &str) -> [String; 3] {
+        let short_id = &container_id[..8];
+
+        [format!("dnat-{}", short_id), format!("snat-{}", short_id), format!("forward-{}", short_id)]
+    }
+
+    fn network_chains(network: &Network) -> [String; 2] {
+        [format!("nat-{}", network.bridge), format!("filter-{}", network.bridge)]
+    }
+
+    /// The table's objects as `nft -j -a` lists them, with rule handles, or `None` if the
+    /// table does not exist.
+    fn listing(&self) -> Result<Option<Vec<serde_json::Value>>> {
+        let output = Command::new("nft")
+            .args(["-j", "-a", "list", "table"])
+            .args(NFT_TABLE.split(' '))
+            .output()?;
+
+        match check(output, "list the turbine nftables table") {
+            Ok(output) => {
+                let listing: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+
+                Ok(Some(listing["nftables"].as_array().cloned().unwrap_or_default()))
+            }
+            Err(TurbineError::NetworkNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The script setting up the network's chains, along with the jumps to them unless
+    /// `jumped`. Every bridge, an internal network's too, joins the bridges set, so that
+    /// networks set up earlier do not accept traffic forwarded to it.
+    fn setup_script(network: &Network, jumped: bool) -> String {
+        let [nat, filter] = Self::network_chains(network);
+        let bridge = &network.bridge;
+        let mut script = format!(
+            "add table {table}\n\
+             add chain {table} prerouting {{ type nat hook prerouting priority dstnat; }}\n\
+             add chain {table} output {{ type nat hook output priority -100; }}\n\
+             add chain {table} postrouting {{ type nat hook postrouting priority srcnat; }}\n\
+             add chain {table} forward {{ type filter hook forward priority filter; }}\n\
+             add set {table} {bridges} {{ type ifname; }}\n\
+             add element {table} {bridges} {{ \"{bridge}\" }}\n\
+             add chain {table} {nat}\n\
+             add chain {table} {filter}\n\
+             flush chain {table} {nat}\n\
+             flush chain {table} {filter}\n\
+             add rule {table} {filter} iifname \"{bridge}\" oifname \"{bridge}\" accept\n",
+            table = NFT_TABLE, bridges = NFT_BRIDGES,
+        );
+
+        if network.internal {
+            script += &format!(
+                "add rule {table} {filter} iifname \"{bridge}\" drop\n\
+                 add rule {table} {filter} oifname \"{bridge}\" drop\n",
+                table = NFT_TABLE,
+            );
+        } else {
+            for (subnet, prefix) in network.subnets() {
+                script += &format!(
+                    "add rule {} {} {} saddr {}/{} oifname != \"{}\" masquerade\n",
+                    NFT_TABLE, nat, nft_family(subnet), subnet, prefix, bridge,
+                );
+            }
+
+            script += &format!(
+                "add rule {table} {filter} iifname \"{bridge}\" oifname @{bridges} drop\n\
+                 add rule {table} {filter} iifname \"{bridge}\" accept\n\
+                 add rule {table} {filter} oifname \"{bridge}\" ct state established,related accept\n",
+                table = NFT_TABLE, bridges = NFT_BRIDGES,
+            );
+        }
+
+        if !jumped {
+            script += &format!(
+                "add rule {table} postrouting jump {nat}\n\
+                 add rule {table} forward jump {filter}\n",
+                table = NFT_TABLE,
+            );
+        }
+
+        script
+    }
+
+    /// Commands deleting those of `chains` in `listing` along with the rules jumping to
+    /// them, or `None` if none of them exist.
+    fn remove_chains(listing: &[serde_json::Value], chains: &[String]) -> Option<String> {
+        let existing: Vec<&str> = listing.iter()
+            .filter_map(|item| item["chain"]["name"].as_str())
+            .filter(|name| chains.iter().any(|chain| chain == name))
+            .collect();
+        if existing.is_empty() {
+            return None;
+        }
+
+        let mut script = String::new();
+
+        for (chain, handle) in Self::jumps(listing, &existing) {
+            script += &format!("delete rule {} {} handle {}\n", NFT_TABLE, chain, handle);
+        }
+
+        for chain in &existing {
+            script += &format!("flush chain {table} {chain}\ndelete chain {table} {chain}\n", table = NFT_TABLE);
+        }
+
+        Some(script)
+    }
+
+    /// The chain and handle of each rule in `listing` that jumps to one of `targets`.
+    fn jumps<'a>(listing: &'a [serde_json::Value], targets: &[&str]) -> Vec<(&'a str, u64)> {
+        listing.iter()
+            .map(|item| &item["rule"])
+            .filter(|rule| rule["expr"].as_array().is_some_and(|expressions| {
+                expressions.iter().any(|expression| {
+                    expression["jump"]["target"].as_str().is_some_and(|target| targets.contains(&target))
+                })
+            }))
+            .filter_map(|rule| Some((rule["chain"].as_str()?, rule["handle"].as_u64()?)))
+            .collect()
+    }
+}
+
+impl Firewall for Nftables {
+    fn name(&self) -> &'static str {
+        "nftables"
+    }
+
+    fn setup(&self, network: &Network) -> Result<()> {
+        let listing = self.listing()?.unwrap_or_default();
+        let [nat, filter] = Self::network_chains(network);
+        // The jumps to the network's chains are only added along with them.
+        let jumped = !Self::jumps(&listing, &[&nat, &filter]).is_empty();
+
+        nft(&Self::setup_script(network, jumped), &format!("set up nftables for network {}", network.name))
+    }
+
+    fn publish(&self, container_id: &str, ips: &[IpAddr], ports: &[PortMapping]) -> Result<()> {
+        if ports.is_empty() {
+            return Ok(());
+        }
+
+        let [dnat, snat, forward] = Self::chains(container_id);
+        let mut script = String::new();
+
+        for chain in [&dnat, &snat, &forward] {
+            script += &format!("add chain {} {}\n", NFT_TABLE, chain);
+        }
+
+        for port in ports {
+            for ip in ips.iter().filter(|ip| port.host_ip.is_none_or(|host_ip| host_ip.is_ipv4() == ip.is_ipv4())) {
+                let family = nft_family(*ip);
+                let host_ip = port.host_ip.map(|host_ip| format!("{} daddr {} ", family, host_ip)).unwrap_or_default();
+                let published = format!(
+                    "{} daddr {} {} dport {}",
+                    family, ip, port.protocol, port_range(port.container_ports(), '-'),
+                );
+
+                for (host_ports, container_port) in translations(port) {
+                    script += &format!(
+                        "add rule {} {} meta nfproto {} {}{} dport {} dnat {} to {}\n",
+                        NFT_TABLE, dnat, match ip { IpAddr::V4(_) => "ipv4", IpAddr::V6(_) => "ipv6" },
+                        host_ip, port.protocol, port_range(host_ports, '-'), family, destination(*ip, container_port),
+                    );
+                }
+
+                script += &format!(
+                    "add rule {table} {snat} {published} ct status dnat masquerade\n\
+                     add rule {table} {forward} {published} ct status dnat accept\n",
+                    table = NFT_TABLE,
+                );
+            }
+        }
+
+        script += &format!(
+            "add rule {table} prerouting fib daddr type local jump {dnat}\n\
+             add rule {table} output fib daddr type local jump {dnat}\n\
+             add rule {table} postrouting jump {snat}\n\
+             add rule {table} forward jump {forward}\n",
+            table = NFT_TABLE,
+        );
+
+        nft(&script, &format!("publish ports of container {}", container_id))
+    }
+
+    fn unpublish(&self, container_id: &str) -> Result<()> {
+        let Some(listing) = self.listing()? else {
+            return Ok(());
+        };
+
+        match Self::remove_chains(&listing, &Self::chains(container_id)) {
+            Some(script) => nft(&script, &format!("unpublish ports of container {}", container_id)),
+            None => Ok(()),
+        }
+    }
+
+    fn teardown(&self, network: &Network) -> Result<()> {
+        let Some(listing) = self.listing()? else {
+            return Ok(());
+        };
+        let mut script = Self::remove_chains(&listing, &Self::network_chains(network)).unwrap_or_default();
+        let registered = listing.iter().any(|item| {
+            item["set"]["name"] == NFT_BRIDGES
+                && item["set"]["elem"].as_array().is_some_and(|elements| elements.iter().any(|element| *element == *network.bridge))
+        });
+
+        if registered {
+            script += &format!("delete element {} {} {{ \"{}\" }}\n", NFT_TABLE, NFT_BRIDGES, network.bridge);
+        }
+
+        if script.is_empty() {
+            return Ok(());
+        }
+
+        nft(&script, &format!("remove nftables rules of network {}", network.name))
+    }
+}
+
+/// Fallback for hosts without nftables. Per-container chains are changed with
+/// `iptables-restore --noflush`, which commits each table atomically.
+pub struct Iptables;
+
+impl Iptables {
+    fn chains(container_id: &str) -> [String; 3] {
+        let short_id = &container_id[..8];
+
+        [format!("TURBINE-D-{}", short_id), format!("TURBINE-S-{}", short_id), format!("TURBINE-F-{}", short_id)]
+    }
+
+    /// The network's rules for one of its subnets, as (table, chain, rule) in `iptables`
+    /// syntax, in the order they are appended.
+    fn setup_rules(network: &Network, subnet: IpAddr, prefix: u8) -> Vec<(&'static str, &'static str, String)> {
+        let bridge = &network.bridge;
+        let mut rules = vec![("filter", "FORWARD", format!("-i {} -o {} -j ACCEPT", bridge, bridge))];
+
+        // Internal bridges too, so that networks set up earlier do not accept traffic
+        // forwarded to them.
+        rules.push(("filter", ISOLATION_CHAIN, format!("-o {} -j DROP", bridge)));
+
+        if network.internal {
+            rules.push(("filter", "FORWARD", format!("-i {} ! -o {} -j DROP", bridge, bridge)));
+            rules.push(("filter", "FORWARD", format!("-o {} ! -i {} -j DROP", bridge, bridge)));
+        } else {
+            rules.push(("nat", "POSTROUTING", format!("-s {}/{} ! -o {} -j MASQUERADE", subnet, prefix, bridge)));
+            rules.push(("filter", "FORWARD", format!("-i {} ! -o {} -j {}", bridge, bridge, ISOLATION_CHAIN)));
+            rules.push(("filter", "FORWARD", format!("-i {} -j ACCEPT", bridge)));
+            rules.push(("filter", "FORWARD", format!("-o {} -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT", bridge)));
+        }
+
+        rules
+    }
+
+    /// The rules jumping to the container's chains, as (table, rule), both for adding
+    /// and for deleting by their exact spec.
+    fn jumps(container_id: &str) -> [(&'static str, String); 4] {
+        let [dnat, snat, forward] = Self::chains(container_id);
+
+        [
+            ("nat", format!("PREROUTING -m addrtype --dst-type LOCAL -j {}", dnat)),
+            ("nat", format!("OUTPUT -m addrtype --dst-type LOCAL -j {}", dnat)),
+            ("nat", format!("POSTROUTING -j {}", snat)),
+            ("filter", format!("FORWARD -j {}", forward)),
+        ]
+    }
+}
+
+impl Firewall for Iptables {
+    fn name(&self) -> &'static str {
+        "iptables"
+    }
+
+    fn setup(&self, network: &Network) -> Result<()> {
+        for (subnet, prefix) in network.subnets() {
+            let program = iptables_program(subnet);
+
+            // Fails once the chain exists.
+            Command::new(program).args(["-t", "filter", "-N", ISOLATION_CHAIN]).output()?;
+
+            for (table, chain, rule) in Self::setup_rules(network, subnet, prefix) {
+                let rule: Vec<&str> = rule.split(' ').collect();
+                let present = Command::new(program)
+                    .args(["-t", table, "-C", chain])
+                    .args(&rule)
+                    .output()?
+                    .status
+                    .success();
+
+                if !present {
+                    let output = Command::new(program).args(["-t", table, "-A", chain]).args(&rule).output()?;
+
+                    check(output, &format!("add {} rule to {}", program, chain))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn publish(&self, container_id: &str, ips: &[IpAddr], ports: &[PortMapping]) -> Result<()> {
+        if ports.is_empty() {
+            return Ok(());
+        }
+
+        let [dnat, snat, forward] = Self::chains(container_id);
+
+        for ip in ips {
+            let mut nat = format!("*nat\n:{} - [0:0]\n:{} - [0:0]\n", dnat, snat);
+            let mut filter = format!("*filter\n:{} - [0:0]\n", forward);
+
+            for port in ports.iter().filter(|port| port.host_ip.is_none_or(|host_ip| host_ip.is_ipv4() == ip.is_ipv4())) {
+                let host_ip = port.host_ip.map(|host_ip| format!("-d {} ", host_ip)).unwrap_or_default();
+                let published = format!(
+                    "-d {} -p {} --dport {} -m conntrack --ctstate DNAT",
+                    ip, port.protocol, port_range(port.container_ports(), ':'),
+                );
+
+                for (host_ports, container_port) in translations(port) {
+                    nat += &format!(
+                        "-A {} {}-p {} --dport {} -j DNAT --to-destination {}\n",
+                        dnat, host_ip, port.protocol, port_range(host_ports, ':'), destination(*ip, container_port),
+                    );
+                }
+
+                nat += &format!("-A {} {} -j MASQUERADE\n", snat, published);
+                filter += &format!("-A {} {} -j ACCEPT\n", forward, published);
+            }
+
+            for (table, jump) in Self::jumps(container_id) {
+                match table {
+                    "nat" => nat += &format!("-A {}\n", jump),
+                    _ => filter += &format!("-A {}\n", jump),
+                }
+            }
+
+            iptables_restore(*ip, &format!("{}COMMIT\n{}COMMIT\n", nat, filter),
+                &format!("publish ports of container {}", container_id))?;
+        }
+
+        Ok(())
+    }
+
+    fn unpublish(&self, container_id: &str) -> Result<()> {
+        let [dnat, snat, forward] = Self::chains(container_id);
+
+        for program in ["iptables", "ip6tables"] {
+            let exists = Command::new(program)
+                .args(["-t", "nat", "-S", &dnat])
+                .output()
+                .is_ok_and(|output| output.status.success());
+            if !exists {
+                continue;
+            }
+
+            let mut nat = String::from("*nat\n");
+            let mut filter = String::from("*filter\n");
+
+            for (table, jump) in Self::jumps(container_id) {
+                match table {
+                    "nat" => nat += &format!("-D {}\n", jump),
+                    _ => filter += &format!("-D {}\n", jump),
+                }
+            }
+
+            nat += &format!("-F {dnat}\n-X {dnat}\n-F {snat}\n-X {snat}\n", dnat = dnat, snat = snat);
+            filter += &format!("-F {forward}\n-X {forward}\n", forward = forward);
+
+            let script = format!("{}COMMIT\n{}COMMIT\n", nat, filter);
+            let output = run_with_input(Command::new(format!("{}-restore", program)).arg("--noflush"), &script)?;
+
+            check(output, &format!("unpublish ports of container {}", container_id))?;
+        }
+
+        Ok(())
+    }
+
+    fn teardown(&self, network: &Network) -> Result<()> {
+        for (subnet, prefix) in network.subnets() {
+            let program = iptables_program(subnet);
+
+            for (table, chain, rule) in Self::setup_rules(network, subnet, prefix) {
+                let output = Command::new(program)
+                    .args(["-t", table, "-D", chain])
+                    .args(rule.split(' '))
+                    .output()?;
+
+                if !output.status.success() {
+                    eprintln!(
+                        "Warning: Failed to remove {} rule from {}: {}",
+                        program, chain, String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The host ports of a mapping with the container port each group is translated to.
+/// A range published on the same ports needs one translation that keeps them; any
+/// other range needs one per port.
+fn translations(port: &PortMapping) -> Vec<(RangeInclusive<u16>, Option<u16>)> {
+    if port.host_port == port.container_port && port.port_count() > 1 {
+        return vec![(port.host_ports(), None)];
+    }
+
+    port.host_ports().zip(port.container_ports()).map(|(host, container)| (host..=host, Some(container))).collect()
+}
+
+/// `port`, or `start` and `end` joined by `separator`.
+fn port_range(ports: RangeInclusive<u16>, separator: char) -> String {
+    if ports.start() == ports.end() {
+        ports.start().to_string()
+    } else {
+        format!("{}{}{}", ports.start(), separator, ports.end())
+    }
+}
+
+/// The DNAT target for `ip`, keeping the destination port unless `port` is given.
+fn destination(ip: IpAddr, port: Option<u16>) -> String {
+    match (ip, port) {
+        (ip, None) => ip.to_string(),
+        (IpAddr::V4(ip), Some(port)) => format!("{}:{}", ip, port),
+        (IpAddr::V6(ip), Some(port)) => format!("[{}]:{}", ip, port),
+    }
+}
+
+fn nft_family(ip: IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "ip",
+        IpAddr::V6(_) => "ip6",
+    }
+}
+
+fn iptables_program(ip: IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "iptables",
+        IpAddr::V6(_) => "ip6tables",
+    }
+}
+
+fn nft(script: &str, action: &str) -> Result<()> {
+    check(run_with_input(Command::new("nft").args(["-f", "-"]), script)?, action).map(drop)
+}
+
+fn iptables_restore(ip: IpAddr, script: &str, action: &str) -> Result<()> {
+    let program = format!("{}-restore", iptables_program(ip));
+
+    check(run_with_input(Command::new(program).arg("--noflush"), script)?, action).map(drop)
+}
+
+fn run_with_input(command: &mut Command, input: &str) -> Result<Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+
+    Ok(child.wait_with_output()?)
+}
+
+/// Turns a failed command into the `NetworkError` variant its message points to.
+fn check(output: Output, action: &str) -> Result<Output> {
+    if output.status.success() {
+        return Ok(output);
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let message = format!("Failed to {}: {}", action, stderr.trim());
+
+    Err(if stderr.contains("No such file or directory") || stderr.contains("does not exist") {
+        TurbineError::NetworkNotFound(message)
+    } else if stderr.contains("File exists") || stderr.contains("already exists") {
+        TurbineError::NetworkExists(message)
+    } else if stderr.contains("Operation not permitted") || stderr.contains("Permission denied") {
+        TurbineError::NetworkPermissionDenied(message)
+    } else {
+        TurbineError::NetworkError(message)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NetworkConfig;
+    use chrono::DateTime;
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+
+    fn network(name: &str, bridge: &str, second: u8, internal: bool) -> Network {
+        Network {
+            name: name.to_string(),
+            bridge: bridge.to_string(),
+            subnet: NetworkConfig::IPv4 { subnet: Ipv4Addr::new(172, second, 0, 0), prefix: 16 },
+            gateway_ipv4: Some(Ipv4Addr::new(172, second, 0, 1)),
+            gateway_ipv6: None,
+            mtu: None,
+            internal,
+            reserved: Vec::new(),
+            created_at: DateTime::UNIX_EPOCH,
+        }
+    }
+
+    /// The verdict of the forward chain built by `scripts` for a new connection
+    /// forwarded from `iif` to `oif`.
+    fn nft_verdict(scripts: &[String], iif: &str, oif: &str) -> &'static str {
+        let mut bridges = Vec::new();
+        let mut chains: HashMap<&str, Vec<Vec<&str>>> = HashMap::new();
+        let mut forward = Vec::new();
+
+        for line in scripts.iter().flat_map(|script| script.lines()) {
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            match words.as_slice() {
+                ["add", "element", "inet", "turbine", "bridges", "{", bridge, "}"] => bridges.push(bridge.trim_matches('"')),
+                ["add", "rule", "inet", "turbine", "forward", "jump", chain] => forward.push(*chain),
+                ["add", "rule", "inet", "turbine", chain, rule @ ..] if chain.starts_with("filter-") => {
+                    chains.entry(chain).or_default().push(rule.to_vec());
+                }
+                _ => {}
+            }
+        }
+
+        for chain in forward {
+            for rule in &chains[chain] {
+                let mut matches = true;
+                let mut words = rule.iter();
+
+                while let Some(word) = words.next() {
+                    match *word {
+                        "iifname" => matches &= words.next().unwrap().trim_matches('"') == iif,
+                        "oifname" => {
+                            let name = words.next().unwrap();
+
+                            matches &= match name.strip_prefix('@') {
+                                Some(_) => bridges.contains(&oif),
+                                None => name.trim_matches('"') == oif,
+                            };
+                        }
+                        "ct" => {
+                            words.nth(1);
+                            matches = false;
+                        }
+                        "accept" if matches => return "accept",
+                        "drop" if matches => return "drop",
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        "accept"
+    }
+
+    /// The verdict of `chain` in the iptables filter `rules`, in the order they are
+    /// appended, for a new connection forwarded from `iif` to `oif`.
+    fn iptables_verdict(rules: &[(&str, String)], chain: &str, iif: &str, oif: &str) -> Option<&'static str> {
+        for (_, rule) in rules.iter().filter(|(rule_chain, _)| *rule_chain == chain) {
+            let mut matches = true;
+            let mut negated = false;
+            let mut words = rule.split(' ');
+
+            while let Some(word) = words.next() {
+                match word {
+                    "!" => {
+                        negated = true;
+                        continue;
+                    }
+                    "-i" => matches &= (words.next().unwrap() == iif) != negated,
+                    "-o" => matches &= (words.next().unwrap() == oif) != negated,
+                    "--ctstate" => {
+                        words.next();
+                        matches = false;
+                    }
+                    "-j" if matches => match words.next().unwrap() {
+                        "ACCEPT" => return Some("accept"),
+                        "DROP" => return Some("drop"),
+                        target => {
+                            if let Some(verdict) = iptables_verdict(rules, target, iif, oif) {
+                                return Some(verdict);
+                            }
+                        }
+                    },
+                    _ => {}
+                }
+                negated = false;
+            }
+        }
+
+        None
+    }
+
+    fn iptables_rules(networks: &[Network]) -> Vec<(&'static str, String)> {
+        networks.iter()
+            .flat_map(|network| network.subnets().into_iter().flat_map(move |(subnet, prefix)| {
+                Iptables::setup_rules(network, subnet, prefix)
+            }))
+            .filter(|(table, _, _)| *table == "filter")
+            .map(|(_, chain, rule)| (chain, rule))
+            .collect()
+    }
+
+    fn assert_isolated(verdict: impl Fn(&str, &str) -> &'static str) {
+        // Within the internal network only.
+        assert_eq!(verdict("turbine1", "turbine1"), "accept");
+        assert_eq!(verdict("turbine0", "turbine1"), "drop");
+        assert_eq!(verdict("turbine1", "turbine0"), "drop");
+        assert_eq!(verdict("eth0", "turbine1"), "drop");
+        assert_eq!(verdict("turbine1", "eth0"), "drop");
+        // The other network keeps its way out.
+        assert_eq!(verdict("turbine0", "eth0"), "accept");
+    }
+
+    #[test]
+    fn nftables_isolates_internal_network_whatever_the_order() {
+        let open = network("open", "turbine0", 17, false);
+        let internal = network("internal", "turbine1", 18, true);
+
+        for networks in [[&open, &internal], [&internal, &open]] {
+            let scripts: Vec<String> = networks.iter().map(|network| Nftables::setup_script(network, false)).collect();
+
+            assert_isolated(|iif, oif| nft_verdict(&scripts, iif, oif));
+        }
+    }
+
+    #[test]
+    fn nftables_setup_adds_jumps_once() {
+        let script = Nftables::setup_script(&network("open", "turbine0", 17, false), true);
+
+        assert!(!script.contains("jump"));
+        assert!(script.contains("add element inet turbine bridges { \"turbine0\" }"));
+    }
+
+    #[test]
+    fn iptables_isolates_internal_network_whatever_the_order() {
+        let open = network("open", "turbine0", 17, false);
+        let internal = network("internal", "turbine1", 18, true);
+
+        for networks in [[open.clone(), internal.clone()], [internal, open]] {
+            let rules = iptables_rules(&networks);
+
+            assert_isolated(|iif, oif| iptables_verdict(&rules, "FORWARD", iif, oif).unwrap_or("accept"));
+        }
+    }
+
+    #[test]
+    fn port_ranges_use_the_separator() {
+        assert_eq!(port_range(8080..=8080, '-'), "8080");
+        assert_eq!(port_range(8080..=8090, '-'), "8080-8090");
+        assert_eq!(port_range(8080..=8090, ':'), "8080:8090");
+    }
+}
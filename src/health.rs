@@ -0,0 +1,410 @@
+use crate::events::{EventAction, EventBus};
+use crate::namespace;
+use crate::process::{ExecOptions, ProcessManager};
+use crate::{Container, ContainerRegistry, HealthCheckConfig, HealthProbe, TurbineError, Result};
+use chrono::{DateTime, Utc};
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::os::fd::OwnedFd;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::unix::pipe;
+use tokio::sync::RwLock;
+
+/// Probe output kept in a container's health log.
+const MAX_PROBE_OUTPUT_BYTES: usize = 4096;
+
+/// Probe results kept in a container's health log.
+const HEALTH_LOG_LENGTH: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    /// No probe has succeeded yet, and too few have failed to tell.
+    Starting,
+    Healthy,
+    /// The last `retries` probes failed.
+    Unhealthy,
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HealthStatus::Starting => "starting",
+            HealthStatus::Healthy => "healthy",
+            HealthStatus::Unhealthy => "unhealthy",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthProbeResult {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub healthy: bool,
+    /// The exec'd command's output, the HTTP status line, or why the probe failed.
+    pub output: String,
+}
+
+/// Health of a running container with a health check.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Health {
+    pub status: HealthStatus,
+    /// Failed probes since the last one that succeeded.
+    pub failing_streak: u32,
+    /// The latest probe results, oldest first.
+    pub log: Vec<HealthProbeResult>,
+}
+
+impl Health {
+    pub fn starting() -> Self {
+        Self {
+            status: HealthStatus::Starting,
+            failing_streak: 0,
+            log: Vec::new(),
+        }
+    }
+
+    /// Records a probe's result, returning whether the status changed. Failures during
+    /// the start period are logged but not counted.
+    pub fn record(&mut self, result: HealthProbeResult, retries: u32, in_start_period: bool) -> bool {
+        let previous = self.status;
+
+        if result.healthy {
+            self.failing_streak = 0;
+            self.status = HealthStatus::Healthy;
+        } else if !in_start_period {
+            self.failing_streak += 1;
+            if self.failing_streak >= retries {
+                self.status = HealthStatus::Unhealthy;
+            }
+        }
+
+        self.log.push(result);
+        if self.log.len() > HEALTH_LOG_LENGTH {
+            self.log.remove(0);
+        }
+
+        self.status != previous
+    }
+}
+
+/// Whether a probe at `probed_at` falls in the start period of a container started at
+/// `started_at`.
+fn in_start_period(started_at: DateTime<Utc>, probed_at: DateTime<Utc>, start_period_secs: u64) -> bool {
+    probed_at.signed_duration_since(started_at)
+        .to_std()
+        .is_ok_and(|elapsed| elapsed < Duration::from_secs(start_period_secs))
+}
+
+/// Probes one container on its health check's interval and keeps its `health` up to
+/// date, for as long as the process it was started for runs.
+pub struct HealthMonitor {
+    registry: Arc<RwLock<ContainerRegistry>>,
+    process: Arc<RwLock<ProcessManager>>,
+    events: Arc<EventBus>,
+}
+
+impl HealthMonitor {
+    pub fn new(
+        registry: Arc<RwLock<ContainerRegistry>>,
+        process: Arc<RwLock<ProcessManager>>,
+        events: Arc<EventBus>,
+    ) -> Self {
+        Self { registry, process, events }
+    }
+
+    /// Monitors the container until it stops or is started again, which `started_at`
+    /// tells apart. Probes are skipped while the container is paused.
+    pub async fn run(self, container_id: String, started_at: DateTime<Utc>) {
+        loop {
+            let Some(container) = self.current(&container_id, started_at).await else {
+                return;
+            };
+            let Some(check) = container.config.healthcheck.clone() else {
+                return;
+            };
+
+            tokio::time::sleep(Duration::from_secs(check.interval_secs)).await;
+
+            let Some(container) = self.current(&container_id, started_at).await else {
+                return;
+            };
+            if container.is_paused() {
+                continue;
+            }
+
+            let start = Utc::now();
+            let (healthy, output) = match self.probe(&container, &check).await {
+                Ok(outcome) => outcome,
+                Err(e) => (false, e.to_string()),
+            };
+            let result = HealthProbeResult { start, end: Utc::now(), healthy, output };
+            let in_start_period = in_start_period(started_at, start, check.start_period_secs);
+
+            if self.record(&container_id, started_at, result, &check, in_start_period).await {
+                self.kill(&container_id).await;
+            }
+        }
+    }
+
+    /// The container, if it still runs the process this monitor was started for.
+    async fn current(&self, container_id: &str, started_at: DateTime<Utc>) -> Option<Container> {
+        self.registry.read().await
+            .get(container_id)
+            .filter(|c| (c.is_running() || c.is_paused()) && c.started_at == Some(started_at))
+            .cloned()
+    }
+
+    /// Stores the probe's result, returning whether the container just turned unhealthy
+    /// and should be killed for it.
+    async fn record(
+        &self,
+        container_id: &str,
+        started_at: DateTime<Utc>,
+        result: HealthProbeResult,
+        check: &HealthCheckConfig,
+        in_start_period: bool,
+    ) -> bool {
+        let mut registry = match ContainerRegistry::write(&self.registry).await {
+            Ok(registry) => registry,
+            Err(e) => {
+                eprintln!("Warning: Failed to save health of container {}: {}", container_id, e);
+                return false;
+            }
+        };
+        let Some(container) = registry.get_mut(container_id)
+            .filter(|c| (c.is_running() || c.is_paused()) && c.started_at == Some(started_at))
+        else {
+            return false;
+        };
+        let health = container.health.get_or_insert_with(Health::starting);
+
+        if !health.record(result, check.retries, in_start_period) {
+            return false;
+        }
+
+        let status = health.status;
+
+        self.events.emit(container, EventAction::HealthStatus { status });
+        if let Err(e) = registry.persist(container_id) {
+            eprintln!("Warning: Failed to save health of container {}: {}", container_id, e);
+        }
+
+        status == HealthStatus::Unhealthy && check.restart_on_unhealthy
+    }
+
+    /// Kills an unhealthy container's init. The supervisor then sees it exit and applies
+    /// its restart policy.
+    async fn kill(&self, container_id: &str) {
+        let Some(container) = self.registry.read().await.get(container_id).cloned() else {
+            return;
+        };
+
+        if let Err(e) = self.process.read().await.signal_init(&container, Signal::SIGKILL) {
+            eprintln!("Warning: Failed to kill unhealthy container {}: {}", container_id, e);
+        }
+    }
+
+    /// Runs one probe, returning whether it passed and what it reported.
+    async fn probe(&self, container: &Container, check: &HealthCheckConfig) -> Result<(bool, String)> {
+        let timeout = Duration::from_secs(check.timeout_secs);
+
+        match &check.probe {
+            HealthProbe::Exec { command } => self.exec_probe(container, command.clone(), timeout).await,
+            HealthProbe::Http { port, path } => {
+                let (port, path) = (*port, path.clone());
+
+                in_container_network(container, move || http_probe(port, &path, timeout)).await
+            }
+            HealthProbe::Tcp { port } => {
+                let port = *port;
+
+                in_container_network(container, move || {
+                    TcpStream::connect_timeout(&loopback(port), timeout)
+                        .map(|_| (true, format!("Connected to port {}", port)))
+                }).await
+            }
+        }
+    }
+
+    async fn exec_probe(&self, container: &Container, command: Vec<String>, timeout: Duration) -> Result<(bool, String)> {
+        let (mut child, io) = self.process.read().await
+            .execute_in_container(container, command, &ExecOptions::default())
+            .await?;
+        let mut fds = io.into_fds().into_iter();
+        let (stdout, stderr) = (fds.next(), fds.next());
+        let result = tokio::time::timeout(timeout, async {
+            let (stdout, stderr) = tokio::join!(read_output(stdout), read_output(stderr));
+            (child.wait().await, stdout, stderr)
+        }).await;
+
+        match result {
+            Ok((status, mut output, stderr)) => {
+                let status = status?;
+
+                output.extend_from_slice(&stderr);
+                output.truncate(MAX_PROBE_OUTPUT_BYTES);
+
+                Ok((status.exit_code() == Some(0), String::from_utf8_lossy(&output).into_owned()))
+            }
+            Err(_) => {
+                child.kill().await?;
+                Ok((false, format!("Health check timed out after {}s", timeout.as_secs())))
+            }
+        }
+    }
+}
+
+/// Runs a blocking network probe from inside the container's network namespace.
+async fn in_container_network<F>(container: &Container, probe: F) -> Result<(bool, String)>
+where
+    F: FnOnce() -> std::io::Result<(bool, String)> + Send + 'static,
+{
+    let pid = container.pid
+        .ok_or_else(|| TurbineError::ProcessError("Container has no running process".to_string()))?;
+
+    Ok(namespace::in_network_namespace(pid, probe).await??)
+}
+
+/// Sends a GET for `path` and passes on a 2xx or 3xx status.
+fn http_probe(port: u16, path: &str, timeout: Duration) -> std::io::Result<(bool, String)> {
+    let mut stream = TcpStream::connect_timeout(&loopback(port), timeout)?;
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 1024];
+
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(stream, "GET {} HTTP/1.0\r\nHost: localhost:{}\r\nUser-Agent: turbine\r\n\r\n", path, port)?;
+
+    // Only the status line matters.
+    while !response.contains(&b'\n') && response.len() < MAX_PROBE_OUTPUT_BYTES {
+        match stream.read(&mut buffer)? {
+            0 => break,
+            length => response.extend_from_slice(&buffer[..length]),
+        }
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default().trim_end();
+    let code = status_line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok());
+
+    Ok((matches!(code, Some(200..=399)), status_line.to_string()))
+}
+
+fn loopback(port: u16) -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+}
+
+/// Reads a probe's output stream to the end, keeping at most `MAX_PROBE_OUTPUT_BYTES`.
+/// The rest is drained so the command never blocks on a full pipe.
+async fn read_output(fd: Option<OwnedFd>) -> Vec<u8> {
+    let mut output = Vec::new();
+    let Some(mut pipe) = fd.and_then(|fd| pipe::Receiver::from_owned_fd(fd).ok()) else {
+        return output;
+    };
+    let mut buffer = [0u8; 4096];
+
+    while let Ok(length @ 1..) = pipe.read(&mut buffer).await {
+        let room = MAX_PROBE_OUTPUT_BYTES.saturating_sub(output.len());
+
+        output.extend_from_slice(&buffer[..length.min(room)]);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(healthy: bool) -> HealthProbeResult {
+        let now = Utc::now();
+
+        HealthProbeResult { start: now, end: now, healthy, output: String::new() }
+    }
+
+    /// Records one result per outcome and returns the status after each.
+    fn run(health: &mut Health, outcomes: &[bool], retries: u32, in_start_period: bool) -> Vec<HealthStatus> {
+        outcomes.iter()
+            .map(|&healthy| {
+                health.record(result(healthy), retries, in_start_period);
+                health.status
+            })
+            .collect()
+    }
+
+    #[test]
+    fn turns_healthy_on_the_first_passing_probe() {
+        let mut health = Health::starting();
+
+        assert!(health.record(result(true), 3, true));
+        assert_eq!(health.status, HealthStatus::Healthy);
+        assert!(!health.record(result(true), 3, false));
+    }
+
+    #[test]
+    fn turns_unhealthy_after_retries_failures() {
+        use HealthStatus::*;
+
+        let mut health = Health::starting();
+
+        assert_eq!(run(&mut health, &[false, false, false], 3, false), [Starting, Starting, Unhealthy]);
+        assert_eq!(health.failing_streak, 3);
+
+        let mut health = Health::starting();
+
+        assert_eq!(run(&mut health, &[true, false, false, false], 3, false), [Healthy, Healthy, Healthy, Unhealthy]);
+    }
+
+    #[test]
+    fn failures_in_the_start_period_do_not_count() {
+        let mut health = Health::starting();
+
+        assert_eq!(run(&mut health, &[false; 5], 1, true), [HealthStatus::Starting; 5]);
+        assert_eq!(health.failing_streak, 0);
+        assert_eq!(health.log.len(), 5);
+
+        assert!(health.record(result(false), 1, false));
+        assert_eq!(health.status, HealthStatus::Unhealthy);
+    }
+
+    #[test]
+    fn a_passing_probe_ends_the_failing_streak() {
+        use HealthStatus::*;
+
+        let mut health = Health::starting();
+
+        assert_eq!(run(&mut health, &[false, false, true, false, false], 3, false), [Starting, Starting, Healthy, Healthy, Healthy]);
+        assert_eq!(health.failing_streak, 2);
+
+        assert_eq!(run(&mut health, &[false, true], 3, false), [Unhealthy, Healthy]);
+        assert_eq!(health.failing_streak, 0);
+    }
+
+    #[test]
+    fn keeps_only_the_latest_results() {
+        let mut health = Health::starting();
+        let outcomes: Vec<bool> = (0..HEALTH_LOG_LENGTH + 2).map(|i| i % 2 == 0).collect();
+
+        run(&mut health, &outcomes, 3, false);
+
+        let logged: Vec<bool> = health.log.iter().map(|result| result.healthy).collect();
+        assert_eq!(logged, outcomes[2..]);
+    }
+
+    #[test]
+    fn start_period_is_measured_from_the_start() {
+        let started_at = Utc::now();
+        let after = |secs| started_at + chrono::Duration::seconds(secs);
+
+        assert!(in_start_period(started_at, after(0), 30));
+        assert!(in_start_period(started_at, after(29), 30));
+        assert!(!in_start_period(started_at, after(30), 30));
+        assert!(!in_start_period(started_at, after(1), 0));
+    }
+}
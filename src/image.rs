@@ -0,0 +1,924 @@
+use crate::{ContainerConfig, TurbineError, Result};
+use crate::filesystem::secure_join;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, ErrorKind, Read};
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+pub const MEDIA_TYPE_IMAGE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const MEDIA_TYPE_IMAGE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const MEDIA_TYPE_DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+const OCI_LAYOUT_VERSION: &str = "1.0.0";
+const WHITEOUT_PREFIX: &str = ".wh.";
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageManifest {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageConfiguration {
+    #[serde(default)]
+    pub architecture: String,
+    #[serde(default)]
+    pub os: String,
+    #[serde(default)]
+    pub config: Option<ImageRuntimeConfig>,
+    pub rootfs: RootFs,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageRuntimeConfig {
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub env: Option<Vec<String>>,
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootFs {
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    pub diff_ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LayoutMarker {
+    image_layout_version: String,
+}
+
+/// An OCI image layout directory (`oci-layout`, `index.json` and `blobs/<alg>/<hex>`).
+pub struct OciLayout {
+    path: PathBuf,
+}
+
+/// A single image resolved from a layout: its manifest and configuration.
+pub struct OciImage {
+    layout_path: PathBuf,
+    manifest_digest: String,
+    manifest: ImageManifest,
+    config: ImageConfiguration,
+}
+
+impl OciLayout {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let marker_path = path.join("oci-layout");
+        let content = fs::read_to_string(&marker_path).map_err(|e| TurbineError::ImageError(
+            format!("{:?} is not an OCI image layout: {}", path, e)
+        ))?;
+        let marker: LayoutMarker = serde_json::from_str(&content)?;
+        if marker.image_layout_version != OCI_LAYOUT_VERSION {
+            return Err(TurbineError::ImageError(
+                format!("Unsupported image layout version {}", marker.image_layout_version)
+            ));
+        }
+
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn index(&self) -> Result<ImageIndex> {
+        let content = fs::read_to_string(self.path.join("index.json"))?;
+
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        let (algorithm, hex) = parse_digest(digest)?;
+
+        Ok(self.path.join("blobs").join(algorithm).join(hex))
+    }
+
+    pub fn has_blob(&self, descriptor: &Descriptor) -> bool {
+        self.blob_path(&descriptor.digest).map(|p| p.exists()).unwrap_or(false)
+    }
+
+    /// Reads a blob into memory after checking its size and digest against `descriptor`.
+    pub fn read_blob(&self, descriptor: &Descriptor) -> Result<Vec<u8>> {
+        let data = fs::read(self.blob_path(&descriptor.digest)?)?;
+        if data.len() as u64 != descriptor.size {
+            return Err(TurbineError::ImageError(format!(
+                "Blob {} has size {}, expected {}", descriptor.digest, data.len(), descriptor.size
+            )));
+        }
+
+        let (algorithm, _) = parse_digest(&descriptor.digest)?;
+        let mut digester = Digester::new(algorithm)?;
+
+        digester.update(&data);
+        check_digest(&descriptor.digest, digester.finalize())?;
+
+        Ok(data)
+    }
+
+    /// Streams a blob from disk and checks its size and digest against `descriptor`.
+    pub fn verify_blob(&self, descriptor: &Descriptor) -> Result<()> {
+        let (algorithm, _) = parse_digest(&descriptor.digest)?;
+        let file = File::open(self.blob_path(&descriptor.digest)?)?;
+        let mut reader = HashingReader::new(BufReader::new(file), Digester::new(algorithm)?);
+        let size = io::copy(&mut reader, &mut io::sink())?;
+        if size != descriptor.size {
+            return Err(TurbineError::ImageError(format!(
+                "Blob {} has size {}, expected {}", descriptor.digest, size, descriptor.size
+            )));
+        }
+
+        check_digest(&descriptor.digest, reader.finalize())
+    }
+
+    /// Picks the manifest matching the host platform from `index.json`, descending
+    /// into nested image indexes.
+    pub fn resolve(&self) -> Result<OciImage> {
+        let index = self.index()?;
+        let descriptor = self.select_manifest(&index.manifests, None)?;
+
+        self.image(&descriptor.digest)
+    }
+
+    /// Picks the manifest whose `org.opencontainers.image.ref.name` annotation equals `reference`.
+    pub fn resolve_reference(&self, reference: &str) -> Result<OciImage> {
+        let index = self.index()?;
+        let descriptor = self.select_manifest(&index.manifests, Some(reference))?;
+
+        self.image(&descriptor.digest)
+    }
+
+    fn select_manifest(&self, manifests: &[Descriptor], reference: Option<&str>) -> Result<Descriptor> {
+        let candidates: Vec<&Descriptor> = manifests.iter()
+            .filter(|d| reference.is_none_or(|r| {
+                d.annotations.get(ANNOTATION_REF_NAME).is_some_and(|name| name == r)
+            }))
+            .filter(|d| d.platform.as_ref().is_none_or(matches_host_platform))
+            .collect();
+        let descriptor = candidates.first().ok_or_else(|| TurbineError::ImageError(match reference {
+            Some(r) => format!("No manifest for reference '{}' in {:?}", r, self.path),
+            None => format!("No manifest for this platform in {:?}", self.path),
+        }))?;
+
+        if descriptor.media_type == MEDIA_TYPE_IMAGE_INDEX
+            || descriptor.media_type == MEDIA_TYPE_DOCKER_MANIFEST_LIST
+        {
+            let nested: ImageIndex = serde_json::from_slice(&self.read_blob(descriptor)?)?;
+
+            return self.select_manifest(&nested.manifests, None);
+        }
+
+        Ok((*descriptor).clone())
+    }
+
+    pub fn image(&self, manifest_digest: &str) -> Result<OciImage> {
+        let manifest_path = self.blob_path(manifest_digest)?;
+        let data = fs::read(&manifest_path)?;
+        let (algorithm, _) = parse_digest(manifest_digest)?;
+        let mut digester = Digester::new(algorithm)?;
+
+        digester.update(&data);
+        check_digest(manifest_digest, digester.finalize())?;
+
+        let manifest: ImageManifest = serde_json::from_slice(&data)?;
+        let config: ImageConfiguration = serde_json::from_slice(&self.read_blob(&manifest.config)?)?;
+        if config.rootfs.diff_ids.len() != manifest.layers.len() {
+            return Err(TurbineError::ImageError(format!(
+                "Image {} has {} layers but {} diff IDs",
+                manifest_digest, manifest.layers.len(), config.rootfs.diff_ids.len()
+            )));
+        }
+
+        Ok(OciImage {
+            layout_path: self.path.clone(),
+            manifest_digest: manifest_digest.to_string(),
+            manifest,
+            config,
+        })
+    }
+}
+
+impl OciImage {
+    pub fn manifest_digest(&self) -> &str {
+        &self.manifest_digest
+    }
+
+    pub fn manifest(&self) -> &ImageManifest {
+        &self.manifest
+    }
+
+    pub fn config(&self) -> &ImageConfiguration {
+        &self.config
+    }
+
+    fn layout(&self) -> OciLayout {
+        OciLayout { path: self.layout_path.clone() }
+    }
+
+    /// Fills command, working directory and environment from the image configuration
+    /// wherever `config` leaves them unset. Explicit environment entries win.
+    pub fn apply_defaults(&self, config: &mut ContainerConfig) {
+        let Some(image_config) = &self.config.config else {
+            return;
+        };
+
+        if config.command.is_empty() {
+            config.command = image_config.entrypoint.iter().flatten()
+                .chain(image_config.cmd.iter().flatten())
+                .cloned()
+                .collect();
+        }
+
+        if config.working_dir.is_none() {
+            config.working_dir = image_config.working_dir.clone().filter(|d| !d.is_empty());
+        }
+
+        for var in image_config.env.iter().flatten() {
+            if let Some((key, value)) = var.split_once('=') {
+                config.environment.entry(key.to_string()).or_insert_with(|| value.to_string());
+            }
+        }
+    }
+
+    /// Sets `uid`/`gid` from the image's `User` when the container config specifies no
+    /// user, resolving names against the unpacked root's `/etc/passwd` and `/etc/group`.
+    pub fn resolve_user(&self, config: &mut ContainerConfig, root: &Path) -> Result<()> {
+        if config.user.is_some() || config.uid.is_some() || config.gid.is_some() {
+            return Ok(());
+        }
+
+        let Some(image_user) = self.config.config.as_ref()
+            .and_then(|c| c.user.as_deref())
+            .filter(|u| !u.is_empty())
+        else {
+            return Ok(());
+        };
+        let (uid, gid) = resolve_user_spec(root, image_user)?;
+
+        config.uid = Some(uid);
+        config.gid = Some(gid);
+
+        Ok(())
+    }
+
+    /// Pairs each layer descriptor with its diff ID, bottom layer first.
+    pub fn layers(&self) -> impl Iterator<Item = (&Descriptor, &String)> {
+        self.manifest.layers.iter().zip(&self.config.rootfs.diff_ids)
+    }
+
+    /// Verifies and extracts every layer into `root` in order, applying whiteouts.
+    pub fn unpack(&self, root: &Path) -> Result<()> {
+        let root = root.canonicalize()?;
+        let layout = self.layout();
+
+        for (layer, diff_id) in self.layers() {
+            layout.verify_blob(layer)?;
+            self.apply_whiteouts(&layout, layer, diff_id, &root)?;
+
+            let mut archive = open_archive(layer_reader(&layout, layer)?);
+
+            extract_entries(&mut archive, layer, &root, false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Verifies and extracts a single layer into its own directory for use as an overlay
+    /// lowerdir. Whiteouts are converted to overlayfs form (0:0 character devices and
+    /// `trusted.overlay.opaque` directories).
+    pub fn unpack_layer(&self, layer: &Descriptor, diff_id: &str, target: &Path) -> Result<()> {
+        let layout = self.layout();
+        let (algorithm, _) = parse_digest(diff_id)?;
+
+        layout.verify_blob(layer)?;
+
+        let reader = HashingReader::new(layer_reader(&layout, layer)?, Digester::new(algorithm)?);
+        let mut archive = open_archive(reader);
+
+        extract_entries(&mut archive, layer, &target.canonicalize()?, true)?;
+
+        let mut reader = archive.into_inner();
+
+        io::copy(&mut reader, &mut io::sink())?;
+        check_digest(diff_id, reader.finalize())
+    }
+
+    /// First pass over a layer: removes paths hidden by whiteout entries from the lower
+    /// layers and checks the uncompressed stream against the layer's diff ID.
+    fn apply_whiteouts(&self, layout: &OciLayout, layer: &Descriptor, diff_id: &str, root: &Path) -> Result<()> {
+        let (algorithm, _) = parse_digest(diff_id)?;
+        let reader = HashingReader::new(layer_reader(layout, layer)?, Digester::new(algorithm)?);
+        let mut archive = tar::Archive::new(reader);
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let path = entry.path()?.into_owned();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if !name.starts_with(WHITEOUT_PREFIX) {
+                continue;
+            }
+
+            let dir = secure_join(root, path.parent().unwrap_or(Path::new("")))?;
+            if name == WHITEOUT_OPAQUE {
+                if dir.is_dir() {
+                    for child in fs::read_dir(&dir)? {
+                        remove_path(&child?.path())?;
+                    }
+                }
+            } else {
+                remove_path(&dir.join(&name[WHITEOUT_PREFIX.len()..]))?;
+            }
+        }
+
+        let mut reader = archive.into_inner();
+
+        io::copy(&mut reader, &mut io::sink())?;
+        check_digest(diff_id, reader.finalize())
+    }
+}
+
+fn open_archive<R: Read>(reader: R) -> tar::Archive<R> {
+    let mut archive = tar::Archive::new(reader);
+
+    archive.set_overwrite(true);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_preserve_ownerships(nix::unistd::geteuid().is_root());
+    archive.set_unpack_xattrs(false);
+
+    archive
+}
+
+/// Extracts the entries of a layer into `root`. Whiteout entries are skipped, or converted
+/// to their overlayfs representation when `overlay_whiteouts` is set.
+fn extract_entries<R: Read>(
+    archive: &mut tar::Archive<R>,
+    layer: &Descriptor,
+    root: &Path,
+    overlay_whiteouts: bool,
+) -> Result<()> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        let whiteout = name.to_str().and_then(|n| n.strip_prefix(WHITEOUT_PREFIX));
+        if whiteout.is_some() && !overlay_whiteouts {
+            continue;
+        }
+
+        let dir = secure_join(root, parent)?;
+
+        fs::create_dir_all(&dir)?;
+
+        if name == WHITEOUT_OPAQUE {
+            set_overlay_opaque(&dir)?;
+            continue;
+        }
+
+        let target = match whiteout {
+            Some(hidden) => dir.join(hidden),
+            None => dir.join(name),
+        };
+        let entry_type = entry.header().entry_type();
+
+        match fs::symlink_metadata(&target) {
+            Ok(meta) if meta.is_dir() && !entry_type.is_dir() => fs::remove_dir_all(&target)?,
+            Ok(meta) if !meta.is_dir() => fs::remove_file(&target)?,
+            _ => {}
+        }
+
+        if whiteout.is_some() {
+            mknod(&target, SFlag::S_IFCHR, Mode::empty(), makedev(0, 0))
+                .map_err(|e| TurbineError::ImageError(format!("Failed to create whiteout {:?}: {}", target, e)))?;
+        } else if entry_type.is_hard_link() {
+            let link_name = entry.link_name()?.ok_or_else(|| TurbineError::ImageError(
+                format!("Hard link {:?} in layer {} has no target", path, layer.digest)
+            ))?;
+
+            fs::hard_link(secure_join(root, &link_name)?, &target)?;
+        } else {
+            entry.unpack(&target)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn set_overlay_opaque(dir: &Path) -> Result<()> {
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| TurbineError::ImageError(format!("Invalid path {:?}", dir)))?;
+    let rc = unsafe {
+        libc::setxattr(path.as_ptr(), c"trusted.overlay.opaque".as_ptr(), b"y".as_ptr().cast(), 1, 0)
+    };
+    if rc != 0 {
+        return Err(TurbineError::ImageError(
+            format!("Failed to mark {:?} opaque: {}", dir, io::Error::last_os_error())
+        ));
+    }
+
+    Ok(())
+}
+
+fn layer_reader(layout: &OciLayout, layer: &Descriptor) -> Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(layout.blob_path(&layer.digest)?)?);
+    let media_type = layer.media_type.as_str();
+
+    if media_type.ends_with("+gzip") || media_type.ends_with(".gzip") {
+        Ok(Box::new(flate2::read::MultiGzDecoder::new(file)))
+    } else if media_type.ends_with("+zstd") {
+        Ok(Box::new(zstd::stream::read::Decoder::with_buffer(file)?))
+    } else if media_type.ends_with(".tar") || media_type.ends_with("diff.tar") {
+        Ok(Box::new(file))
+    } else {
+        Err(TurbineError::ImageError(format!("Unsupported layer media type {}", media_type)))
+    }
+}
+
+/// Resolves a `user[:group]` spec, each given by name or numeric ID, to a UID and GID
+/// using `/etc/passwd` and `/etc/group` under `root`. Without a group, the user's
+/// primary group is used.
+pub fn resolve_user_spec(root: &Path, spec: &str) -> Result<(u32, u32)> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    let passwd = read_root_file(root, "etc/passwd")?;
+    let entry = passwd.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 4 && (fields[0] == user || fields[2] == user));
+    let (uid, default_gid) = match (user.parse::<u32>(), entry) {
+        (_, Some(fields)) => (parse_id(fields[2])?, parse_id(fields[3])?),
+        (Ok(uid), None) => (uid, 0),
+        (Err(_), None) => {
+            return Err(TurbineError::ImageError(format!("User '{}' not found in /etc/passwd", user)));
+        }
+    };
+    let gid = match group {
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => {
+                let groups = read_root_file(root, "etc/group")?;
+                let fields = groups.lines()
+                    .map(|line| line.split(':').collect::<Vec<_>>())
+                    .find(|fields| fields.len() >= 3 && fields[0] == group)
+                    .ok_or_else(|| TurbineError::ImageError(
+                        format!("Group '{}' not found in /etc/group", group)
+                    ))?;
+
+                parse_id(fields[2])?
+            }
+        },
+        None => default_gid,
+    };
+
+    Ok((uid, gid))
+}
+
+fn read_root_file(root: &Path, relative: &str) -> Result<String> {
+    match fs::read_to_string(secure_join(root, relative)?) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => Ok(fs::remove_dir_all(path)?),
+        Ok(_) => Ok(fs::remove_file(path)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parse_id(value: &str) -> Result<u32> {
+    value.parse().map_err(|_| TurbineError::ImageError(format!("Invalid user or group ID '{}'", value)))
+}
+
+fn matches_host_platform(platform: &Platform) -> bool {
+    platform.os == "linux" && platform.architecture == host_architecture()
+}
+
+/// The host architecture in Go/OCI naming.
+pub fn host_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        "powerpc64" => "ppc64le",
+        other => other,
+    }
+}
+
+/// Splits `algorithm:hex` and validates both halves.
+pub fn parse_digest(digest: &str) -> Result<(&str, &str)> {
+    let (algorithm, hex) = digest.split_once(':')
+        .ok_or_else(|| TurbineError::ImageError(format!("Invalid digest '{}'", digest)))?;
+    let expected_len = match algorithm {
+        "sha256" => 64,
+        "sha512" => 128,
+        _ => return Err(TurbineError::ImageError(format!("Unsupported digest algorithm '{}'", algorithm))),
+    };
+    if hex.len() != expected_len || !hex.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+        return Err(TurbineError::ImageError(format!("Invalid digest '{}'", digest)));
+    }
+
+    Ok((algorithm, hex))
+}
+
+/// Returns the `sha256:<hex>` digest of `data`.
+pub fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+fn check_digest(expected: &str, actual: String) -> Result<()> {
+    if expected != actual {
+        return Err(TurbineError::ImageError(
+            format!("Digest mismatch: expected {}, got {}", expected, actual)
+        ));
+    }
+
+    Ok(())
+}
+
+pub(crate) enum Digester {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Digester {
+    pub(crate) fn new(algorithm: &str) -> Result<Self> {
+        match algorithm {
+            "sha256" => Ok(Digester::Sha256(Sha256::new())),
+            "sha512" => Ok(Digester::Sha512(Sha512::new())),
+            _ => Err(TurbineError::ImageError(format!("Unsupported digest algorithm '{}'", algorithm))),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Digester::Sha256(hasher) => hasher.update(data),
+            Digester::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> String {
+        match self {
+            Digester::Sha256(hasher) => format!("sha256:{:x}", hasher.finalize()),
+            Digester::Sha512(hasher) => format!("sha512:{:x}", hasher.finalize()),
+        }
+    }
+}
+
+/// Reader adapter that digests everything read through it.
+pub(crate) struct HashingReader<R> {
+    inner: R,
+    digester: Digester,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub(crate) fn new(inner: R, digester: Digester) -> Self {
+        Self { inner, digester }
+    }
+
+    pub(crate) fn finalize(self) -> String {
+        self.digester.finalize()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+
+        self.digester.update(&buf[..n]);
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::os::unix::fs::FileTypeExt;
+
+    const MEDIA_TYPE_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+    const MEDIA_TYPE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+
+    /// An entry of a test layer. Paths are written as given, `..` and all.
+    pub(crate) enum Entry<'a> {
+        Dir(&'a str),
+        File(&'a str, &'a str),
+        Symlink(&'a str, &'a str),
+        HardLink(&'a str, &'a str),
+    }
+
+    /// An uncompressed layer tarball of `entries`.
+    pub(crate) fn layer(entries: &[Entry]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+
+        for entry in entries {
+            let mut header = tar::Header::new_gnu();
+            let (path, contents, link) = match *entry {
+                Entry::Dir(path) => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    (path, "", None)
+                }
+                Entry::File(path, contents) => (path, contents, None),
+                Entry::Symlink(path, target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    (path, "", Some(target))
+                }
+                Entry::HardLink(path, target) => {
+                    header.set_entry_type(tar::EntryType::Link);
+                    (path, "", Some(target))
+                }
+            };
+            let gnu = header.as_gnu_mut().unwrap();
+
+            // Bypasses the builder's checks, which would reject the paths worth testing.
+            gnu.name[..path.len()].copy_from_slice(path.as_bytes());
+            if let Some(link) = link {
+                gnu.linkname[..link.len()].copy_from_slice(link.as_bytes());
+            }
+
+            header.set_mode(if matches!(entry, Entry::Dir(_)) { 0o755 } else { 0o644 });
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes()).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    /// An OCI image layout in a fresh directory under the temporary directory.
+    pub(crate) struct TestLayout {
+        pub(crate) path: PathBuf,
+    }
+
+    impl TestLayout {
+        pub(crate) fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("turbine-image-{}", uuid::Uuid::new_v4()));
+
+            fs::create_dir_all(path.join("blobs/sha256")).unwrap();
+            fs::write(path.join("oci-layout"), br#"{"imageLayoutVersion":"1.0.0"}"#).unwrap();
+            fs::write(path.join("index.json"), br#"{"schemaVersion":2,"manifests":[]}"#).unwrap();
+
+            Self { path }
+        }
+
+        pub(crate) fn layout(&self) -> OciLayout {
+            OciLayout::open(&self.path).unwrap()
+        }
+
+        pub(crate) fn add_blob(&self, media_type: &str, data: &[u8]) -> Descriptor {
+            let digest = sha256_digest(data);
+
+            fs::write(self.layout().blob_path(&digest).unwrap(), data).unwrap();
+
+            Descriptor {
+                media_type: media_type.to_string(),
+                digest,
+                size: data.len() as u64,
+                annotations: HashMap::new(),
+                platform: None,
+            }
+        }
+
+        /// Adds an image of uncompressed `layers` to the index, under `tag` if given,
+        /// and returns its manifest digest.
+        pub(crate) fn add_image(&self, layers: &[Vec<u8>], tag: Option<&str>) -> String {
+            let config = serde_json::json!({
+                "architecture": host_architecture(),
+                "os": "linux",
+                "rootfs": {
+                    "type": "layers",
+                    "diff_ids": layers.iter().map(|layer| sha256_digest(layer)).collect::<Vec<_>>(),
+                },
+            });
+            let manifest = ImageManifest {
+                schema_version: 2,
+                media_type: Some(MEDIA_TYPE_IMAGE_MANIFEST.to_string()),
+                config: self.add_blob(MEDIA_TYPE_CONFIG, &serde_json::to_vec(&config).unwrap()),
+                layers: layers.iter().map(|layer| self.add_blob(MEDIA_TYPE_LAYER, layer)).collect(),
+            };
+            let mut descriptor = self.add_blob(MEDIA_TYPE_IMAGE_MANIFEST, &serde_json::to_vec(&manifest).unwrap());
+            let mut index = self.layout().index().unwrap();
+
+            if let Some(tag) = tag {
+                descriptor.annotations.insert(ANNOTATION_REF_NAME.to_string(), tag.to_string());
+            }
+
+            index.manifests.push(descriptor.clone());
+            fs::write(self.path.join("index.json"), serde_json::to_vec(&index).unwrap()).unwrap();
+
+            descriptor.digest
+        }
+
+        pub(crate) fn image(&self, digest: &str) -> OciImage {
+            self.layout().image(digest).unwrap()
+        }
+    }
+
+    impl Drop for TestLayout {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    /// A directory to unpack into, next to the layout.
+    fn target(layout: &TestLayout, name: &str) -> PathBuf {
+        let path = layout.path.join(name);
+
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn whiteout_layers() -> [Vec<u8>; 2] {
+        [
+            layer(&[
+                Entry::Dir("a"),
+                Entry::File("a/keep", "keep"),
+                Entry::File("a/gone", "gone"),
+                Entry::Dir("b"),
+                Entry::File("b/old", "old"),
+                Entry::Dir("b/sub"),
+                Entry::File("b/sub/old", "old"),
+            ]),
+            layer(&[
+                Entry::File("a/.wh.gone", ""),
+                Entry::File("b/.wh..wh..opq", ""),
+                Entry::File("b/new", "new"),
+            ]),
+        ]
+    }
+
+    #[test]
+    fn unpack_applies_whiteouts() {
+        let layout = TestLayout::new();
+        let digest = layout.add_image(&whiteout_layers(), None);
+        let root = target(&layout, "root");
+
+        layout.image(&digest).unpack(&root).unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("a/keep")).unwrap(), "keep");
+        assert!(!root.join("a/gone").exists());
+        assert!(!root.join("a/.wh.gone").exists());
+
+        let mut children: Vec<_> = fs::read_dir(root.join("b")).unwrap()
+            .map(|child| child.unwrap().file_name())
+            .collect();
+        children.sort();
+        assert_eq!(children, ["new"]);
+    }
+
+    #[test]
+    fn unpack_layer_converts_whiteouts_for_overlayfs() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let layout = TestLayout::new();
+        let digest = layout.add_image(&whiteout_layers(), None);
+        let image = layout.image(&digest);
+        let (layer, diff_id) = image.layers().nth(1).unwrap();
+        let dir = target(&layout, "layer");
+
+        image.unpack_layer(layer, diff_id, &dir).unwrap();
+
+        let whiteout = fs::symlink_metadata(dir.join("a/gone")).unwrap();
+        assert!(whiteout.file_type().is_char_device());
+        assert_eq!(std::os::unix::fs::MetadataExt::rdev(&whiteout), 0);
+
+        let mut opaque = [0u8; 1];
+        let path = CString::new(dir.join("b").as_os_str().as_bytes()).unwrap();
+        let length = unsafe {
+            libc::getxattr(path.as_ptr(), c"trusted.overlay.opaque".as_ptr(), opaque.as_mut_ptr().cast(), 1)
+        };
+        assert_eq!((length, opaque), (1, *b"y"));
+        assert!(dir.join("b/new").exists());
+    }
+
+    #[test]
+    fn rejects_layers_not_matching_their_digest() {
+        let layout = TestLayout::new();
+        let digest = layout.add_image(&[layer(&[Entry::File("file", "original")])], None);
+        let image = layout.image(&digest);
+        let (descriptor, diff_id) = image.layers().next().unwrap();
+        let blob = layout.layout().blob_path(&descriptor.digest).unwrap();
+
+        // Same size, different content.
+        let tampered = String::from_utf8(fs::read(&blob).unwrap()).unwrap().replace("original", "tampered");
+        fs::write(&blob, tampered).unwrap();
+
+        let error = image.unpack(&target(&layout, "root")).unwrap_err().to_string();
+        assert!(error.contains("Digest mismatch"), "{}", error);
+
+        let error = image.unpack_layer(descriptor, diff_id, &target(&layout, "layer")).unwrap_err().to_string();
+        assert!(error.contains("Digest mismatch"), "{}", error);
+
+        fs::write(&blob, b"short").unwrap();
+        let error = image.unpack(&target(&layout, "root")).unwrap_err().to_string();
+        assert!(error.contains("has size 5"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_layers_not_matching_their_diff_id() {
+        let layout = TestLayout::new();
+        let layers = [layer(&[Entry::File("file", "contents")])];
+        let digest = layout.add_image(&layers, None);
+        let image = layout.image(&digest);
+        let wrong = sha256_digest(b"another layer");
+
+        let error = image.unpack_layer(&image.manifest().layers[0], &wrong, &target(&layout, "layer")).unwrap_err();
+        assert!(error.to_string().contains("Digest mismatch"), "{}", error);
+    }
+
+    #[test]
+    fn entries_stay_inside_the_root() {
+        let layout = TestLayout::new();
+        let outside = target(&layout, "outside");
+        let link = outside.to_string_lossy().into_owned();
+        let digest = layout.add_image(&[layer(&[
+            Entry::File("../dotdot", "x"),
+            Entry::File("a/../../nested", "x"),
+            Entry::File("/absolute", "x"),
+            Entry::Symlink("escape", &link),
+            Entry::File("escape/through-link", "x"),
+            Entry::Symlink("up", "../../.."),
+            Entry::File("up/through-relative-link", "x"),
+            Entry::HardLink("hard", "../outside/secret"),
+        ])], None);
+        let root = target(&layout, "root");
+
+        fs::write(outside.join("secret"), "secret").unwrap();
+
+        // The hard link's target does not exist inside the root.
+        let error = layout.image(&digest).unpack(&root).unwrap_err().to_string();
+        assert!(error.contains("No such file"), "{}", error);
+
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 1);
+        for escaped in ["dotdot", "nested", "absolute"] {
+            assert!(!layout.path.join(escaped).exists(), "{} escaped", escaped);
+            assert!(root.join(escaped).exists(), "{} is missing", escaped);
+        }
+        assert!(!Path::new("/absolute").exists());
+        assert!(root.join(outside.strip_prefix("/").unwrap()).join("through-link").exists());
+        assert!(root.join("through-relative-link").exists());
+    }
+}
@@ -0,0 +1,785 @@
+use crate::image::{
+    self, Descriptor, ImageConfiguration, ImageIndex, ImageManifest, OciImage, OciLayout,
+    ANNOTATION_REF_NAME, MEDIA_TYPE_DOCKER_MANIFEST_LIST, MEDIA_TYPE_IMAGE_INDEX, MEDIA_TYPE_IMAGE_MANIFEST,
+};
+use crate::state::{lock_file, write_atomic};
+use crate::{TurbineError, Result};
+use nix::fcntl::Flock;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const METADATA_VERSION: u32 = 1;
+const DEFAULT_TAG: &str = "latest";
+const MIN_SHORT_DIGEST_LEN: usize = 4;
+const ANNOTATION_CONTAINERD_IMAGE_NAME: &str = "io.containerd.image.name";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ImageRecord {
+    descriptor: Descriptor,
+    tags: BTreeSet<String>,
+    imported_at: chrono::DateTime<chrono::Utc>,
+    containers: BTreeSet<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoreMetadata {
+    version: u32,
+    images: BTreeMap<String, ImageRecord>,
+}
+
+impl Default for StoreMetadata {
+    fn default() -> Self {
+        Self {
+            version: METADATA_VERSION,
+            images: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageSummary {
+    pub digest: String,
+    pub tags: Vec<String>,
+    pub size: u64,
+    pub imported_at: chrono::DateTime<chrono::Utc>,
+    pub containers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageDetails {
+    pub summary: ImageSummary,
+    pub manifest: ImageManifest,
+    pub config: ImageConfiguration,
+}
+
+/// Content-addressed image store under `<base_path>/images`.
+///
+/// The directory is itself an OCI image layout; `images.json` maps manifest digests to
+/// their tags and to the containers created from them.
+pub struct ImageStore {
+    path: PathBuf,
+}
+
+impl ImageStore {
+    pub fn open<P: AsRef<Path>>(base_path: P) -> Result<Self> {
+        let path = base_path.as_ref().join("images");
+
+        fs::create_dir_all(path.join("blobs"))?;
+
+        let store = Self { path };
+        let marker = store.path.join("oci-layout");
+        if !marker.exists() {
+            write_atomic(&marker, br#"{"imageLayoutVersion":"1.0.0"}"#)?;
+        }
+
+        if !store.path.join("index.json").exists() {
+            let _lock = store.lock()?;
+
+            store.write_metadata(&StoreMetadata::default())?;
+        }
+
+        Ok(store)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn layout(&self) -> Result<OciLayout> {
+        OciLayout::open(&self.path)
+    }
+
+    fn lock(&self) -> Result<Flock<File>> {
+        lock_file(&self.path.join("images.lock"), true)
+    }
+
+    fn read_metadata(&self) -> Result<StoreMetadata> {
+        let metadata: StoreMetadata = match fs::read_to_string(self.path.join("images.json")) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(StoreMetadata::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        if metadata.version != METADATA_VERSION {
+            return Err(TurbineError::ImageError(
+                format!("Unsupported image store version {}", metadata.version)
+            ));
+        }
+
+        Ok(metadata)
+    }
+
+    /// Writes `images.json` and regenerates the layout's `index.json` from it.
+    fn write_metadata(&self, metadata: &StoreMetadata) -> Result<()> {
+        let mut manifests = Vec::new();
+
+        for record in metadata.images.values() {
+            if record.tags.is_empty() {
+                manifests.push(record.descriptor.clone());
+            }
+
+            for tag in &record.tags {
+                let mut descriptor = record.descriptor.clone();
+
+                descriptor.annotations.insert(ANNOTATION_REF_NAME.to_string(), tag.clone());
+                manifests.push(descriptor);
+            }
+        }
+
+        let index = ImageIndex {
+            schema_version: 2,
+            media_type: Some(MEDIA_TYPE_IMAGE_INDEX.to_string()),
+            manifests,
+        };
+
+        write_atomic(&self.path.join("images.json"), &serde_json::to_vec_pretty(metadata)?)?;
+        write_atomic(&self.path.join("index.json"), &serde_json::to_vec_pretty(&index)?)
+    }
+
+    /// Imports every image for this platform from an OCI layout directory or a (optionally
+    /// gzip-compressed) tar archive of one. `tag` overrides the layout's ref-name annotations.
+    pub fn import<P: AsRef<Path>>(&self, source: P, tag: Option<&str>) -> Result<Vec<ImageSummary>> {
+        let source = source.as_ref();
+        let name = source_name(source);
+        if source.is_dir() {
+            return self.import_layout(&OciLayout::open(source)?, &name, tag);
+        }
+
+        let scratch = self.path.join("tmp").join(Uuid::new_v4().to_string());
+
+        fs::create_dir_all(&scratch)?;
+
+        let result = extract_archive(source, &scratch)
+            .and_then(|_| OciLayout::open(&scratch))
+            .and_then(|layout| self.import_layout(&layout, &name, tag));
+
+        let _ = fs::remove_dir_all(&scratch);
+
+        result
+    }
+
+    fn import_layout(&self, source: &OciLayout, name: &str, tag: Option<&str>) -> Result<Vec<ImageSummary>> {
+        let _lock = self.lock()?;
+        let mut imported: Vec<(Descriptor, Option<String>)> = Vec::new();
+
+        for descriptor in source.index()?.manifests {
+            let ref_name = reference_from_annotations(&descriptor, name);
+            let Some(manifest) = self.platform_manifest(source, descriptor)? else {
+                continue;
+            };
+            let manifest = self.copy_image(source, manifest)?;
+
+            imported.push((manifest, tag.map(str::to_string).or(ref_name)));
+        }
+
+        if imported.is_empty() {
+            return Err(TurbineError::ImageError(
+                format!("No image for this platform in {:?}", source.path())
+            ));
+        }
+
+        let mut metadata = self.read_metadata()?;
+        let mut digests = Vec::new();
+
+        for (descriptor, tag) in imported {
+            let digest = descriptor.digest.clone();
+
+            metadata.images.entry(digest.clone()).or_insert_with(|| ImageRecord {
+                descriptor: Descriptor {
+                    annotations: Default::default(),
+                    platform: None,
+                    ..descriptor
+                },
+                tags: BTreeSet::new(),
+                imported_at: chrono::Utc::now(),
+                containers: BTreeSet::new(),
+            });
+
+            if let Some(tag) = tag {
+                let tag = normalize_tag(&tag);
+
+                for record in metadata.images.values_mut() {
+                    record.tags.remove(&tag);
+                }
+
+                if let Some(record) = metadata.images.get_mut(&digest) {
+                    record.tags.insert(tag);
+                }
+            }
+
+            if !digests.contains(&digest) {
+                digests.push(digest);
+            }
+        }
+
+        self.write_metadata(&metadata)?;
+
+        let layout = self.layout()?;
+
+        digests.iter()
+            .map(|digest| summarize(&layout, digest, &metadata.images[digest]))
+            .collect()
+    }
+
+    /// Resolves a (possibly nested) index entry to the manifest for this platform.
+    fn platform_manifest(&self, source: &OciLayout, descriptor: Descriptor) -> Result<Option<Descriptor>> {
+        if descriptor.platform.as_ref().is_some_and(|p| {
+            p.os != "linux" || p.architecture != image::host_architecture()
+        }) {
+            return Ok(None);
+        }
+
+        if descriptor.media_type == MEDIA_TYPE_IMAGE_INDEX
+            || descriptor.media_type == MEDIA_TYPE_DOCKER_MANIFEST_LIST
+        {
+            let nested: ImageIndex = serde_json::from_slice(&source.read_blob(&descriptor)?)?;
+
+            for child in nested.manifests {
+                if let Some(manifest) = self.platform_manifest(source, child)? {
+                    return Ok(Some(manifest));
+                }
+            }
+
+            return Ok(None);
+        }
+
+        Ok(Some(descriptor))
+    }
+
+    /// Copies a manifest and all blobs it references, verifying each digest.
+    fn copy_image(&self, source: &OciLayout, descriptor: Descriptor) -> Result<Descriptor> {
+        let image = source.image(&descriptor.digest)?;
+        let manifest = image.manifest();
+
+        for blob in manifest.layers.iter().chain(std::iter::once(&manifest.config)) {
+            self.copy_blob(source, blob)?;
+        }
+
+        self.copy_blob(source, &descriptor)?;
+
+        Ok(Descriptor {
+            media_type: manifest.media_type.clone().unwrap_or_else(|| MEDIA_TYPE_IMAGE_MANIFEST.to_string()),
+            ..descriptor
+        })
+    }
+
+    fn copy_blob(&self, source: &OciLayout, descriptor: &Descriptor) -> Result<()> {
+        let layout = self.layout()?;
+        if layout.has_blob(descriptor) {
+            return Ok(());
+        }
+
+        source.verify_blob(descriptor)?;
+
+        let target = layout.blob_path(&descriptor.digest)?;
+        let parent = target.parent().ok_or_else(|| {
+            TurbineError::ImageError(format!("Invalid blob path {:?}", target))
+        })?;
+        let tmp_path = parent.join(format!(".{}.tmp", Uuid::new_v4()));
+
+        fs::create_dir_all(parent)?;
+        fs::copy(source.blob_path(&descriptor.digest)?, &tmp_path)?;
+        fs::rename(&tmp_path, &target)?;
+
+        Ok(())
+    }
+
+    /// Resolves a full digest, a tag (`name[:tag]`) or a unique digest prefix.
+    pub fn resolve(&self, reference: &str) -> Result<String> {
+        let metadata = self.read_metadata()?;
+
+        resolve_reference(&metadata, reference)
+    }
+
+    pub fn image(&self, digest: &str) -> Result<OciImage> {
+        self.layout()?.image(digest)
+    }
+
+    pub fn list(&self) -> Result<Vec<ImageSummary>> {
+        let metadata = self.read_metadata()?;
+        let layout = self.layout()?;
+
+        metadata.images.iter()
+            .map(|(digest, record)| summarize(&layout, digest, record))
+            .collect()
+    }
+
+    pub fn inspect(&self, reference: &str) -> Result<ImageDetails> {
+        let metadata = self.read_metadata()?;
+        let digest = resolve_reference(&metadata, reference)?;
+        let layout = self.layout()?;
+        let image = layout.image(&digest)?;
+
+        Ok(ImageDetails {
+            summary: summarize(&layout, &digest, &metadata.images[&digest])?,
+            manifest: image.manifest().clone(),
+            config: image.config().clone(),
+        })
+    }
+
+    /// Removes a tag, or the whole image when given a digest or its last tag. Images still
+    /// referenced by containers are never deleted.
+    pub fn remove(&self, reference: &str) -> Result<Vec<String>> {
+        let _lock = self.lock()?;
+        let mut metadata = self.read_metadata()?;
+        let digest = resolve_reference(&metadata, reference)?;
+        let tag = normalize_tag(reference);
+        let record = metadata.images.get_mut(&digest)
+            .ok_or_else(|| TurbineError::ImageError(format!("Image '{}' not found", reference)))?;
+        let mut messages = Vec::new();
+
+        if record.tags.contains(&tag) && record.tags.len() > 1 {
+            record.tags.remove(&tag);
+            messages.push(format!("Untagged: {}", tag));
+            self.write_metadata(&metadata)?;
+
+            return Ok(messages);
+        }
+
+        if !record.containers.is_empty() {
+            return Err(TurbineError::ImageError(format!(
+                "Image {} is in use by containers: {}",
+                digest, record.containers.iter().cloned().collect::<Vec<_>>().join(", ")
+            )));
+        }
+
+        for tag in &record.tags {
+            messages.push(format!("Untagged: {}", tag));
+        }
+
+        metadata.images.remove(&digest);
+        messages.push(format!("Deleted: {}", digest));
+        self.write_metadata(&metadata)?;
+        self.collect_garbage(&metadata)?;
+
+        Ok(messages)
+    }
+
+    /// Deletes untagged images that no container references, then any orphaned blobs.
+    pub fn prune(&self) -> Result<Vec<String>> {
+        let _lock = self.lock()?;
+        let mut metadata = self.read_metadata()?;
+        let dangling: Vec<String> = metadata.images.iter()
+            .filter(|(_, record)| record.tags.is_empty() && record.containers.is_empty())
+            .map(|(digest, _)| digest.clone())
+            .collect();
+        let mut messages = Vec::new();
+
+        for digest in dangling {
+            metadata.images.remove(&digest);
+            messages.push(format!("Deleted: {}", digest));
+        }
+
+        self.write_metadata(&metadata)?;
+
+        let reclaimed = self.collect_garbage(&metadata)?;
+
+        messages.push(format!("Reclaimed {} bytes", reclaimed));
+
+        Ok(messages)
+    }
+
+    pub fn add_reference(&self, digest: &str, container_id: &str) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut metadata = self.read_metadata()?;
+        let record = metadata.images.get_mut(digest)
+            .ok_or_else(|| TurbineError::ImageError(format!("Image {} not found", digest)))?;
+
+        if record.containers.insert(container_id.to_string()) {
+            self.write_metadata(&metadata)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn release(&self, digest: &str, container_id: &str) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut metadata = self.read_metadata()?;
+
+        if let Some(record) = metadata.images.get_mut(digest) {
+            if record.containers.remove(container_id) {
+                self.write_metadata(&metadata)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes every blob not reachable from a remaining manifest. Returns the bytes freed.
+    fn collect_garbage(&self, metadata: &StoreMetadata) -> Result<u64> {
+        let layout = self.layout()?;
+        let mut live: HashSet<PathBuf> = HashSet::new();
+
+        for digest in metadata.images.keys() {
+            let image = layout.image(digest)?;
+            let manifest = image.manifest();
+
+            live.insert(layout.blob_path(digest)?);
+            for blob in manifest.layers.iter().chain(std::iter::once(&manifest.config)) {
+                live.insert(layout.blob_path(&blob.digest)?);
+            }
+
+            for (_, diff_id) in image.layers() {
+                live.insert(self.layer_path(diff_id)?);
+            }
+        }
+
+        let mut reclaimed = 0;
+
+        for algorithm in fs::read_dir(self.path.join("blobs"))? {
+            for blob in fs::read_dir(algorithm?.path())? {
+                let blob = blob?;
+                let path = blob.path();
+                if live.contains(&path) || blob.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+
+                reclaimed += blob.metadata()?.len();
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let layers_path = self.path.join("layers");
+        if layers_path.exists() {
+            for algorithm in fs::read_dir(&layers_path)? {
+                let algorithm = algorithm?;
+                if algorithm.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+
+                for layer in fs::read_dir(algorithm.path())? {
+                    let path = layer?.path();
+                    if !live.contains(&path) {
+                        fs::remove_dir_all(&path)?;
+                    }
+                }
+            }
+        }
+
+        Ok(reclaimed)
+    }
+
+    fn layer_path(&self, diff_id: &str) -> Result<PathBuf> {
+        let (algorithm, hex) = image::parse_digest(diff_id)?;
+
+        Ok(self.path.join("layers").join(algorithm).join(hex))
+    }
+
+    /// Returns the unpacked directory of every layer of `image`, topmost first as overlayfs
+    /// expects its `lowerdir` list. Layers are unpacked once and shared between containers.
+    pub fn layer_dirs(&self, image: &OciImage) -> Result<Vec<PathBuf>> {
+        let mut dirs = Vec::new();
+
+        for (layer, diff_id) in image.layers() {
+            let target = self.layer_path(diff_id)?;
+
+            if !target.exists() {
+                let parent = target.parent().ok_or_else(|| {
+                    TurbineError::ImageError(format!("Invalid layer path {:?}", target))
+                })?;
+                let scratch = self.path.join("layers").join(format!(".{}", Uuid::new_v4()));
+
+                fs::create_dir_all(parent)?;
+                fs::create_dir_all(&scratch)?;
+
+                let unpacked = image.unpack_layer(layer, diff_id, &scratch)
+                    .and_then(|_| Ok(fs::rename(&scratch, &target)?));
+                if let Err(e) = unpacked {
+                    let _ = fs::remove_dir_all(&scratch);
+                    if !target.exists() {
+                        return Err(e);
+                    }
+                }
+            }
+
+            dirs.push(target);
+        }
+
+        dirs.reverse();
+
+        Ok(dirs)
+    }
+}
+
+/// Returns true if an image reference names an OCI layout or archive on disk rather than
+/// an image in the store.
+pub fn is_image_path(reference: &str) -> bool {
+    reference.starts_with('/') || reference.starts_with("./")
+}
+
+/// Repository name for images whose ref-name annotation is a bare tag, taken from the
+/// layout directory or archive file name.
+fn source_name(source: &Path) -> String {
+    let file_name = source.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let name = [".tar.gz", ".tgz", ".tar"].iter()
+        .find_map(|ext| file_name.strip_suffix(ext))
+        .unwrap_or(&file_name);
+
+    name.to_ascii_lowercase()
+}
+
+/// Builds a `name:tag` reference from a manifest's annotations. A bare tag in
+/// `org.opencontainers.image.ref.name` is combined with `io.containerd.image.name` or,
+/// failing that, `default_name`.
+fn reference_from_annotations(descriptor: &Descriptor, default_name: &str) -> Option<String> {
+    let ref_name = descriptor.annotations.get(ANNOTATION_REF_NAME)?;
+    if ref_name.contains(':') || ref_name.contains('/') {
+        return Some(ref_name.clone());
+    }
+
+    match descriptor.annotations.get(ANNOTATION_CONTAINERD_IMAGE_NAME) {
+        Some(full_name) => Some(full_name.clone()),
+        None if !default_name.is_empty() => Some(format!("{}:{}", default_name, ref_name)),
+        None => None,
+    }
+}
+
+fn normalize_tag(tag: &str) -> String {
+    let name_start = tag.rfind('/').map(|i| i + 1).unwrap_or(0);
+
+    if tag[name_start..].contains(':') {
+        tag.to_string()
+    } else {
+        format!("{}:{}", tag, DEFAULT_TAG)
+    }
+}
+
+fn resolve_reference(metadata: &StoreMetadata, reference: &str) -> Result<String> {
+    if metadata.images.contains_key(reference) {
+        return Ok(reference.to_string());
+    }
+
+    let tag = normalize_tag(reference);
+    if let Some((digest, _)) = metadata.images.iter().find(|(_, r)| r.tags.contains(&tag)) {
+        return Ok(digest.clone());
+    }
+
+    let prefix = reference.strip_prefix("sha256:").unwrap_or(reference);
+    if prefix.len() >= MIN_SHORT_DIGEST_LEN && prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+        let matches: Vec<&String> = metadata.images.keys()
+            .filter(|d| d.strip_prefix("sha256:").is_some_and(|hex| hex.starts_with(prefix)))
+            .collect();
+
+        match matches.as_slice() {
+            [digest] => return Ok((*digest).clone()),
+            [] => {}
+            _ => {
+                return Err(TurbineError::ImageError(
+                    format!("Image reference '{}' is ambiguous", reference)
+                ));
+            }
+        }
+    }
+
+    Err(TurbineError::ImageError(format!("Image '{}' not found", reference)))
+}
+
+fn summarize(layout: &OciLayout, digest: &str, record: &ImageRecord) -> Result<ImageSummary> {
+    let image = layout.image(digest)?;
+    let manifest = image.manifest();
+    let size = record.descriptor.size
+        + manifest.config.size
+        + manifest.layers.iter().map(|l| l.size).sum::<u64>();
+
+    Ok(ImageSummary {
+        digest: digest.to_string(),
+        tags: record.tags.iter().cloned().collect(),
+        size,
+        imported_at: record.imported_at,
+        containers: record.containers.iter().cloned().collect(),
+    })
+}
+
+fn extract_archive(source: &Path, target: &Path) -> Result<()> {
+    let mut file = BufReader::new(File::open(source)?);
+    let mut magic = [0u8; 2];
+
+    file.read_exact(&mut magic)?;
+
+    let file = BufReader::new(File::open(source)?);
+    let reader: Box<dyn Read> = if magic == [0x1f, 0x8b] {
+        Box::new(flate2::read::MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    tar::Archive::new(reader).unpack(target)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::tests::{layer, Entry, TestLayout};
+
+    /// A store in a fresh directory, with a layout to import images from.
+    struct TestStore {
+        store: ImageStore,
+        base_path: PathBuf,
+        source: TestLayout,
+    }
+
+    impl TestStore {
+        fn open() -> Self {
+            let base_path = std::env::temp_dir().join(format!("turbine-images-{}", Uuid::new_v4()));
+
+            Self { store: ImageStore::open(&base_path).unwrap(), base_path, source: TestLayout::new() }
+        }
+
+        /// Imports an image with a file of `contents` as its only layer.
+        fn import(&self, contents: &str, tag: Option<&str>) -> String {
+            self.source.add_image(&[layer(&[Entry::File("file", contents)])], None);
+
+            let imported = self.store.import(&self.source.path, tag).unwrap();
+            let digest = imported.last().unwrap().digest.clone();
+
+            // Later imports only bring their own image.
+            fs::write(self.source.path.join("index.json"), br#"{"schemaVersion":2,"manifests":[]}"#).unwrap();
+            digest
+        }
+
+        fn tags(&self, digest: &str) -> Vec<String> {
+            self.store.list().unwrap().into_iter()
+                .find(|summary| summary.digest == digest)
+                .map(|summary| summary.tags)
+                .unwrap_or_default()
+        }
+
+        fn has_blob(&self, digest: &str) -> bool {
+            self.store.layout().unwrap().blob_path(digest).unwrap().exists()
+        }
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base_path);
+        }
+    }
+
+    #[test]
+    fn resolves_tags_digests_and_prefixes() {
+        let test = TestStore::open();
+        let digest = test.import("web", Some("web"));
+        let hex = digest.strip_prefix("sha256:").unwrap();
+
+        assert_eq!(test.tags(&digest), ["web:latest"]);
+        for reference in ["web", "web:latest", digest.as_str(), &hex[..12], &format!("sha256:{}", &hex[..4])] {
+            assert_eq!(test.store.resolve(reference).unwrap(), digest, "{}", reference);
+        }
+
+        for reference in ["web:1.0", "db", &hex[..3], "sha256:"] {
+            assert!(test.store.resolve(reference).is_err(), "resolved {}", reference);
+        }
+    }
+
+    #[test]
+    fn tags_come_from_annotations_unless_given() {
+        let test = TestStore::open();
+
+        test.source.add_image(&[layer(&[Entry::File("file", "app")])], Some("1.0"));
+
+        let digest = test.store.import(&test.source.path, None).unwrap()[0].digest.clone();
+        let name = test.source.path.file_name().unwrap().to_string_lossy().to_ascii_lowercase();
+
+        assert_eq!(test.tags(&digest), [format!("{}:1.0", name)]);
+
+        test.store.import(&test.source.path, Some("registry.local:5000/app")).unwrap();
+        assert!(test.tags(&digest).contains(&"registry.local:5000/app:latest".to_string()));
+    }
+
+    #[test]
+    fn tagging_another_image_moves_the_tag() {
+        let test = TestStore::open();
+        let old = test.import("v1", Some("app"));
+        let new = test.import("v2", Some("app"));
+
+        assert_eq!(test.store.resolve("app").unwrap(), new);
+        assert!(test.tags(&old).is_empty());
+        assert_eq!(test.tags(&new), ["app:latest"]);
+    }
+
+    #[test]
+    fn counts_references_per_container() {
+        let test = TestStore::open();
+        let digest = test.import("app", Some("app"));
+        let containers = || test.store.inspect("app").unwrap().summary.containers;
+
+        test.store.add_reference(&digest, "one").unwrap();
+        test.store.add_reference(&digest, "two").unwrap();
+        test.store.add_reference(&digest, "two").unwrap();
+        assert_eq!(containers(), ["one", "two"]);
+
+        test.store.release(&digest, "two").unwrap();
+        test.store.release(&digest, "unknown").unwrap();
+        assert_eq!(containers(), ["one"]);
+
+        assert!(test.store.add_reference("sha256:0000", "three").is_err());
+    }
+
+    #[test]
+    fn remove_refuses_images_in_use() {
+        let test = TestStore::open();
+        let digest = test.import("app", Some("app"));
+
+        test.store.add_reference(&digest, "one").unwrap();
+
+        let error = test.store.remove("app").unwrap_err().to_string();
+        assert!(error.contains("in use by containers: one"), "{}", error);
+        assert!(test.store.remove(&digest).is_err());
+        assert!(test.has_blob(&digest));
+
+        test.store.release(&digest, "one").unwrap();
+
+        assert_eq!(test.store.remove("app").unwrap(), ["Untagged: app:latest".to_string(), format!("Deleted: {}", digest)]);
+        assert!(!test.has_blob(&digest));
+        assert!(test.store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn remove_untags_images_with_other_tags() {
+        let test = TestStore::open();
+        let digest = test.import("app", Some("app"));
+
+        // The same image again.
+        assert_eq!(test.import("app", Some("app:stable")), digest);
+        test.store.add_reference(&digest, "one").unwrap();
+
+        // Untagging works even while the image is in use.
+        assert_eq!(test.store.remove("app").unwrap(), ["Untagged: app:latest"]);
+        assert_eq!(test.tags(&digest), ["app:stable"]);
+        assert!(test.store.remove("app:stable").is_err());
+    }
+
+    #[test]
+    fn prune_keeps_tagged_and_referenced_images() {
+        let test = TestStore::open();
+        let tagged = test.import("tagged", Some("app"));
+        let referenced = test.import("referenced", None);
+        let dangling = test.import("dangling", None);
+        let dangling_layer = test.store.image(&dangling).unwrap().manifest().layers[0].digest.clone();
+
+        test.store.add_reference(&referenced, "one").unwrap();
+
+        let messages = test.store.prune().unwrap();
+        assert_eq!(messages[0], format!("Deleted: {}", dangling));
+        assert!(messages[1].starts_with("Reclaimed "));
+
+        let mut remaining: Vec<String> = test.store.list().unwrap().into_iter().map(|summary| summary.digest).collect();
+        let mut expected = vec![tagged.clone(), referenced.clone()];
+        remaining.sort();
+        expected.sort();
+        assert_eq!(remaining, expected);
+        assert!(!test.has_blob(&dangling) && !test.has_blob(&dangling_layer));
+        assert!(test.has_blob(&tagged) && test.has_blob(&referenced));
+
+        // Once released, the untagged image goes too.
+        test.store.release(&referenced, "one").unwrap();
+        test.store.prune().unwrap();
+        assert_eq!(test.store.list().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,598 @@
+use crate::network::Network;
+use crate::state::write_atomic;
+use crate::{Container, TurbineError, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A range of addresses of one family, written as `start-end`, a single address or a
+/// subnet in CIDR notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpRange {
+    pub start: IpAddr,
+    pub end: IpAddr,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.start.is_ipv4() && (bits(self.start).0..=bits(self.end).0).contains(&bits(ip).0)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse = |address: &str| address.parse::<IpAddr>()
+            .map_err(|e| format!("Invalid address '{}' in range '{}': {}", address, s, e));
+        let range = if let Some((subnet, prefix)) = s.split_once('/') {
+            let subnet = parse(subnet)?;
+            let prefix = prefix.parse::<u8>().ok()
+                .filter(|prefix| *prefix as u32 <= bits(subnet).1)
+                .ok_or_else(|| format!("Invalid prefix in range '{}'", s))?;
+
+            IpRange { start: masked(subnet, prefix), end: broadcast(subnet, prefix) }
+        } else if let Some((start, end)) = s.split_once('-') {
+            IpRange { start: parse(start)?, end: parse(end)? }
+        } else {
+            let address = parse(s)?;
+
+            IpRange { start: address, end: address }
+        };
+
+        if range.start.is_ipv4() != range.end.is_ipv4() || bits(range.start).0 > bits(range.end).0 {
+            return Err(format!("Invalid range '{}'", s));
+        }
+
+        Ok(range)
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// An address handed to a container on a network.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+    pub container_id: String,
+    pub ip: IpAddr,
+    /// The container asked for this address rather than getting the next free one.
+    #[serde(default)]
+    pub requested: bool,
+    pub leased_at: DateTime<Utc>,
+}
+
+/// Hands out container addresses on each network and keeps the leases under
+/// `<base_path>/state/ipam`, one `<network>.json` each, so they survive restarts.
+pub struct Ipam {
+    dir: PathBuf,
+    /// Leases on each network, by address.
+    leases: HashMap<String, BTreeMap<IpAddr, Lease>>,
+}
+
+impl Ipam {
+    pub fn open<P: AsRef<Path>>(base_path: P) -> Result<Self> {
+        let dir = base_path.as_ref().join("state").join("ipam");
+        let mut leases = HashMap::new();
+
+        fs::create_dir_all(&dir)?;
+
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Some(network) = path.file_stem().filter(|_| path.extension().is_some_and(|extension| extension == "json")) else {
+                continue;
+            };
+
+            match load_leases(&path) {
+                Ok(loaded) => {
+                    leases.insert(
+                        network.to_string_lossy().into_owned(),
+                        loaded.into_iter().map(|lease| (lease.ip, lease)).collect(),
+                    );
+                }
+                Err(e) => eprintln!("Warning: Failed to load address leases from {:?}: {}", path, e),
+            }
+        }
+
+        Ok(Self { dir, leases })
+    }
+
+    /// Checks the stored leases against the networks and the addresses containers were
+    /// given, e.g. after a restart of the runtime. Leases no container holds are released,
+    /// addresses without a lease are leased to their container if free, and addresses
+    /// leased to another container or unusable on their network are warned about; such
+    /// a container cannot start until it is reconnected to the network.
+    pub fn restore(&mut self, networks: &BTreeMap<String, Network>, containers: &[&Container]) {
+        for network in self.leases.keys().filter(|name| !networks.contains_key(*name)).cloned().collect::<Vec<_>>() {
+            eprintln!("Warning: Releasing address leases of unknown network {}", network);
+            if let Err(e) = self.remove_network(&network) {
+                eprintln!("Warning: Failed to remove address leases of network {}: {}", network, e);
+            }
+        }
+
+        let mut changed = Vec::new();
+
+        for (network, leases) in &mut self.leases {
+            leases.retain(|ip, lease| {
+                let held = containers.iter().any(|container| {
+                    container.id == lease.container_id
+                        && container.networks.iter().any(|attachment| attachment.network == *network && attachment.ips.contains(ip))
+                });
+                if !held {
+                    eprintln!("Warning: Releasing stale lease of {} on network {} to container {}", ip, network, lease.container_id);
+                    changed.push(network.clone());
+                }
+
+                held
+            });
+        }
+
+        for container in containers {
+            for attachment in &container.networks {
+                let Some(network) = networks.get(&attachment.network) else {
+                    eprintln!("Warning: Container {} is connected to unknown network {}", container.id, attachment.network);
+                    continue;
+                };
+                let leases = self.leases.entry(network.name.clone()).or_default();
+
+                for ip in &attachment.ips {
+                    match leases.get(ip) {
+                        Some(lease) if lease.container_id == container.id => {}
+                        Some(lease) => eprintln!(
+                            "Warning: Address {} of container {} on network {} is leased to container {}",
+                            ip, container.id, network.name, lease.container_id
+                        ),
+                        None => match check_usable(network, *ip) {
+                            Ok(()) => {
+                                leases.insert(*ip, Lease {
+                                    container_id: container.id.clone(),
+                                    ip: *ip,
+                                    requested: container.config.network.ips.contains(ip),
+                                    leased_at: Utc::now(),
+                                });
+                                changed.push(network.name.clone());
+                            }
+                            Err(e) => eprintln!("Warning: Container {}: {}", container.id, e),
+                        },
+                    }
+                }
+            }
+        }
+
+        changed.sort();
+        changed.dedup();
+        for network in changed {
+            if let Err(e) = self.persist(&network) {
+                eprintln!("Warning: Failed to store address leases of network {}: {}", network, e);
+            }
+        }
+    }
+
+    /// Leases the container an address on each of the network's subnets: the one of
+    /// `requested` in that subnet, or else the lowest free address outside the network's
+    /// reserved ranges.
+    pub fn allocate(&mut self, network: &Network, container_id: &str, requested: &[IpAddr]) -> Result<Vec<IpAddr>> {
+        let mut leased = Vec::new();
+
+        for (subnet, prefix) in network.subnets() {
+            let ip = match requested.iter().find(|ip| masked(**ip, prefix) == subnet) {
+                Some(ip) => {
+                    check_usable(network, *ip).map_err(TurbineError::NetworkError)?;
+                    if let Some(holder) = self.holder(&network.name, *ip) {
+                        return Err(TurbineError::NetworkExists(format!(
+                            "Address {} on network {} is already in use by container {}", ip, network.name, holder
+                        )));
+                    }
+
+                    *ip
+                }
+                None => self.free_address(network, subnet, prefix).ok_or_else(|| TurbineError::NetworkError(format!(
+                    "No available addresses in {}/{} of network {}", subnet, prefix, network.name
+                )))?,
+            };
+
+            leased.push(Lease {
+                container_id: container_id.to_string(),
+                ip,
+                requested: requested.contains(&ip),
+                leased_at: Utc::now(),
+            });
+        }
+
+        let ips = leased.iter().map(|lease| lease.ip).collect();
+        let leases = self.leases.entry(network.name.clone()).or_default();
+
+        for lease in leased {
+            leases.insert(lease.ip, lease);
+        }
+
+        if let Err(e) = self.persist(&network.name) {
+            self.release(&network.name, container_id);
+            return Err(e);
+        }
+
+        Ok(ips)
+    }
+
+    /// Releases the container's addresses on `network`.
+    pub fn release(&mut self, network: &str, container_id: &str) {
+        let Some(leases) = self.leases.get_mut(network) else {
+            return;
+        };
+        let count = leases.len();
+
+        leases.retain(|_, lease| lease.container_id != container_id);
+        if leases.len() != count {
+            if let Err(e) = self.persist(network) {
+                eprintln!("Warning: Failed to store address leases of network {}: {}", network, e);
+            }
+        }
+    }
+
+    /// Releases the container's addresses on every network.
+    pub fn release_all(&mut self, container_id: &str) {
+        let networks: Vec<String> = self.leases.keys().cloned().collect();
+
+        for network in networks {
+            self.release(&network, container_id);
+        }
+    }
+
+    /// The container `ip` is leased to on `network`.
+    pub fn holder(&self, network: &str, ip: IpAddr) -> Option<&str> {
+        self.leases.get(network)?.get(&ip).map(|lease| lease.container_id.as_str())
+    }
+
+    pub fn leases(&self, network: &str) -> Vec<Lease> {
+        self.leases.get(network).map(|leases| leases.values().cloned().collect()).unwrap_or_default()
+    }
+
+    pub fn has_leases(&self, network: &str) -> bool {
+        self.leases.get(network).is_some_and(|leases| !leases.is_empty())
+    }
+
+    /// Forgets the leases of a removed network.
+    pub fn remove_network(&mut self, network: &str) -> Result<()> {
+        self.leases.remove(network);
+
+        match fs::remove_file(self.leases_path(network)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The lowest address of `subnet`/`prefix` that is usable, not a gateway, not
+    /// reserved and not leased.
+    fn free_address(&self, network: &Network, subnet: IpAddr, prefix: u8) -> Option<IpAddr> {
+        let range = usable_range(subnet, prefix);
+        let leases = self.leases.get(&network.name);
+        let gateways = network.gateways();
+        let mut candidate = range.start;
+
+        while range.contains(candidate) {
+            if let Some(reserved) = network.reserved.iter().find(|reserved| reserved.contains(candidate)) {
+                if bits(reserved.end).0 >= bits(range.end).0 {
+                    return None;
+                }
+
+                candidate = offset(reserved.end, 1);
+            } else if gateways.contains(&candidate) || leases.is_some_and(|leases| leases.contains_key(&candidate)) {
+                if candidate == range.end {
+                    return None;
+                }
+
+                candidate = offset(candidate, 1);
+            } else {
+                return Some(candidate);
+            }
+        }
+
+        None
+    }
+
+    fn persist(&self, network: &str) -> Result<()> {
+        let leases: Vec<&Lease> = self.leases.get(network).map(|leases| leases.values().collect()).unwrap_or_default();
+
+        write_atomic(&self.leases_path(network), &serde_json::to_vec_pretty(&leases)?)
+    }
+
+    fn leases_path(&self, network: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", network))
+    }
+}
+
+fn load_leases(path: &Path) -> Result<Vec<Lease>> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// The addresses of `subnet`/`prefix` containers can be given: every one but the
+/// subnet's own address and, for IPv4, its broadcast address.
+pub fn usable_range(subnet: IpAddr, prefix: u8) -> IpRange {
+    let last = broadcast(subnet, prefix);
+
+    IpRange {
+        start: offset(subnet, 1),
+        end: if subnet.is_ipv4() { from_bits(bits(last).0 - 1, last) } else { last },
+    }
+}
+
+/// Whether `ip` may be given to a container on `network`, explaining why not.
+/// Reserved ranges only keep addresses from being picked automatically.
+fn check_usable(network: &Network, ip: IpAddr) -> std::result::Result<(), String> {
+    let subnet = network.subnets().into_iter()
+        .find(|(subnet, prefix)| subnet.is_ipv4() == ip.is_ipv4() && masked(ip, *prefix) == *subnet);
+
+    match subnet {
+        None => Err(format!("Address {} is not in a subnet of network {}", ip, network.name)),
+        Some((subnet, prefix)) if !usable_range(subnet, prefix).contains(ip) => Err(format!(
+            "Address {} is not a host address of {}/{}", ip, subnet, prefix
+        )),
+        Some(_) if network.gateways().contains(&ip) => Err(format!(
+            "Address {} is the gateway of network {}", ip, network.name
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+fn bits(ip: IpAddr) -> (u128, u32) {
+    match ip {
+        IpAddr::V4(ip) => (u32::from(ip) as u128, 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    }
+}
+
+fn from_bits(value: u128, family: IpAddr) -> IpAddr {
+    match family {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(value as u32)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(value)),
+    }
+}
+
+/// `ip` with the bits past `prefix` cleared.
+pub(crate) fn masked(ip: IpAddr, prefix: u8) -> IpAddr {
+    let (value, width) = bits(ip);
+    let host_bits = width.saturating_sub(prefix as u32);
+    let mask = if host_bits >= 128 { 0 } else { !0u128 << host_bits };
+
+    from_bits(value & mask, ip)
+}
+
+/// The last address of `subnet`/`prefix`.
+pub(crate) fn broadcast(subnet: IpAddr, prefix: u8) -> IpAddr {
+    let (value, width) = bits(subnet);
+    let host_bits = width.saturating_sub(prefix as u32);
+    let host_mask = if host_bits >= 128 { !0u128 } else { (1u128 << host_bits) - 1 };
+
+    from_bits(value | host_mask, subnet)
+}
+
+/// The address `count` past `ip`.
+pub(crate) fn offset(ip: IpAddr, count: u128) -> IpAddr {
+    from_bits(bits(ip).0.wrapping_add(count), ip)
+}
+
+pub(crate) fn overlaps((a, a_prefix): (IpAddr, u8), (b, b_prefix): (IpAddr, u8)) -> bool {
+    let prefix = a_prefix.min(b_prefix);
+
+    a.is_ipv4() == b.is_ipv4() && masked(a, prefix) == masked(b, prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{NetworkAttachment, NetworkConfig};
+    use crate::ContainerConfig;
+
+    /// Leases kept in a fresh directory under the temporary directory.
+    struct TestIpam {
+        ipam: Ipam,
+        base_path: PathBuf,
+    }
+
+    impl TestIpam {
+        fn open() -> Self {
+            let base_path = std::env::temp_dir().join(format!("turbine-ipam-{}", uuid::Uuid::new_v4()));
+
+            Self { ipam: Ipam::open(&base_path).unwrap(), base_path }
+        }
+
+        fn reopen(&mut self) {
+            self.ipam = Ipam::open(&self.base_path).unwrap();
+        }
+    }
+
+    impl Drop for TestIpam {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base_path);
+        }
+    }
+
+    fn network(subnet: &str, reserved: &[&str]) -> Network {
+        let (address, prefix) = subnet.split_once('/').unwrap();
+        let (subnet, prefix): (Ipv4Addr, u8) = (address.parse().unwrap(), prefix.parse().unwrap());
+
+        Network {
+            name: "test".to_string(),
+            bridge: "turbine-test".to_string(),
+            subnet: NetworkConfig::IPv4 { subnet, prefix },
+            gateway_ipv4: (prefix < 31).then(|| Ipv4Addr::from(u32::from(subnet) + 1)),
+            gateway_ipv6: None,
+            mtu: None,
+            internal: false,
+            reserved: reserved.iter().map(|range| range.parse().unwrap()).collect(),
+            created_at: Utc::now(),
+        }
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn container(network: &Network, ips: &[&str]) -> Container {
+        let mut container = Container::new(ContainerConfig {
+            name: "web".to_string(),
+            image: "alpine".to_string(),
+            ..Default::default()
+        }).unwrap();
+
+        container.networks.push(NetworkAttachment {
+            network: network.name.clone(),
+            interface: "eth0".to_string(),
+            ips: ips.iter().map(|address| ip(address)).collect(),
+            aliases: Vec::new(),
+        });
+        container
+    }
+
+    #[test]
+    fn usable_range_excludes_network_and_broadcast_addresses() {
+        assert_eq!(usable_range(ip("10.0.0.0"), 24).to_string(), "10.0.0.1-10.0.0.254");
+        assert_eq!(usable_range(ip("10.0.0.0"), 30).to_string(), "10.0.0.1-10.0.0.2");
+        assert_eq!(usable_range(ip("fd00::"), 126).to_string(), "fd00::1-fd00::3");
+    }
+
+    #[test]
+    fn allocates_the_lowest_free_address_past_the_gateway() {
+        let mut test = TestIpam::open();
+        let network = network("10.0.0.0/24", &[]);
+
+        assert_eq!(test.ipam.allocate(&network, "a", &[]).unwrap(), [ip("10.0.0.2")]);
+        assert_eq!(test.ipam.allocate(&network, "b", &[]).unwrap(), [ip("10.0.0.3")]);
+
+        test.ipam.release(&network.name, "a");
+
+        assert_eq!(test.ipam.allocate(&network, "c", &[]).unwrap(), [ip("10.0.0.2")]);
+    }
+
+    #[test]
+    fn requested_addresses_must_be_host_addresses() {
+        let mut test = TestIpam::open();
+        let network = network("10.0.0.0/24", &[]);
+
+        for address in ["10.0.0.0", "10.0.0.255", "10.0.0.1"] {
+            assert!(test.ipam.allocate(&network, "a", &[ip(address)]).is_err(), "{} was leased", address);
+        }
+
+        assert_eq!(test.ipam.allocate(&network, "a", &[ip("10.0.0.254")]).unwrap(), [ip("10.0.0.254")]);
+        assert!(matches!(
+            test.ipam.allocate(&network, "b", &[ip("10.0.0.254")]),
+            Err(TurbineError::NetworkExists(_))
+        ));
+    }
+
+    #[test]
+    fn point_to_point_and_host_subnets_have_no_addresses() {
+        let mut test = TestIpam::open();
+
+        for subnet in ["10.0.0.0/31", "10.0.0.4/32"] {
+            let network = network(subnet, &[]);
+
+            assert!(test.ipam.allocate(&network, "a", &[]).is_err(), "{} had an address", subnet);
+            assert!(test.ipam.allocate(&network, "a", &[ip("10.0.0.4")]).is_err());
+        }
+
+        assert!(!test.ipam.has_leases("test"));
+    }
+
+    #[test]
+    fn reserved_ranges_are_skipped_but_may_be_requested() {
+        let mut test = TestIpam::open();
+        let network = network("10.0.0.0/24", &["10.0.0.2-10.0.0.9", "10.0.0.11"]);
+
+        assert_eq!(test.ipam.allocate(&network, "a", &[]).unwrap(), [ip("10.0.0.10")]);
+        assert_eq!(test.ipam.allocate(&network, "b", &[]).unwrap(), [ip("10.0.0.12")]);
+        assert_eq!(test.ipam.allocate(&network, "c", &[ip("10.0.0.5")]).unwrap(), [ip("10.0.0.5")]);
+
+        // A reservation reaching the end of the subnet leaves nothing past it.
+        let network = self::network("10.0.1.0/24", &["10.0.1.2-10.0.1.255"]);
+
+        assert!(test.ipam.allocate(&network, "d", &[]).is_err());
+    }
+
+    #[test]
+    fn exhausted_subnets_fail_until_an_address_is_released() {
+        let mut test = TestIpam::open();
+        let network = network("10.0.0.0/29", &[]);
+        let leased: Vec<IpAddr> = (0..5).flat_map(|i| test.ipam.allocate(&network, &i.to_string(), &[]).unwrap()).collect();
+
+        assert_eq!(leased.first(), Some(&ip("10.0.0.2")));
+        assert_eq!(leased.last(), Some(&ip("10.0.0.6")));
+
+        let error = test.ipam.allocate(&network, "full", &[]).unwrap_err();
+
+        assert!(error.to_string().contains("No available addresses"), "{}", error);
+
+        test.ipam.release(&network.name, "3");
+
+        assert_eq!(test.ipam.allocate(&network, "full", &[]).unwrap(), [ip("10.0.0.5")]);
+    }
+
+    #[test]
+    fn leases_survive_reopening() {
+        let mut test = TestIpam::open();
+        let network = network("10.0.0.0/24", &[]);
+        let leased = test.ipam.allocate(&network, "a", &[]).unwrap();
+
+        test.reopen();
+
+        assert_eq!(test.ipam.holder(&network.name, leased[0]), Some("a"));
+        assert_eq!(test.ipam.allocate(&network, "b", &[]).unwrap(), [ip("10.0.0.3")]);
+    }
+
+    #[test]
+    fn restore_keeps_persisted_leases_of_conflicting_addresses() {
+        let mut test = TestIpam::open();
+        let network = network("10.0.0.0/24", &[]);
+        let networks = BTreeMap::from([(network.name.clone(), network.clone())]);
+        let holder = container(&network, &["10.0.0.2"]);
+        let other = container(&network, &["10.0.0.2"]);
+        let stale = container(&network, &["10.0.0.3"]);
+
+        test.ipam.allocate(&network, &holder.id, &[ip("10.0.0.2")]).unwrap();
+        test.ipam.allocate(&network, &stale.id, &[]).unwrap();
+        test.reopen();
+        test.ipam.restore(&networks, &[&other, &holder]);
+
+        // The lease stays with its holder, the stale one is released.
+        assert_eq!(test.ipam.holder(&network.name, ip("10.0.0.2")), Some(holder.id.as_str()));
+        assert_eq!(test.ipam.holder(&network.name, ip("10.0.0.3")), None);
+        assert!(test.ipam.allocate(&network, &other.id, &[ip("10.0.0.2")]).is_err());
+
+        test.reopen();
+
+        assert_eq!(test.ipam.leases(&network.name).len(), 1);
+    }
+
+    #[test]
+    fn restore_leases_unleased_addresses_and_drops_unknown_networks() {
+        let mut test = TestIpam::open();
+        let network = network("10.0.0.0/24", &[]);
+        let mut gone = network.clone();
+        let first = container(&network, &["10.0.0.7"]);
+        let second = container(&network, &["10.0.0.7"]);
+        let unusable = container(&network, &["10.0.0.1"]);
+
+        gone.name = "gone".to_string();
+        test.ipam.allocate(&gone, "a", &[]).unwrap();
+        test.ipam.restore(&BTreeMap::from([(network.name.clone(), network.clone())]), &[&first, &second, &unusable]);
+
+        assert!(!test.ipam.has_leases("gone"));
+        assert!(!test.base_path.join("state/ipam/gone.json").exists());
+        assert_eq!(test.ipam.holder(&network.name, ip("10.0.0.7")), Some(first.id.as_str()));
+        assert_eq!(test.ipam.holder(&network.name, ip("10.0.0.1")), None);
+    }
+}
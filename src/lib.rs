@@ -2,10 +2,29 @@ pub mod config;
 pub mod container;
 pub mod runtime;
 pub mod network;
+pub mod ipam;
+pub mod dns;
+pub mod proxy;
+pub mod firewall;
+pub mod netlink;
 pub mod filesystem;
+pub mod image;
+pub mod image_store;
 pub mod process;
+pub mod logs;
+pub mod log_drivers;
+pub mod namespace;
+pub mod cgroup;
 pub mod security;
+pub mod state;
 pub mod error;
+pub mod api;
+pub mod daemon;
+pub mod client;
+pub mod console;
+pub mod attach;
+pub mod events;
+pub mod health;
 
 pub use config::*;
 pub use container::*;
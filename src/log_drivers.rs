@@ -0,0 +1,469 @@
+use crate::logs::{LogDriver, LogEntry, LogStream};
+use crate::{Container, TurbineError, Result};
+use chrono::SecondsFormat;
+use serde::Serialize;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Datagram socket journald reads native protocol messages from.
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Bounds how long a slow or unreachable TCP sink can hold up a container's output.
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long output is dropped after connecting to a TCP sink failed, doubling with
+/// each failure in a row up to `TCP_RECONNECT_MAX`.
+const TCP_RECONNECT_BASE: Duration = Duration::from_secs(1);
+
+const TCP_RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+/// Syslog facility names, indexed by facility code.
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news",
+    "uucp", "cron", "authpriv", "ftp", "ntp", "security", "console", "solaris-cron",
+    "local0", "local1", "local2", "local3", "local4", "local5", "local6", "local7",
+];
+
+pub fn facility_code(name: &str) -> Option<u8> {
+    FACILITIES.iter().position(|facility| *facility == name).map(|code| code as u8)
+}
+
+/// Syslog severity of a stream: informational for stdout, error for stderr.
+fn severity(stream: LogStream) -> u8 {
+    match stream {
+        LogStream::Stdout => 6,
+        LogStream::Stderr => 3,
+    }
+}
+
+enum DatagramSocket {
+    /// Reconnected when sending fails, as a restarted daemon binds a new socket at
+    /// the same path.
+    Unix { path: PathBuf, socket: UnixDatagram },
+    Udp(UdpSocket),
+}
+
+impl DatagramSocket {
+    fn unix(path: &Path) -> std::io::Result<Self> {
+        Ok(DatagramSocket::Unix { path: path.to_path_buf(), socket: connect_unix(path)? })
+    }
+
+    fn send(&mut self, message: &[u8]) -> std::io::Result<usize> {
+        match self {
+            DatagramSocket::Unix { path, socket } => match socket.send(message) {
+                Ok(length) => Ok(length),
+                Err(_) => {
+                    *socket = connect_unix(path)?;
+                    socket.send(message)
+                }
+            },
+            DatagramSocket::Udp(socket) => socket.send(message),
+        }
+    }
+}
+
+fn connect_unix(path: &Path) -> std::io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+/// Sends each entry as an RFC 5424 message to a syslog daemon.
+pub struct SyslogDriver {
+    socket: DatagramSocket,
+    facility: u8,
+    hostname: String,
+    tag: String,
+}
+
+impl SyslogDriver {
+    /// Connects to `unix:///path` (a datagram socket such as `/dev/log`) or `udp://host:port`.
+    pub fn connect(address: &str, facility: &str, tag: String) -> Result<Self> {
+        let facility = facility_code(facility)
+            .ok_or_else(|| TurbineError::LogError(format!("Unknown syslog facility: {}", facility)))?;
+        let socket = if let Some(path) = address.strip_prefix("unix://") {
+            DatagramSocket::unix(Path::new(path))
+                .map_err(|e| TurbineError::LogError(format!("Failed to connect to syslog at {}: {}", path, e)))?
+        } else if let Some(target) = address.strip_prefix("udp://") {
+            let target = target.to_socket_addrs()
+                .map_err(|e| TurbineError::LogError(format!("Invalid syslog address {}: {}", target, e)))?
+                .next()
+                .ok_or_else(|| TurbineError::LogError(format!("Syslog address {} did not resolve", target)))?;
+            let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+
+            socket.connect(target)?;
+            DatagramSocket::Udp(socket)
+        } else {
+            return Err(TurbineError::LogError(
+                format!("Syslog address must be unix:///path or udp://host:port: {}", address)
+            ));
+        };
+        let hostname = nix::unistd::gethostname()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "-".to_string());
+
+        Ok(Self {
+            socket,
+            facility,
+            hostname,
+            tag: header_field(&tag, 48),
+        })
+    }
+}
+
+impl LogDriver for SyslogDriver {
+    fn log(&mut self, entry: &LogEntry) -> Result<()> {
+        let message = format!(
+            "<{}>1 {} {} {} - - - {}",
+            self.facility * 8 + severity(entry.stream),
+            entry.time.to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            self.tag,
+            entry.log.trim_end_matches('\n'),
+        );
+
+        self.socket.send(message.as_bytes())
+            .map_err(|e| TurbineError::LogError(format!("Failed to send to syslog: {}", e)))?;
+
+        Ok(())
+    }
+}
+
+/// Makes `value` a valid RFC 5424 header field: printable ASCII without spaces, at
+/// most `max_len` characters, `-` when empty.
+fn header_field(value: &str, max_len: usize) -> String {
+    let field: String = value.chars()
+        .map(|c| if c.is_ascii_graphic() { c } else { '_' })
+        .take(max_len)
+        .collect();
+
+    if field.is_empty() { "-".to_string() } else { field }
+}
+
+/// Sends each entry to journald using its native protocol, tagged with the container's
+/// ID and name.
+pub struct JournaldDriver {
+    socket: DatagramSocket,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl JournaldDriver {
+    pub fn connect(container: &Container, tag: String) -> Result<Self> {
+        Self::connect_to(Path::new(JOURNALD_SOCKET), container, tag)
+    }
+
+    fn connect_to(path: &Path, container: &Container, tag: String) -> Result<Self> {
+        let socket = DatagramSocket::unix(path)
+            .map_err(|e| TurbineError::LogError(format!("Failed to connect to journald at {}: {}", path.display(), e)))?;
+
+        Ok(Self {
+            socket,
+            fields: vec![
+                ("SYSLOG_IDENTIFIER", tag),
+                ("CONTAINER_ID", container.id.chars().take(12).collect()),
+                ("CONTAINER_ID_FULL", container.id.clone()),
+                ("CONTAINER_NAME", container.config.name.clone()),
+            ],
+        })
+    }
+}
+
+impl LogDriver for JournaldDriver {
+    fn log(&mut self, entry: &LogEntry) -> Result<()> {
+        let mut message = Vec::new();
+
+        append_journal_field(&mut message, "MESSAGE", entry.log.trim_end_matches('\n'));
+        append_journal_field(&mut message, "PRIORITY", &severity(entry.stream).to_string());
+        append_journal_field(
+            &mut message, "SYSLOG_TIMESTAMP", &entry.time.to_rfc3339_opts(SecondsFormat::Micros, true)
+        );
+        for (key, value) in &self.fields {
+            append_journal_field(&mut message, key, value);
+        }
+
+        self.socket.send(&message)
+            .map_err(|e| TurbineError::LogError(format!("Failed to send to journald: {}", e)))?;
+
+        Ok(())
+    }
+}
+
+/// Appends `KEY=value`, or for values containing newlines the length-prefixed
+/// binary form of the native protocol.
+fn append_journal_field(message: &mut Vec<u8>, key: &str, value: &str) {
+    message.extend_from_slice(key.as_bytes());
+
+    if value.contains('\n') {
+        message.push(b'\n');
+        message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        message.push(b'=');
+    }
+
+    message.extend_from_slice(value.as_bytes());
+    message.push(b'\n');
+}
+
+/// Sends each entry as a line of JSON over TCP, reconnecting when the connection drops.
+/// While the sink cannot be reached, output is dropped between attempts to reconnect.
+pub struct TcpDriver {
+    address: String,
+    stream: Option<TcpStream>,
+    /// When to try connecting again after connecting failed.
+    reconnect_at: Option<Instant>,
+    reconnect_delay: Duration,
+    container_id: String,
+    container_name: String,
+}
+
+#[derive(Serialize)]
+struct TcpRecord<'a> {
+    container_id: &'a str,
+    container_name: &'a str,
+    #[serde(flatten)]
+    entry: &'a LogEntry,
+}
+
+impl TcpDriver {
+    /// Creates the driver without connecting; the connection is made on the first entry.
+    pub fn new(container: &Container, address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            stream: None,
+            reconnect_at: None,
+            reconnect_delay: TCP_RECONNECT_BASE,
+            container_id: container.id.clone(),
+            container_name: container.config.name.clone(),
+        }
+    }
+
+    /// Connects unless an attempt failed too recently, backing off further if this one
+    /// fails too.
+    fn reconnect(&mut self) -> Result<TcpStream> {
+        if let Some(reconnect_at) = self.reconnect_at.filter(|at| Instant::now() < *at) {
+            return Err(TurbineError::LogError(format!(
+                "Log sink at {} is unreachable; retrying in {}s",
+                self.address, reconnect_at.saturating_duration_since(Instant::now()).as_secs() + 1,
+            )));
+        }
+
+        match self.connect() {
+            Ok(stream) => {
+                self.reconnect_at = None;
+                self.reconnect_delay = TCP_RECONNECT_BASE;
+                Ok(stream)
+            }
+            Err(e) => {
+                self.reconnect_at = Some(Instant::now() + self.reconnect_delay);
+                self.reconnect_delay = (self.reconnect_delay * 2).min(TCP_RECONNECT_MAX);
+                Err(e)
+            }
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let failed = |e: std::io::Error| TurbineError::LogError(
+            format!("Failed to connect to log sink at {}: {}", self.address, e)
+        );
+        let target = self.address.to_socket_addrs().map_err(failed)?
+            .next()
+            .ok_or_else(|| TurbineError::LogError(format!("Log sink address {} did not resolve", self.address)))?;
+        let stream = TcpStream::connect_timeout(&target, TCP_TIMEOUT).map_err(failed)?;
+
+        stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+
+        Ok(stream)
+    }
+}
+
+impl LogDriver for TcpDriver {
+    fn log(&mut self, entry: &LogEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(&TcpRecord {
+            container_id: &self.container_id,
+            container_name: &self.container_name,
+            entry,
+        })?;
+
+        line.push(b'\n');
+
+        // A write to a connection the sink has closed may only fail on the next one, so
+        // a failed write gets one retry on a fresh connection.
+        for attempt in 0..2 {
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                None => {
+                    let stream = self.reconnect()?;
+                    self.stream.insert(stream)
+                }
+            };
+
+            match stream.write_all(&line) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    self.stream = None;
+                    if attempt == 1 {
+                        return Err(TurbineError::LogError(
+                            format!("Failed to write to log sink at {}: {}", self.address, e)
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ContainerConfig;
+    use chrono::Utc;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    fn container() -> Container {
+        Container::new(ContainerConfig {
+            name: "web".to_string(),
+            image: "alpine".to_string(),
+            ..Default::default()
+        }).unwrap()
+    }
+
+    fn entry(log: &str, stream: LogStream) -> LogEntry {
+        LogEntry { log: log.to_string(), stream, time: Utc::now() }
+    }
+
+    /// A socket path in a fresh directory under the temporary directory.
+    fn socket_path() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("turbine-log-{}", uuid::Uuid::new_v4()));
+
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("socket")
+    }
+
+    fn receive(socket: &UnixDatagram) -> Vec<u8> {
+        let mut buffer = [0u8; 4096];
+
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let length = socket.recv(&mut buffer).unwrap();
+        buffer[..length].to_vec()
+    }
+
+    #[test]
+    fn syslog_sends_rfc5424_over_udp() {
+        let sink = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = format!("udp://{}", sink.local_addr().unwrap());
+        let mut driver = SyslogDriver::connect(&address, "local0", "web app".to_string()).unwrap();
+        let mut buffer = [0u8; 4096];
+
+        driver.log(&entry("failed\n", LogStream::Stderr)).unwrap();
+        sink.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let length = sink.recv(&mut buffer).unwrap();
+        let message = String::from_utf8_lossy(&buffer[..length]);
+
+        // local0 (16) * 8 + error (3).
+        assert!(message.starts_with("<131>1 "), "{}", message);
+        assert!(message.ends_with(" web_app - - - failed"), "{}", message);
+    }
+
+    #[test]
+    fn syslog_reconnects_to_restarted_daemon() {
+        let path = socket_path();
+        let sink = UnixDatagram::bind(&path).unwrap();
+        let mut driver = SyslogDriver::connect(&format!("unix://{}", path.display()), "user", "web".to_string()).unwrap();
+
+        driver.log(&entry("first\n", LogStream::Stdout)).unwrap();
+        assert!(String::from_utf8(receive(&sink)).unwrap().starts_with("<14>1 "));
+
+        drop(sink);
+        std::fs::remove_file(&path).unwrap();
+        let sink = UnixDatagram::bind(&path).unwrap();
+
+        driver.log(&entry("second\n", LogStream::Stdout)).unwrap();
+        assert!(String::from_utf8(receive(&sink)).unwrap().ends_with(" web - - - second"));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn syslog_rejects_unknown_addresses_and_facilities() {
+        assert!(SyslogDriver::connect("tcp://127.0.0.1:514", "user", String::new()).is_err());
+        assert!(SyslogDriver::connect("udp://127.0.0.1:514", "nope", String::new()).is_err());
+    }
+
+    #[test]
+    fn journald_sends_native_fields() {
+        let path = socket_path();
+        let sink = UnixDatagram::bind(&path).unwrap();
+        let container = container();
+        let mut driver = JournaldDriver::connect_to(&path, &container, "web".to_string()).unwrap();
+
+        driver.log(&entry("two\nlines\n", LogStream::Stdout)).unwrap();
+        let message = receive(&sink);
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\nPRIORITY=6\n");
+        assert!(message.starts_with(&expected));
+
+        let text = String::from_utf8_lossy(&message);
+        assert!(text.contains("\nSYSLOG_IDENTIFIER=web\n"));
+        assert!(text.contains(&format!("\nCONTAINER_ID={}\n", &container.id[..12])));
+        assert!(text.contains(&format!("\nCONTAINER_ID_FULL={}\n", container.id)));
+        assert!(text.ends_with("\nCONTAINER_NAME=web\n"));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn tcp_sends_json_lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = container();
+        let mut driver = TcpDriver::new(&container, &listener.local_addr().unwrap().to_string());
+
+        driver.log(&entry("hello\n", LogStream::Stdout)).unwrap();
+        driver.log(&entry("oops\n", LogStream::Stderr)).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let records: Vec<serde_json::Value> = BufReader::new(stream).lines()
+            .take(2)
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+
+        assert_eq!(records[0]["container_id"], container.id.as_str());
+        assert_eq!(records[0]["container_name"], "web");
+        assert_eq!(records[0]["log"], "hello\n");
+        assert_eq!(records[0]["stream"], "stdout");
+        assert_eq!(records[1]["log"], "oops\n");
+        assert_eq!(records[1]["stream"], "stderr");
+    }
+
+    #[test]
+    fn tcp_drops_output_while_backing_off() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut driver = TcpDriver::new(&container(), &address.to_string());
+
+        drop(listener);
+        assert!(driver.log(&entry("lost\n", LogStream::Stdout)).is_err());
+
+        // The sink is back, but the driver does not try again before its delay is up.
+        let listener = TcpListener::bind(address).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        assert!(driver.log(&entry("dropped\n", LogStream::Stdout)).is_err());
+        assert!(listener.accept().is_err());
+        assert_eq!(driver.reconnect_delay, TCP_RECONNECT_BASE * 2);
+
+        driver.reconnect_at = Some(Instant::now());
+        driver.log(&entry("delivered\n", LogStream::Stdout)).unwrap();
+        assert_eq!(driver.reconnect_delay, TCP_RECONNECT_BASE);
+
+        listener.set_nonblocking(false).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let line = BufReader::new(stream).lines().next().unwrap().unwrap();
+        assert!(line.contains("\"log\":\"delivered\\n\""), "{}", line);
+    }
+}
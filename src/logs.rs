@@ -0,0 +1,491 @@
+use crate::attach::ContainerStreams;
+use crate::log_drivers::{JournaldDriver, SyslogDriver, TcpDriver};
+use crate::{Container, LogDriverConfig, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Active log file in a container's log directory. Rotated files get a `.1`, `.2`, ...
+/// suffix, `.1` being the most recent.
+pub const LOG_FILE: &str = "container-json.log";
+
+/// Longest chunk of output stored as a single entry; longer lines are split.
+const MAX_ENTRY_BYTES: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+/// One line of container output, stored as a JSON object per line of the log file.
+/// `log` keeps the trailing newline, if the line had one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub log: String,
+    pub stream: LogStream,
+    pub time: DateTime<Utc>,
+}
+
+/// Selects the entries returned by `TurbineRuntime::get_container_logs`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogQuery {
+    /// Keep streaming new entries until the container stops.
+    pub follow: bool,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Only return the last `tail` entries already in the log.
+    pub tail: Option<usize>,
+}
+
+impl LogQuery {
+    fn matches(&self, entry: &LogEntry) -> bool {
+        self.since.is_none_or(|since| entry.time >= since)
+            && self.until.is_none_or(|until| entry.time <= until)
+    }
+}
+
+/// Destination for a container's output, chosen by its `log_driver`.
+pub trait LogDriver: Send {
+    fn log(&mut self, entry: &LogEntry) -> Result<()>;
+}
+
+/// Opens the log driver configured for `container`. json-file logs are kept in `dir`.
+pub fn open_driver(container: &Container, dir: &Path) -> Result<Box<dyn LogDriver>> {
+    let default_tag = || container.id.chars().take(12).collect::<String>();
+
+    Ok(match &container.config.log_driver {
+        LogDriverConfig::JsonFile { max_size_kb, max_files } => {
+            Box::new(JsonFileLogger::open(dir, *max_size_kb, *max_files)?)
+        }
+        LogDriverConfig::Syslog { address, facility, tag } => {
+            Box::new(SyslogDriver::connect(address, facility, tag.clone().unwrap_or_else(default_tag))?)
+        }
+        LogDriverConfig::Journald { tag } => {
+            Box::new(JournaldDriver::connect(container, tag.clone().unwrap_or_else(default_tag))?)
+        }
+        LogDriverConfig::Tcp { address } => Box::new(TcpDriver::new(container, address)),
+    })
+}
+
+/// Appends entries to a container's json-file log, rotating it once it reaches
+/// `max_size_kb`.
+pub struct JsonFileLogger {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl JsonFileLogger {
+    pub fn open(dir: &Path, max_size_kb: u64, max_files: u32) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join(LOG_FILE);
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size: max_size_kb * 1024,
+            max_files: max_files.max(1),
+        })
+    }
+
+    /// Shifts every rotated file up one index, dropping the oldest, and starts a new
+    /// active file. With a single file the active one is truncated instead.
+    fn rotate(&mut self) -> Result<()> {
+        if self.max_files > 1 {
+            for index in (1..self.max_files - 1).rev() {
+                let from = rotated_path(&self.path, index);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, index + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+            self.file = open_append(&self.path)?;
+        } else {
+            self.file.set_len(0)?;
+        }
+
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl LogDriver for JsonFileLogger {
+    fn log(&mut self, entry: &LogEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+
+        line.push(b'\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+}
+
+/// Drains a container's stdout and stderr into `driver` on background threads, until
+/// every process holding them has exited. Output is also handed to `streams` as it is
+/// read, for attached clients.
+pub fn collect(driver: Box<dyn LogDriver>, stdout: File, stderr: File, streams: Arc<ContainerStreams>) -> Result<()> {
+    let driver = Arc::new(Mutex::new(driver));
+
+    for (stream, pipe) in [(LogStream::Stdout, stdout), (LogStream::Stderr, stderr)] {
+        let driver = Arc::clone(&driver);
+        let streams = Arc::clone(&streams);
+
+        std::thread::Builder::new()
+            .name(format!("log-{:?}", stream).to_lowercase())
+            .spawn(move || {
+                drain(pipe, stream, &driver, &streams);
+                streams.close_stream();
+            })?;
+    }
+
+    Ok(())
+}
+
+fn drain(mut pipe: File, stream: LogStream, driver: &Mutex<Box<dyn LogDriver>>, streams: &ContainerStreams) {
+    let mut buffer = [0u8; 8192];
+    let mut pending = Vec::new();
+    // Only the first of a run of failures is reported, so an unreachable log
+    // destination does not flood the runtime's own output.
+    let mut failing = false;
+    let mut log = |line: &[u8]| {
+        let entry = LogEntry {
+            log: String::from_utf8_lossy(line).into_owned(),
+            stream,
+            time: Utc::now(),
+        };
+        let mut driver = driver.lock().unwrap_or_else(|e| e.into_inner());
+
+        match driver.log(&entry) {
+            Ok(()) => failing = false,
+            Err(e) if !failing => {
+                eprintln!("Warning: Failed to write container log, dropping output: {}", e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    };
+
+    loop {
+        let length = match pipe.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(length) => length,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+
+        streams.publish(stream, &buffer[..length]);
+        pending.extend_from_slice(&buffer[..length]);
+
+        while let Some(end) = entry_end(&pending) {
+            log(&pending[..end]);
+            pending.drain(..end);
+        }
+    }
+
+    if !pending.is_empty() {
+        log(&pending);
+    }
+}
+
+/// Length of the first entry in `pending`: a complete line, or `MAX_ENTRY_BYTES` of a
+/// longer one.
+fn entry_end(pending: &[u8]) -> Option<usize> {
+    pending.iter()
+        .take(MAX_ENTRY_BYTES)
+        .position(|&byte| byte == b'\n')
+        .map(|newline| newline + 1)
+        .or((pending.len() >= MAX_ENTRY_BYTES).then_some(MAX_ENTRY_BYTES))
+}
+
+/// Reads the entries in the log directory `dir` matching `query`.
+pub fn read_logs(dir: &Path, query: &LogQuery) -> Result<Vec<LogEntry>> {
+    Ok(LogFollower::open(dir, query)?.0)
+}
+
+/// Picks up entries appended to a container's log after its existing entries were
+/// read, following the active file across rotations.
+pub struct LogFollower {
+    path: PathBuf,
+    file: Option<File>,
+    offset: u64,
+    query: LogQuery,
+}
+
+impl LogFollower {
+    /// Reads the existing entries matching `query`, oldest first, and returns them with
+    /// a follower positioned right after them.
+    pub fn open(dir: &Path, query: &LogQuery) -> Result<(Vec<LogEntry>, Self)> {
+        let path = dir.join(LOG_FILE);
+        let rotated: Vec<PathBuf> = (1..)
+            .map(|index| rotated_path(&path, index))
+            .take_while(|path| path.exists())
+            .collect();
+        // Open everything before reading so a rotation in between cannot skip entries.
+        let mut files: Vec<File> = rotated.iter().rev().filter_map(|path| File::open(path).ok()).collect();
+        let mut follower = Self {
+            file: open_existing(&path)?,
+            path,
+            offset: 0,
+            query: query.clone(),
+        };
+        let mut entries = Vec::new();
+
+        for file in &mut files {
+            let mut buffer = Vec::new();
+
+            file.read_to_end(&mut buffer)?;
+            entries.extend(parse_entries(&buffer, query));
+        }
+
+        entries.extend(follower.read_active()?);
+
+        if let Some(tail) = query.tail {
+            entries.drain(..entries.len().saturating_sub(tail));
+        }
+
+        Ok((entries, follower))
+    }
+
+    /// Returns the matching entries written since the last call.
+    pub fn poll(&mut self) -> Result<Vec<LogEntry>> {
+        let mut entries = self.read_active()?;
+        let Some(current) = open_existing(&self.path)? else {
+            return Ok(entries);
+        };
+        let rotated = match &self.file {
+            Some(file) => file.metadata()?.ino() != current.metadata()?.ino(),
+            None => true,
+        };
+
+        if rotated {
+            self.file = Some(current);
+            self.offset = 0;
+            entries.extend(self.read_active()?);
+        }
+
+        Ok(entries)
+    }
+
+    /// Reads the complete lines past `offset` in the file being followed.
+    fn read_active(&mut self) -> Result<Vec<LogEntry>> {
+        let Some(file) = &mut self.file else {
+            return Ok(Vec::new());
+        };
+
+        if file.metadata()?.len() < self.offset {
+            // Truncated in place by a single-file rotation.
+            self.offset = 0;
+        }
+
+        let mut buffer = Vec::new();
+
+        file.seek(SeekFrom::Start(self.offset))?;
+        file.read_to_end(&mut buffer)?;
+
+        let complete = buffer.iter().rposition(|&byte| byte == b'\n').map_or(0, |end| end + 1);
+
+        self.offset += complete as u64;
+
+        Ok(parse_entries(&buffer[..complete], &self.query))
+    }
+}
+
+fn parse_entries(buffer: &[u8], query: &LogQuery) -> Vec<LogEntry> {
+    buffer
+        .split(|&byte| byte == b'\n')
+        .filter_map(|line| serde_json::from_slice::<LogEntry>(line).ok())
+        .filter(|entry| query.matches(entry))
+        .collect()
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+fn open_existing(path: &Path) -> Result<Option<File>> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    /// A log directory under the temporary directory, removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("turbine-logs-{}", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn start() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap()
+    }
+
+    /// Entry `index`, written `index` seconds after `start`, of about 200 bytes.
+    fn entry(index: usize) -> LogEntry {
+        LogEntry {
+            log: format!("line {:03} {}\n", index, "x".repeat(120)),
+            stream: if index.is_multiple_of(2) { LogStream::Stdout } else { LogStream::Stderr },
+            time: start() + TimeDelta::seconds(index as i64),
+        }
+    }
+
+    fn write(logger: &mut JsonFileLogger, entries: std::ops::Range<usize>) {
+        for index in entries {
+            logger.log(&entry(index)).unwrap();
+        }
+    }
+
+    fn indexes(entries: &[LogEntry]) -> Vec<usize> {
+        entries.iter()
+            .map(|entry| entry.log[5..8].parse().unwrap())
+            .collect()
+    }
+
+    fn read(dir: &Path, query: LogQuery) -> Vec<usize> {
+        indexes(&read_logs(dir, &query).unwrap())
+    }
+
+    #[test]
+    fn rotates_at_the_size_limit_keeping_max_files() {
+        let dir = TestDir::new();
+        let mut logger = JsonFileLogger::open(&dir.0, 1, 3).unwrap();
+
+        write(&mut logger, 0..40);
+
+        let path = dir.0.join(LOG_FILE);
+        for file in [path.clone(), rotated_path(&path, 1), rotated_path(&path, 2)] {
+            assert!(fs::metadata(&file).unwrap().len() <= 1024, "{:?} is over the limit", file);
+        }
+        assert!(!rotated_path(&path, 3).exists());
+
+        // The oldest entries are gone, the rest are in order without gaps.
+        let kept = read(&dir.0, LogQuery::default());
+        assert_eq!(kept.last(), Some(&39));
+        assert!(kept.len() > 8 && kept.len() < 40);
+        assert!(kept.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    }
+
+    #[test]
+    fn a_single_file_is_truncated() {
+        let dir = TestDir::new();
+        let mut logger = JsonFileLogger::open(&dir.0, 1, 1).unwrap();
+
+        write(&mut logger, 0..12);
+
+        assert!(!rotated_path(&dir.0.join(LOG_FILE), 1).exists());
+
+        let kept = read(&dir.0, LogQuery::default());
+        assert_eq!(kept.last(), Some(&11));
+        assert!(kept.len() < 12);
+    }
+
+    #[test]
+    fn reopening_continues_the_active_file() {
+        let dir = TestDir::new();
+
+        write(&mut JsonFileLogger::open(&dir.0, 1, 2).unwrap(), 0..3);
+        write(&mut JsonFileLogger::open(&dir.0, 1, 2).unwrap(), 3..6);
+
+        assert!(fs::metadata(dir.0.join(LOG_FILE)).unwrap().len() <= 1024);
+        assert_eq!(read(&dir.0, LogQuery::default()).last(), Some(&5));
+    }
+
+    #[test]
+    fn tail_spans_rotated_files() {
+        let dir = TestDir::new();
+        let mut logger = JsonFileLogger::open(&dir.0, 1, 5).unwrap();
+
+        write(&mut logger, 0..12);
+
+        let tail = |tail| read(&dir.0, LogQuery { tail: Some(tail), ..Default::default() });
+
+        assert!(rotated_path(&dir.0.join(LOG_FILE), 2).exists());
+        assert_eq!(tail(7), (5..12).collect::<Vec<_>>());
+        assert_eq!(tail(0), Vec::<usize>::new());
+        assert_eq!(tail(100), (0..12).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn since_and_until_span_rotated_files() {
+        let dir = TestDir::new();
+        let mut logger = JsonFileLogger::open(&dir.0, 1, 5).unwrap();
+        let at = |index| Some(start() + TimeDelta::seconds(index));
+
+        write(&mut logger, 0..12);
+
+        assert_eq!(read(&dir.0, LogQuery { since: at(2), until: at(9), ..Default::default() }), (2..10).collect::<Vec<_>>());
+        assert_eq!(read(&dir.0, LogQuery { since: at(10), ..Default::default() }), [10, 11]);
+        assert_eq!(read(&dir.0, LogQuery { until: at(1), ..Default::default() }), [0, 1]);
+        // The tail is taken of the entries in the time range.
+        assert_eq!(read(&dir.0, LogQuery { until: at(5), tail: Some(2), ..Default::default() }), [4, 5]);
+    }
+
+    #[test]
+    fn follower_picks_up_entries_across_rotations() {
+        let dir = TestDir::new();
+        let mut logger = JsonFileLogger::open(&dir.0, 1, 5).unwrap();
+
+        write(&mut logger, 0..3);
+
+        let (existing, mut follower) = LogFollower::open(&dir.0, &LogQuery::default()).unwrap();
+        assert_eq!(indexes(&existing), [0, 1, 2]);
+
+        write(&mut logger, 3..5);
+        assert_eq!(indexes(&follower.poll().unwrap()), [3, 4]);
+        assert!(follower.poll().unwrap().is_empty());
+
+        // The followed file is rotated away once it is full.
+        write(&mut logger, 5..8);
+        assert!(rotated_path(&dir.0.join(LOG_FILE), 1).exists());
+        assert_eq!(indexes(&follower.poll().unwrap()), [5, 6, 7]);
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let line = vec![b'a'; MAX_ENTRY_BYTES + 10];
+
+        assert_eq!(entry_end(b"one\ntwo"), Some(4));
+        assert_eq!(entry_end(b"partial"), None);
+        assert_eq!(entry_end(&line), Some(MAX_ENTRY_BYTES));
+    }
+}
@@ -1,6 +1,20 @@
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::io::Write;
+use std::net::IpAddr;
 use std::path::PathBuf;
-use turbine::{ContainerConfig, TurbineRuntime, Result};
+use turbine::api::{self, Request, Response, ResponseSink, DEFAULT_SOCKET_PATH};
+use turbine::cgroup::DEFAULT_CGROUP_PARENT;
+use turbine::client::DaemonClient;
+use turbine::console::{parse_detach_keys, window_size, Console, DEFAULT_DETACH_KEYS};
+use turbine::events::{EventAction, EventQuery};
+use turbine::filesystem::ChangeKind;
+use turbine::health::HealthStatus;
+use turbine::ipam::IpRange;
+use turbine::logs::{LogQuery, LogStream};
+use turbine::network::{NetworkConfig, NetworkOptions};
+use turbine::process::ExecOptions;
+use turbine::{ContainerConfig, ContainerStats, PortMapping, PortMode, TurbineError, TurbineRuntime, Result};
 
 #[derive(Parser)]
 #[command(name = "turbine")]
@@ -11,6 +25,12 @@ struct Cli {
     command: Commands,
     #[arg(long, default_value = "/tmp/turbine")]
     base_path: PathBuf,
+    /// Control socket of turbined. Without a daemon listening, only commands that read state work
+    #[arg(long, default_value = DEFAULT_SOCKET_PATH)]
+    socket: PathBuf,
+    /// Cgroup, relative to the cgroup v2 root, that container cgroups are created under
+    #[arg(long, default_value = DEFAULT_CGROUP_PARENT)]
+    cgroup_parent: PathBuf,
 }
 
 #[derive(Subcommand)]
@@ -21,6 +41,26 @@ enum Commands {
         
         #[arg(short, long)]
         name: Option<String>,
+
+        /// Publish container ports as [host_ip:]host_port[-end]:container_port[-end][/tcp|udp|sctp]; may be repeated
+        #[arg(short, long = "publish")]
+        publish: Vec<PortMapping>,
+
+        /// Forward published ports with NAT rules (nat) or a proxy in turbined that also serves localhost (proxy)
+        #[arg(long)]
+        port_mode: Option<PortMode>,
+
+        /// Connect the container to a network; may be repeated
+        #[arg(long = "network")]
+        networks: Vec<String>,
+
+        /// Use this address on the network whose subnet contains it; may be repeated
+        #[arg(long = "ip")]
+        ips: Vec<IpAddr>,
+
+        /// Another name the container is found by on its networks; may be repeated
+        #[arg(long = "network-alias")]
+        aliases: Vec<String>,
     },
     Start {
         container_id: String,
@@ -43,13 +83,93 @@ enum Commands {
     List,
     Logs {
         container_id: String,
+
+        /// Keep printing new output until the container stops
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Only show output since a timestamp (RFC 3339 or Unix seconds) or a duration ago (e.g. 10m)
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Only show output until a timestamp or a duration ago
+        #[arg(long)]
+        until: Option<String>,
+
+        /// Only show the last N lines of existing output
+        #[arg(short = 'n', long)]
+        tail: Option<usize>,
+
+        /// Prefix each line with the time it was written
+        #[arg(short, long)]
+        timestamps: bool,
     },
     Exec {
+        /// Forward stdin to the process
+        #[arg(short, long)]
+        interactive: bool,
+
+        /// Allocate a pseudo-terminal for the process
+        #[arg(short, long)]
+        tty: bool,
+
+        /// User to run as, as user[:group] by name or ID
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Set an environment variable (KEY=VALUE); may be repeated
+        #[arg(short, long = "env")]
+        env: Vec<String>,
+
+        /// Working directory inside the container
+        #[arg(short, long)]
+        workdir: Option<String>,
+
         container_id: String,
+
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
         command: Vec<String>,
     },
+    Attach {
+        /// Don't forward stdin to the container
+        #[arg(long)]
+        no_stdin: bool,
+
+        /// Key sequence that detaches without stopping the container
+        #[arg(long, default_value = DEFAULT_DETACH_KEYS)]
+        detach_keys: String,
+
+        container_id: String,
+    },
+    Events {
+        /// Keep printing new events as they happen
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Only show events since a timestamp (RFC 3339 or Unix seconds) or a duration ago (e.g. 10m)
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Only show events until a timestamp or a duration ago
+        #[arg(long)]
+        until: Option<String>,
+
+        /// Only show matching events: container=<id, id prefix or name> or event=<name>; may be repeated
+        #[arg(long = "filter")]
+        filters: Vec<String>,
+
+        #[arg(long, value_enum, default_value_t = EventFormat::Text)]
+        format: EventFormat,
+    },
     Stats {
         container_id: String,
+
+        /// Keep sampling and redraw the stats until interrupted
+        #[arg(long)]
+        stream: bool,
+    },
+    Diff {
+        container_id: String,
     },
     Pause {
         container_id: String,
@@ -68,120 +188,636 @@ enum Commands {
         port: u16,
     },
     Cleanup,
+    Image {
+        #[command(subcommand)]
+        command: ImageCommands,
+    },
+    Network {
+        #[command(subcommand)]
+        command: NetworkCommands,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum EventFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Subcommand)]
+enum ImageCommands {
+    /// Import an OCI image layout directory or a tar archive of one
+    Import {
+        source: PathBuf,
+
+        #[arg(short, long)]
+        tag: Option<String>,
+    },
+    Ls,
+    Inspect {
+        reference: String,
+    },
+    Rm {
+        reference: String,
+    },
+    Prune,
+}
+
+#[derive(Subcommand)]
+enum NetworkCommands {
+    Create {
+        name: String,
+
+        /// Bridge device of the network; a free turbineN is picked by default
+        #[arg(long)]
+        bridge: Option<String>,
+
+        /// Subnet in CIDR notation, at most one IPv4 and one IPv6; a free IPv4 /16 is picked by default
+        #[arg(long = "subnet", value_parser = parse_subnet)]
+        subnets: Vec<(IpAddr, u8)>,
+
+        /// Gateway address of a subnet; defaults to its first address
+        #[arg(long = "gateway")]
+        gateways: Vec<IpAddr>,
+
+        #[arg(long)]
+        mtu: Option<u32>,
+
+        /// Keep the network's traffic off the host's other networks
+        #[arg(long)]
+        internal: bool,
+
+        /// Addresses, as start-end, an address or CIDR, not to give out unless asked for; may be repeated
+        #[arg(long = "reserve")]
+        reserved: Vec<IpRange>,
+    },
+    Ls,
+    Inspect {
+        name: String,
+    },
+    Rm {
+        name: String,
+    },
+    Connect {
+        network: String,
+        container_id: String,
+
+        /// Another name the container is found by on the network; may be repeated
+        #[arg(long = "alias")]
+        aliases: Vec<String>,
+    },
+    Disconnect {
+        network: String,
+        container_id: String,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-    let runtime = TurbineRuntime::new(&cli.base_path);
+    let client = DaemonClient::new(&cli.socket);
+    let runtime = if client.is_available().await {
+        None
+    } else {
+        Some(TurbineRuntime::new(&cli.base_path)?.with_cgroup_parent(&cli.cgroup_parent))
+    };
+    // Containers started in-process would run unsupervised once the CLI exits.
+    let local = |request: &Request| match &runtime {
+        Some(_) if !request.is_read_only() => Err(TurbineError::RuntimeError(format!(
+            "turbined is not running on {}; start it to manage containers", cli.socket.display()
+        ))),
+        runtime => Ok(runtime.as_ref()),
+    };
+    let call = |request: Request| async {
+        let response = match local(&request)? {
+            Some(runtime) => api::dispatch(runtime, request).await,
+            None => client.call(request).await?,
+        };
 
-    runtime.initialize().await?;
+        match response {
+            Response::Error(message) => Err(TurbineError::RuntimeError(message)),
+            response => Ok(response),
+        }
+    };
 
     match cli.command {
-        Commands::Create { config, name } => {
+        Commands::Create { config, name, publish, port_mode, networks, ips, aliases } => {
             let mut container_config = ContainerConfig::from_file(&config)?;
             if let Some(name) = name {
                 container_config.name = name;
             }
 
-            let container_id = runtime.create_container(container_config).await?;
-            println!("Container created: {}", container_id);
+            container_config.ports.extend(publish);
+            if let Some(port_mode) = port_mode {
+                container_config.port_mode = port_mode;
+            }
+            container_config.network.networks.extend(networks);
+            container_config.network.ips.extend(ips);
+            container_config.network.aliases.extend(aliases);
+
+            if let Response::ContainerId(container_id) = call(Request::Create { config: Box::new(container_config) }).await? {
+                println!("Container created: {}", container_id);
+            }
         }
 
         Commands::Start { container_id } => {
-            runtime.start_container(&container_id).await?;
+            call(Request::Start { container_id: container_id.clone() }).await?;
             println!("Container started: {}", container_id);
         }
 
         Commands::Stop { container_id, force } => {
-            runtime.stop_container(&container_id, force).await?;
+            call(Request::Stop { container_id: container_id.clone(), force }).await?;
             println!("Container stopped: {}", container_id);
         }
 
         Commands::Restart { container_id } => {
-            runtime.restart_container(&container_id).await?;
+            call(Request::Restart { container_id: container_id.clone() }).await?;
             println!("Container restarted: {}", container_id);
         }
 
         Commands::Remove { container_id, force } => {
-            runtime.remove_container(&container_id, force).await?;
+            call(Request::Remove { container_id: container_id.clone(), force }).await?;
             println!("Container removed: {}", container_id);
         }
 
         Commands::List => {
-            let containers = runtime.list_containers().await?;
+            let containers = match call(Request::List).await? {
+                Response::Containers(containers) => containers,
+                _ => Vec::new(),
+            };
             if containers.is_empty() {
                 println!("No containers found");
             } else {
-                println!("{:<12} {:<20} {:<15} {:<10}", "ID", "NAME", "IMAGE", "STATUS");
-                println!("{}", "-".repeat(60));
+                println!("{:<12} {:<20} {:<15} {:<20} {:<8}", "ID", "NAME", "IMAGE", "STATUS", "RESTARTS");
+                println!("{}", "-".repeat(79));
 
                 for container in containers {
                     let short_id = &container.id[..8];
-                    let status = format!("{:?}", container.state);
+                    let status = match &container.health {
+                        Some(health) => format!("{:?} ({})", container.state, health.status),
+                        None => format!("{:?}", container.state),
+                    };
 
-                    println!("{:<12} {:<20} {:<15} {:<10}", 
+                    println!("{:<12} {:<20} {:<15} {:<20} {:<8}", 
                         short_id, 
                         container.config.name, 
                         container.config.image,
-                        status
+                        status,
+                        container.restart_count
                     );
                 }
             }
         }
 
-        Commands::Logs { container_id } => {
-            let (stdout, stderr) = runtime.get_container_logs(&container_id).await?;            
-            if !stdout.is_empty() {
-                println!("STDOUT:");
-                println!("{}", stdout);
+        Commands::Logs { container_id, follow, since, until, tail, timestamps } => {
+            let query = LogQuery {
+                follow,
+                since: since.as_deref().map(parse_time).transpose()?,
+                until: until.as_deref().map(parse_time).transpose()?,
+                tail,
+            };
+            let request = Request::Logs { container_id, query };
+            let mut printer = LogPrinter { timestamps };
+
+            match local(&request)? {
+                Some(runtime) => api::dispatch_streaming(runtime, request, &mut printer).await?,
+                None => client.call_streaming(request, &mut printer).await?,
+            }
+        }
+
+        Commands::Exec { interactive, tty, user, env, workdir, container_id, command } => {
+            let options = ExecOptions {
+                tty,
+                interactive,
+                user,
+                env,
+                workdir,
+                window_size: if tty { window_size(std::io::stdout()) } else { None },
+            };
+            let request = Request::Exec { container_id, command, options };
+            let mut console = Console::new();
+
+            match local(&request)? {
+                Some(runtime) => api::dispatch_streaming(runtime, request, &mut console).await?,
+                None => client.call_streaming(request, &mut console).await?,
+            }
+
+            match console.exit_code() {
+                Some(0) => {}
+                Some(code) => std::process::exit(code),
+                None => return Err(TurbineError::RuntimeError("Exec ended without an exit code".to_string())),
             }
+        }
+
+        Commands::Attach { no_stdin, detach_keys, container_id } => {
+            let request = Request::Attach { container_id, stdin: !no_stdin };
+            let mut console = Console::new().with_detach_keys(parse_detach_keys(&detach_keys)?);
 
-            if !stderr.is_empty() {
-                println!("STDERR:");
-                println!("{}", stderr);
+            match local(&request)? {
+                Some(runtime) => api::dispatch_streaming(runtime, request, &mut console).await?,
+                None => client.call_streaming(request, &mut console).await?,
             }
         }
 
-        Commands::Exec { container_id, command } => {
-            let output = runtime.execute_in_container(&container_id, command).await?;
+        Commands::Events { mut follow, since, until, filters, format } => {
+            if follow && runtime.is_some() {
+                eprintln!("Warning: turbined is not running, so there are no live events to follow");
+                follow = false;
+            }
+
+            let mut query = EventQuery {
+                follow,
+                since: since.as_deref().map(parse_time).transpose()?,
+                until: until.as_deref().map(parse_time).transpose()?,
+                ..Default::default()
+            };
+
+            for filter in filters {
+                query.add_filter(&filter)?;
+            }
+
+            let request = Request::Events { query };
+            let mut printer = EventPrinter { format };
 
-            println!("{}", output);
+            match local(&request)? {
+                Some(runtime) => api::dispatch_streaming(runtime, request, &mut printer).await?,
+                None => client.call_streaming(request, &mut printer).await?,
+            }
         }
 
-        Commands::Stats { container_id } => {
-            let stats = runtime.get_container_stats(&container_id).await?;
+        Commands::Stats { container_id, stream } => loop {
+            let request = Request::Stats { container_id: container_id.clone() };
+            if let Response::Stats(stats) = call(request).await? {
+                if stream {
+                    print!("\x1b[2J\x1b[H");
+                }
+
+                print_stats(&stats);
+            }
+
+            if !stream {
+                break;
+            }
+        },
+
+        Commands::Diff { container_id } => {
+            if let Response::Changes(changes) = call(Request::Diff { container_id }).await? {
+                for change in changes {
+                    let marker = match change.kind {
+                        ChangeKind::Added => 'A',
+                        ChangeKind::Changed => 'C',
+                        ChangeKind::Deleted => 'D',
+                    };
 
-            println!("Container: {}", stats.container_id);
-            println!("Memory Usage: {} MB", stats.memory_usage / 1024 / 1024);
-            println!("CPU Usage: {:.2}%", stats.cpu_usage);
-            println!("Network RX: {} bytes", stats.network_rx);
-            println!("Network TX: {} bytes", stats.network_tx);
-            println!("Uptime: {} seconds", stats.uptime);
+                    println!("{} {}", marker, change.path.display());
+                }
+            }
         }
 
         Commands::Pause { container_id } => {
-            runtime.pause_container(&container_id).await?;
+            call(Request::Pause { container_id: container_id.clone() }).await?;
             println!("Container paused: {}", container_id);
         }
 
         Commands::Resume { container_id } => {
-            runtime.resume_container(&container_id).await?;
+            call(Request::Resume { container_id: container_id.clone() }).await?;
             println!("Container resumed: {}", container_id);
         }
 
         Commands::Deploy { name, image, port } => {
-            let container_id = runtime.deploy_web_app(name.clone(), image, port).await?;
+            if let Response::Container(container) = call(Request::Deploy { name: name.clone(), image, port }).await? {
+                println!("Web application '{}' deployed: {}", name, container.id);
 
-            println!("Web application '{}' deployed: {}", name, container_id);
-            println!("Access your application at: http://localhost:{}", port);
+                match container.health.as_ref().map(|health| health.status) {
+                    _ if !container.is_running() => {
+                        eprintln!("Warning: Application is not running ({:?}); check 'turbine logs {}'", container.state, container.id);
+                    }
+                    Some(HealthStatus::Unhealthy) => {
+                        eprintln!("Warning: Application is unhealthy; check 'turbine logs {}'", container.id);
+                    }
+                    Some(HealthStatus::Starting) => {
+                        println!("Application is still starting; it will be at: http://localhost:{}", port);
+                    }
+                    Some(HealthStatus::Healthy) | None => {
+                        println!("Access your application at: http://localhost:{}", port);
+                    }
+                }
+            }
         }
 
         Commands::Cleanup => {
-            runtime.cleanup().await?;
+            call(Request::Cleanup).await?;
             println!("Cleanup completed");
         }
+
+        Commands::Image { command } => match command {
+            ImageCommands::Import { source, tag } => {
+                let source = source.canonicalize()?;
+
+                if let Response::Images(images) = call(Request::ImageImport { source, tag }).await? {
+                    for image in images {
+                        println!("Imported: {} {}", image.digest, image.tags.join(", "));
+                    }
+                }
+            }
+
+            ImageCommands::Ls => {
+                let images = match call(Request::ImageList).await? {
+                    Response::Images(images) => images,
+                    _ => Vec::new(),
+                };
+                if images.is_empty() {
+                    println!("No images found");
+                } else {
+                    println!("{:<14} {:<30} {:<10} {:<10}", "DIGEST", "TAGS", "SIZE", "CONTAINERS");
+                    println!("{}", "-".repeat(66));
+
+                    for image in images {
+                        let short_digest = image.digest.trim_start_matches("sha256:");
+                        let tags = if image.tags.is_empty() { "<none>".to_string() } else { image.tags.join(",") };
+
+                        println!("{:<14} {:<30} {:<10} {:<10}",
+                            &short_digest[..12.min(short_digest.len())],
+                            tags,
+                            format!("{}MB", image.size / 1024 / 1024),
+                            image.containers.len()
+                        );
+                    }
+                }
+            }
+
+            ImageCommands::Inspect { reference } => {
+                if let Response::Image(details) = call(Request::ImageInspect { reference }).await? {
+                    println!("{}", serde_json::to_string_pretty(&details)?);
+                }
+            }
+
+            ImageCommands::Rm { reference } => {
+                if let Response::Messages(messages) = call(Request::ImageRemove { reference }).await? {
+                    for message in messages {
+                        println!("{}", message);
+                    }
+                }
+            }
+
+            ImageCommands::Prune => {
+                if let Response::Messages(messages) = call(Request::ImagePrune).await? {
+                    for message in messages {
+                        println!("{}", message);
+                    }
+                }
+            }
+        },
+
+        Commands::Network { command } => match command {
+            NetworkCommands::Create { name, bridge, subnets, gateways, mtu, internal, reserved } => {
+                let mut options = NetworkOptions {
+                    name,
+                    bridge,
+                    subnet: if subnets.is_empty() { None } else { Some(NetworkConfig::from_subnets(&subnets)?) },
+                    mtu,
+                    internal,
+                    reserved,
+                    ..Default::default()
+                };
+
+                for gateway in gateways {
+                    let duplicate = match gateway {
+                        IpAddr::V4(gateway) => options.gateway_ipv4.replace(gateway).is_some(),
+                        IpAddr::V6(gateway) => options.gateway_ipv6.replace(gateway).is_some(),
+                    };
+                    if duplicate {
+                        return Err(TurbineError::ConfigError("A network has at most one gateway of each family".to_string()));
+                    }
+                }
+
+                if let Response::Networks(networks) = call(Request::NetworkCreate { options }).await? {
+                    for network in networks {
+                        println!("Network created: {} ({})", network.name, network.bridge);
+                    }
+                }
+            }
+
+            NetworkCommands::Ls => {
+                let networks = match call(Request::NetworkList).await? {
+                    Response::Networks(networks) => networks,
+                    _ => Vec::new(),
+                };
+
+                println!("{:<20} {:<16} {:<40} {:<8}", "NAME", "BRIDGE", "SUBNETS", "INTERNAL");
+                println!("{}", "-".repeat(87));
+
+                for network in networks {
+                    let subnets = network.subnets()
+                        .iter()
+                        .map(|(subnet, prefix)| format!("{}/{}", subnet, prefix))
+                        .collect::<Vec<_>>()
+                        .join(",");
+
+                    println!("{:<20} {:<16} {:<40} {:<8}", network.name, network.bridge, subnets, network.internal);
+                }
+            }
+
+            NetworkCommands::Inspect { name } => {
+                if let Response::Network(details) = call(Request::NetworkInspect { name }).await? {
+                    println!("{}", serde_json::to_string_pretty(&details)?);
+                }
+            }
+
+            NetworkCommands::Rm { name } => {
+                call(Request::NetworkRemove { name: name.clone() }).await?;
+                println!("Network removed: {}", name);
+            }
+
+            NetworkCommands::Connect { network, container_id, aliases } => {
+                call(Request::NetworkConnect { network: network.clone(), container_id: container_id.clone(), aliases }).await?;
+                println!("Container {} connected to network {}", container_id, network);
+            }
+
+            NetworkCommands::Disconnect { network, container_id } => {
+                call(Request::NetworkDisconnect { network: network.clone(), container_id: container_id.clone() }).await?;
+                println!("Container {} disconnected from network {}", container_id, network);
+            }
+        },
     }
 
     Ok(())
 }
+
+/// Prints streamed log entries, each to the stream it was written to.
+struct LogPrinter {
+    timestamps: bool,
+}
+
+impl ResponseSink for LogPrinter {
+    async fn send(&mut self, response: Response) -> Result<()> {
+        let entries = match response {
+            Response::LogEntries(entries) => entries,
+            Response::Error(message) => return Err(TurbineError::RuntimeError(message)),
+            _ => return Ok(()),
+        };
+        let mut stdout = std::io::stdout().lock();
+        let mut stderr = std::io::stderr().lock();
+
+        for entry in entries {
+            let out: &mut dyn Write = match entry.stream {
+                LogStream::Stdout => &mut stdout,
+                LogStream::Stderr => &mut stderr,
+            };
+
+            if self.timestamps {
+                write!(out, "{} ", entry.time.to_rfc3339_opts(SecondsFormat::Nanos, true))?;
+            }
+
+            out.write_all(entry.log.as_bytes())?;
+        }
+
+        stdout.flush()?;
+
+        Ok(())
+    }
+}
+
+struct EventPrinter {
+    format: EventFormat,
+}
+
+impl ResponseSink for EventPrinter {
+    async fn send(&mut self, response: Response) -> Result<()> {
+        let events = match response {
+            Response::Events(events) => events,
+            Response::Error(message) => return Err(TurbineError::RuntimeError(message)),
+            _ => return Ok(()),
+        };
+        let mut stdout = std::io::stdout().lock();
+
+        for event in events {
+            match self.format {
+                EventFormat::Json => writeln!(stdout, "{}", serde_json::to_string(&event)?)?,
+                EventFormat::Text => {
+                    let details = match &event.action {
+                        EventAction::Die { exit_code: Some(code) } => format!(", exit_code={}", code),
+                        EventAction::HealthStatus { status } => format!(", status={}", status),
+                        _ => String::new(),
+                    };
+
+                    writeln!(stdout, "{} container {} {} (name={}{})",
+                        event.time.to_rfc3339_opts(SecondsFormat::Nanos, true),
+                        event.action.name(), event.container_id, event.container_name, details)?;
+                }
+            }
+        }
+
+        stdout.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Parses a subnet in CIDR notation, e.g. `172.20.0.0/16`.
+fn parse_subnet(value: &str) -> std::result::Result<(IpAddr, u8), String> {
+    let (address, prefix) = value.split_once('/')
+        .ok_or_else(|| format!("Invalid subnet '{}', expected address/prefix", value))?;
+    let address = address.parse::<IpAddr>()
+        .map_err(|e| format!("Invalid subnet address '{}': {}", address, e))?;
+    let prefix = prefix.parse::<u8>()
+        .map_err(|e| format!("Invalid subnet prefix '{}': {}", prefix, e))?;
+
+    Ok((address, prefix))
+}
+
+/// Parses a `--since`/`--until` value: an RFC 3339 timestamp, Unix seconds, or a
+/// duration such as `90s`, `10m`, `2h` or `1d` counted back from now.
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    if let Ok(seconds) = value.parse::<i64>() {
+        return DateTime::from_timestamp(seconds, 0)
+            .ok_or_else(|| TurbineError::ConfigError(format!("Timestamp out of range: {}", value)));
+    }
+
+    let unit = value.len() - value.chars().last().map_or(0, char::len_utf8);
+    let amount = value[..unit].parse::<i64>().ok();
+    let duration = match (amount, &value[unit..]) {
+        (Some(amount), "s") => TimeDelta::try_seconds(amount),
+        (Some(amount), "m") => TimeDelta::try_minutes(amount),
+        (Some(amount), "h") => TimeDelta::try_hours(amount),
+        (Some(amount), "d") => TimeDelta::try_days(amount),
+        _ => None,
+    };
+
+    duration
+        .map(|duration| Utc::now() - duration)
+        .ok_or_else(|| TurbineError::ConfigError(format!("Invalid time '{}'", value)))
+}
+
+fn print_stats(stats: &ContainerStats) {
+    let limit = stats.memory_limit
+        .map(|limit| format!("{} MB", limit / 1024 / 1024))
+        .unwrap_or_else(|| "unlimited".to_string());
+
+    println!("Container: {}", stats.container_id);
+    println!("CPU Usage: {:.2}%", stats.cpu_usage);
+    println!("Memory Usage: {} MB / {} (cache {} MB)",
+        stats.memory_usage / 1024 / 1024, limit, stats.memory_cache / 1024 / 1024);
+    println!("PIDs: {}", stats.pids);
+    println!("Block IO: {} bytes read / {} bytes written", stats.block_read, stats.block_write);
+    println!("Network RX: {} bytes", stats.network_rx);
+    println!("Network TX: {} bytes", stats.network_tx);
+
+    for interface in &stats.networks {
+        println!("  {}: rx {} bytes ({} packets), tx {} bytes ({} packets)",
+            interface.name, interface.rx_bytes, interface.rx_packets, interface.tx_bytes, interface.tx_packets);
+    }
+
+    for proxy in &stats.ports {
+        println!("Proxy {}/{} -> {}: {} connections, {} bytes in, {} bytes out",
+            proxy.listen, proxy.protocol, proxy.target, proxy.total_connections, proxy.bytes_in, proxy.bytes_out);
+
+        for connection in &proxy.connections {
+            println!("  {} since {}: {} bytes in, {} bytes out",
+                connection.client, connection.opened_at.to_rfc3339_opts(SecondsFormat::Secs, true), connection.bytes_in, connection.bytes_out);
+        }
+    }
+
+    println!("Uptime: {} seconds", stats.uptime);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        let time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        assert_eq!(parse_time("1700000000").unwrap(), time);
+        assert_eq!(parse_time("2023-11-14T22:13:20Z").unwrap(), time);
+        assert_eq!(parse_time("2023-11-15T00:13:20+02:00").unwrap(), time);
+    }
+
+    #[test]
+    fn parses_durations_back_from_now() {
+        for (value, duration) in [
+            ("90s", TimeDelta::seconds(90)),
+            ("10m", TimeDelta::minutes(10)),
+            ("2h", TimeDelta::hours(2)),
+            ("1d", TimeDelta::days(1)),
+        ] {
+            let expected = Utc::now() - duration;
+            let skew = (parse_time(value).unwrap() - expected).abs();
+
+            assert!(skew < TimeDelta::seconds(5), "{} was off by {}", value, skew);
+        }
+    }
+
+    #[test]
+    fn rejects_other_times() {
+        for value in ["", "m", "10x", "-", "1.5h", "yesterday", "2023-11-14", "10mé", "99999999999999999d"] {
+            assert!(parse_time(value).is_err(), "accepted '{}'", value);
+        }
+    }
+}
@@ -0,0 +1,806 @@
+use crate::cgroup;
+use crate::filesystem::secure_join;
+use crate::security::resource_limits;
+use crate::{Container, ExitStatus, TurbineError, Result};
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{clone, setns, CloneFlags};
+use nix::sys::prctl;
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::sys::socket::{socket, AddressFamily, SockFlag, SockType};
+use nix::sys::stat::Mode;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{
+    chdir, chroot, dup2_stderr, dup2_stdin, dup2_stdout, fchdir, fork, getppid, mkdir, pipe2, pivot_root,
+    read, setgid, setgroups, sethostname, setsid, setuid, write, ForkResult, Gid, Pid, Uid,
+};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Read;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+const STACK_SIZE: usize = 1024 * 1024;
+
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Host device nodes bind-mounted into the container's `/dev`.
+const DEVICES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+
+const DEV_SYMLINKS: [(&str, &str); 5] = [
+    ("/proc/self/fd", "fd"),
+    ("/proc/self/fd/0", "stdin"),
+    ("/proc/self/fd/1", "stdout"),
+    ("/proc/self/fd/2", "stderr"),
+    ("pts/ptmx", "ptmx"),
+];
+
+/// Setup stages of the container init and of exec'd processes, reported back to the
+/// runtime when one fails.
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Sync,
+    Cgroup,
+    Namespaces,
+    Fork,
+    Stdio,
+    Terminal,
+    Hostname,
+    Loopback,
+    MountPropagation,
+    BindRoot,
+    Dev,
+    PivotRoot,
+    Root,
+    Proc,
+    Sys,
+    WorkingDir,
+    ResourceLimits,
+    Groups,
+    Gid,
+    Uid,
+    Exec,
+}
+
+impl Step {
+    const ALL: [Step; 21] = [
+        Step::Sync,
+        Step::Cgroup,
+        Step::Namespaces,
+        Step::Fork,
+        Step::Stdio,
+        Step::Terminal,
+        Step::Hostname,
+        Step::Loopback,
+        Step::MountPropagation,
+        Step::BindRoot,
+        Step::Dev,
+        Step::PivotRoot,
+        Step::Root,
+        Step::Proc,
+        Step::Sys,
+        Step::WorkingDir,
+        Step::ResourceLimits,
+        Step::Groups,
+        Step::Gid,
+        Step::Uid,
+        Step::Exec,
+    ];
+
+    fn describe(self) -> &'static str {
+        match self {
+            Step::Sync => "wait for the runtime",
+            Step::Cgroup => "join the container's cgroup",
+            Step::Namespaces => "join the container's namespaces",
+            Step::Fork => "fork into the container's PID namespace",
+            Step::Stdio => "set up stdio",
+            Step::Terminal => "set up the controlling terminal",
+            Step::Hostname => "set the hostname",
+            Step::Loopback => "bring up the loopback interface",
+            Step::MountPropagation => "make mounts private",
+            Step::BindRoot => "bind mount the root filesystem",
+            Step::Dev => "set up /dev",
+            Step::PivotRoot => "pivot into the root filesystem",
+            Step::Root => "change into the container's root",
+            Step::Proc => "mount /proc",
+            Step::Sys => "mount /sys",
+            Step::WorkingDir => "change to the working directory",
+            Step::ResourceLimits => "set resource limits",
+            Step::Groups => "set supplementary groups",
+            Step::Gid => "set the group ID",
+            Step::Uid => "set the user ID",
+            Step::Exec => "execute the command",
+        }
+    }
+}
+
+/// Everything the container init needs, prepared before `clone` so the child does not
+/// allocate between `clone` and `exec`.
+struct InitSpec {
+    root: PathBuf,
+    hostname: String,
+    dev: PathBuf,
+    proc: PathBuf,
+    sys: PathBuf,
+    devices: Vec<(PathBuf, PathBuf)>,
+    dev_pts: PathBuf,
+    dev_shm: PathBuf,
+    dev_symlinks: Vec<(PathBuf, PathBuf)>,
+    process: ProcessSpec,
+}
+
+/// The command a container init or exec'd process ends up executing, with the working
+/// directory, limits and credentials it runs with.
+struct ProcessSpec {
+    working_dir: PathBuf,
+    rlimits: Vec<(Resource, u64)>,
+    groups: Option<Vec<Gid>>,
+    gid: Option<Gid>,
+    uid: Option<Uid>,
+    program: CString,
+    // Own the strings that `argv_ptrs` and `envp_ptrs` point into.
+    #[allow(dead_code)]
+    argv: Vec<CString>,
+    #[allow(dead_code)]
+    envp: Vec<CString>,
+    argv_ptrs: Vec<*const libc::c_char>,
+    envp_ptrs: Vec<*const libc::c_char>,
+}
+
+/// A command to run in a running container with [`exec`].
+pub struct ExecCommand {
+    pub command: Vec<String>,
+    pub environment: HashMap<String, String>,
+    pub working_dir: String,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub groups: Option<Vec<u32>>,
+    /// Make the terminal on stdin the process's controlling terminal.
+    pub tty: bool,
+}
+
+/// What an exec'd process needs, prepared before `clone` like [`InitSpec`].
+struct ExecSpec {
+    cgroup_procs: Option<CString>,
+    namespaces: Vec<(File, CloneFlags)>,
+    root: File,
+    tty: bool,
+    process: ProcessSpec,
+}
+
+struct ExecFds {
+    stdio: [OwnedFd; 3],
+    error: OwnedFd,
+}
+
+struct InitFds {
+    stdin: OwnedFd,
+    stdout: OwnedFd,
+    stderr: OwnedFd,
+    sync: OwnedFd,
+    error: OwnedFd,
+}
+
+/// A container init process created by [`spawn`]. Its PID is the real PID of the
+/// workload as seen from the host.
+pub struct ContainerProcess {
+    pid: Pid,
+    stdin: Option<File>,
+    output: Option<(File, File)>,
+    exit_status: Option<ExitStatus>,
+}
+
+impl ContainerProcess {
+    pub fn id(&self) -> u32 {
+        self.pid.as_raw() as u32
+    }
+
+    /// Reaps the process if it has exited.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        if self.exit_status.is_some() {
+            return Ok(self.exit_status);
+        }
+
+        let status = waitpid(self.pid, Some(WaitPidFlag::WNOHANG))
+            .map_err(|e| TurbineError::ProcessError(format!("Failed to wait for process: {}", e)))?;
+
+        self.exit_status = match status {
+            WaitStatus::Exited(_, code) => Some(ExitStatus { code: Some(code), signal: None }),
+            WaitStatus::Signaled(_, signal, _) => Some(ExitStatus { code: None, signal: Some(signal as i32) }),
+            _ => None,
+        };
+
+        Ok(self.exit_status)
+    }
+
+    pub async fn wait(&mut self) -> Result<ExitStatus> {
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(status);
+            }
+
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
+
+    pub async fn kill(&mut self) -> Result<()> {
+        if self.try_wait()?.is_none() {
+            // An exec'd command runs in its own session under the process relaying its
+            // exit status, and would otherwise keep running along with its children.
+            for child in self.children() {
+                let _ = killpg(child, Signal::SIGKILL);
+            }
+
+            match kill(self.pid, Signal::SIGKILL) {
+                Ok(()) | Err(Errno::ESRCH) => {}
+                Err(e) => return Err(TurbineError::ProcessError(format!("Failed to kill process: {}", e))),
+            }
+
+            self.wait().await?;
+        }
+
+        Ok(())
+    }
+
+    fn children(&self) -> Vec<Pid> {
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return Vec::new();
+        };
+
+        entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+            .filter(|pid| {
+                let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+
+                // The parent PID is the second field after the parenthesised command name.
+                stat.rsplit_once(')')
+                    .and_then(|(_, fields)| fields.split_whitespace().nth(1)?.parse::<i32>().ok())
+                    == Some(self.pid.as_raw())
+            })
+            .map(Pid::from_raw)
+            .collect()
+    }
+
+    /// Takes the write end of the container's stdin pipe, which only exists when the
+    /// container was created with `stdin_open`.
+    pub fn take_stdin(&mut self) -> Option<File> {
+        self.stdin.take()
+    }
+
+    /// Takes the read ends of the container's stdout and stderr pipes.
+    pub fn take_output(&mut self) -> Option<(File, File)> {
+        self.output.take()
+    }
+}
+
+/// Starts the container's command as PID 1 of new PID, mount, UTS, IPC, network and
+/// user namespaces, with its root pivoted to `container.root_path`. The init joins
+/// `container.cgroup_path` before it runs anything. `setup` is called with the init's
+/// PID once the namespaces exist and before the init goes on, to wire up its network
+/// namespace.
+pub fn spawn<F>(container: &Container, setup: F) -> Result<ContainerProcess>
+where
+    F: FnOnce(u32) -> Result<()>,
+{
+    let spec = InitSpec::new(container)?;
+    let pipe = || pipe2(OFlag::O_CLOEXEC)
+        .map_err(|e| TurbineError::ProcessError(format!("Failed to create pipe: {}", e)));
+    let (stdin_r, stdin_w) = if container.config.stdin_open {
+        let (read, write) = pipe()?;
+        (read, Some(File::from(write)))
+    } else {
+        (File::open("/dev/null")?.into(), None)
+    };
+    let (stdout_r, stdout_w) = pipe()?;
+    let (stderr_r, stderr_w) = pipe()?;
+    let (sync_r, sync_w) = pipe()?;
+    let (error_r, error_w) = pipe()?;
+    let fds = InitFds {
+        stdin: stdin_r,
+        stdout: stdout_w,
+        stderr: stderr_w,
+        sync: sync_r,
+        error: error_w,
+    };
+    let flags = user_namespace_flag()
+        | CloneFlags::CLONE_NEWPID
+        | CloneFlags::CLONE_NEWNS
+        | CloneFlags::CLONE_NEWUTS
+        | CloneFlags::CLONE_NEWIPC
+        | CloneFlags::CLONE_NEWNET;
+    let pid = clone_child(flags, || run_init(&spec, &fds), &fds.error)
+        .map_err(|e| TurbineError::ProcessError(format!("Failed to create container namespaces: {}", e)))?;
+
+    drop(fds);
+
+    let joined = write_id_maps(pid, flags)
+        .and_then(|_| match &container.cgroup_path {
+            Some(path) => cgroup::add_process(path, pid.as_raw() as u32),
+            None => Ok(()),
+        })
+        .and_then(|_| setup(pid.as_raw() as u32));
+    if let Err(e) = joined {
+        let _ = kill(pid, Signal::SIGKILL);
+        let _ = waitpid(pid, None);
+        return Err(e);
+    }
+
+    write(&sync_w, &[0])
+        .map_err(|e| TurbineError::ProcessError(format!("Failed to signal container init: {}", e)))?;
+    drop(sync_w);
+
+    if let Some(step) = read_failure(error_r)? {
+        let _ = waitpid(pid, None);
+
+        return Err(TurbineError::ProcessError(format!("Container init failed to {}", step)));
+    }
+
+    Ok(ContainerProcess {
+        pid,
+        stdin: stdin_w,
+        output: Some((File::from(stdout_r), File::from(stderr_r))),
+        exit_status: None,
+    })
+}
+
+/// Runs `command` in the cgroup and namespaces of the running container whose init is
+/// `init_pid`, chrooted to the init's root. `stdio` become the process's stdin, stdout
+/// and stderr. The returned process is an intermediate that exits with the command's
+/// status, since only children of a process that joins a PID namespace are in it.
+pub fn exec(container: &Container, init_pid: u32, command: ExecCommand, stdio: [OwnedFd; 3]) -> Result<ContainerProcess> {
+    let spec = ExecSpec::new(container, init_pid, command)?;
+    let (error_r, error_w) = pipe2(OFlag::O_CLOEXEC)
+        .map_err(|e| TurbineError::ProcessError(format!("Failed to create pipe: {}", e)))?;
+    let fds = ExecFds { stdio, error: error_w };
+    let pid = clone_child(CloneFlags::empty(), || run_exec(&spec, &fds), &fds.error)
+        .map_err(|e| TurbineError::ProcessError(format!("Failed to start process: {}", e)))?;
+
+    drop(fds);
+
+    if let Some(step) = read_failure(error_r)? {
+        let _ = waitpid(pid, None);
+
+        return Err(TurbineError::ProcessError(format!("Exec failed to {}", step)));
+    }
+
+    Ok(ContainerProcess {
+        pid,
+        stdin: None,
+        output: None,
+        exit_status: None,
+    })
+}
+
+/// Clones a child running `child`, which only returns on failure; the failing step and
+/// errno are then written to `error` for [`read_failure`].
+fn clone_child<F>(flags: CloneFlags, child: F, error: &OwnedFd) -> nix::Result<Pid>
+where
+    F: Fn() -> std::result::Result<Infallible, (Step, Errno)>,
+{
+    let mut stack = vec![0u8; STACK_SIZE];
+
+    unsafe {
+        clone(
+            Box::new(|| {
+                let Err((step, errno)) = child();
+                let mut report = [0u8; 8];
+
+                report[..4].copy_from_slice(&(step as u32).to_ne_bytes());
+                report[4..].copy_from_slice(&(errno as i32).to_ne_bytes());
+                let _ = write(error, &report);
+
+                libc::_exit(127)
+            }),
+            &mut stack,
+            flags,
+            Some(libc::SIGCHLD),
+        )
+    }
+}
+
+/// Waits until the child execs or fails, returning what failed.
+fn read_failure(error: OwnedFd) -> Result<Option<String>> {
+    let mut report = Vec::new();
+
+    File::from(error).read_to_end(&mut report)?;
+    if report.len() < 8 {
+        return Ok(None);
+    }
+
+    let step = u32::from_ne_bytes(report[..4].try_into().unwrap());
+    let errno = Errno::from_raw(i32::from_ne_bytes(report[4..8].try_into().unwrap()));
+    let step = Step::ALL.get(step as usize).map_or("start", |step| step.describe());
+
+    Ok(Some(format!("{}: {}", step, errno)))
+}
+
+impl InitSpec {
+    fn new(container: &Container) -> Result<Self> {
+        let config = &container.config;
+        let root = container.root_path.clone();
+        let dev = root.join("dev");
+        let hostname = container.hostname();
+        let environment = process_environment(container);
+        let groups = match (&config.groups, config.gid) {
+            (Some(groups), _) => Some(groups.clone()),
+            (None, Some(gid)) => Some(vec![gid]),
+            (None, None) => None,
+        };
+        let working_dir = config.working_dir.as_deref().unwrap_or("/");
+        let process = ProcessSpec::new(
+            container, &config.command, &environment, working_dir, config.uid, config.gid, groups,
+        )?;
+
+        Ok(Self {
+            hostname,
+            devices: DEVICES.iter()
+                .map(|name| (Path::new("/dev").join(name), dev.join(name)))
+                .collect(),
+            dev_pts: dev.join("pts"),
+            dev_shm: dev.join("shm"),
+            dev_symlinks: DEV_SYMLINKS.iter()
+                .map(|(target, name)| (PathBuf::from(target), dev.join(name)))
+                .collect(),
+            proc: root.join("proc"),
+            sys: root.join("sys"),
+            dev,
+            process,
+            root,
+        })
+    }
+}
+
+impl ExecSpec {
+    fn new(container: &Container, init_pid: u32, command: ExecCommand) -> Result<Self> {
+        let proc_path = PathBuf::from(format!("/proc/{}", init_pid));
+        let open = |path: PathBuf| File::open(&path)
+            .map_err(|e| TurbineError::ProcessError(format!("Failed to open {:?}: {}", path, e)));
+        // The user namespace goes first so the others, which it owns, can be joined. The
+        // one turbine is in already cannot be joined.
+        let own_user_namespace = fs::metadata(proc_path.join("ns/user"))
+            .and_then(|target| Ok(target.ino() == fs::metadata("/proc/self/ns/user")?.ino()))?;
+        let namespaces = [
+            ("user", CloneFlags::CLONE_NEWUSER),
+            ("ipc", CloneFlags::CLONE_NEWIPC),
+            ("uts", CloneFlags::CLONE_NEWUTS),
+            ("net", CloneFlags::CLONE_NEWNET),
+            ("pid", CloneFlags::CLONE_NEWPID),
+            ("mnt", CloneFlags::CLONE_NEWNS),
+        ];
+        let cgroup_procs = match &container.cgroup_path {
+            Some(path) => Some(cstring(path.join("cgroup.procs").as_os_str().as_bytes())?),
+            None => None,
+        };
+
+        Ok(Self {
+            cgroup_procs,
+            namespaces: namespaces.into_iter()
+                .filter(|(name, _)| !(own_user_namespace && *name == "user"))
+                .map(|(name, flag)| Ok((open(proc_path.join("ns").join(name))?, flag)))
+                .collect::<Result<_>>()?,
+            root: open(proc_path.join("root"))?,
+            tty: command.tty,
+            process: ProcessSpec::new(
+                container, &command.command, &command.environment, &command.working_dir,
+                command.uid, command.gid, command.groups,
+            )?,
+        })
+    }
+}
+
+impl ProcessSpec {
+    fn new(
+        container: &Container,
+        command: &[String],
+        environment: &HashMap<String, String>,
+        working_dir: &str,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        groups: Option<Vec<u32>>,
+    ) -> Result<Self> {
+        let program = command.first()
+            .ok_or_else(|| TurbineError::ProcessError("No command to run".to_string()))?;
+        let path = environment.get("PATH").map_or(DEFAULT_PATH, String::as_str);
+        let program = resolve_program(&container.root_path, program, path)?;
+        let argv: Vec<CString> = command.iter()
+            .map(|arg| cstring(arg.as_bytes()))
+            .collect::<Result<_>>()?;
+        let envp: Vec<CString> = environment.iter()
+            .map(|(key, value)| cstring(format!("{}={}", key, value).as_bytes()))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            working_dir: PathBuf::from(working_dir),
+            rlimits: resource_limits(&container.config.resources),
+            groups: groups.map(|groups| groups.into_iter().map(Gid::from_raw).collect()),
+            gid: gid.map(Gid::from_raw),
+            uid: uid.map(Uid::from_raw),
+            program: cstring(program.as_os_str().as_bytes())?,
+            argv_ptrs: null_terminated(&argv),
+            envp_ptrs: null_terminated(&envp),
+            argv,
+            envp,
+        })
+    }
+
+    /// Changes to the working directory, drops to the process's limits and credentials
+    /// and executes the command. Only returns on failure.
+    fn exec(&self) -> std::result::Result<Infallible, (Step, Errno)> {
+        at(Step::WorkingDir, chdir(&self.working_dir))?;
+
+        for &(resource, limit) in &self.rlimits {
+            at(Step::ResourceLimits, setrlimit(resource, limit, limit))?;
+        }
+
+        if let Some(groups) = &self.groups {
+            at(Step::Groups, setgroups(groups))?;
+        }
+
+        if let Some(gid) = self.gid {
+            at(Step::Gid, setgid(gid))?;
+        }
+
+        if let Some(uid) = self.uid {
+            at(Step::Uid, setuid(uid))?;
+        }
+
+        unsafe {
+            libc::execve(self.program.as_ptr(), self.argv_ptrs.as_ptr(), self.envp_ptrs.as_ptr());
+        }
+
+        Err((Step::Exec, Errno::last()))
+    }
+}
+
+/// Runs `f` on a thread that has joined the network namespace of process `pid`, so the
+/// sockets it opens are the container's and reach its loopback address.
+pub async fn in_network_namespace<T, F>(pid: u32, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let namespace = File::open(format!("/proc/{}/ns/net", pid))
+        .map_err(|e| TurbineError::ProcessError(format!("Failed to open network namespace of {}: {}", pid, e)))?;
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+
+    // The thread ends with the call, so the namespace it joined goes with it.
+    std::thread::Builder::new().spawn(move || {
+        let _ = sender.send(setns(&namespace, CloneFlags::CLONE_NEWNET).map(|_| f()));
+    })?;
+
+    receiver.await
+        .map_err(|_| TurbineError::ProcessError("Network namespace thread panicked".to_string()))?
+        .map_err(|e| TurbineError::ProcessError(format!("Failed to join network namespace of {}: {}", pid, e)))
+}
+
+/// Environment processes in `container` start with: its configured environment plus a
+/// default `PATH` and the container's `HOSTNAME`.
+pub fn process_environment(container: &Container) -> HashMap<String, String> {
+    let mut environment = container.config.environment.clone();
+
+    environment.entry("PATH".to_string()).or_insert_with(|| DEFAULT_PATH.to_string());
+    environment.entry("HOSTNAME".to_string()).or_insert_with(|| container.hostname());
+    environment
+}
+
+fn null_terminated(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings.iter()
+        .map(|s| s.as_ptr())
+        .chain(std::iter::once(std::ptr::null()))
+        .collect()
+}
+
+fn cstring(bytes: &[u8]) -> Result<CString> {
+    CString::new(bytes)
+        .map_err(|_| TurbineError::ProcessError("Command or environment contains a NUL byte".to_string()))
+}
+
+/// Finds `program` in the container's `PATH` under `root`. Resolving it here keeps the
+/// init from having to search (and allocate) between `clone` and `exec`.
+fn resolve_program(root: &Path, program: &str, path: &str) -> Result<PathBuf> {
+    if program.contains('/') {
+        return Ok(PathBuf::from(program));
+    }
+
+    for dir in path.split(':').filter(|dir| !dir.is_empty()) {
+        let candidate = Path::new(dir).join(program);
+        let Ok(host_path) = secure_join(root, &candidate) else {
+            continue;
+        };
+        if fs::metadata(&host_path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0) {
+            return Ok(candidate);
+        }
+    }
+
+    Err(TurbineError::ProcessError(
+        format!("Executable '{}' not found in the container's PATH", program)
+    ))
+}
+
+/// Containers of a turbine running as root stay in the host's user namespace. Image
+/// files are owned by host IDs, so the only map root could give them is the full range
+/// onto itself, which isolates nothing. Otherwise a user namespace makes the invoking
+/// user root in the container.
+fn user_namespace_flag() -> CloneFlags {
+    if Uid::effective().is_root() {
+        CloneFlags::empty()
+    } else {
+        CloneFlags::CLONE_NEWUSER
+    }
+}
+
+/// Maps the invoking user to root inside the container's user namespace, if `flags`
+/// created one.
+fn write_id_maps(pid: Pid, flags: CloneFlags) -> Result<()> {
+    if !flags.contains(CloneFlags::CLONE_NEWUSER) {
+        return Ok(());
+    }
+
+    let proc_path = PathBuf::from(format!("/proc/{}", pid));
+    let write_map = |name: &str, contents: String| fs::write(proc_path.join(name), contents)
+        .map_err(|e| TurbineError::ProcessError(format!("Failed to write {}: {}", name, e)));
+
+    write_map("setgroups", "deny".to_string())?;
+    write_map("uid_map", format!("0 {} 1\n", Uid::effective()))?;
+    write_map("gid_map", format!("0 {} 1\n", Gid::effective()))
+}
+
+/// Body of the container init. Runs in the cloned child and only returns on failure.
+fn run_init(spec: &InitSpec, fds: &InitFds) -> std::result::Result<Infallible, (Step, Errno)> {
+    let mut byte = [0u8];
+
+    match read(&fds.sync, &mut byte) {
+        Ok(1) => {}
+        Ok(_) => return Err((Step::Sync, Errno::EPIPE)),
+        Err(e) => return Err((Step::Sync, e)),
+    }
+
+    at(Step::Stdio, setsid())?;
+    at(Step::Stdio, dup2_stdin(&fds.stdin))?;
+    at(Step::Stdio, dup2_stdout(&fds.stdout))?;
+    at(Step::Stdio, dup2_stderr(&fds.stderr))?;
+    at(Step::Hostname, sethostname(&spec.hostname))?;
+    at(Step::Loopback, loopback_up())?;
+    at(Step::MountPropagation, mount(
+        None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>,
+    ))?;
+    at(Step::BindRoot, mount(
+        Some(&spec.root), &spec.root, None::<&str>, MsFlags::MS_BIND | MsFlags::MS_REC, None::<&str>,
+    ))?;
+    setup_dev(spec).map_err(|e| (Step::Dev, e))?;
+
+    // proc and sysfs can only be mounted in a user namespace while the host's instances
+    // are still visible, so they go in before the pivot.
+    at(Step::Proc, mount(
+        Some("proc"), &spec.proc, Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC, None::<&str>,
+    ))?;
+    at(Step::Sys, mount(
+        Some("sysfs"), &spec.sys, Some("sysfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC | MsFlags::MS_RDONLY, None::<&str>,
+    ))?;
+    at(Step::PivotRoot, chdir(&spec.root))?;
+    at(Step::PivotRoot, pivot_root(".", "."))?;
+    at(Step::PivotRoot, umount2(".", MntFlags::MNT_DETACH))?;
+    at(Step::PivotRoot, chdir("/"))?;
+
+    spec.process.exec()
+}
+
+fn run_exec(spec: &ExecSpec, fds: &ExecFds) -> std::result::Result<Infallible, (Step, Errno)> {
+    if let Some(procs) = &spec.cgroup_procs {
+        let file = at(Step::Cgroup, open(procs.as_c_str(), OFlag::O_WRONLY | OFlag::O_CLOEXEC, Mode::empty()))?;
+
+        at(Step::Cgroup, write(&file, b"0"))?;
+    }
+
+    for (namespace, flag) in &spec.namespaces {
+        at(Step::Namespaces, setns(namespace, *flag))?;
+    }
+
+    at(Step::Root, fchdir(&spec.root))?;
+    at(Step::Root, chroot("."))?;
+    at(Step::Stdio, dup2_stdin(&fds.stdio[0]))?;
+    at(Step::Stdio, dup2_stdout(&fds.stdio[1]))?;
+    at(Step::Stdio, dup2_stderr(&fds.stdio[2]))?;
+
+    if let ForkResult::Parent { child } = at(Step::Fork, unsafe { fork() })? {
+        // Never having exec'd, this process still holds everything the runtime had open,
+        // including the caller's ends of the command's stdio and the error pipe.
+        unsafe { libc::syscall(libc::SYS_close_range, 0, u32::MAX, 0) };
+        relay_exit(child);
+    }
+
+    // Killing the process relaying the exit status, as `ContainerProcess::kill` does,
+    // must not leave the command running.
+    at(Step::Fork, prctl::set_pdeathsig(Signal::SIGKILL))?;
+    // The parent lives outside the PID namespace, so the command only sees a parent
+    // once it has been reparented to the container's init.
+    if getppid() == Pid::from_raw(1) {
+        return Err((Step::Fork, Errno::ESRCH));
+    }
+
+    at(Step::Terminal, setsid())?;
+    if spec.tty && unsafe { libc::ioctl(0, libc::TIOCSCTTY, 0) } == -1 {
+        return Err((Step::Terminal, Errno::last()));
+    }
+
+    spec.process.exec()
+}
+
+/// Waits for `child` and exits with its status, or 128 plus the signal that killed it.
+fn relay_exit(child: Pid) -> ! {
+    loop {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => unsafe { libc::_exit(code) },
+            Ok(WaitStatus::Signaled(_, signal, _)) => unsafe { libc::_exit(128 + signal as i32) },
+            Ok(_) | Err(Errno::EINTR) => continue,
+            Err(_) => unsafe { libc::_exit(127) },
+        }
+    }
+}
+
+/// Mounts a fresh tmpfs on the container's `/dev` with the basic device nodes, a private
+/// devpts instance and `/dev/shm`.
+fn setup_dev(spec: &InitSpec) -> nix::Result<()> {
+    mount(
+        Some("tmpfs"), &spec.dev, Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_STRICTATIME, Some("mode=755,size=65536k"),
+    )?;
+
+    for (source, target) in &spec.devices {
+        OpenOptions::new().write(true).create(true).truncate(false).open(target)
+            .map_err(io_errno)?;
+        mount(Some(source), target, None::<&str>, MsFlags::MS_BIND, None::<&str>)?;
+    }
+
+    mkdir(&spec.dev_pts, Mode::from_bits_truncate(0o755))?;
+    mount(
+        Some("devpts"), &spec.dev_pts, Some("devpts"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC, Some("newinstance,ptmxmode=0666,mode=0620"),
+    )?;
+    mkdir(&spec.dev_shm, Mode::from_bits_truncate(0o1777))?;
+    mount(
+        Some("shm"), &spec.dev_shm, Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC, Some("mode=1777,size=65536k"),
+    )?;
+
+    for (target, link) in &spec.dev_symlinks {
+        std::os::unix::fs::symlink(target, link).map_err(io_errno)?;
+    }
+
+    Ok(())
+}
+
+/// Sets `lo` up in the init's new network namespace, where it starts out down.
+fn loopback_up() -> nix::Result<()> {
+    let socket = socket(AddressFamily::Inet, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+
+    request.ifr_name[0] = b'l' as libc::c_char;
+    request.ifr_name[1] = b'o' as libc::c_char;
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, &mut request) } == -1 {
+        return Err(Errno::last());
+    }
+
+    unsafe { request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short };
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, &request) } == -1 {
+        return Err(Errno::last());
+    }
+
+    Ok(())
+}
+
+fn at<T>(step: Step, result: nix::Result<T>) -> std::result::Result<T, (Step, Errno)> {
+    result.map_err(|e| (step, e))
+}
+
+fn io_errno(error: std::io::Error) -> Errno {
+    Errno::from_raw(error.raw_os_error().unwrap_or(libc::EIO))
+}
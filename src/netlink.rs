@@ -0,0 +1,498 @@
+use crate::{TurbineError, Result};
+use nix::errno::Errno;
+use nix::sched::{setns, CloneFlags};
+use nix::sys::socket::{bind, recv, send, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType};
+use std::fs::File;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, OwnedFd};
+
+const HEADER_LEN: usize = 16;
+
+const RECEIVE_BUFFER_LEN: usize = 32 * 1024;
+
+/// `IFLA_INFO_DATA` attribute of a veth link holding its peer's `ifinfomsg` and name.
+const VETH_INFO_PEER: u16 = 1;
+
+/// A route netlink socket for configuring the links, addresses and routes of the
+/// network namespace it was opened in. Failures carry the kernel's reason as
+/// `NetworkExists`, `NetworkNotFound` or `NetworkPermissionDenied` where it is one of those.
+pub struct Netlink {
+    socket: OwnedFd,
+    sequence: u32,
+}
+
+impl Netlink {
+    pub fn open() -> Result<Self> {
+        let socket = socket(AddressFamily::Netlink, SockType::Raw, SockFlag::SOCK_CLOEXEC, SockProtocol::NetlinkRoute)
+            .map_err(|e| failure("open a netlink socket".to_string(), e))?;
+
+        bind(socket.as_raw_fd(), &NetlinkAddr::new(0, 0))
+            .map_err(|e| failure("bind the netlink socket".to_string(), e))?;
+
+        Ok(Self { socket, sequence: 0 })
+    }
+
+    /// Opens a socket in the network namespace of process `pid`. Sockets stay in the
+    /// namespace they were created in, so only the creating thread has to join it.
+    pub fn open_in(pid: u32) -> Result<Self> {
+        let namespace = File::open(format!("/proc/{}/ns/net", pid))
+            .map_err(|e| TurbineError::NetworkNotFound(format!("Failed to open network namespace of {}: {}", pid, e)))?;
+
+        std::thread::spawn(move || {
+            setns(&namespace, CloneFlags::CLONE_NEWNET)
+                .map_err(|e| failure(format!("join network namespace of {}", pid), e))?;
+
+            Self::open()
+        })
+        .join()
+        .map_err(|_| TurbineError::NetworkError("Network namespace thread panicked".to_string()))?
+    }
+
+    pub fn link_index(&mut self, name: &str) -> Result<u32> {
+        let request = Message::link(libc::RTM_GETLINK, 0, 0).string_attribute(libc::IFLA_IFNAME, name);
+        let replies = self.execute(request)
+            .map_err(|e| failure(format!("find link {}", name), e))?;
+
+        // ifinfomsg: family, padding and type come before the index.
+        replies.first()
+            .and_then(|reply| reply.get(4..8))
+            .map(|index| u32::from_ne_bytes(index.try_into().unwrap_or_default()))
+            .ok_or_else(|| TurbineError::NetworkError(format!("No reply for link {}", name)))
+    }
+
+    pub fn link_exists(&mut self, name: &str) -> Result<bool> {
+        match self.link_index(name) {
+            Ok(_) => Ok(true),
+            Err(TurbineError::NetworkNotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Creates a bridge with a random, locally administered MAC address. Without one set,
+    /// the bridge takes the lowest of its ports' and changes it as ports come and go,
+    /// leaving containers with a stale neighbor entry for the gateway.
+    pub fn create_bridge(&mut self, name: &str) -> Result<()> {
+        let mut mac = [0u8; 6];
+
+        mac.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[..6]);
+        mac[0] = (mac[0] & 0xfe) | 0x02;
+
+        let request = Message::link(libc::RTM_NEWLINK, create_flags(), 0)
+            .string_attribute(libc::IFLA_IFNAME, name)
+            .attribute(libc::IFLA_ADDRESS, &mac)
+            .nested(libc::IFLA_LINKINFO, |info| info.attribute(libc::IFLA_INFO_KIND, b"bridge"));
+
+        self.execute(request)
+            .map(drop)
+            .map_err(|e| failure(format!("create bridge {}", name), e))
+    }
+
+    pub fn create_veth_pair(&mut self, name: &str, peer: &str) -> Result<()> {
+        let request = Message::link(libc::RTM_NEWLINK, create_flags(), 0)
+            .string_attribute(libc::IFLA_IFNAME, name)
+            .nested(libc::IFLA_LINKINFO, |info| {
+                info.attribute(libc::IFLA_INFO_KIND, b"veth")
+                    .nested(libc::IFLA_INFO_DATA, |data| {
+                        data.nested(VETH_INFO_PEER, |peer_info| {
+                            peer_info.bytes(&link_header(0, 0))
+                                .string_attribute(libc::IFLA_IFNAME, peer)
+                        })
+                    })
+            });
+
+        self.execute(request)
+            .map(drop)
+            .map_err(|e| failure(format!("create veth pair {}", name), e))
+    }
+
+    pub fn delete_link(&mut self, name: &str) -> Result<()> {
+        let index = self.link_index(name)?;
+
+        self.execute(Message::link(libc::RTM_DELLINK, 0, index))
+            .map(drop)
+            .map_err(|e| failure(format!("delete link {}", name), e))
+    }
+
+    pub fn set_up(&mut self, name: &str) -> Result<()> {
+        let index = self.link_index(name)?;
+        let request = Message::new(libc::RTM_NEWLINK, 0).bytes(&link_header(index, libc::IFF_UP as u32));
+
+        self.execute(request)
+            .map(drop)
+            .map_err(|e| failure(format!("bring up {}", name), e))
+    }
+
+    pub fn set_master(&mut self, name: &str, master: &str) -> Result<()> {
+        let index = self.link_index(name)?;
+        let master_index = self.link_index(master)?;
+        let request = Message::link(libc::RTM_NEWLINK, 0, index)
+            .attribute(libc::IFLA_MASTER, &master_index.to_ne_bytes());
+
+        self.execute(request)
+            .map(drop)
+            .map_err(|e| failure(format!("attach {} to {}", name, master), e))
+    }
+
+    pub fn set_mtu(&mut self, name: &str, mtu: u32) -> Result<()> {
+        let index = self.link_index(name)?;
+        let request = Message::link(libc::RTM_NEWLINK, 0, index)
+            .attribute(libc::IFLA_MTU, &mtu.to_ne_bytes());
+
+        self.execute(request)
+            .map(drop)
+            .map_err(|e| failure(format!("set the MTU of {} to {}", name, mtu), e))
+    }
+
+    /// Moves a link into the network namespace of process `pid`, where it is named
+    /// `new_name`.
+    pub fn move_to_namespace(&mut self, name: &str, pid: u32, new_name: &str) -> Result<()> {
+        let index = self.link_index(name)?;
+        let request = Message::link(libc::RTM_NEWLINK, 0, index)
+            .attribute(libc::IFLA_NET_NS_PID, &pid.to_ne_bytes())
+            .string_attribute(libc::IFLA_IFNAME, new_name);
+
+        self.execute(request)
+            .map(drop)
+            .map_err(|e| failure(format!("move {} into network namespace of {}", name, pid), e))
+    }
+
+    /// Adds an address to the link. IPv6 addresses skip duplicate address detection, so
+    /// they can be bound right away.
+    pub fn add_address(&mut self, name: &str, address: IpAddr, prefix: u8) -> Result<()> {
+        let index = self.link_index(name)?;
+        let (family, octets) = address_bytes(address);
+        let flags = if address.is_ipv6() { libc::IFA_F_NODAD as u8 } else { 0 };
+        let mut header = vec![family, prefix, flags, libc::RT_SCOPE_UNIVERSE];
+
+        header.extend_from_slice(&index.to_ne_bytes());
+
+        let request = Message::new(libc::RTM_NEWADDR, create_flags())
+            .bytes(&header)
+            .attribute(libc::IFA_LOCAL, &octets)
+            .attribute(libc::IFA_ADDRESS, &octets);
+
+        self.execute(request)
+            .map(drop)
+            .map_err(|e| failure(format!("add address {}/{} to {}", address, prefix, name), e))
+    }
+
+    pub fn add_default_route(&mut self, name: &str, gateway: IpAddr) -> Result<()> {
+        let index = self.link_index(name)?;
+        let (family, octets) = address_bytes(gateway);
+        let mut header = vec![
+            family, 0, 0, 0,
+            libc::RT_TABLE_MAIN, libc::RTPROT_BOOT, libc::RT_SCOPE_UNIVERSE, libc::RTN_UNICAST,
+        ];
+
+        header.extend_from_slice(&0u32.to_ne_bytes());
+
+        let request = Message::new(libc::RTM_NEWROUTE, create_flags())
+            .bytes(&header)
+            .attribute(libc::RTA_GATEWAY, &octets)
+            .attribute(libc::RTA_OIF, &index.to_ne_bytes());
+
+        self.execute(request)
+            .map(drop)
+            .map_err(|e| failure(format!("add default route via {}", gateway), e))
+    }
+
+    /// Sends a request and collects the payloads of its replies up to the kernel's
+    /// acknowledgement.
+    fn execute(&mut self, message: Message) -> nix::Result<Vec<Vec<u8>>> {
+        self.sequence = self.sequence.wrapping_add(1);
+        send(self.socket.as_raw_fd(), &message.finish(self.sequence), MsgFlags::empty())?;
+
+        let mut replies = Vec::new();
+        let mut buffer = vec![0u8; RECEIVE_BUFFER_LEN];
+
+        loop {
+            let length = recv(self.socket.as_raw_fd(), &mut buffer, MsgFlags::empty())?;
+
+            if read_replies(&buffer[..length], self.sequence, &mut replies)? {
+                return Ok(replies);
+            }
+        }
+    }
+}
+
+/// Adds the payloads of the messages in `received` answering request `sequence` to
+/// `replies`, returning whether the kernel's acknowledgement was among them. An error
+/// message carries the negated errno of the failed request.
+fn read_replies(mut received: &[u8], sequence: u32, replies: &mut Vec<Vec<u8>>) -> nix::Result<bool> {
+    while received.len() >= HEADER_LEN {
+        let message_len = u32::from_ne_bytes(received[0..4].try_into().unwrap_or_default()) as usize;
+        let kind = u16::from_ne_bytes(received[4..6].try_into().unwrap_or_default());
+        let message_sequence = u32::from_ne_bytes(received[8..12].try_into().unwrap_or_default());
+        if message_len < HEADER_LEN || message_len > received.len() {
+            return Err(Errno::EBADMSG);
+        }
+
+        let payload = &received[HEADER_LEN..message_len];
+
+        if message_sequence == sequence {
+            if kind == libc::NLMSG_ERROR as u16 {
+                let code = payload.get(0..4)
+                    .map(|code| i32::from_ne_bytes(code.try_into().unwrap_or_default()))
+                    .ok_or(Errno::EBADMSG)?;
+
+                return match code {
+                    0 => Ok(true),
+                    code => Err(Errno::from_raw(-code)),
+                };
+            }
+
+            replies.push(payload.to_vec());
+        }
+
+        received = &received[align(message_len).min(received.len())..];
+    }
+
+    Ok(false)
+}
+
+/// A netlink request being built: the header, the family-specific fixed part, then
+/// attributes.
+struct Message {
+    buffer: Vec<u8>,
+}
+
+impl Message {
+    fn new(kind: u16, flags: libc::c_int) -> Self {
+        let mut buffer = vec![0u8; HEADER_LEN];
+        let flags = (flags | libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16;
+
+        buffer[4..6].copy_from_slice(&kind.to_ne_bytes());
+        buffer[6..8].copy_from_slice(&flags.to_ne_bytes());
+
+        Self { buffer }
+    }
+
+    fn link(kind: u16, flags: libc::c_int, index: u32) -> Self {
+        Self::new(kind, flags).bytes(&link_header(index, 0))
+    }
+
+    fn bytes(mut self, data: &[u8]) -> Self {
+        self.buffer.extend_from_slice(data);
+        self.buffer.resize(align(self.buffer.len()), 0);
+        self
+    }
+
+    fn attribute(self, kind: u16, data: &[u8]) -> Self {
+        let mut attribute = ((4 + data.len()) as u16).to_ne_bytes().to_vec();
+
+        attribute.extend_from_slice(&kind.to_ne_bytes());
+        attribute.extend_from_slice(data);
+
+        self.bytes(&attribute)
+    }
+
+    fn string_attribute(self, kind: u16, value: &str) -> Self {
+        let mut data = value.as_bytes().to_vec();
+
+        data.push(0);
+        self.attribute(kind, &data)
+    }
+
+    /// Adds an attribute holding the attributes `build` adds.
+    fn nested(mut self, kind: u16, build: impl FnOnce(Self) -> Self) -> Self {
+        let start = self.buffer.len();
+
+        self.buffer.extend_from_slice(&[0, 0]);
+        self.buffer.extend_from_slice(&kind.to_ne_bytes());
+        self = build(self);
+
+        let length = (self.buffer.len() - start) as u16;
+
+        self.buffer[start..start + 2].copy_from_slice(&length.to_ne_bytes());
+        self
+    }
+
+    fn finish(mut self, sequence: u32) -> Vec<u8> {
+        let length = self.buffer.len() as u32;
+
+        self.buffer[0..4].copy_from_slice(&length.to_ne_bytes());
+        self.buffer[8..12].copy_from_slice(&sequence.to_ne_bytes());
+        self.buffer
+    }
+}
+
+/// An `ifinfomsg` for link `index`, setting the `up` flags and leaving the others.
+fn link_header(index: u32, up: u32) -> Vec<u8> {
+    let mut header = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+
+    header.extend_from_slice(&index.to_ne_bytes());
+    header.extend_from_slice(&up.to_ne_bytes());
+    header.extend_from_slice(&up.to_ne_bytes());
+    header
+}
+
+fn create_flags() -> libc::c_int {
+    libc::NLM_F_CREATE | libc::NLM_F_EXCL
+}
+
+fn address_bytes(address: IpAddr) -> (u8, Vec<u8>) {
+    match address {
+        IpAddr::V4(address) => (libc::AF_INET as u8, address.octets().to_vec()),
+        IpAddr::V6(address) => (libc::AF_INET6 as u8, address.octets().to_vec()),
+    }
+}
+
+fn align(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+fn failure(action: String, errno: Errno) -> TurbineError {
+    let message = format!("Failed to {}: {}", action, errno.desc());
+
+    match errno {
+        Errno::EEXIST => TurbineError::NetworkExists(message),
+        Errno::ENODEV | Errno::ENOENT | Errno::ESRCH => TurbineError::NetworkNotFound(message),
+        Errno::EPERM | Errno::EACCES => TurbineError::NetworkPermissionDenied(message),
+        _ => TurbineError::NetworkError(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(buffer: &[u8], offset: usize) -> u16 {
+        u16::from_ne_bytes(buffer[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(buffer: &[u8], offset: usize) -> u32 {
+        u32::from_ne_bytes(buffer[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// A message as the kernel sends it, padded to the next message.
+    fn reply(kind: u16, sequence: u32, payload: &[u8]) -> Vec<u8> {
+        let mut message = ((HEADER_LEN + payload.len()) as u32).to_ne_bytes().to_vec();
+
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(&0u16.to_ne_bytes());
+        message.extend_from_slice(&sequence.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(payload);
+        message.resize(align(message.len()), 0);
+        message
+    }
+
+    fn error(sequence: u32, code: i32) -> Vec<u8> {
+        reply(libc::NLMSG_ERROR as u16, sequence, &code.to_ne_bytes())
+    }
+
+    #[test]
+    fn finish_fills_in_the_header() {
+        let message = Message::new(libc::RTM_NEWLINK, create_flags()).finish(42);
+        let flags = u16_at(&message, 6) as libc::c_int;
+
+        assert_eq!(message.len(), HEADER_LEN);
+        assert_eq!(u32_at(&message, 0), HEADER_LEN as u32);
+        assert_eq!(u16_at(&message, 4), libc::RTM_NEWLINK);
+        assert_eq!(flags, libc::NLM_F_REQUEST | libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_EXCL);
+        assert_eq!(u32_at(&message, 8), 42);
+    }
+
+    #[test]
+    fn link_header_sets_index_and_up_flags() {
+        let header = link_header(7, libc::IFF_UP as u32);
+
+        assert_eq!(header.len(), 16);
+        assert_eq!(header[0], libc::AF_UNSPEC as u8);
+        assert_eq!(u32_at(&header, 4), 7);
+        assert_eq!(u32_at(&header, 8), libc::IFF_UP as u32);
+        assert_eq!(u32_at(&header, 12), libc::IFF_UP as u32);
+        assert_eq!(link_header(3, 0)[8..], [0; 8]);
+    }
+
+    #[test]
+    fn attributes_are_padded_to_four_bytes() {
+        let message = Message::new(libc::RTM_GETLINK, 0)
+            .string_attribute(libc::IFLA_IFNAME, "eth0")
+            .attribute(libc::IFLA_MTU, &1500u32.to_ne_bytes())
+            .finish(1);
+
+        // The length covers "eth0\0" but not the three bytes padding it.
+        assert_eq!(u16_at(&message, 16), 9);
+        assert_eq!(u16_at(&message, 18), libc::IFLA_IFNAME);
+        assert_eq!(&message[20..28], b"eth0\0\0\0\0");
+        assert_eq!(u16_at(&message, 28), 8);
+        assert_eq!(u16_at(&message, 30), libc::IFLA_MTU);
+        assert_eq!(u32_at(&message, 32), 1500);
+        assert_eq!(message.len(), 36);
+        assert_eq!(u32_at(&message, 0), 36);
+    }
+
+    #[test]
+    fn nested_attributes_cover_their_children() {
+        let message = Message::link(libc::RTM_NEWLINK, 0, 0)
+            .nested(libc::IFLA_LINKINFO, |info| {
+                info.attribute(libc::IFLA_INFO_KIND, b"veth")
+                    .nested(libc::IFLA_INFO_DATA, |data| data.attribute(VETH_INFO_PEER, b"abc"))
+            })
+            .string_attribute(libc::IFLA_IFNAME, "a")
+            .finish(1);
+        let start = HEADER_LEN + 16;
+
+        assert_eq!(u16_at(&message, start), 24);
+        assert_eq!(u16_at(&message, start + 2), libc::IFLA_LINKINFO);
+        assert_eq!(u16_at(&message, start + 4), 8);
+        assert_eq!(&message[start + 8..start + 12], b"veth");
+        // The inner nest counts its child's padding, its child only the data.
+        assert_eq!(u16_at(&message, start + 12), 12);
+        assert_eq!(u16_at(&message, start + 14), libc::IFLA_INFO_DATA);
+        assert_eq!(u16_at(&message, start + 16), 7);
+        assert_eq!(u16_at(&message, start + 18), VETH_INFO_PEER);
+        assert_eq!(&message[start + 20..start + 24], b"abc\0");
+        // Attributes after the nest start right where it ends.
+        assert_eq!(u16_at(&message, start + 24), 6);
+        assert_eq!(u16_at(&message, start + 26), libc::IFLA_IFNAME);
+        assert_eq!(message.len(), start + 32);
+    }
+
+    #[test]
+    fn replies_are_collected_up_to_the_acknowledgement() {
+        let mut received = reply(libc::RTM_NEWLINK, 5, b"first");
+        received.extend(reply(libc::RTM_NEWLINK, 4, b"stale"));
+        received.extend(reply(libc::RTM_NEWLINK, 5, b"second"));
+
+        let mut replies = Vec::new();
+
+        assert_eq!(read_replies(&received, 5, &mut replies), Ok(false));
+        assert_eq!(read_replies(&error(5, 0), 5, &mut replies), Ok(true));
+        assert_eq!(replies, [b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn errors_carry_the_kernel_errno() {
+        let mut replies = Vec::new();
+
+        assert_eq!(read_replies(&error(3, -libc::EEXIST), 3, &mut replies), Err(Errno::EEXIST));
+        assert_eq!(read_replies(&error(3, -libc::ENODEV), 3, &mut replies), Err(Errno::ENODEV));
+        // Errors for other requests are not ours to report.
+        assert_eq!(read_replies(&error(2, -libc::EPERM), 3, &mut replies), Ok(false));
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn malformed_replies_are_rejected() {
+        let mut replies = Vec::new();
+        let mut truncated = reply(libc::RTM_NEWLINK, 1, b"payload");
+        let mut short = reply(libc::RTM_NEWLINK, 1, b"");
+
+        truncated.truncate(HEADER_LEN + 2);
+        short[0..4].copy_from_slice(&8u32.to_ne_bytes());
+
+        assert_eq!(read_replies(&truncated, 1, &mut replies), Err(Errno::EBADMSG));
+        assert_eq!(read_replies(&short, 1, &mut replies), Err(Errno::EBADMSG));
+        assert_eq!(read_replies(&reply(libc::NLMSG_ERROR as u16, 1, b""), 1, &mut replies), Err(Errno::EBADMSG));
+    }
+
+    #[test]
+    fn looks_up_links_through_the_kernel() {
+        let mut netlink = Netlink::open().unwrap();
+
+        assert!(netlink.link_index("lo").unwrap() > 0);
+        assert!(!netlink.link_exists("turbine-none0").unwrap());
+    }
+}
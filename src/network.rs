@@ -1,9 +1,31 @@
-use crate::{Container, TurbineError, Result};
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::process::Command;
-
-#[derive(Debug, Clone)]
+use crate::firewall::{self, Firewall};
+use crate::ipam::{self, IpRange, Ipam};
+use crate::netlink::Netlink;
+use crate::proxy::{self, PortProxy, ProxyStats};
+use crate::state::write_atomic;
+use crate::{Container, PortMode, PortProtocol, TurbineError, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// Network containers join when their configuration names none. It always exists and
+/// cannot be removed.
+pub const DEFAULT_NETWORK: &str = "default";
+
+const DEFAULT_BRIDGE: &str = "turbine0";
+
+/// Bridges of networks created without a bridge name are this followed by a number.
+const BRIDGE_PREFIX: &str = "turbine";
+
+/// Interfaces a container can have, `eth0` to `eth99`. Host-side interface names are
+/// derived from them and must fit in 15 characters.
+const MAX_CONTAINER_INTERFACES: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum NetworkConfig {
     IPv4 { subnet: Ipv4Addr, prefix: u8 },
     IPv6 { subnet: Ipv6Addr, prefix: u8 },
@@ -24,393 +46,797 @@ impl Default for NetworkConfig {
     }
 }
 
-pub struct NetworkManager {
-    bridge_name: String,
-    network_config: NetworkConfig,
-    allocated_ips: HashMap<String, Vec<IpAddr>>,
-    port_mappings: HashMap<u16, String>,
+impl NetworkConfig {
+    /// The subnets containers get addresses from, with their prefix lengths.
+    pub fn subnets(&self) -> Vec<(IpAddr, u8)> {
+        match self {
+            NetworkConfig::IPv4 { subnet, prefix } => vec![(IpAddr::V4(*subnet), *prefix)],
+            NetworkConfig::IPv6 { subnet, prefix } => vec![(IpAddr::V6(*subnet), *prefix)],
+            NetworkConfig::DualStack { ipv4_subnet, ipv4_prefix, ipv6_subnet, ipv6_prefix } => vec![
+                (IpAddr::V4(*ipv4_subnet), *ipv4_prefix),
+                (IpAddr::V6(*ipv6_subnet), *ipv6_prefix),
+            ],
+        }
+    }
+
+    /// Builds the configuration for at most one subnet of each family.
+    pub fn from_subnets(subnets: &[(IpAddr, u8)]) -> Result<Self> {
+        match *subnets {
+            [(IpAddr::V4(subnet), prefix)] => Ok(NetworkConfig::IPv4 { subnet, prefix }),
+            [(IpAddr::V6(subnet), prefix)] => Ok(NetworkConfig::IPv6 { subnet, prefix }),
+            [(IpAddr::V4(ipv4_subnet), ipv4_prefix), (IpAddr::V6(ipv6_subnet), ipv6_prefix)]
+            | [(IpAddr::V6(ipv6_subnet), ipv6_prefix), (IpAddr::V4(ipv4_subnet), ipv4_prefix)] => {
+                Ok(NetworkConfig::DualStack { ipv4_subnet, ipv4_prefix, ipv6_subnet, ipv6_prefix })
+            }
+            [] => Err(TurbineError::ConfigError("A network needs a subnet".to_string())),
+            _ => Err(TurbineError::ConfigError("A network has at most one subnet of each family".to_string())),
+        }
+    }
 }
 
-impl NetworkManager {
-    pub fn new(bridge_name: String) -> Self {
+/// A bridge network containers can join.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Network {
+    pub name: String,
+    pub bridge: String,
+    pub subnet: NetworkConfig,
+    pub gateway_ipv4: Option<Ipv4Addr>,
+    pub gateway_ipv6: Option<Ipv6Addr>,
+    pub mtu: Option<u32>,
+    /// Containers on an internal network reach each other, but not the host's other
+    /// networks or other turbine networks.
+    pub internal: bool,
+    /// Ranges addresses are not picked from automatically. Containers may still ask
+    /// for addresses in them.
+    #[serde(default)]
+    pub reserved: Vec<IpRange>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Network {
+    /// The subnets containers get addresses from, with their prefix lengths.
+    pub fn subnets(&self) -> Vec<(IpAddr, u8)> {
+        self.subnet.subnets()
+    }
+
+    /// The bridge's addresses, which containers route through.
+    pub fn gateways(&self) -> Vec<IpAddr> {
+        self.gateway_ipv4.map(IpAddr::V4).into_iter()
+            .chain(self.gateway_ipv6.map(IpAddr::V6))
+            .collect()
+    }
+
+    /// The gateway for addresses of `ip`'s family, if the network has one.
+    pub fn gateway(&self, ip: IpAddr) -> Option<IpAddr> {
+        self.gateways().into_iter().find(|gateway| gateway.is_ipv4() == ip.is_ipv4())
+    }
+
+    /// Prefix length of the subnet for addresses of `ip`'s family, if the network has one.
+    pub fn prefix(&self, ip: IpAddr) -> Option<u8> {
+        self.subnets().into_iter()
+            .find(|(subnet, _)| subnet.is_ipv4() == ip.is_ipv4())
+            .map(|(_, prefix)| prefix)
+    }
+
+    fn default_network() -> Self {
         Self {
-            bridge_name,
-            network_config: NetworkConfig::default(),
-            allocated_ips: HashMap::new(),
-            port_mappings: HashMap::new(),
+            name: DEFAULT_NETWORK.to_string(),
+            bridge: DEFAULT_BRIDGE.to_string(),
+            subnet: NetworkConfig::default(),
+            gateway_ipv4: Some(Ipv4Addr::new(172, 17, 0, 1)),
+            gateway_ipv6: None,
+            mtu: None,
+            internal: false,
+            reserved: Vec::new(),
+            created_at: DateTime::UNIX_EPOCH,
         }
     }
+}
 
-    pub fn with_config(bridge_name: String, config: NetworkConfig) -> Self {
-        Self {
-            bridge_name,
-            network_config: config,
-            allocated_ips: HashMap::new(),
-            port_mappings: HashMap::new(),
+/// What to create a network with. The bridge, subnet and gateways are picked when unset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NetworkOptions {
+    pub name: String,
+    pub bridge: Option<String>,
+    pub subnet: Option<NetworkConfig>,
+    pub gateway_ipv4: Option<Ipv4Addr>,
+    pub gateway_ipv6: Option<Ipv6Addr>,
+    pub mtu: Option<u32>,
+    pub internal: bool,
+    pub reserved: Vec<IpRange>,
+}
+
+/// A container's connection to a network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkAttachment {
+    pub network: String,
+    /// The container's interface on the network, e.g. `eth0`.
+    pub interface: String,
+    pub ips: Vec<IpAddr>,
+    /// Names other containers on the network find it by, besides its name and hostname.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+/// A network with the containers connected to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkDetails {
+    #[serde(flatten)]
+    pub network: Network,
+    pub containers: Vec<NetworkEndpoint>,
+}
+
+/// A container's end of a network.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkEndpoint {
+    pub container_id: String,
+    pub container_name: String,
+    pub interface: String,
+    pub ips: Vec<IpAddr>,
+    pub aliases: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterfaceStats {
+    pub name: String,
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+}
+
+pub struct NetworkManager {
+    /// Where user-defined networks are stored, one `<name>.json` each.
+    dir: PathBuf,
+    networks: BTreeMap<String, Network>,
+    ipam: Ipam,
+    /// Owner of each published (host address, host port, protocol); no address means
+    /// every address.
+    port_mappings: HashMap<(Option<IpAddr>, u16, PortProtocol), String>,
+    firewall: Box<dyn Firewall>,
+    /// Set once `enable_proxies` ran; only then are ports of containers in proxy mode
+    /// forwarded. The CLI's in-process runtime never sets it, as nothing would forward
+    /// them once it exits.
+    proxying: bool,
+    /// Proxies of the running containers in proxy mode.
+    proxies: HashMap<String, Vec<PortProxy>>,
+}
+
+impl NetworkManager {
+    /// Loads the networks stored under `<base_path>/state/networks`, next to the
+    /// default network.
+    pub fn open<P: AsRef<Path>>(base_path: P) -> Result<Self> {
+        let dir = base_path.as_ref().join("state").join("networks");
+        let ipam = Ipam::open(&base_path)?;
+        let mut networks = BTreeMap::new();
+
+        fs::create_dir_all(&dir)?;
+        networks.insert(DEFAULT_NETWORK.to_string(), Network::default_network());
+
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+
+            match load_network(&path) {
+                Ok(network) => {
+                    networks.insert(network.name.clone(), network);
+                }
+                Err(e) => eprintln!("Warning: Failed to load network from {:?}: {}", path, e),
+            }
         }
+
+        Ok(Self {
+            dir,
+            networks,
+            ipam,
+            port_mappings: HashMap::new(),
+            firewall: firewall::detect(),
+            proxying: false,
+            proxies: HashMap::new(),
+        })
     }
 
-    pub fn setup_bridge(&self) -> Result<()> {
-        if !self.bridge_exists()? {
-            self.create_bridge()?;
-            self.configure_bridge()?;
+    /// Takes note of the addresses and ports of existing containers, e.g. after a restart
+    /// of the runtime.
+    pub fn restore(&mut self, containers: &[&Container]) {
+        self.ipam.restore(&self.networks, containers);
+
+        for container in containers {
+            for port in &container.config.ports {
+                for host_port in port.host_ports() {
+                    self.port_mappings.insert((port.host_ip, host_port, port.protocol), container.id.clone());
+                }
+            }
         }
+    }
 
-        Ok(())
+    pub fn networks(&self) -> Vec<Network> {
+        self.networks.values().cloned().collect()
     }
 
-    fn bridge_exists(&self) -> Result<bool> {
-        let output = Command::new("ip")
-            .args(&["link", "show", &self.bridge_name])
-            .output()?;
+    pub fn network(&self, name: &str) -> Result<&Network> {
+        self.networks.get(name)
+            .ok_or_else(|| TurbineError::NetworkNotFound(format!("No such network: {}", name)))
+    }
 
-        Ok(output.status.success())
+    /// The network whose bridge is `bridge`.
+    pub fn network_by_bridge(&self, bridge: &str) -> Result<&Network> {
+        self.networks.values()
+            .find(|network| network.bridge == bridge)
+            .ok_or_else(|| TurbineError::NetworkNotFound(format!("No network uses bridge {}", bridge)))
     }
 
-    fn create_bridge(&self) -> Result<()> {
-        let output = Command::new("ip")
-            .args(&["link", "add", "name", &self.bridge_name, "type", "bridge"])
-            .output()?;
-        if !output.status.success() {
-            return Err(TurbineError::NetworkError(
-                format!("Failed to create bridge: {}", String::from_utf8_lossy(&output.stderr))
-            ));
+    /// Creates, stores and sets up a network.
+    pub fn create_network(&mut self, options: NetworkOptions) -> Result<Network> {
+        if !is_valid_network_name(&options.name) {
+            return Err(TurbineError::ConfigError(format!("Invalid network name: {}", options.name)));
         }
 
-        let output = Command::new("ip")
-            .args(&["link", "set", "dev", &self.bridge_name, "up"])
-            .output()?;
-        if !output.status.success() {
-            return Err(TurbineError::NetworkError(
-                format!("Failed to bring up bridge: {}", String::from_utf8_lossy(&output.stderr))
-            ));
+        if self.networks.contains_key(&options.name) {
+            return Err(TurbineError::NetworkExists(format!("Network {} already exists", options.name)));
         }
 
-        Ok(())
-    }
+        let bridge = match options.bridge {
+            Some(bridge) => bridge,
+            None => self.free_bridge_name()?,
+        };
+        if !is_valid_interface_name(&bridge) {
+            return Err(TurbineError::ConfigError(format!("Invalid bridge name: {}", bridge)));
+        }
 
-    fn configure_bridge(&self) -> Result<()> {
-        match &self.network_config {
-            NetworkConfig::IPv4 { subnet, prefix } => {
-                let octets = subnet.octets();
-                let bridge_ip = format!("{}.{}.{}.1/{}", octets[0], octets[1], octets[2], prefix);
-                self.add_bridge_address(&bridge_ip)?;
-            }
-            NetworkConfig::IPv6 { subnet, prefix } => {
-                let segments = subnet.segments();
-                let bridge_ip = format!("{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:1/{}",
-                                        segments[0], segments[1], segments[2], segments[3],
-                                        segments[4], segments[5], segments[6], prefix);
-                self.add_bridge_address(&bridge_ip)?;
+        if let Some(other) = self.networks.values().find(|network| network.bridge == bridge) {
+            return Err(TurbineError::NetworkExists(format!("Bridge {} is used by network {}", bridge, other.name)));
+        }
+
+        let subnet = match options.subnet {
+            Some(subnet) => subnet,
+            None => self.free_subnet()?,
+        };
+
+        for (address, prefix) in subnet.subnets() {
+            let max_prefix = if address.is_ipv4() { 30 } else { 126 };
+            if prefix == 0 || prefix > max_prefix || ipam::masked(address, prefix) != address {
+                return Err(TurbineError::ConfigError(format!("Invalid subnet: {}/{}", address, prefix)));
             }
-            NetworkConfig::DualStack { ipv4_subnet, ipv4_prefix, ipv6_subnet, ipv6_prefix } => {
-                let octets = ipv4_subnet.octets();
-                let ipv4_bridge_ip = format!("{}.{}.{}.1/{}", octets[0], octets[1], octets[2], ipv4_prefix);
-                self.add_bridge_address(&ipv4_bridge_ip)?;
-
-                let segments = ipv6_subnet.segments();
-                let ipv6_bridge_ip = format!("{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:1/{}",
-                                             segments[0], segments[1], segments[2], segments[3],
-                                             segments[4], segments[5], segments[6], ipv6_prefix);
-                self.add_bridge_address(&ipv6_bridge_ip)?;
+
+            if let Some(other) = self.networks.values().find(|network| {
+                network.subnets().into_iter().any(|(other, other_prefix)| ipam::overlaps((address, prefix), (other, other_prefix)))
+            }) {
+                return Err(TurbineError::NetworkExists(format!(
+                    "Subnet {}/{} overlaps network {}", address, prefix, other.name
+                )));
             }
         }
 
-        Ok(())
-    }
+        if options.mtu.is_some_and(|mtu| !(68..=65535).contains(&mtu)) {
+            return Err(TurbineError::ConfigError("MTU must be between 68 and 65535".to_string()));
+        }
+
+        let requested = [options.gateway_ipv4.map(IpAddr::V4), options.gateway_ipv6.map(IpAddr::V6)];
 
-    fn add_bridge_address(&self, ip_with_prefix: &str) -> Result<()> {
-        let output = Command::new("ip")
-            .args(&["addr", "add", ip_with_prefix, "dev", &self.bridge_name])
-            .output()?;
-        if !output.status.success() {
-            return Err(TurbineError::NetworkError(
-                format!("Failed to configure bridge IP {}: {}",
-                    ip_with_prefix, String::from_utf8_lossy(&output.stderr))
-            ));
+        for gateway in requested.iter().flatten() {
+            if subnet.subnets().iter().all(|(address, _)| address.is_ipv4() != gateway.is_ipv4()) {
+                return Err(TurbineError::ConfigError(format!("Network has no subnet for gateway {}", gateway)));
+            }
         }
-        Ok(())
-    }
 
-    pub fn setup_container_network(&mut self, container: &Container) -> Result<()> {
-        let container_ips = self.allocate_ips(&container.id)?;
-        let veth_host = format!("veth-{}", &container.id[..8]);
-        let veth_container = format!("veth-c-{}", &container.id[..8]);
+        let mut gateways = Vec::new();
 
-        self.create_veth_pair(&veth_host, &veth_container)?;
-        self.attach_to_bridge(&veth_host)?;
+        for (address, prefix) in subnet.subnets() {
+            let gateway = requested.iter()
+                .flatten()
+                .find(|gateway| gateway.is_ipv4() == address.is_ipv4())
+                .copied()
+                .unwrap_or_else(|| ipam::offset(address, 1));
+
+            if !ipam::usable_range(address, prefix).contains(gateway) {
+                return Err(TurbineError::ConfigError(format!(
+                    "Gateway {} is not a host address of {}/{}", gateway, address, prefix
+                )));
+            }
 
-        for ip in &container_ips {
-            self.configure_container_interface(&veth_container, *ip)?;
+            gateways.push(gateway);
         }
 
-        for port in &container.config.ports {
-            if self.port_mappings.contains_key(&port.host_port) {
-                return Err(TurbineError::NetworkError(
-                    format!("Port {} is already in use", port.host_port)
-                ));
+        for range in &options.reserved {
+            if !subnet.subnets().iter().any(|(address, prefix)| {
+                ipam::masked(range.start, *prefix) == *address && ipam::masked(range.end, *prefix) == *address
+            }) {
+                return Err(TurbineError::ConfigError(format!("Reserved range {} is not within a subnet of the network", range)));
             }
+        }
 
-            let target_ip = container_ips.iter()
-                .find(|ip| ip.is_ipv4())
-                .or_else(|| container_ips.first())
-                .ok_or_else(|| TurbineError::NetworkError("No IP allocated for container".to_string()))?;
+        let network = Network {
+            name: options.name,
+            bridge,
+            subnet,
+            gateway_ipv4: gateways.iter().find_map(|gateway| match gateway {
+                IpAddr::V4(gateway) => Some(*gateway),
+                IpAddr::V6(_) => None,
+            }),
+            gateway_ipv6: gateways.iter().find_map(|gateway| match gateway {
+                IpAddr::V6(gateway) => Some(*gateway),
+                IpAddr::V4(_) => None,
+            }),
+            mtu: options.mtu,
+            internal: options.internal,
+            reserved: options.reserved,
+            created_at: Utc::now(),
+        };
 
-            self.setup_port_forwarding(port.host_port, *target_ip, port.container_port)?;
-            self.port_mappings.insert(port.host_port, container.id.clone());
+        self.setup_bridge(&network)?;
+        if let Err(e) = write_atomic(&self.network_path(&network.name), &serde_json::to_vec_pretty(&network)?) {
+            let _ = self.remove_bridge(&network);
+            return Err(e);
         }
 
-        Ok(())
+        self.networks.insert(network.name.clone(), network.clone());
+        Ok(network)
     }
 
-    fn allocate_ips(&mut self, container_id: &str) -> Result<Vec<IpAddr>> {
-        if let Some(existing_ips) = self.allocated_ips.get(container_id) {
-            return Ok(existing_ips.clone());
+    /// Removes a network no container is connected to, along with its bridge.
+    pub fn remove_network(&mut self, name: &str) -> Result<()> {
+        if name == DEFAULT_NETWORK {
+            return Err(TurbineError::NetworkError("The default network cannot be removed".to_string()));
         }
 
-        let mut ips = Vec::new();
+        let network = self.network(name)?.clone();
+        if self.ipam.has_leases(name) {
+            return Err(TurbineError::NetworkError(format!("Network {} has containers connected to it", name)));
+        }
 
-        match &self.network_config {
-            NetworkConfig::IPv4 { subnet, .. } => {
-                let ipv4 = self.allocate_ipv4(container_id, *subnet)?;
+        self.remove_bridge(&network)?;
 
-                ips.push(IpAddr::V4(ipv4));
-            }
-            NetworkConfig::IPv6 { subnet, .. } => {
-                let ipv6 = self.allocate_ipv6(container_id, *subnet)?;
+        match fs::remove_file(self.network_path(name)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
 
-                ips.push(IpAddr::V6(ipv6));
-            }
-            NetworkConfig::DualStack { ipv4_subnet, ipv6_subnet, .. } => {
-                let ipv4 = self.allocate_ipv4(container_id, *ipv4_subnet)?;
-                let ipv6 = self.allocate_ipv6(container_id, *ipv6_subnet)?;
+        self.networks.remove(name);
+        self.ipam.remove_network(name)
+    }
 
-                ips.push(IpAddr::V4(ipv4));
-                ips.push(IpAddr::V6(ipv6));
+    /// Sets up the bridge of every network. Only a failure with the default network is
+    /// an error; the others are warned about.
+    pub fn setup_bridges(&self) -> Result<()> {
+        for network in self.networks.values() {
+            match self.setup_bridge(network) {
+                Ok(()) => {}
+                Err(e) if network.name == DEFAULT_NETWORK => return Err(e),
+                Err(e) => eprintln!("Warning: Failed to set up network {}: {}", network.name, e),
             }
         }
 
-        self.allocated_ips.insert(container_id.to_string(), ips.clone());
-        Ok(ips)
+        Ok(())
     }
 
-    fn allocate_ipv4(&self, container_id: &str, subnet: Ipv4Addr) -> Result<Ipv4Addr> {
-        if let Some(existing_ips) = self.allocated_ips.get(container_id) {
-            if let Some(ipv4) = existing_ips.iter().find_map(|ip| match ip {
-                IpAddr::V4(v4) => Some(*v4),
-                                                             _ => None,
-            }) {
-                return Ok(ipv4);
-            }
+    /// Creates the bridge if needed and gives it the gateway addresses. Safe to call on
+    /// an already configured bridge.
+    fn setup_bridge(&self, network: &Network) -> Result<()> {
+        let mut netlink = Netlink::open()?;
+
+        match netlink.create_bridge(&network.bridge) {
+            Ok(()) | Err(TurbineError::NetworkExists(_)) => {}
+            Err(e) => return Err(e),
         }
 
-        let mut ip_suffix = 2u8;
-        let octets = subnet.octets();
+        if let Some(mtu) = network.mtu {
+            netlink.set_mtu(&network.bridge, mtu)?;
+        }
 
-        loop {
-            let ip = Ipv4Addr::new(octets[0], octets[1], octets[2], ip_suffix);
-            let ip_addr = IpAddr::V4(ip);
+        netlink.set_up(&network.bridge)?;
 
-            if !self.allocated_ips.values().any(|ips| ips.contains(&ip_addr)) {
-                return Ok(ip);
-            }
+        for gateway in network.gateways() {
+            let prefix = network.prefix(gateway).unwrap_or_default();
 
-            ip_suffix += 1;
-            if ip_suffix == 255 {
-                return Err(TurbineError::NetworkError(
-                    "No available IPv4 addresses in subnet".to_string()
-                ));
+            match netlink.add_address(&network.bridge, gateway, prefix) {
+                Ok(()) | Err(TurbineError::NetworkExists(_)) => {}
+                Err(e) => return Err(e),
             }
         }
+
+        // Containers still reach each other and the host without NAT, so a host
+        // without a usable firewall only loses outbound traffic and published ports.
+        firewall::enable_forwarding(network);
+        if let Err(e) = self.firewall.setup(network) {
+            eprintln!("Warning: Failed to set up {} rules for network {}: {}", self.firewall.name(), network.name, e);
+        }
+
+        Ok(())
     }
 
-    fn allocate_ipv6(&self, container_id: &str, subnet: Ipv6Addr) -> Result<Ipv6Addr> {
-        if let Some(existing_ips) = self.allocated_ips.get(container_id) {
-            if let Some(ipv6) = existing_ips.iter().find_map(|ip| match ip {
-                IpAddr::V6(v6) => Some(v6),
-                _ => None,
-            }) {
-                return Ok(*ipv6);
+    fn remove_bridge(&self, network: &Network) -> Result<()> {
+        if let Err(e) = self.firewall.teardown(network) {
+            eprintln!("Warning: Failed to remove {} rules of network {}: {}", self.firewall.name(), network.name, e);
+        }
+
+        match Netlink::open()?.delete_link(&network.bridge) {
+            Ok(()) | Err(TurbineError::NetworkNotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Connects the container to the networks its configuration names, or to the one
+    /// using its configured bridge, or to the default network, and forwards its published
+    /// ports to its addresses on the first of them. Its interfaces only exist while it
+    /// runs; see `connect_container`.
+    pub fn setup_container_network(&mut self, container: &mut Container) -> Result<()> {
+        let config = &container.config.network;
+        let names = if !config.networks.is_empty() {
+            config.networks.clone()
+        } else if let Some(bridge) = &config.bridge {
+            vec![self.network_by_bridge(bridge)?.name.clone()]
+        } else {
+            vec![DEFAULT_NETWORK.to_string()]
+        };
+
+        for ip in &container.config.network.ips {
+            let in_subnet = names.iter().any(|name| self.network(name).is_ok_and(|network| {
+                network.subnets().into_iter().any(|(subnet, prefix)| ipam::masked(*ip, prefix) == subnet)
+            }));
+            if !in_subnet {
+                return Err(TurbineError::NetworkError(format!(
+                    "Address {} is not in a subnet of the container's networks", ip
+                )));
             }
         }
 
-        let mut host_suffix = 2u16;
-        let segments = subnet.segments();
+        for port in &container.config.ports {
+            for host_port in port.host_ports() {
+                if let Some(owner) = self.port_owner(port.host_ip, host_port, port.protocol) {
+                    return Err(TurbineError::NetworkError(format!(
+                        "Port {}/{} is already in use by container {}", host_port, port.protocol, owner
+                    )));
+                }
+            }
 
-        loop {
-            let ip = Ipv6Addr::new(
-                segments[0], segments[1], segments[2], segments[3],
-                segments[4], segments[5], segments[6], host_suffix
-            );
-            let ip_addr = IpAddr::V6(ip);
-            if !self.allocated_ips.values().any(|ips| ips.contains(&ip_addr)) {
-                return Ok(ip);
+            if port.host_ip.is_some_and(|host_ip| self.network(&names[0]).is_ok_and(|network| network.prefix(host_ip).is_none())) {
+                return Err(TurbineError::NetworkError(format!(
+                    "Cannot publish {}: network {} has no addresses of that family", port, names[0]
+                )));
             }
+        }
 
-            host_suffix += 1;
-            if host_suffix == 0xFFFF {
-                return Err(TurbineError::NetworkError(
-                    format!("No available IPv6 addresses in subnet for container {}", container_id)
-                ));
+        container.networks.clear();
+        for name in &names {
+            match self.attach(container, name) {
+                Ok(attachment) => container.networks.push(attachment),
+                Err(e) => {
+                    self.release(container);
+                    return Err(e);
+                }
             }
         }
-    }
 
-    fn create_veth_pair(&self, host_name: &str, container_name: &str) -> Result<()> {
-        let output = Command::new("ip")
-            .args(&["link", "add", host_name, "type", "veth", "peer", "name", container_name])
-            .output()?;
-        if !output.status.success() {
-            return Err(TurbineError::NetworkError(
-                format!("Failed to create veth pair: {}", String::from_utf8_lossy(&output.stderr))
-            ));
+        // Proxied ports are forwarded while the container runs; see `start_proxies`.
+        let published = match container.config.port_mode {
+            PortMode::Nat => self.firewall.publish(&container.id, &container.networks[0].ips, &container.config.ports),
+            PortMode::Proxy => Ok(()),
+        };
+        if let Err(e) = published {
+            // The iptables backend commits each address family on its own.
+            let _ = self.firewall.unpublish(&container.id);
+            self.release(container);
+            return Err(e);
+        }
+
+        for port in &container.config.ports {
+            for host_port in port.host_ports() {
+                self.port_mappings.insert((port.host_ip, host_port, port.protocol), container.id.clone());
+            }
         }
 
         Ok(())
     }
 
-    fn attach_to_bridge(&self, interface: &str) -> Result<()> {
-        let output = Command::new("ip")
-            .args(&["link", "set", interface, "master", &self.bridge_name])
-            .output()?;
-        if !output.status.success() {
-            return Err(TurbineError::NetworkError(
-                format!("Failed to attach to bridge: {}", String::from_utf8_lossy(&output.stderr))
-            ));
+    /// Leases the container addresses on a network it is not yet connected to, the ones
+    /// its configuration asks for where they fall in the network's subnets, and picks its
+    /// next free interface for it. The attachment gets the configured aliases.
+    pub fn attach(&mut self, container: &Container, network: &str) -> Result<NetworkAttachment> {
+        let network = self.networks.get(network)
+            .ok_or_else(|| TurbineError::NetworkNotFound(format!("No such network: {}", network)))?;
+        if container.networks.iter().any(|attachment| attachment.network == network.name) {
+            return Err(TurbineError::NetworkExists(format!(
+                "Container {} is already connected to network {}", container.id, network.name
+            )));
         }
 
-        let output = Command::new("ip")
-            .args(&["link", "set", "dev", interface, "up"])
-            .output()?;
-        if !output.status.success() {
-            return Err(TurbineError::NetworkError(
-                format!("Failed to bring up interface: {}", String::from_utf8_lossy(&output.stderr))
-            ));
+        let interface = (0..MAX_CONTAINER_INTERFACES)
+            .map(|index| format!("eth{}", index))
+            .find(|interface| container.networks.iter().all(|attachment| attachment.interface != *interface))
+            .ok_or_else(|| TurbineError::NetworkError(format!("Container {} is on too many networks", container.id)))?;
+        let ips = self.ipam.allocate(network, &container.id, &container.config.network.ips)?;
+
+        Ok(NetworkAttachment {
+            network: network.name.clone(),
+            interface,
+            ips,
+            aliases: container.config.network.aliases.clone(),
+        })
+    }
+
+    /// Releases the container's addresses on `network`.
+    pub fn detach(&mut self, container_id: &str, network: &str) {
+        self.ipam.release(network, container_id);
+    }
+
+    fn release(&mut self, container: &Container) {
+        self.ipam.release_all(&container.id);
+    }
+
+    /// Connects the network namespace of the container's init, `pid`, to the bridge of
+    /// each network the container is on.
+    pub fn connect_container(&mut self, container: &Container, pid: u32) -> Result<()> {
+        let mut routed = false;
+
+        for attachment in &container.networks {
+            let internal = self.network(&attachment.network)?.internal;
+
+            self.connect_interface(container, attachment, pid, !routed && !internal)?;
+            routed |= !internal;
         }
 
         Ok(())
     }
 
-    fn configure_container_interface(&self, interface: &str, ip: IpAddr) -> Result<()> {
-        let (ip_with_mask, family) = match ip {
-            IpAddr::V4(ipv4) => {
-                let prefix = match &self.network_config {
-                    NetworkConfig::IPv4 { prefix, .. } => prefix,
-                    NetworkConfig::DualStack { ipv4_prefix, .. } => ipv4_prefix,
-                    _ => &24u8,
-                };
-                (format!("{}/{}", ipv4, prefix), "-4")
-            }
-            IpAddr::V6(ipv6) => {
-                let prefix = match &self.network_config {
-                    NetworkConfig::IPv6 { prefix, .. } => prefix,
-                    NetworkConfig::DualStack { ipv6_prefix, .. } => ipv6_prefix,
-                    _ => &64u8,
-                };
-                (format!("{}/{}", ipv6, prefix), "-6")
+    /// Connects the network namespace of `pid` to the attachment's network with a veth
+    /// pair. The container's end is moved into the namespace under the attachment's
+    /// interface name, with its addresses and, if `default_route` is set, default routes
+    /// via the bridge. The pair goes away with the namespace when the container stops.
+    pub fn connect_interface(&mut self, container: &Container, attachment: &NetworkAttachment, pid: u32, default_route: bool) -> Result<()> {
+        let network = self.network(&attachment.network)?;
+
+        for ip in &attachment.ips {
+            if self.ipam.holder(&network.name, *ip) != Some(container.id.as_str()) {
+                return Err(TurbineError::NetworkError(format!(
+                    "Address {} on network {} is not leased to container {}; disconnect it from the network and connect it again",
+                    ip, network.name, container.id
+                )));
             }
-        };
+        }
+
+        let (veth_host, veth_container) = veth_names(container, attachment);
+        let mut netlink = Netlink::open()?;
 
-        let output = Command::new("ip")
-            .args(&[family, "addr", "add", &ip_with_mask, "dev", interface])
-            .output()?;
-        if !output.status.success() {
-            return Err(TurbineError::NetworkError(
-                format!("Failed to configure container interface: {}", String::from_utf8_lossy(&output.stderr))
-            ));
+        // Left behind if a previous start failed before the pair was moved.
+        match netlink.delete_link(&veth_host) {
+            Ok(()) | Err(TurbineError::NetworkNotFound(_)) => {}
+            Err(e) => return Err(e),
         }
 
-        Ok(())
+        netlink.create_veth_pair(&veth_host, &veth_container)?;
+
+        let connected = network.mtu
+            .map_or(Ok(()), |mtu| netlink.set_mtu(&veth_host, mtu).and_then(|_| netlink.set_mtu(&veth_container, mtu)))
+            .and_then(|_| netlink.set_master(&veth_host, &network.bridge))
+            .and_then(|_| netlink.set_up(&veth_host))
+            .and_then(|_| netlink.move_to_namespace(&veth_container, pid, &attachment.interface))
+            .and_then(|_| configure_container_interface(network, pid, attachment, default_route));
+        if connected.is_err() {
+            let _ = netlink.delete_link(&veth_host);
+        }
+
+        connected
     }
 
-    fn setup_port_forwarding(&self, host_port: u16, container_ip: IpAddr, container_port: u16) -> Result<()> {
-        let (iptables_cmd, rule) = match container_ip {
-            IpAddr::V4(ipv4) => (
-                "iptables",
-                format!("DNAT --to-destination {}:{}", ipv4, container_port)
-            ),
-            IpAddr::V6(ipv6) => (
-                "ip6tables",
-                format!("DNAT --to-destination [{}]:{}", ipv6, container_port)
-            ),
-        };
+    /// Removes the container's interface on the attachment's network. Deleting the host
+    /// end of the pair deletes the container's end too.
+    pub fn disconnect_interface(&mut self, container: &Container, attachment: &NetworkAttachment) -> Result<()> {
+        let (veth_host, _) = veth_names(container, attachment);
 
-        let output = Command::new(iptables_cmd)
-            .args(&[
-                "-t", "nat",
-                "-A", "PREROUTING",
-                "-p", "tcp",
-                "--dport", &host_port.to_string(),
-                "-j", &rule
-            ])
-            .output()?;
-        if !output.status.success() {
-            return Err(TurbineError::NetworkError(
-                format!("Failed to setup port forwarding: {}", String::from_utf8_lossy(&output.stderr))
-            ));
+        match Netlink::open()?.delete_link(&veth_host) {
+            Ok(()) | Err(TurbineError::NetworkNotFound(_)) => Ok(()),
+            Err(e) => Err(e),
         }
+    }
 
-        Ok(())
+    /// Lets `start_proxies` forward ports from now on.
+    pub fn enable_proxies(&mut self) {
+        self.proxying = true;
     }
 
-    pub fn cleanup_container_network(&mut self, container: &Container) -> Result<()> {
-        let veth_host = format!("veth-{}", &container.id[..8]);
-        let _ = Command::new("ip")
-            .args(&["link", "del", &veth_host])
-            .output();
+    /// Forwards each published port of a container in proxy mode to the port at the same
+    /// offset on its addresses on its first network, for each address family it has
+    /// there. Ports published on every address are proxied on every address of the
+    /// family, loopback included.
+    pub fn start_proxies(&mut self, container: &Container) -> Result<()> {
+        if container.config.port_mode != PortMode::Proxy || container.config.ports.is_empty() {
+            return Ok(());
+        }
 
-        for port in &container.config.ports {
-            self.cleanup_port_forwarding(port.host_port)?;
-            self.port_mappings.remove(&port.host_port);
+        if !self.proxying {
+            eprintln!("Warning: Ports of container {} are only forwarded while turbined runs", container.id);
+            return Ok(());
         }
 
-        self.allocated_ips.remove(&container.id);
+        let ips = container.networks.first().map(|attachment| attachment.ips.as_slice()).unwrap_or_default();
+        let mut proxies = Vec::new();
+
+        for port in &container.config.ports {
+            for ip in ips.iter().filter(|ip| port.host_ip.is_none_or(|host_ip| host_ip.is_ipv4() == ip.is_ipv4())) {
+                let listen_ip = port.host_ip.unwrap_or_else(|| proxy::unspecified(*ip));
 
+                for (host_port, container_port) in port.host_ports().zip(port.container_ports()) {
+                    let listen = SocketAddr::new(listen_ip, host_port);
+
+                    // Dropping the proxies started so far stops them.
+                    proxies.push(PortProxy::bind(port.protocol, listen, SocketAddr::new(*ip, container_port))?);
+                }
+            }
+        }
+
+        self.proxies.insert(container.id.clone(), proxies);
         Ok(())
     }
 
-    fn cleanup_port_forwarding(&self, host_port: u16) -> Result<()> {
-        for (cmd, family) in [("iptables", "IPv4"), ("ip6tables", "IPv6")] {
-            let output = Command::new(cmd)
-                .args(&[
-                    "-t", "nat",
-                    "-D", "PREROUTING",
-                    "-p", "tcp",
-                    "--dport", &host_port.to_string(),
-                    "-j", "DNAT"
-                ])
-                .output()?;
-            if !output.status.success() {
-                eprintln!(
-                    "Warning: Failed to cleanup {} port forwarding for port {}: {}",
-                    family, host_port, String::from_utf8_lossy(&output.stderr)
-                );
+    /// Stops the container's proxies taking new connections and hands them over, so the
+    /// open ones can finish while the container shuts down. Dropping them ends those.
+    pub fn close_proxies(&mut self, container_id: &str) -> Vec<PortProxy> {
+        let proxies = self.proxies.remove(container_id).unwrap_or_default();
+
+        for proxy in &proxies {
+            proxy.close();
+        }
+
+        proxies
+    }
+
+    pub fn proxy_stats(&self, container_id: &str) -> Vec<ProxyStats> {
+        self.proxies.get(container_id)
+            .map(|proxies| proxies.iter().map(PortProxy::stats).collect())
+            .unwrap_or_default()
+    }
+
+    /// The container publishing `port` on `host_ip`, or on every address if that is unset.
+    fn port_owner(&self, host_ip: Option<IpAddr>, port: u16, protocol: PortProtocol) -> Option<&String> {
+        self.port_mappings.iter()
+            .find(|((ip, published, published_protocol), _)| {
+                *published == port
+                    && *published_protocol == protocol
+                    && (ip.is_none() || host_ip.is_none() || *ip == host_ip)
+            })
+            .map(|(_, container_id)| container_id)
+    }
+
+    /// The first `turbine<N>` bridge name no network uses and no interface has.
+    fn free_bridge_name(&self) -> Result<String> {
+        let mut netlink = Netlink::open()?;
+
+        for number in 1.. {
+            let name = format!("{}{}", BRIDGE_PREFIX, number);
+            if name.len() > 15 {
+                break;
+            }
+
+            if self.networks.values().all(|network| network.bridge != name) && !netlink.link_exists(&name)? {
+                return Ok(name);
             }
         }
 
-        Ok(())
+        Err(TurbineError::NetworkError("No free bridge name".to_string()))
+    }
+
+    /// The first of 172.18.0.0/16 to 172.31.0.0/16 no network overlaps.
+    fn free_subnet(&self) -> Result<NetworkConfig> {
+        (18..=31)
+            .map(|second| NetworkConfig::IPv4 { subnet: Ipv4Addr::new(172, second, 0, 0), prefix: 16 })
+            .find(|candidate| self.networks.values().all(|network| {
+                network.subnets().into_iter().all(|subnet| !ipam::overlaps(subnet, candidate.subnets()[0]))
+            }))
+            .ok_or_else(|| TurbineError::NetworkError("No free subnet; pass one explicitly".to_string()))
     }
 
-    pub fn cleanup_bridge(&self) -> Result<()> {
-        if self.bridge_exists()? {
-            let output = Command::new("ip")
-                .args(&["link", "del", &self.bridge_name])
-                .output()?;
-            if !output.status.success() {
-                return Err(TurbineError::NetworkError(
-                    format!("Failed to cleanup bridge: {}", String::from_utf8_lossy(&output.stderr))
-                ));
+    fn network_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    pub fn cleanup_container_network(&mut self, container: &Container) -> Result<()> {
+        for attachment in &container.networks {
+            if let Err(e) = self.disconnect_interface(container, attachment) {
+                eprintln!("Warning: Failed to remove interface {} of container {}: {}", attachment.interface, container.id, e);
             }
         }
 
+        if let Err(e) = self.firewall.unpublish(&container.id) {
+            eprintln!("Warning: Failed to remove port forwarding of container {}: {}", container.id, e);
+        }
+
+        self.proxies.remove(&container.id);
+        self.port_mappings.retain(|_, container_id| *container_id != container.id);
+        self.release(container);
+
+        Ok(())
+    }
+
+    /// Removes the bridge and firewall rules of every network. Networks stay defined and
+    /// are set up again by `setup_bridges`.
+    pub fn cleanup_bridges(&self) -> Result<()> {
+        for network in self.networks.values() {
+            self.remove_bridge(network)?;
+        }
+
         Ok(())
     }
+}
+
+/// Configures the attachment's interface in the network namespace of `pid` with its
+/// addresses and, if `default_route` is set, default routes via the network's gateways,
+/// and brings it and loopback up.
+fn configure_container_interface(network: &Network, pid: u32, attachment: &NetworkAttachment, default_route: bool) -> Result<()> {
+    let mut netlink = Netlink::open_in(pid)?;
+
+    for ip in &attachment.ips {
+        let prefix = network.prefix(*ip).unwrap_or(match ip {
+            IpAddr::V4(_) => 24,
+            IpAddr::V6(_) => 64,
+        });
 
-    pub fn get_container_ips(&self, container_id: &str) -> Option<&Vec<IpAddr>> {
-        self.allocated_ips.get(container_id)
+        netlink.add_address(&attachment.interface, *ip, prefix)?;
     }
 
-    pub fn network_config(&self) -> &NetworkConfig {
-        &self.network_config
+    netlink.set_up("lo")?;
+    netlink.set_up(&attachment.interface)?;
+
+    if default_route {
+        for gateway in attachment.ips.iter().filter_map(|ip| network.gateway(*ip)) {
+            netlink.add_default_route(&attachment.interface, gateway)?;
+        }
     }
+
+    Ok(())
+}
+
+/// Names of the host and container ends of the veth pair for an attachment, before the
+/// container's end is renamed to the attachment's interface.
+fn veth_names(container: &Container, attachment: &NetworkAttachment) -> (String, String) {
+    let short_id = &container.id[..8];
+    let index = attachment.interface.trim_start_matches("eth");
+
+    (format!("veth{}{}", short_id, index), format!("vethc{}{}", short_id, index))
+}
+
+fn load_network(path: &Path) -> Result<Network> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+fn is_valid_network_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+fn is_valid_interface_name(name: &str) -> bool {
+    (1..=15).contains(&name.len())
+        && name != "."
+        && name != ".."
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// Byte and packet counters of every interface but loopback in the network namespace of `pid`.
+pub fn interface_stats(pid: u32) -> Result<Vec<InterfaceStats>> {
+    let content = std::fs::read_to_string(format!("/proc/{}/net/dev", pid))?;
+
+    Ok(content.lines()
+        .skip(2)
+        .filter_map(|line| {
+            let (name, counters) = line.split_once(':')?;
+            let counters: Vec<u64> = counters.split_whitespace()
+                .map(|value| value.parse().unwrap_or(0))
+                .collect();
+            if name.trim() == "lo" || counters.len() < 10 {
+                return None;
+            }
+
+            Some(InterfaceStats {
+                name: name.trim().to_string(),
+                rx_bytes: counters[0],
+                rx_packets: counters[1],
+                tx_bytes: counters[8],
+                tx_packets: counters[9],
+            })
+        })
+        .collect())
 }
@@ -1,248 +1,368 @@
-use crate::{Container, TurbineError, Result};
+use crate::attach::ContainerStreams;
+use crate::cgroup;
+use crate::image::resolve_user_spec;
+use crate::logs::{self, LogDriver};
+use crate::namespace::{self, ContainerProcess, ExecCommand};
+use crate::{Container, ExitStatus, TurbineError, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::pty::{openpty, Winsize};
 use nix::sys::signal::{self, Signal};
-use nix::unistd::Pid;
-use std::collections::HashMap;
-use std::os::unix::process::CommandExt;
-use std::process::Stdio;
-use tokio::process::Child;
+use nix::unistd::{pipe2, Pid};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::os::fd::OwnedFd;
+use std::sync::Arc;
+
+/// How long a container gets to exit after SIGTERM before it is killed.
+const STOP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
 
 pub struct ProcessManager {
-    running_processes: HashMap<String, Child>,
+    running_processes: HashMap<String, ContainerProcess>,
+    streams: HashMap<String, Arc<ContainerStreams>>,
+    /// Containers whose process is being stopped outside of this manager.
+    stopping: HashSet<String>,
 }
 
 impl ProcessManager {
     pub fn new() -> Self {
         Self {
             running_processes: HashMap::new(),
+            streams: HashMap::new(),
+            stopping: HashSet::new(),
         }
     }
 
-    pub async fn start_container(&mut self, container: &Container) -> Result<u32> {
-        let std_cmd = self.build_command(container)?;
-        let mut cmd = tokio::process::Command::from(std_cmd);
-
-        cmd.stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
-
-        let child = cmd.spawn()
-            .map_err(|e| TurbineError::ProcessError(format!("Failed to spawn process: {}", e)))?;
-        let pid = child.id().ok_or_else(|| {
-            TurbineError::ProcessError("Failed to get process ID".to_string())
-        })?;
+    /// Spawns the container's process and has its output collected into `driver` and
+    /// made available to attached clients. `connect` wires up the network namespace of
+    /// the process, whose PID it is given, before the command runs.
+    pub async fn start_container<F>(&mut self, container: &Container, driver: Box<dyn LogDriver>, connect: F) -> Result<u32>
+    where
+        F: FnOnce(u32) -> Result<()>,
+    {
+        let mut process = namespace::spawn(container, connect)?;
+        let pid = process.id();
+        let streams = Arc::new(ContainerStreams::new(process.take_stdin())?);
+
+        if let Some((stdout, stderr)) = process.take_output() {
+            logs::collect(driver, stdout, stderr, Arc::clone(&streams))?;
+        }
 
-        self.running_processes.insert(container.id.clone(), child);
+        self.running_processes.insert(container.id.clone(), process);
+        self.streams.insert(container.id.clone(), streams);
 
         Ok(pid)
     }
 
-    fn build_command(&self, container: &Container) -> Result<std::process::Command> {
-        let mut cmd = std::process::Command::new("unshare");
-
-        cmd.args(&[
-            "--pid",
-            "--net",
-            "--mount",
-            "--uts",
-            "--ipc",
-            "--fork",
-        ]);
+    /// Stops a container's process, killing it if it ignores SIGTERM (as a PID 1 without
+    /// a handler does), and returns how it ended. Containers started by another turbine
+    /// process are not tracked here, in which case the recorded `pid` is signalled
+    /// directly and its status is unknown.
+    pub async fn stop_container(&mut self, container_id: &str, pid: Option<u32>, force: bool) -> Result<Option<ExitStatus>> {
+        let process = self.begin_stop(container_id);
+        let status = stop_process(process, pid, force).await;
 
-        if let Some(user) = &container.config.user {
-            cmd.arg("--user");
-            cmd.arg(user);
-        }
+        self.finish_stop(container_id);
 
-        if let Some(uid) = container.config.uid {
-            unsafe {
-                cmd.pre_exec(move || {
-                    use nix::unistd::{setuid, Uid};
+        status
+    }
 
-                    setuid(Uid::from_raw(uid))
-                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    /// Hands out the container's process to be stopped with `stop_process` without
+    /// holding on to this manager. Until `finish_stop`, its exit is not reported by
+    /// `exit_status`.
+    pub fn begin_stop(&mut self, container_id: &str) -> Option<ContainerProcess> {
+        self.streams.remove(container_id);
+        self.stopping.insert(container_id.to_string());
+        self.running_processes.remove(container_id)
+    }
 
-                    Ok(())
-                });
-            }
-        }
+    pub fn finish_stop(&mut self, container_id: &str) {
+        self.stopping.remove(container_id);
+    }
 
-        if let Some(gid) = container.config.gid {
-            unsafe {
-                cmd.pre_exec(move || {
-                    use nix::unistd::{setgid, Gid};
+    pub fn is_stopping(&self, container_id: &str) -> bool {
+        self.stopping.contains(container_id)
+    }
 
-                    setgid(Gid::from_raw(gid))
-                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    pub async fn restart_container<F>(&mut self, container: &Container, driver: Box<dyn LogDriver>, connect: F) -> Result<u32>
+    where
+        F: FnOnce(u32) -> Result<()>,
+    {
+        self.stop_container(&container.id, container.pid, false).await?;
+        self.start_container(container, driver, connect).await
+    }
 
-                    Ok(())
-                });
-            }
+    /// Freezes every process in the container's cgroup. Containers without a cgroup
+    /// only have their init stopped.
+    pub async fn pause_container(&self, container: &Container) -> Result<()> {
+        match &container.cgroup_path {
+            Some(path) => cgroup::set_frozen(path, true).await,
+            None => self.signal_init(container, Signal::SIGSTOP),
         }
+    }
 
-        if let Some(ref groups) = container.config.groups {
-            let groups_clone = groups.clone();
+    pub async fn resume_container(&self, container: &Container) -> Result<()> {
+        match &container.cgroup_path {
+            Some(path) => cgroup::set_frozen(path, false).await,
+            None => self.signal_init(container, Signal::SIGCONT),
+        }
+    }
 
-            unsafe {
-                cmd.pre_exec(move || {
-                    use nix::unistd::{setgroups, Gid};
+    pub fn signal_init(&self, container: &Container, signal: Signal) -> Result<()> {
+        let pid = self.running_processes.get(&container.id)
+            .map(|child| child.id())
+            .or(container.pid)
+            .ok_or_else(|| TurbineError::ProcessError("Container has no running process".to_string()))?;
 
-                    let gids: Vec<Gid> = groups_clone
-                    .iter()
-                    .map(|&g| Gid::from_raw(g)).collect();
+        send_signal(pid, signal)
+    }
 
-                    setgroups(&gids)
-                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    /// The live stdio of a container started by this manager.
+    pub fn streams(&self, container_id: &str) -> Option<Arc<ContainerStreams>> {
+        self.streams.get(container_id).cloned()
+    }
 
-                    Ok(())
-                });
-            }
+    pub fn is_running(&self, container_id: &str) -> bool {
+        if let Some(child) = self.running_processes.get(container_id) {
+            is_alive(child.id())
+        } else {
+            false
         }
+    }
 
-        cmd.arg("chroot");
-        cmd.arg(&container.root_path);
+    /// Runs `command` in the container's namespaces and root, as the container's user
+    /// unless `options` override it. The caller gets the process and its side of the
+    /// process's terminal or pipes.
+    pub async fn execute_in_container(
+        &self,
+        container: &Container,
+        command: Vec<String>,
+        options: &ExecOptions,
+    ) -> Result<(ContainerProcess, ExecIo)> {
+        let pid = self.running_processes.get(&container.id)
+            .map(|child| child.id())
+            .or(container.pid)
+            .ok_or_else(|| TurbineError::ProcessError("Container has no running process".to_string()))?;
+        let (uid, gid, groups) = match &options.user {
+            Some(user) => {
+                let (uid, gid) = resolve_user_spec(&container.root_path, user)?;
+                (Some(uid), Some(gid), Some(vec![gid]))
+            }
+            None => {
+                let config = &container.config;
+                let groups = config.groups.clone().or(config.gid.map(|gid| vec![gid]));
 
-        if let Some(working_dir) = &container.config.working_dir {
-            cmd.current_dir(working_dir);
-        }
+                (config.uid, config.gid, groups)
+            }
+        };
+        let mut environment = namespace::process_environment(container);
 
-        for (key, value) in &container.config.environment {
-            cmd.env(key, value);
+        for pair in &options.env {
+            let (key, value) = pair.split_once('=')
+                .ok_or_else(|| TurbineError::ConfigError(format!("Environment variable must be KEY=VALUE: {}", pair)))?;
+            environment.insert(key.to_string(), value.to_string());
         }
 
-        cmd.args(&container.config.command);
+        let working_dir = options.workdir.clone()
+            .or_else(|| container.config.working_dir.clone())
+            .unwrap_or_else(|| "/".to_string());
+        let (stdio, io) = if options.tty {
+            let window_size = options.window_size.map(|(rows, columns)| Winsize {
+                ws_row: rows,
+                ws_col: columns,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            });
+            let pty = openpty(window_size.as_ref(), None)
+                .map_err(|e| TurbineError::ProcessError(format!("Failed to allocate a pseudo-terminal: {}", e)))?;
+
+            // openpty does not set close-on-exec, and neither end may leak into other
+            // containers' processes.
+            for fd in [&pty.master, &pty.slave] {
+                fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+                    .map_err(|e| TurbineError::ProcessError(format!("Failed to set up pseudo-terminal: {}", e)))?;
+            }
 
-        Ok(cmd)
+            ([pty.slave.try_clone()?, pty.slave.try_clone()?, pty.slave], ExecIo::Tty(pty.master))
+        } else {
+            let pipe = || pipe2(OFlag::O_CLOEXEC)
+                .map_err(|e| TurbineError::ProcessError(format!("Failed to create pipe: {}", e)));
+            let (stdin_r, stdin_w) = if options.interactive {
+                let (read, write) = pipe()?;
+                (read, Some(write))
+            } else {
+                (File::open("/dev/null")?.into(), None)
+            };
+            let (stdout_r, stdout_w) = pipe()?;
+            let (stderr_r, stderr_w) = pipe()?;
+
+            ([stdin_r, stdout_w, stderr_w], ExecIo::Pipes { stdin: stdin_w, stdout: stdout_r, stderr: stderr_r })
+        };
+        let command = ExecCommand {
+            command,
+            environment,
+            working_dir,
+            uid,
+            gid,
+            groups,
+            tty: options.tty,
+        };
+        let process = namespace::exec(container, pid, command, stdio)?;
+
+        Ok((process, io))
     }
 
+    /// Returns how the container's process ended, or `None` while it is still running.
+    /// Processes this manager did not start can only be seen to disappear, so their
+    /// status is unknown.
+    pub fn exit_status(&mut self, container_id: &str, pid: Option<u32>) -> Result<Option<ExitStatus>> {
+        if self.stopping.contains(container_id) {
+            return Ok(None);
+        }
 
+        if let Some(child) = self.running_processes.get_mut(container_id) {
+            return child.try_wait();
+        }
 
-    pub async fn stop_container(&mut self, container_id: &str, force: bool) -> Result<()> {
-        if let Some(mut child) = self.running_processes.remove(container_id) {
-            if force {
-                child.kill().await
-                    .map_err(|e| TurbineError::ProcessError(format!("Failed to kill process: {}", e)))?;
-            } else {
-                if let Some(pid) = child.id() {
-                    self.send_signal(pid, Signal::SIGTERM)?;
-
-                    tokio::time::timeout(
-                        std::time::Duration::from_secs(10),
-                                         child.wait()
-                    ).await
-                        .map_err(|_| TurbineError::ProcessError("Process did not terminate gracefully".to_string()))?
-                        .map_err(|e| TurbineError::ProcessError(format!("Failed to wait for process: {}", e)))?;
-                }
-            }
+        if pid.is_some_and(is_alive) {
+            return Ok(None);
         }
 
-        Ok(())
+        Ok(Some(ExitStatus { code: None, signal: None }))
     }
 
-    pub async fn restart_container(&mut self, container: &Container) -> Result<u32> {
-        self.stop_container(&container.id, false).await?;
-        self.start_container(container).await
+    /// Forgets the stdio of a container whose process has exited, so no client attaches
+    /// to it anymore. Clients still attached get the rest of its output.
+    pub fn remove_streams(&mut self, container_id: &str) {
+        self.streams.remove(container_id);
     }
 
-    pub fn pause_container(&self, container_id: &str) -> Result<()> {
-        if let Some(child) = self.running_processes.get(container_id) {
-            if let Some(pid) = child.id() {
-                self.send_signal(pid, Signal::SIGSTOP)?;
-            }
-        }
-
-        Ok(())
+    pub fn get_running_containers(&self) -> Vec<String> {
+        self.running_processes.keys().cloned().collect()
     }
 
-    pub fn resume_container(&self, container_id: &str) -> Result<()> {
-        if let Some(child) = self.running_processes.get(container_id) {
-            if let Some(pid) = child.id() {
-                self.send_signal(pid, Signal::SIGCONT)?;
-            }
+    pub async fn cleanup_all(&mut self) -> Result<()> {
+        let container_ids: Vec<String> = self.running_processes.keys().cloned().collect();
+
+        for container_id in container_ids {
+            self.stop_container(&container_id, None, true).await?;
         }
 
         Ok(())
     }
+}
 
-    fn send_signal(&self, pid: u32, signal: Signal) -> Result<()> {
-        let nix_pid = Pid::from_raw(pid as i32);
-
-        signal::kill(nix_pid, signal)
-            .map_err(|e| TurbineError::ProcessError(format!("Failed to send signal: {}", e)))?;
+/// How `execute_in_container` runs a command.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExecOptions {
+    /// Run the process on a new pseudo-terminal instead of pipes.
+    pub tty: bool,
+    /// Give the process a stdin the caller can write to.
+    pub interactive: bool,
+    /// `user[:group]`, by name or ID, instead of the container's user.
+    pub user: Option<String>,
+    /// `KEY=VALUE` pairs added to the container's environment.
+    pub env: Vec<String>,
+    pub workdir: Option<String>,
+    /// Initial terminal size as (rows, columns).
+    pub window_size: Option<(u16, u16)>,
+}
 
-        Ok(())
-    }
+/// The caller's side of an exec'd process's stdio.
+pub enum ExecIo {
+    /// Master side of the process's pseudo-terminal, used for both input and output.
+    Tty(OwnedFd),
+    Pipes {
+        stdin: Option<OwnedFd>,
+        stdout: OwnedFd,
+        stderr: OwnedFd,
+    },
+}
 
-    pub fn is_running(&self, container_id: &str) -> bool {
-        if let Some(child) = self.running_processes.get(container_id) {
-            child.id().is_some()
-        } else {
-            false
+impl ExecIo {
+    pub fn into_fds(self) -> Vec<OwnedFd> {
+        match self {
+            ExecIo::Tty(master) => vec![master],
+            ExecIo::Pipes { stdin, stdout, stderr } => stdin.into_iter().chain([stdout, stderr]).collect(),
         }
     }
 
-    pub async fn get_container_logs(&mut self, container_id: &str) -> Result<(String, String)> {
-        if let Some(child) = self.running_processes.get_mut(container_id) {
-            match child.try_wait() {
-                Ok(Some(_status)) => {
-                    let child = self.running_processes.remove(container_id).unwrap();
-                    let output = child.wait_with_output().await
-                        .map_err(|e| TurbineError::ProcessError(format!("Failed to get output: {}", e)))?;
-                    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-                    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-
-                    Ok((stdout, stderr))
-                }
-                Ok(None) => {
-                    Err(TurbineError::ProcessError("Container is still running".to_string()))
-                }
-                Err(e) => {
-                    Err(TurbineError::ProcessError(format!("Failed to check process status: {}", e)))
-                }
-            }
+    /// Rebuilds the layout `into_fds` produced, for a process with a pseudo-terminal
+    /// if `tty`, or with a stdin pipe if `stdin`.
+    pub fn from_fds(tty: bool, stdin: bool, fds: Vec<OwnedFd>) -> Result<Self> {
+        let mut fds = fds.into_iter();
+        let mut next = || fds.next()
+            .ok_or_else(|| TurbineError::ProcessError("Missing file descriptor for exec".to_string()));
+
+        Ok(if tty {
+            ExecIo::Tty(next()?)
         } else {
-            Err(TurbineError::ProcessError("Container not found".to_string()))
-        }
+            ExecIo::Pipes {
+                stdin: if stdin { Some(next()?) } else { None },
+                stdout: next()?,
+                stderr: next()?,
+            }
+        })
     }
+}
+
+/// Stops a process handed out by `ProcessManager::begin_stop`, or else the process
+/// `pid`, as `ProcessManager::stop_container` does.
+pub async fn stop_process(process: Option<ContainerProcess>, pid: Option<u32>, force: bool) -> Result<Option<ExitStatus>> {
+    if let Some(mut child) = process {
+        if !force && child.try_wait()?.is_none() {
+            send_signal(child.id(), Signal::SIGTERM)?;
 
-    pub async fn execute_in_container(&self, container: &Container, command: Vec<String>) -> Result<String> {
-        let mut cmd = tokio::process::Command::new("nsenter");
-        if let Some(child) = self.running_processes.get(&container.id) {
-            if let Some(pid) = child.id() {
-                cmd.args(&[
-                    "--target", &pid.to_string(),
-                     "--pid", "--net", "--mount", "--uts", "--ipc",
-                ]);
+            if let Ok(result) = tokio::time::timeout(STOP_TIMEOUT, child.wait()).await {
+                result?;
             }
         }
 
-        cmd.arg("chroot");
-        cmd.arg(&container.root_path);
-        cmd.args(&command);
+        child.kill().await?;
 
-        let output = cmd.output().await
-            .map_err(|e| TurbineError::ProcessError(format!("Failed to execute command: {}", e)))?;
-        if !output.status.success() {
-            return Err(TurbineError::ProcessError(
-                format!("Command failed: {}", String::from_utf8_lossy(&output.stderr))
-            ));
-        }
-
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        return child.try_wait();
     }
 
-    pub fn get_running_containers(&self) -> Vec<String> {
-        self.running_processes.keys().cloned().collect()
+    if let Some(pid) = pid.filter(|&pid| is_alive(pid)) {
+        stop_pid(pid, force).await?;
     }
 
-    pub async fn cleanup_all(&mut self) -> Result<()> {
-        let container_ids: Vec<String> = self.running_processes.keys().cloned().collect();
+    Ok(None)
+}
 
-        for container_id in container_ids {
-            self.stop_container(&container_id, true).await?;
+async fn stop_pid(pid: u32, force: bool) -> Result<()> {
+    let signal = if force { Signal::SIGKILL } else { Signal::SIGTERM };
+
+    send_signal(pid, signal)?;
+
+    let deadline = tokio::time::Instant::now() + STOP_TIMEOUT;
+
+    while is_alive(pid) {
+        if tokio::time::Instant::now() >= deadline {
+            if force {
+                return Err(TurbineError::ProcessError("Process did not terminate".to_string()));
+            }
+
+            return Box::pin(stop_pid(pid, true)).await;
         }
 
-        Ok(())
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     }
+
+    Ok(())
+}
+
+fn send_signal(pid: u32, signal: Signal) -> Result<()> {
+    let nix_pid = Pid::from_raw(pid as i32);
+
+    signal::kill(nix_pid, signal)
+        .map_err(|e| TurbineError::ProcessError(format!("Failed to send signal: {}", e)))?;
+
+    Ok(())
+}
+
+pub fn is_alive(pid: u32) -> bool {
+    !matches!(signal::kill(Pid::from_raw(pid as i32), None), Err(nix::errno::Errno::ESRCH))
 }
 
 impl Default for ProcessManager {
@@ -0,0 +1,434 @@
+use crate::config::PortProtocol;
+use crate::{TurbineError, Result};
+use chrono::{DateTime, Utc};
+use nix::sys::socket::{self, sockopt, AddressFamily, Backlog, SockFlag, SockType, SockaddrStorage};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+
+/// How long connecting to the container may take before the client is disconnected.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A UDP client without a datagram in either direction for this long is forgotten.
+const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait before accepting again after accepting failed, e.g. for lack of
+/// file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+const LISTEN_BACKLOG: i32 = 128;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Traffic through a proxy. Inbound bytes go from clients to the container, outbound
+/// ones back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyStats {
+    pub protocol: PortProtocol,
+    pub listen: SocketAddr,
+    pub target: SocketAddr,
+    /// Connections, or UDP clients, served since the proxy started.
+    pub total_connections: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Connections open now.
+    pub connections: Vec<ConnectionStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub client: SocketAddr,
+    pub opened_at: DateTime<Utc>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Default)]
+struct ProxyState {
+    total_connections: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    next_id: u64,
+    connections: BTreeMap<u64, (ConnectionStats, Instant)>,
+}
+
+/// Forwards connections to a published port on the host to the container, in
+/// userspace. Runs until dropped; `close` lets open connections finish first.
+pub struct PortProxy {
+    protocol: PortProtocol,
+    listen: SocketAddr,
+    target: SocketAddr,
+    state: Arc<Mutex<ProxyState>>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl PortProxy {
+    /// Listens on `listen` and forwards what arrives to `target`. IPv6 addresses only
+    /// take IPv6 clients, so both families can be proxied on the same port.
+    pub fn bind(protocol: PortProtocol, listen: SocketAddr, target: SocketAddr) -> Result<Self> {
+        let fd = bind_socket(protocol, listen).map_err(|e| TurbineError::NetworkError(format!(
+            "Failed to listen on {}/{}: {}", listen, protocol, e
+        )))?;
+        let state = Arc::new(Mutex::new(ProxyState::default()));
+        let (shutdown, closed) = watch::channel(false);
+        let task = match protocol {
+            PortProtocol::Tcp => {
+                let listener = TcpListener::from_std(std::net::TcpListener::from(fd))?;
+                tokio::spawn(serve_tcp(listener, target, Arc::clone(&state), closed))
+            }
+            PortProtocol::Udp => {
+                let socket = UdpSocket::from_std(std::net::UdpSocket::from(fd))?;
+                tokio::spawn(serve_udp(Arc::new(socket), target, Arc::clone(&state), closed))
+            }
+            PortProtocol::Sctp => {
+                return Err(TurbineError::NetworkError(format!("Cannot proxy SCTP port {}", listen.port())));
+            }
+        };
+
+        Ok(Self { protocol, listen, target, state, shutdown, task })
+    }
+
+    /// Stops taking new connections, and releases the port once the open ones have
+    /// finished. Dropping the proxy ends them right away.
+    pub fn close(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn stats(&self) -> ProxyStats {
+        let state = self.state.lock().unwrap();
+
+        ProxyStats {
+            protocol: self.protocol,
+            listen: self.listen,
+            target: self.target,
+            total_connections: state.total_connections,
+            bytes_in: state.bytes_in,
+            bytes_out: state.bytes_out,
+            connections: state.connections.values().map(|(stats, _)| stats.clone()).collect(),
+        }
+    }
+}
+
+impl Drop for PortProxy {
+    fn drop(&mut self) {
+        // Connection tasks belong to the proxy's task and go with it.
+        self.task.abort();
+    }
+}
+
+/// The unspecified address of `ip`'s family, to listen on every address of it.
+pub fn unspecified(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+fn bind_socket(protocol: PortProtocol, listen: SocketAddr) -> nix::Result<OwnedFd> {
+    let family = if listen.is_ipv4() { AddressFamily::Inet } else { AddressFamily::Inet6 };
+    let stream = protocol == PortProtocol::Tcp;
+    let fd = socket::socket(
+        family,
+        if stream { SockType::Stream } else { SockType::Datagram },
+        SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+        None,
+    )?;
+
+    if stream {
+        // Connections of the previous run may linger in TIME_WAIT after a restart.
+        socket::setsockopt(&fd, sockopt::ReuseAddr, &true)?;
+    }
+
+    if listen.is_ipv6() {
+        socket::setsockopt(&fd, sockopt::Ipv6V6Only, &true)?;
+    }
+
+    socket::bind(fd.as_raw_fd(), &SockaddrStorage::from(listen))?;
+
+    if stream {
+        socket::listen(&fd, Backlog::new(LISTEN_BACKLOG)?)?;
+    }
+
+    Ok(fd)
+}
+
+/// A connection's entry in the proxy's stats.
+#[derive(Clone)]
+struct Connection {
+    state: Arc<Mutex<ProxyState>>,
+    id: u64,
+}
+
+impl Connection {
+    fn open(state: &Arc<Mutex<ProxyState>>, client: SocketAddr) -> Self {
+        let mut locked = state.lock().unwrap();
+        let id = locked.next_id;
+        let stats = ConnectionStats { client, opened_at: Utc::now(), bytes_in: 0, bytes_out: 0 };
+
+        locked.next_id += 1;
+        locked.total_connections += 1;
+        locked.connections.insert(id, (stats, Instant::now()));
+
+        Self { state: Arc::clone(state), id }
+    }
+
+    fn record(&self, inbound: bool, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        let bytes = bytes as u64;
+
+        if inbound {
+            state.bytes_in += bytes;
+        } else {
+            state.bytes_out += bytes;
+        }
+
+        if let Some((stats, last_active)) = state.connections.get_mut(&self.id) {
+            if inbound {
+                stats.bytes_in += bytes;
+            } else {
+                stats.bytes_out += bytes;
+            }
+            *last_active = Instant::now();
+        }
+    }
+
+    fn last_active(&self) -> Instant {
+        self.state.lock().unwrap().connections.get(&self.id).map_or_else(Instant::now, |(_, last_active)| *last_active)
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().connections.remove(&self.id);
+    }
+}
+
+/// Accepts connections until closed, then waits for the open ones to finish.
+async fn serve_tcp(listener: TcpListener, target: SocketAddr, state: Arc<Mutex<ProxyState>>, mut closed: watch::Receiver<bool>) {
+    let mut relays = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((client, peer)) => {
+                    let connection = Connection::open(&state, peer);
+
+                    relays.spawn(async move {
+                        // The client sees the connection close either way.
+                        let _ = relay_tcp(client, target, &connection).await;
+                        connection.close();
+                    });
+                }
+                Err(e) => {
+                    eprintln!("Warning: Proxy to {} failed to accept a connection: {}", target, e);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                }
+            },
+            Some(_) = relays.join_next() => {}
+            _ = closed.changed() => break,
+        }
+    }
+
+    drop(listener);
+    while relays.join_next().await.is_some() {}
+}
+
+/// Connects to `target` and copies between it and the client until both sides have
+/// closed their end, or either fails.
+async fn relay_tcp(client: TcpStream, target: SocketAddr, connection: &Connection) -> std::io::Result<()> {
+    let upstream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(target)).await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    client.set_nodelay(true)?;
+    upstream.set_nodelay(true)?;
+
+    let (mut client_read, mut client_write) = client.into_split();
+    let (mut upstream_read, mut upstream_write) = upstream.into_split();
+
+    tokio::try_join!(
+        copy(&mut client_read, &mut upstream_write, connection, true),
+        copy(&mut upstream_read, &mut client_write, connection, false),
+    )?;
+
+    Ok(())
+}
+
+/// Copies until `reader` reaches its end, which is then passed on by shutting down
+/// `writer`.
+async fn copy<R, W>(reader: &mut R, writer: &mut W, connection: &Connection, inbound: bool) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+
+    loop {
+        let length = reader.read(&mut buffer).await?;
+        if length == 0 {
+            return writer.shutdown().await;
+        }
+
+        writer.write_all(&buffer[..length]).await?;
+        connection.record(inbound, length);
+    }
+}
+
+/// Datagrams of one client, sent to the container from a socket of their own so its
+/// replies can be told apart.
+struct Flow {
+    upstream: Arc<UdpSocket>,
+    connection: Connection,
+}
+
+/// Forwards datagrams of each client until closed, after which only known clients
+/// are served. Returns, releasing the port, once closed and the last flow has expired.
+async fn serve_udp(socket: Arc<UdpSocket>, target: SocketAddr, state: Arc<Mutex<ProxyState>>, mut closed: watch::Receiver<bool>) {
+    let mut flows: HashMap<SocketAddr, Flow> = HashMap::new();
+    let mut replies = JoinSet::new();
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut accepting = true;
+
+    while accepting || !flows.is_empty() {
+        tokio::select! {
+            received = socket.recv_from(&mut buffer) => {
+                let (length, client) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("Warning: Proxy to {} failed to receive a datagram: {}", target, e);
+                        return;
+                    }
+                };
+
+                let flow = match flows.entry(client) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(_) if !accepting => continue,
+                    Entry::Vacant(entry) => {
+                        let upstream = match connect_udp(target).await {
+                            Ok(upstream) => Arc::new(upstream),
+                            Err(e) => {
+                                eprintln!("Warning: Proxy failed to reach {}: {}", target, e);
+                                continue;
+                            }
+                        };
+                        let connection = Connection::open(&state, client);
+
+                        replies.spawn(relay_replies(Arc::clone(&socket), Arc::clone(&upstream), client, connection.clone()));
+                        entry.insert(Flow { upstream, connection })
+                    }
+                };
+
+                // Lost datagrams are UDP's to lose.
+                if flow.upstream.send(&buffer[..length]).await.is_ok() {
+                    flow.connection.record(true, length);
+                }
+            }
+            Some(Ok(client)) = replies.join_next() => {
+                if let Some(flow) = flows.remove(&client) {
+                    flow.connection.close();
+                }
+            }
+            changed = closed.changed(), if accepting => {
+                accepting = false;
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn connect_udp(target: SocketAddr) -> std::io::Result<UdpSocket> {
+    let upstream = UdpSocket::bind(SocketAddr::new(unspecified(target.ip()), 0)).await?;
+
+    upstream.connect(target).await?;
+    Ok(upstream)
+}
+
+/// Sends the container's replies to `client` from the proxy's socket until the flow has
+/// been idle for `UDP_FLOW_TIMEOUT`. Returns the client.
+async fn relay_replies(socket: Arc<UdpSocket>, upstream: Arc<UdpSocket>, client: SocketAddr, connection: Connection) -> SocketAddr {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+
+    loop {
+        let deadline = connection.last_active() + UDP_FLOW_TIMEOUT;
+
+        match tokio::time::timeout_at(deadline.into(), upstream.recv(&mut buffer)).await {
+            Ok(Ok(length)) => {
+                if socket.send_to(&buffer[..length], client).await.is_ok() {
+                    connection.record(false, length);
+                }
+            }
+            // E.g. the container refused the datagram; it may listen later.
+            Ok(Err(_)) => {}
+            Err(_) if connection.last_active() + UDP_FLOW_TIMEOUT <= Instant::now() => return client,
+            Err(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A local port nothing listens on right now.
+    fn free_port() -> u16 {
+        std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    async fn port_released(listen: SocketAddr) -> bool {
+        for _ in 0..50 {
+            if std::net::UdpSocket::bind(listen).is_ok() {
+                return true;
+            }
+
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        false
+    }
+
+    #[tokio::test]
+    async fn udp_proxy_releases_its_port_when_closed_idle() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen = SocketAddr::from((Ipv4Addr::LOCALHOST, free_port()));
+        let proxy = PortProxy::bind(PortProtocol::Udp, listen, target.local_addr().unwrap()).unwrap();
+
+        assert!(std::net::UdpSocket::bind(listen).is_err());
+
+        proxy.close();
+
+        assert!(port_released(listen).await);
+    }
+
+    #[tokio::test]
+    async fn udp_proxy_serves_open_flows_after_close() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen = SocketAddr::from((Ipv4Addr::LOCALHOST, free_port()));
+        let proxy = PortProxy::bind(PortProtocol::Udp, listen, target.local_addr().unwrap()).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buffer = [0u8; 16];
+
+        client.send_to(b"ping", listen).await.unwrap();
+        let (length, upstream) = target.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..length], b"ping");
+
+        proxy.close();
+        target.send_to(b"pong", upstream).await.unwrap();
+
+        let (length, from) = client.recv_from(&mut buffer).await.unwrap();
+        assert_eq!((&buffer[..length], from), (&b"pong"[..], listen));
+        assert!(std::net::UdpSocket::bind(listen).is_err());
+        assert_eq!(proxy.stats().connections.len(), 1);
+
+        drop(proxy);
+
+        assert!(port_released(listen).await);
+    }
+}
@@ -1,36 +1,128 @@
 use crate::{
-    Container, ContainerConfig, ContainerRegistry, ContainerState,
+    attach::ContainerStreams,
+    ExitStatus, RestartPolicy,
+    cgroup::{self, CgroupManager, CgroupStats, DEFAULT_CGROUP_PARENT},
+    config::is_valid_hostname,
+    dns::DnsServer,
+    Container, ContainerConfig, ContainerRegistry, ContainerState, LogDriverConfig,
     TurbineError, Result, 
-    filesystem::FilesystemManager,
-    network::NetworkManager,
-    process::ProcessManager,
+    events::{Event, EventAction, EventBus, EventQuery},
+    health::{Health, HealthMonitor, HealthStatus},
+    filesystem::{FileChange, FilesystemManager},
+    image_store::{is_image_path, ImageDetails, ImageStore, ImageSummary},
+    logs::{self, LogEntry, LogFollower, LogQuery},
+    namespace::ContainerProcess,
+    network::{self, InterfaceStats, Network, NetworkDetails, NetworkEndpoint, NetworkManager, NetworkOptions, DEFAULT_NETWORK},
+    process::{self, ExecIo, ExecOptions, ProcessManager},
+    proxy::ProxyStats,
     security::SecurityManager,
+    state::StateStore,
 };
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{Arc, Mutex};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, RwLock};
+
+/// Window over which `get_container_stats` measures CPU usage.
+pub const STATS_SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How often the supervisor checks for exited containers when no SIGCHLD arrives.
+const SUPERVISOR_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+const RESTART_BACKOFF_BASE: std::time::Duration = std::time::Duration::from_millis(100);
+
+const RESTART_BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How often `deploy_web_app` checks whether the application's health is known.
+const HEALTH_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// A run at least this long resets the restart backoff.
+const RESTART_STABLE_RUN: std::time::Duration = std::time::Duration::from_secs(10);
 
 pub struct TurbineRuntime {
     registry: Arc<RwLock<ContainerRegistry>>,
     filesystem: FilesystemManager,
     network: Arc<RwLock<NetworkManager>>,
+    dns: DnsServer,
     process: Arc<RwLock<ProcessManager>>,
     security: SecurityManager,
+    images: ImageStore,
+    cgroups: CgroupManager,
+    events: Arc<EventBus>,
+    /// OOM kills last seen in each running container's cgroup.
+    oom_kills: Mutex<HashMap<String, u64>>,
     base_path: PathBuf,
 }
 
 impl TurbineRuntime {
-    pub fn new<P: AsRef<std::path::Path>>(base_path: P) -> Self {
+    pub fn new<P: AsRef<std::path::Path>>(base_path: P) -> Result<Self> {
         let base_path = base_path.as_ref().to_path_buf();
+        let store = StateStore::open(&base_path)?;
+        let mut registry = ContainerRegistry::with_store(store)?;
+        let mut network = NetworkManager::open(&base_path)?;
+
+        registry.lock()?;
+        Self::reconcile_state(&mut registry)?;
+        registry.unlock();
+        network.restore(&registry.list());
 
-        Self {
-            registry: Arc::new(RwLock::new(ContainerRegistry::new())),
+        let registry = Arc::new(RwLock::new(registry));
+
+        Ok(Self {
+            dns: DnsServer::new(Arc::clone(&registry)),
+            registry,
             filesystem: FilesystemManager::new(&base_path),
-            network: Arc::new(RwLock::new(NetworkManager::new("turbine0".to_string()))),
+            network: Arc::new(RwLock::new(network)),
             process: Arc::new(RwLock::new(ProcessManager::new())),
             security: SecurityManager::new(),
+            images: ImageStore::open(&base_path)?,
+            cgroups: CgroupManager::new(DEFAULT_CGROUP_PARENT),
+            events: Arc::new(EventBus::open(&base_path)?),
+            oom_kills: Mutex::new(HashMap::new()),
             base_path,
+        })
+    }
+
+    /// Creates container cgroups under `parent`, relative to the cgroup v2 root.
+    pub fn with_cgroup_parent<P: AsRef<std::path::Path>>(mut self, parent: P) -> Self {
+        self.cgroups = CgroupManager::new(parent);
+        self
+    }
+
+    /// Marks containers whose recorded process is gone (e.g. after a reboot) as stopped,
+    /// and takes the paused state of live ones from their cgroup's freezer.
+    fn reconcile_state(registry: &mut ContainerRegistry) -> Result<()> {
+        let active: Vec<String> = registry.list()
+            .into_iter()
+            .filter(|c| c.is_running() || c.is_paused())
+            .map(|c| c.id.clone())
+            .collect();
+
+        for container_id in active {
+            let Some(container) = registry.get_mut(&container_id) else {
+                continue;
+            };
+            let state = if !container.pid.is_some_and(process::is_alive) {
+                ContainerState::Stopped
+            } else {
+                match container.cgroup_path.as_deref().map(cgroup::is_frozen) {
+                    Some(Ok(true)) => ContainerState::Paused,
+                    Some(Ok(false)) => ContainerState::Running,
+                    _ => continue,
+                }
+            };
+            if std::mem::discriminant(&state) == std::mem::discriminant(&container.state) {
+                continue;
+            }
+
+            container.set_state(state);
+            registry.persist(&container_id)?;
         }
+
+        Ok(())
     }
 
     pub async fn initialize(&self) -> Result<()> {
@@ -38,7 +130,7 @@ impl TurbineRuntime {
 
         let network = self.network.read().await;
 
-        network.setup_bridge()?;
+        network.setup_bridges()?;
 
         Ok(())
     }
@@ -50,56 +142,224 @@ impl TurbineRuntime {
 
         config.validate()?;
 
-        let container = Container::new(config)?;
+        let image_digest = self.resolve_image(&config.image)?;
+        let image = self.images.image(&image_digest)?;
+
+        image.apply_defaults(&mut config);
+        if config.command.is_empty() {
+            return Err(TurbineError::ConfigError(
+                "No command specified and the image defines no Entrypoint or Cmd".to_string()
+            ));
+        }
+
+        let mut container = Container::new(config)?;
         let container_id = container.id.clone();
 
-        self.filesystem.create_container_root(&container)?;
+        self.images.add_reference(&image_digest, &container_id)?;
+        container.image_digest = Some(image_digest.clone());
+
+        let populated = self.images.layer_dirs(&image)
+            .and_then(|layers| self.filesystem.create_container_root(&mut container, &image, layers))
+            .and_then(|_| image.resolve_user(&mut container.config, &container.root_path));
+        if let Err(e) = populated {
+            let _ = self.filesystem.cleanup_container(&container);
+            let _ = self.images.release(&image_digest, &container_id);
+            return Err(e);
+        }
+
         self.filesystem.setup_volumes(&container)?;
         self.filesystem.create_working_directory(&container)?;
 
         let mut network = self.network.write().await;
 
-        network.setup_container_network(&container)?;
+        if let Err(e) = network.setup_container_network(&mut container) {
+            let _ = self.filesystem.cleanup_container(&container);
+            let _ = self.images.release(&image_digest, &container_id);
+            return Err(e);
+        }
         drop(network);
 
-        let mut registry = self.registry.write().await;
+        let mut registry = ContainerRegistry::write(&self.registry).await?;
 
         registry.register(container)?;
+        if let Some(container) = registry.get(&container_id) {
+            self.events.emit(container, EventAction::Create);
+        }
 
         Ok(container_id)
     }
 
+    /// Maps a container's `image` to a stored manifest digest, importing OCI layouts and
+    /// archives given by path on first use.
+    fn resolve_image(&self, image: &str) -> Result<String> {
+        if !is_image_path(image) {
+            return self.images.resolve(image);
+        }
+
+        self.images.import(image, None)?
+            .into_iter()
+            .next()
+            .map(|summary| summary.digest)
+            .ok_or_else(|| TurbineError::ImageError(format!("No image found in {}", image)))
+    }
+
     pub async fn start_container(&self, container_id: &str) -> Result<()> {
-        let mut registry = self.registry.write().await;
+        self.launch(container_id, false).await
+    }
+
+    /// Starts the container's process. Starts requested by the user reset the restart
+    /// policy's bookkeeping; restarts made by the policy count towards its retry limit.
+    async fn launch(&self, container_id: &str, policy_restart: bool) -> Result<()> {
+        let mut registry = ContainerRegistry::write(&self.registry).await?;
         let container = registry.get_mut(container_id)
             .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))?;
-        if container.is_running() {
+        if container.is_running() || container.is_paused() {
             return Err(TurbineError::ContainerError("Container is already running".to_string()));
         }
 
-        self.security.create_secure_environment(container)?;
- 
-        let mut process = self.process.write().await;
-        let pid = process.start_container(container).await?;
+        if policy_restart {
+            container.restart_count += 1;
+        } else {
+            container.restart_count = 0;
+            container.manually_stopped = false;
+        }
+
+        let mounted = self.filesystem.mount_rootfs(container)?;
+        let pid = match self.spawn(container).await {
+            Ok(pid) => pid,
+            Err(e) => {
+                self.undo_launch(container, mounted).await;
+                return Err(e);
+            }
+        };
 
         container.set_pid(pid);
         container.set_state(ContainerState::Running);
+        container.health = container.config.healthcheck.as_ref().map(|_| Health::starting());
+        self.monitor_health(container);
 
-        Ok(())
+        if let Some(path) = &container.cgroup_path {
+            let kills = cgroup::oom_kills(path).unwrap_or(0);
+            self.oom_kills.lock().unwrap_or_else(|e| e.into_inner()).insert(container_id.to_string(), kills);
+        }
+
+        self.events.emit(container, EventAction::Start);
+        if policy_restart {
+            self.events.emit(container, EventAction::Restart);
+        }
+
+        registry.persist(container_id)
+    }
+
+    /// Sets up the cgroup and network of a container whose root is mounted and spawns
+    /// its process.
+    async fn spawn(&self, container: &mut Container) -> Result<u32> {
+        self.security.create_secure_environment(container)?;
+        container.cgroup_path = self.cgroups.setup(container)?;
+
+        let driver = logs::open_driver(container, &self.log_dir(&container.id))?;
+        let mut process = self.process.write().await;
+        let mut network = self.network.write().await;
+
+        self.filesystem.write_network_files(container, self.resolver(&network, container))?;
+        network.start_proxies(container)?;
+
+        match process.start_container(container, driver, |pid| network.connect_container(container, pid)).await {
+            Ok(pid) => Ok(pid),
+            Err(e) => {
+                network.close_proxies(&container.id);
+                Err(e)
+            }
+        }
+    }
+
+    /// Removes the cgroup of a container that failed to start and, if `mounted` by the
+    /// failed start, unmounts its root, in the reverse order of `launch`.
+    async fn undo_launch(&self, container: &mut Container, mounted: bool) {
+        if let Err(e) = self.cgroups.remove(container).await {
+            eprintln!("Warning: Failed to remove cgroup of container {}: {}", container.id, e);
+        }
+
+        container.cgroup_path = None;
+
+        if mounted {
+            if let Err(e) = self.filesystem.unmount_rootfs(container) {
+                eprintln!("Warning: Failed to unmount root of container {}: {}", container.id, e);
+            }
+        }
+    }
+
+    /// Starts probing the container in the background if it has a health check.
+    fn monitor_health(&self, container: &Container) {
+        let (Some(_), Some(started_at)) = (&container.config.healthcheck, container.started_at) else {
+            return;
+        };
+        let monitor = HealthMonitor::new(
+            Arc::clone(&self.registry),
+            Arc::clone(&self.process),
+            Arc::clone(&self.events),
+        );
+
+        tokio::spawn(monitor.run(container.id.clone(), started_at));
     }
 
     pub async fn stop_container(&self, container_id: &str, force: bool) -> Result<()> {
-        let mut registry = self.registry.write().await;
+        let mut registry = ContainerRegistry::write(&self.registry).await?;
         let container = registry.get_mut(container_id)
             .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))?;
-        if !container.is_running() {
+        if container.is_restarting() {
+            container.manually_stopped = true;
+            container.set_state(ContainerState::Stopped);
+            return registry.persist(container_id);
+        }
+
+        if !container.is_running() && !container.is_paused() {
             return Err(TurbineError::ContainerError("Container is not running".to_string()));
         }
 
         let mut process = self.process.write().await;
 
-        process.stop_container(container_id, force).await?;
+        if process.is_stopping(container_id) {
+            return Err(TurbineError::ContainerError("Container is already being stopped".to_string()));
+        }
+
+        if container.is_paused() {
+            process.resume_container(container).await?;
+        }
+
+        container.manually_stopped = true;
+
+        let pid = container.pid;
+        let child = process.begin_stop(container_id);
+        // New connections would not be served anymore; open ones get until the process is gone.
+        let proxies = self.network.write().await.close_proxies(container_id);
+
+        // The process may take until STOP_TIMEOUT to go, during which DNS and the other
+        // containers are served.
+        drop(process);
+        drop(registry);
+
+        let status = process::stop_process(child, pid, force).await;
+
+        drop(proxies);
+
+        let registry = ContainerRegistry::write(&self.registry).await;
+
+        self.process.write().await.finish_stop(container_id);
+
+        let mut registry = registry?;
+        let status = status?;
+        let Some(container) = registry.get_mut(container_id) else {
+            return Ok(());
+        };
+
+        if status.is_some() {
+            container.exit_status = status;
+        }
+
         container.set_state(ContainerState::Stopped);
+        self.events.emit(container, EventAction::Die { exit_code: status.and_then(|s| s.exit_code()) });
+        registry.persist(container_id)?;
 
         Ok(())
     }
@@ -107,11 +367,17 @@ impl TurbineRuntime {
     pub async fn restart_container(&self, container_id: &str) -> Result<()> {
         self.stop_container(container_id, false).await?;
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-        self.start_container(container_id).await
+        self.start_container(container_id).await?;
+
+        if let Some(container) = self.registry.read().await.get(container_id) {
+            self.events.emit(container, EventAction::Restart);
+        }
+
+        Ok(())
     }
 
     pub async fn pause_container(&self, container_id: &str) -> Result<()> {
-        let mut registry = self.registry.write().await;
+        let mut registry = ContainerRegistry::write(&self.registry).await?;
         let container = registry.get_mut(container_id)
             .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))?;
         if !container.is_running() {
@@ -120,14 +386,16 @@ impl TurbineRuntime {
 
         let process = self.process.read().await;
 
-        process.pause_container(container_id)?;
+        process.pause_container(container).await?;
         container.set_state(ContainerState::Paused);
+        self.events.emit(container, EventAction::Pause);
+        registry.persist(container_id)?;
 
         Ok(())
     }
 
     pub async fn resume_container(&self, container_id: &str) -> Result<()> {
-        let mut registry = self.registry.write().await;
+        let mut registry = ContainerRegistry::write(&self.registry).await?;
         let container = registry.get_mut(container_id)
             .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))?;
         if !matches!(container.state, ContainerState::Paused) {
@@ -136,8 +404,10 @@ impl TurbineRuntime {
 
         let process = self.process.read().await;
 
-        process.resume_container(container_id)?;
+        process.resume_container(container).await?;
         container.set_state(ContainerState::Running);
+        self.events.emit(container, EventAction::Unpause);
+        registry.persist(container_id)?;
 
         Ok(())
     }
@@ -146,7 +416,8 @@ impl TurbineRuntime {
         let registry_read = self.registry.read().await;
         let container = registry_read.get(container_id)
             .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))?;
-        if container.is_running() && !force {
+        let active = container.is_running() || container.is_paused();
+        if active && !force {
             return Err(TurbineError::ContainerError(
                 "Container is running. Use force=true to remove running container".to_string()
             ));
@@ -156,7 +427,7 @@ impl TurbineRuntime {
 
         drop(registry_read);
 
-        if container_clone.is_running() {
+        if active {
             self.stop_container(container_id, true).await?;
         }
 
@@ -166,14 +437,137 @@ impl TurbineRuntime {
         drop(network);
 
         self.filesystem.cleanup_container(&container_clone)?;
+        self.cgroups.remove(&container_clone).await?;
+        self.remove_logs(container_id)?;
+
+        let mut registry = ContainerRegistry::write(&self.registry).await?;
 
-        let mut registry = self.registry.write().await;
+        registry.remove(container_id)?;
+        drop(registry);
+        self.oom_kills.lock().unwrap_or_else(|e| e.into_inner()).remove(container_id);
+        self.events.emit(&container_clone, EventAction::Destroy);
 
-        registry.remove(container_id);
+        if let Some(image_digest) = &container_clone.image_digest {
+            self.images.release(image_digest, container_id)?;
+        }
 
         Ok(())
     }
 
+    pub async fn create_network(&self, options: NetworkOptions) -> Result<Network> {
+        let network = self.network.write().await.create_network(options)?;
+
+        self.dns.serve(&network);
+
+        Ok(network)
+    }
+
+    pub async fn list_networks(&self) -> Result<Vec<Network>> {
+        Ok(self.network.read().await.networks())
+    }
+
+    /// The network with the containers connected to it, stopped ones included.
+    pub async fn inspect_network(&self, name: &str) -> Result<NetworkDetails> {
+        let registry = self.registry.read().await;
+        let network = self.network.read().await.network(name)?.clone();
+        let containers = registry.list()
+            .into_iter()
+            .flat_map(|container| container.networks.iter()
+                .filter(|attachment| attachment.network == network.name)
+                .map(|attachment| NetworkEndpoint {
+                    container_id: container.id.clone(),
+                    container_name: container.config.name.clone(),
+                    interface: attachment.interface.clone(),
+                    ips: attachment.ips.clone(),
+                    aliases: attachment.aliases.clone(),
+                }))
+            .collect();
+
+        Ok(NetworkDetails { network, containers })
+    }
+
+    pub async fn remove_network(&self, name: &str) -> Result<()> {
+        self.network.write().await.remove_network(name)?;
+        self.dns.stop(name);
+
+        Ok(())
+    }
+
+    /// Connects a container to another network, where it is also found by `aliases`. A
+    /// running container gets its interface on the network right away, and a default
+    /// route through it if it had none.
+    pub async fn connect_network(&self, name: &str, container_id: &str, aliases: Vec<String>) -> Result<()> {
+        if let Some(alias) = aliases.iter().find(|alias| !is_valid_hostname(alias)) {
+            return Err(TurbineError::ConfigError(format!("Invalid network alias: {}", alias)));
+        }
+
+        let mut registry = ContainerRegistry::write(&self.registry).await?;
+        let container = registry.get_mut(container_id)
+            .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))?;
+        let mut network = self.network.write().await;
+        let mut attachment = network.attach(container, name)?;
+
+        for alias in aliases {
+            if !attachment.aliases.contains(&alias) {
+                attachment.aliases.push(alias);
+            }
+        }
+
+        if let Some(pid) = container.pid.filter(|_| container.is_running() || container.is_paused()) {
+            let routed = container.networks.iter()
+                .any(|attachment| network.network(&attachment.network).is_ok_and(|network| !network.internal));
+            let default_route = !routed && !network.network(name)?.internal;
+
+            if let Err(e) = network.connect_interface(container, &attachment, pid, default_route) {
+                network.detach(container_id, name);
+                return Err(e);
+            }
+        }
+
+        container.networks.push(attachment);
+        self.filesystem.write_network_files(container, self.resolver(&network, container))?;
+        drop(network);
+        registry.persist(container_id)
+    }
+
+    /// Disconnects a container from a network, removing its interface there if it runs.
+    /// The network its published ports forward to cannot be left.
+    pub async fn disconnect_network(&self, name: &str, container_id: &str) -> Result<()> {
+        let mut registry = ContainerRegistry::write(&self.registry).await?;
+        let container = registry.get_mut(container_id)
+            .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))?;
+        let index = container.networks.iter()
+            .position(|attachment| attachment.network == name)
+            .ok_or_else(|| TurbineError::NetworkNotFound(format!(
+                "Container {} is not connected to network {}", container_id, name
+            )))?;
+        if index == 0 && !container.config.ports.is_empty() {
+            return Err(TurbineError::NetworkError(format!(
+                "Container {} publishes ports on network {}", container_id, name
+            )));
+        }
+
+        let mut network = self.network.write().await;
+
+        if container.is_running() || container.is_paused() {
+            network.disconnect_interface(container, &container.networks[index])?;
+        }
+
+        network.detach(container_id, name);
+        container.networks.remove(index);
+        self.filesystem.write_network_files(container, self.resolver(&network, container))?;
+        drop(network);
+        registry.persist(container_id)
+    }
+
+    /// The embedded DNS server of the first user-defined network the container is on,
+    /// if it is being served.
+    fn resolver(&self, network: &NetworkManager, container: &Container) -> Option<IpAddr> {
+        container.networks.iter()
+            .filter(|attachment| attachment.network != DEFAULT_NETWORK)
+            .find_map(|attachment| network.network(&attachment.network).ok().and_then(|network| self.dns.resolver(network)))
+    }
+
     pub async fn list_containers(&self) -> Result<Vec<Container>> {
         let registry = self.registry.read().await;
 
@@ -188,22 +582,45 @@ impl TurbineRuntime {
             .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))
     }
 
-    pub async fn get_container_logs(&self, container_id: &str) -> Result<(String, String)> {
+    pub async fn container_diff(&self, container_id: &str) -> Result<Vec<FileChange>> {
         let registry = self.registry.read().await;
         let container = registry.get(container_id)
             .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))?;
-        if !container.is_running() {
-            return Err(TurbineError::ContainerError("Container is not running".to_string()));
+
+        self.filesystem.container_changes(container)
+    }
+
+    /// Reads the container's logged output matching `query`, whatever its state. The
+    /// returned follower picks up where the entries end. Only json-file logs are kept
+    /// where they can be read back.
+    pub async fn get_container_logs(&self, container_id: &str, query: &LogQuery) -> Result<(Vec<LogEntry>, LogFollower)> {
+        let container = self.get_container(container_id).await?;
+        if !matches!(container.config.log_driver, LogDriverConfig::JsonFile { .. }) {
+            return Err(TurbineError::ContainerError(format!(
+                "Container logs to {}, whose output cannot be read back", container.config.log_driver.name()
+            )));
         }
-        
-        drop(registry);
 
-        let mut process = self.process.write().await;
+        LogFollower::open(&self.log_dir(container_id), query)
+    }
 
-        process.get_container_logs(container_id).await
+    fn log_dir(&self, container_id: &str) -> PathBuf {
+        self.base_path.join("logs").join(container_id)
     }
 
-    pub async fn execute_in_container(&self, container_id: &str, command: Vec<String>) -> Result<String> {
+    fn remove_logs(&self, container_id: &str) -> Result<()> {
+        match std::fs::remove_dir_all(self.log_dir(container_id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn execute_in_container(
+        &self,
+        container_id: &str,
+        command: Vec<String>,
+        options: &ExecOptions,
+    ) -> Result<(ContainerProcess, ExecIo)> {
         let registry = self.registry.read().await;
         let container = registry.get(container_id)
             .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))?;
@@ -217,7 +634,31 @@ impl TurbineRuntime {
 
         let process = self.process.read().await;
 
-        process.execute_in_container(&container_clone, command).await
+        process.execute_in_container(&container_clone, command, options).await
+    }
+
+    /// The live stdio of a running container, for attaching to.
+    pub async fn attach_container(&self, container_id: &str) -> Result<Arc<ContainerStreams>> {
+        let registry = self.registry.read().await;
+        let container = registry.get(container_id)
+            .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))?;
+        if !container.is_running() && !container.is_paused() {
+            return Err(TurbineError::ContainerError("Container is not running".to_string()));
+        }
+
+        let process = self.process.read().await;
+
+        process.streams(container_id).ok_or_else(|| TurbineError::ContainerError(
+            "Container was started by another turbine process and cannot be attached to".to_string()
+        ))
+    }
+
+    /// Reads the recorded events matching `query`, and subscribes to the ones emitted
+    /// from now on.
+    pub fn events(&self, query: &EventQuery) -> Result<(Vec<Event>, broadcast::Receiver<Event>)> {
+        let receiver = self.events.subscribe();
+
+        Ok((self.events.read(query)?, receiver))
     }
 
     pub async fn get_running_containers(&self) -> Result<Vec<String>> {
@@ -226,44 +667,116 @@ impl TurbineRuntime {
         Ok(process.get_running_containers())
     }
 
+    pub async fn import_image(&self, source: &std::path::Path, tag: Option<&str>) -> Result<Vec<ImageSummary>> {
+        self.images.import(source, tag)
+    }
+
+    pub async fn list_images(&self) -> Result<Vec<ImageSummary>> {
+        self.images.list()
+    }
+
+    pub async fn inspect_image(&self, reference: &str) -> Result<ImageDetails> {
+        self.images.inspect(reference)
+    }
+
+    pub async fn remove_image(&self, reference: &str) -> Result<Vec<String>> {
+        self.images.remove(reference)
+    }
+
+    pub async fn prune_images(&self) -> Result<Vec<String>> {
+        self.images.prune()
+    }
+
     pub async fn create_web_container(&self, name: String, image: String, port: u16) -> Result<String> {
-        let mut config = ContainerConfig::default();
+        let mut config = ContainerConfig {
+            name,
+            image,
+            ..Default::default()
+        };
 
-        config.name = name;
-        config.image = image;
         config.set_web_defaults(port);
 
         self.create_container(config).await
     }
 
-    pub async fn deploy_web_app(&self, name: String, image: String, port: u16) -> Result<String> {
+    /// Creates and starts a web container, then waits until its health check has
+    /// decided whether the application is up, or for as long as that can take.
+    pub async fn deploy_web_app(&self, name: String, image: String, port: u16) -> Result<Container> {
         let container_id = self.create_web_container(name, image, port).await?;
 
         self.start_container(&container_id).await?;
+        self.wait_for_health(&container_id).await
+    }
 
-        Ok(container_id)
+    /// Waits until a started container's health is no longer `Starting`. Gives up once
+    /// the container stops, or after the start period plus enough probes to fail its
+    /// retries.
+    async fn wait_for_health(&self, container_id: &str) -> Result<Container> {
+        let container = self.get_container(container_id).await?;
+        let Some(check) = &container.config.healthcheck else {
+            return Ok(container);
+        };
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(
+            check.start_period_secs + (check.interval_secs + check.timeout_secs) * u64::from(check.retries),
+        );
+
+        loop {
+            let container = self.get_container(container_id).await?;
+            let starting = container.health.as_ref().is_some_and(|h| h.status == HealthStatus::Starting);
+
+            if !starting || !container.is_running() || tokio::time::Instant::now() >= deadline {
+                return Ok(container);
+            }
+
+            tokio::time::sleep(HEALTH_POLL_INTERVAL).await;
+        }
     }
 
+    /// Samples a container's resource usage. CPU usage is measured over
+    /// `STATS_SAMPLE_INTERVAL`, so this takes about that long to return.
     pub async fn get_container_stats(&self, container_id: &str) -> Result<ContainerStats> {
-        let registry = self.registry.read().await;
-        let container = registry.get(container_id)
-            .ok_or_else(|| TurbineError::ContainerError("Container not found".to_string()))?;
-        if !container.is_running() {
+        let container = self.get_container(container_id).await?;
+        if !container.is_running() && !container.is_paused() {
             return Err(TurbineError::ContainerError("Container is not running".to_string()));
         }
 
-        let stats = ContainerStats {
+        let pid = container.pid
+            .ok_or_else(|| TurbineError::ContainerError("Container has no process".to_string()))?;
+        let started = std::time::Instant::now();
+        let cpu_start = self.cpu_time_usec(&container, pid)?;
+
+        tokio::time::sleep(STATS_SAMPLE_INTERVAL).await;
+
+        let cpu_end = self.cpu_time_usec(&container, pid)?;
+        let elapsed_usec = started.elapsed().as_micros().max(1) as f64;
+        let cgroup_stats = match &container.cgroup_path {
+            Some(path) => cgroup::read_stats(path)?,
+            None => CgroupStats {
+                memory_current: self.get_memory_usage(pid).await?,
+                pids_current: 1,
+                ..Default::default()
+            },
+        };
+        let networks = network::interface_stats(pid)?;
+        let ports = self.network.read().await.proxy_stats(container_id);
+
+        Ok(ContainerStats {
             container_id: container_id.to_string(),
-            memory_usage: self.get_memory_usage(container.pid.unwrap_or(0)).await?,
-            cpu_usage: self.get_cpu_usage(container.pid.unwrap_or(0)).await?,
-            network_rx: 0,
-            network_tx: 0,
+            memory_usage: cgroup_stats.memory_current,
+            memory_limit: cgroup_stats.memory_max,
+            memory_cache: cgroup_stats.memory_file,
+            cpu_usage: cpu_end.saturating_sub(cpu_start) as f64 / elapsed_usec * 100.0,
+            pids: cgroup_stats.pids_current,
+            block_read: cgroup_stats.io_read_bytes,
+            block_write: cgroup_stats.io_write_bytes,
+            network_rx: networks.iter().map(|n| n.rx_bytes).sum(),
+            network_tx: networks.iter().map(|n| n.tx_bytes).sum(),
+            networks,
+            ports,
             uptime: container.started_at
                 .map(|start| chrono::Utc::now().signed_duration_since(start).num_seconds())
                 .unwrap_or(0),
-        };
-
-        Ok(stats)
+        })
     }
 
     async fn get_memory_usage(&self, pid: u32) -> Result<u64> {
@@ -282,19 +795,179 @@ impl TurbineRuntime {
         Ok(0)
     }
 
-    async fn get_cpu_usage(&self, pid: u32) -> Result<f64> {
-        let stat_path = format!("/proc/{}/stat", pid);
-        let content = tokio::fs::read_to_string(&stat_path).await?;        
-        let parts: Vec<&str> = content.split_whitespace().collect();
-        if parts.len() >= 15 {
-            let utime: u64 = parts[13].parse().unwrap_or(0);
-            let stime: u64 = parts[14].parse().unwrap_or(0);
-            let total_time = utime + stime;
-            
-            return Ok(total_time as f64 / 100.0);
+    /// Total CPU time consumed by the container, from its cgroup or, without one, from
+    /// the init process alone.
+    fn cpu_time_usec(&self, container: &Container, pid: u32) -> Result<u64> {
+        if let Some(path) = &container.cgroup_path {
+            return Ok(cgroup::read_stats(path)?.cpu_usage_usec);
+        }
+
+        let content = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+        let fields: Vec<&str> = content.rsplit_once(')')
+            .map(|(_, rest)| rest.split_whitespace().collect())
+            .unwrap_or_default();
+        if fields.len() < 13 {
+            return Ok(0);
+        }
+
+        let ticks: u64 = fields[11].parse::<u64>().unwrap_or(0) + fields[12].parse::<u64>().unwrap_or(0);
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+
+        Ok(ticks * 1_000_000 / ticks_per_second)
+    }
+
+    /// Watches for container exits and applies each container's restart policy. Runs
+    /// until the task is dropped; `turbined` keeps one alive for its whole lifetime.
+    pub async fn supervise(self: Arc<Self>) {
+        let registry = self.registry.read().await;
+        let mut network = self.network.write().await;
+
+        self.dns.start(&network.networks());
+        network.enable_proxies();
+
+        // Containers left running by a previous runtime were not being probed, nor
+        // their ports proxied.
+        for container in registry.list() {
+            if container.is_running() || container.is_paused() {
+                self.monitor_health(container);
+
+                if let Err(e) = network.start_proxies(container) {
+                    eprintln!("Warning: Failed to proxy ports of container {}: {}", container.id, e);
+                }
+            }
+        }
+
+        drop(network);
+        drop(registry);
+
+        self.restart_after_boot().await;
+
+        let mut sigchld = signal(SignalKind::child()).ok();
+        let mut interval = tokio::time::interval(SUPERVISOR_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = async {
+                    match sigchld.as_mut() {
+                        Some(sigchld) => { sigchld.recv().await; }
+                        None => std::future::pending::<()>().await,
+                    }
+                } => {}
+                _ = interval.tick() => {}
+            }
+
+            if let Err(e) = self.handle_exits().await {
+                eprintln!("Supervisor error: {}", e);
+            }
+        }
+    }
+
+    /// Brings back containers whose policy wants them running after the runtime (or the
+    /// host) restarted. `UnlessStopped` skips containers the user stopped.
+    async fn restart_after_boot(self: &Arc<Self>) {
+        let pending: Vec<String> = self.registry.read().await
+            .list()
+            .into_iter()
+            .filter(|c| c.is_stopped() || c.is_restarting())
+            .filter(|c| match c.config.restart_policy {
+                RestartPolicy::Always => true,
+                RestartPolicy::UnlessStopped => !c.manually_stopped,
+                RestartPolicy::Never | RestartPolicy::OnFailure(_) => c.is_restarting(),
+            })
+            .map(|c| c.id.clone())
+            .collect();
+
+        for container_id in pending {
+            if let Err(e) = self.launch(&container_id, true).await {
+                eprintln!("Failed to restart container {}: {}", container_id, e);
+            }
+        }
+    }
+
+    async fn handle_exits(self: &Arc<Self>) -> Result<()> {
+        let mut registry = ContainerRegistry::write(&self.registry).await?;
+        let mut process = self.process.write().await;
+        let active: Vec<(String, Option<u32>)> = registry.list()
+            .into_iter()
+            .filter(|c| c.is_running() || c.is_paused())
+            .map(|c| (c.id.clone(), c.pid))
+            .collect();
+
+        for (container_id, pid) in active {
+            let status = process.exit_status(&container_id, pid)?;
+            let Some(container) = registry.get_mut(&container_id) else {
+                continue;
+            };
+
+            self.check_oom(container);
+
+            let Some(status) = status else {
+                continue;
+            };
+            let ran_for = container.started_at
+                .and_then(|start| chrono::Utc::now().signed_duration_since(start).to_std().ok())
+                .unwrap_or_default();
+
+            container.exit_status = Some(status);
+            container.set_state(ContainerState::Stopped);
+            process.remove_streams(&container_id);
+            self.network.write().await.close_proxies(&container_id);
+            self.events.emit(container, EventAction::Die { exit_code: status.exit_code() });
+
+            if should_restart(&container.config.restart_policy, &status, container.restart_count) {
+                let delay = restart_delay(container.restart_count, ran_for);
+                let runtime = Arc::clone(self);
+                let id = container_id.clone();
+
+                container.set_state(ContainerState::Restarting);
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    runtime.restart_exited(&id).await;
+                });
+            }
+
+            registry.persist(&container_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Emits an `oom` event when the OOM killer has struck in the container's cgroup
+    /// since it was last checked.
+    fn check_oom(&self, container: &Container) {
+        let Some(kills) = container.cgroup_path.as_deref().and_then(|path| cgroup::oom_kills(path).ok()) else {
+            return;
+        };
+        let mut seen = self.oom_kills.lock().unwrap_or_else(|e| e.into_inner());
+
+        // The first sighting of a container this runtime did not start only sets the baseline.
+        if seen.insert(container.id.clone(), kills).is_some_and(|previous| kills > previous) {
+            self.events.emit(container, EventAction::Oom);
+        }
+    }
+
+    /// Restarts a container once its backoff has elapsed, unless it was stopped, started
+    /// or removed in the meantime.
+    async fn restart_exited(&self, container_id: &str) {
+        let restarting = self.registry.read().await
+            .get(container_id)
+            .is_some_and(|c| c.is_restarting());
+        if !restarting {
+            return;
+        }
+
+        if let Err(e) = self.launch(container_id, true).await {
+            eprintln!("Failed to restart container {}: {}", container_id, e);
+
+            let Ok(mut registry) = ContainerRegistry::write(&self.registry).await else {
+                return;
+            };
+
+            if let Some(container) = registry.get_mut(container_id) {
+                container.set_state(ContainerState::Error(e.to_string()));
+                let _ = registry.persist(container_id);
+            }
         }
-        
-        Ok(0.0)
     }
 
     pub async fn cleanup(&self) -> Result<()> {
@@ -310,23 +983,123 @@ impl TurbineRuntime {
                 self.remove_container(&container.id, true).await?;
             } else {
                 self.filesystem.cleanup_container(&container)?;
+                self.cgroups.remove(&container).await?;
+                self.remove_logs(&container.id)?;
+                ContainerRegistry::write(&self.registry).await?.remove(&container.id)?;
+                self.events.emit(&container, EventAction::Destroy);
+
+                if let Some(image_digest) = &container.image_digest {
+                    self.images.release(image_digest, &container.id)?;
+                }
             }
         }
 
         let network = self.network.read().await;
 
-        network.cleanup_bridge()?;
+        self.dns.stop_all();
+        network.cleanup_bridges()?;
 
         Ok(())
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContainerStats {
     pub container_id: String,
     pub memory_usage: u64,
+    pub memory_limit: Option<u64>,
+    pub memory_cache: u64,
+    /// CPU time used over the sampling interval, as a percentage of one CPU.
     pub cpu_usage: f64,
+    pub pids: u64,
+    pub block_read: u64,
+    pub block_write: u64,
     pub network_rx: u64,
     pub network_tx: u64,
+    pub networks: Vec<InterfaceStats>,
+    /// Traffic through the proxies of the container's published ports, in proxy mode.
+    #[serde(default)]
+    pub ports: Vec<ProxyStats>,
     pub uptime: i64,
 }
+
+fn should_restart(policy: &RestartPolicy, status: &ExitStatus, restart_count: u32) -> bool {
+    match policy {
+        RestartPolicy::Never => false,
+        RestartPolicy::Always | RestartPolicy::UnlessStopped => true,
+        RestartPolicy::OnFailure(max_retries) => {
+            status.failed() && max_retries.is_none_or(|max| restart_count < max)
+        }
+    }
+}
+
+/// Exponential backoff between restarts, starting over after a run of at least
+/// `RESTART_STABLE_RUN`.
+fn restart_delay(restart_count: u32, ran_for: std::time::Duration) -> std::time::Duration {
+    if ran_for >= RESTART_STABLE_RUN {
+        return RESTART_BACKOFF_BASE;
+    }
+
+    RESTART_BACKOFF_BASE
+        .saturating_mul(2u32.saturating_pow(restart_count.min(16)))
+        .min(RESTART_BACKOFF_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const EXITED: ExitStatus = ExitStatus { code: Some(0), signal: None };
+    const FAILED: ExitStatus = ExitStatus { code: Some(1), signal: None };
+    const KILLED: ExitStatus = ExitStatus { code: None, signal: Some(9) };
+    const UNKNOWN: ExitStatus = ExitStatus { code: None, signal: None };
+
+    fn policy(policy: &str) -> RestartPolicy {
+        policy.parse().unwrap()
+    }
+
+    #[test]
+    fn on_failure_restarts_known_failures_only() {
+        let policy = policy("OnFailure:3");
+
+        assert!(should_restart(&policy, &FAILED, 0));
+        assert!(should_restart(&policy, &KILLED, 2));
+        assert!(!should_restart(&policy, &FAILED, 3));
+        assert!(!should_restart(&policy, &EXITED, 0));
+        // A process that exited while no runtime watched it may well have succeeded.
+        assert!(!should_restart(&policy, &UNKNOWN, 0));
+        assert!(!should_restart(&RestartPolicy::OnFailure(None), &UNKNOWN, 0));
+        assert!(should_restart(&self::policy("OnFailure"), &FAILED, 1000));
+    }
+
+    #[test]
+    fn other_policies_ignore_the_status() {
+        for status in [EXITED, FAILED, KILLED, UNKNOWN] {
+            assert!(!should_restart(&policy("Never"), &status, 0));
+            assert!(should_restart(&policy("Always"), &status, 10));
+            assert!(should_restart(&policy("UnlessStopped"), &status, 10));
+        }
+    }
+
+    #[test]
+    fn restart_delay_doubles_up_to_the_maximum() {
+        let quick = Duration::from_secs(1);
+        let delays: Vec<Duration> = (0..12).map(|count| restart_delay(count, quick)).collect();
+
+        assert_eq!(delays[..4], [
+            Duration::from_millis(100), Duration::from_millis(200),
+            Duration::from_millis(400), Duration::from_millis(800),
+        ]);
+        assert_eq!(delays[9], Duration::from_millis(51200));
+        assert_eq!(delays[10], RESTART_BACKOFF_MAX);
+        assert_eq!(delays[11], RESTART_BACKOFF_MAX);
+        assert_eq!(restart_delay(u32::MAX, quick), RESTART_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn restart_delay_starts_over_after_a_stable_run() {
+        assert_eq!(restart_delay(8, RESTART_STABLE_RUN), RESTART_BACKOFF_BASE);
+        assert_eq!(restart_delay(8, RESTART_STABLE_RUN - Duration::from_millis(1)), RESTART_BACKOFF_BASE * 256);
+    }
+}
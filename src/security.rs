@@ -1,4 +1,5 @@
 use crate::{Container, TurbineError, Result};
+use crate::image_store::is_image_path;
 use nix::sys::resource::{setrlimit, Resource};
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
@@ -149,21 +150,9 @@ impl SecurityManager {
     }
 
     pub fn apply_resource_limits(&self, resources: &crate::ResourceLimits) -> Result<()> {
-        if let Some(memory_mb) = resources.memory_mb {
-            let memory_bytes = (memory_mb * 1024 * 1024) as u64;
-            setrlimit(Resource::RLIMIT_AS, memory_bytes, memory_bytes)
-                .map_err(|e| TurbineError::SecurityError(format!("Failed to set memory limit: {}", e)))?;
-        }
-
-        if let Some(max_processes) = resources.max_processes {
-            setrlimit(Resource::RLIMIT_NPROC, max_processes as u64, max_processes as u64)
-                .map_err(|e| TurbineError::SecurityError(format!("Failed to set process limit: {}", e)))?;
-        }
-
-        if let Some(disk_mb) = resources.disk_mb {
-            let disk_bytes = (disk_mb * 1024 * 1024) as u64;
-            setrlimit(Resource::RLIMIT_FSIZE, disk_bytes, disk_bytes)
-                .map_err(|e| TurbineError::SecurityError(format!("Failed to set disk limit: {}", e)))?;
+        for (resource, limit) in resource_limits(resources) {
+            setrlimit(resource, limit, limit)
+                .map_err(|e| TurbineError::SecurityError(format!("Failed to set {:?}: {}", resource, e)))?;
         }
 
         Ok(())
@@ -186,9 +175,9 @@ impl SecurityManager {
         Ok(())
     }
 
+    /// Prepares the container root before start. The user and resource limits are applied
+    /// by the container init itself, not by the runtime.
     pub fn create_secure_environment(&self, container: &Container) -> Result<()> {
-        self.setup_container_user(container)?;
-        self.apply_resource_limits(&container.config.resources)?;
         self.setup_secure_filesystem(container)?;
 
         Ok(())
@@ -217,16 +206,20 @@ impl SecurityManager {
         Ok(())
     }
 
-    pub fn validate_image_security(&self, image_path: &str) -> Result<()> {
-        if image_path.contains("..") {
+    pub fn validate_image_security(&self, image: &str) -> Result<()> {
+        if image.contains("..") {
             return Err(TurbineError::SecurityError(
                 "Image path contains directory traversal".to_string()
             ));
         }
 
-        if !image_path.starts_with("/") && !image_path.starts_with("./") {
+        if is_image_path(image) {
+            return Ok(());
+        }
+
+        if !image.chars().all(|c| c.is_ascii_alphanumeric() || "._-:/@".contains(c)) {
             return Err(TurbineError::SecurityError(
-                "Image path must be absolute or relative to current directory".to_string()
+                format!("Invalid image reference '{}'", image)
             ));
         }
 
@@ -251,6 +244,18 @@ impl SecurityManager {
     }
 }
 
+/// The rlimits implied by `resources`. Memory, CPU and process limits are enforced
+/// through the container's cgroup instead.
+pub fn resource_limits(resources: &crate::ResourceLimits) -> Vec<(Resource, u64)> {
+    let mut limits = Vec::new();
+
+    if let Some(disk_mb) = resources.disk_mb {
+        limits.push((Resource::RLIMIT_FSIZE, disk_mb * 1024 * 1024));
+    }
+
+    limits
+}
+
 impl Default for SecurityManager {
     fn default() -> Self {
         Self::new()
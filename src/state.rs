@@ -0,0 +1,199 @@
+use crate::{Container, TurbineError, Result};
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+const INDEX_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateIndex {
+    version: u32,
+    /// Bumped by every write, so a process can tell whether its copy is current.
+    #[serde(default)]
+    generation: u64,
+    containers: BTreeMap<String, IndexEntry>,
+}
+
+impl Default for StateIndex {
+    fn default() -> Self {
+        Self {
+            version: INDEX_VERSION,
+            generation: 0,
+            containers: BTreeMap::new(),
+        }
+    }
+}
+
+/// The exclusive lock on the state store that writes are made under. Held across
+/// reading, changing and writing containers, it keeps other processes from
+/// overwriting the change or having it overwritten.
+pub struct StateLock {
+    _lock: Flock<File>,
+}
+
+/// On-disk container state under `<base_path>/state`.
+///
+/// Every container is stored in its own `containers/<id>.json` file and listed in
+/// `index.json`. All writes go through a temporary file and a rename, and are made
+/// under a `StateLock`, an advisory lock on `state.lock`.
+pub struct StateStore {
+    state_path: PathBuf,
+}
+
+impl StateStore {
+    pub fn open<P: AsRef<Path>>(base_path: P) -> Result<Self> {
+        let state_path = base_path.as_ref().join("state");
+
+        fs::create_dir_all(state_path.join("containers"))?;
+
+        Ok(Self { state_path })
+    }
+
+    pub fn state_path(&self) -> &Path {
+        &self.state_path
+    }
+
+    fn container_path(&self, container_id: &str) -> PathBuf {
+        self.state_path.join("containers").join(format!("{}.json", container_id))
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.state_path.join("index.json")
+    }
+
+    pub fn lock(&self) -> Result<StateLock> {
+        let _lock = lock_file(&self.state_path.join("state.lock"), true)?;
+
+        Ok(StateLock { _lock })
+    }
+
+    /// The number of writes made to the store so far.
+    pub fn generation(&self, _lock: &StateLock) -> Result<u64> {
+        Ok(self.read_index()?.generation)
+    }
+
+    /// Reads every container along with the store's generation.
+    pub fn load_all(&self) -> Result<(Vec<Container>, u64)> {
+        let _lock = lock_file(&self.state_path.join("state.lock"), false)?;
+
+        self.read_all()
+    }
+
+    pub fn load_all_locked(&self, _lock: &StateLock) -> Result<(Vec<Container>, u64)> {
+        self.read_all()
+    }
+
+    fn read_all(&self) -> Result<(Vec<Container>, u64)> {
+        let index = self.read_index()?;
+        let mut containers = Vec::with_capacity(index.containers.len());
+
+        for container_id in index.containers.keys() {
+            match fs::read_to_string(self.container_path(container_id)) {
+                Ok(content) => containers.push(serde_json::from_str(&content)?),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    eprintln!("Warning: State file missing for container {}", container_id);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok((containers, index.generation))
+    }
+
+    /// Writes a container, returning the store's new generation.
+    pub fn save(&self, _lock: &StateLock, container: &Container) -> Result<u64> {
+        write_atomic(&self.container_path(&container.id), &serde_json::to_vec_pretty(container)?)?;
+
+        let mut index = self.read_index()?;
+
+        index.containers.insert(container.id.clone(), IndexEntry {
+            name: container.config.name.clone(),
+            created_at: container.created_at,
+        });
+
+        self.write_index(&mut index)
+    }
+
+    /// Removes a container, returning the store's new generation.
+    pub fn remove(&self, _lock: &StateLock, container_id: &str) -> Result<u64> {
+        let mut index = self.read_index()?;
+
+        index.containers.remove(container_id);
+
+        let generation = self.write_index(&mut index)?;
+
+        match fs::remove_file(self.container_path(container_id)) {
+            Ok(()) => Ok(generation),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(generation),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read_index(&self) -> Result<StateIndex> {
+        let index: StateIndex = match fs::read_to_string(self.index_path()) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(StateIndex::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        if index.version != INDEX_VERSION {
+            return Err(TurbineError::StateError(
+                format!("Unsupported state index version {}", index.version)
+            ));
+        }
+
+        Ok(index)
+    }
+
+    fn write_index(&self, index: &mut StateIndex) -> Result<u64> {
+        index.generation += 1;
+        write_atomic(&self.index_path(), &serde_json::to_vec_pretty(index)?)?;
+
+        Ok(index.generation)
+    }
+}
+
+pub(crate) fn lock_file(path: &Path, exclusive: bool) -> Result<Flock<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let arg = if exclusive { FlockArg::LockExclusive } else { FlockArg::LockShared };
+
+    Flock::lock(file, arg)
+        .map_err(|(_, e)| TurbineError::StateError(format!("Failed to lock {:?}: {}", path, e)))
+}
+
+/// Replaces `path` with `contents` so readers only ever observe the old or the new file.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let parent = path.parent().ok_or_else(|| {
+        TurbineError::StateError(format!("Invalid state path: {:?}", path))
+    })?;
+    let file_name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let tmp_path = parent.join(format!(".{}.{}.tmp", file_name, std::process::id()));
+    let mut file = File::create(&tmp_path)?;
+
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+
+    File::open(parent)?.sync_all()?;
+
+    Ok(())
+}